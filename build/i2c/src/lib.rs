@@ -88,6 +88,13 @@ struct I2cDevice {
     /// device is removable
     #[serde(default)]
     removable: bool,
+
+    /// device uses SMBus packet error checking
+    #[serde(default)]
+    pec: bool,
+
+    /// sensors provided by the device, if any
+    sensors: Option<I2cSensors>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -124,6 +131,42 @@ struct I2cPmbus {
     rails: Option<Vec<String>>,
 }
 
+//
+// The number of sensors of each kind that a device provides. Each sensor is
+// given a sensor ID, numbered in the order of the devices.
+//
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct I2cSensors {
+    #[serde(default)]
+    temperature: usize,
+
+    #[serde(default)]
+    voltage: usize,
+
+    #[serde(default)]
+    current: usize,
+
+    #[serde(default)]
+    speed: usize,
+}
+
+impl I2cSensors {
+    //
+    // Returns each kind of sensor (as its name, and that of its variant in
+    // `SensorKind`), along with the number of them; sensor IDs are assigned
+    // in this order within a device.
+    //
+    fn kinds(&self) -> [(&'static str, &'static str, usize); 4] {
+        [
+            ("temperature", "Temperature", self.temperature),
+            ("voltage", "Voltage", self.voltage),
+            ("current", "Current", self.current),
+            ("speed", "Speed", self.speed),
+        ]
+    }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum Artifact {
     /// part of a complete distribution of an application
@@ -143,6 +186,12 @@ pub enum Disposition {
 
     /// only devices are used (i.e., controller is not used)
     Devices,
+
+    /// only sensor IDs are used (i.e., neither controller nor devices are)
+    Sensors,
+
+    /// only the inventory of devices is used
+    Inventory,
 }
 
 struct ConfigGenerator {
@@ -529,6 +578,44 @@ impl ConfigGenerator {
     }
 
     fn generate_device(&self, d: &I2cDevice) -> String {
+        self.generate_device_on(d, "None")
+    }
+
+    ///
+    /// Returns the mux and segment of a device, as an expression for its
+    /// `I2cDevice` -- panicking if they're invalid.
+    ///
+    fn generate_segment(&self, d: &I2cDevice) -> String {
+        match (d.mux, d.segment) {
+            (Some(mux), Some(segment)) => {
+                if !(1..=4).contains(&mux) {
+                    panic!("device {} has invalid mux {}", d.device, mux);
+                }
+
+                if !(1..=8).contains(&segment) {
+                    panic!(
+                        "device {} has invalid segment {}",
+                        d.device, segment
+                    );
+                }
+
+                format!(
+                    "Some((drv_i2c_api::Mux::M{}, drv_i2c_api::Segment::S{}))",
+                    mux, segment
+                )
+            }
+            (None, None) => "None".to_string(),
+            (_, _) => {
+                panic!("device {} must have both mux and segment", d.device);
+            }
+        }
+    }
+
+    ///
+    /// Like `generate_device`, but with `segment` as the device's mux and
+    /// segment.
+    ///
+    fn generate_device_on(&self, d: &I2cDevice, segment: &str) -> String {
         let controller = match &d.bus {
             Some(bus) => self.buses.get(bus).unwrap().0,
             None => d.controller.unwrap(),
@@ -575,12 +662,13 @@ impl ConfigGenerator {
                 PortIndex({port}),
                 {segment},
                 0x{address:x}
-            )"##,
+            ){pec}"##,
             description = d.description,
             controller = controller,
             port = port,
-            segment = "None",
+            segment = segment,
             address = d.address,
+            pec = if d.pec { ".with_pec()" } else { "" },
         )
     }
 
@@ -762,6 +850,228 @@ impl ConfigGenerator {
         Ok(())
     }
 
+    pub fn generate_sensors(&mut self) -> Result<()> {
+        //
+        // Sensor IDs are assigned in device order and, within a device, by
+        // kind.  Along the way, we gather them by device, by named device,
+        // and by PMBus rail -- the latter for devices that have as many
+        // sensors of a kind as they have rails.
+        //
+        let mut kinds = vec![];
+        let mut bydevice = IndexMap::new();
+        let mut byname = IndexMap::new();
+        let mut byrail = IndexMap::new();
+
+        for d in &self.devices {
+            let sensors = match &d.sensors {
+                Some(sensors) => sensors,
+                None => continue,
+            };
+
+            for (kind, variant, count) in sensors.kinds() {
+                if count == 0 {
+                    continue;
+                }
+
+                let ids =
+                    (kinds.len()..kinds.len() + count).collect::<Vec<_>>();
+                kinds.extend(std::iter::repeat(variant).take(count));
+
+                bydevice
+                    .entry((&d.device, kind))
+                    .or_insert_with(Vec::new)
+                    .extend(&ids);
+
+                if let (Some(bus), Some(name)) = (&d.bus, &d.name) {
+                    if byname
+                        .insert((&d.device, bus, name, kind), ids.clone())
+                        .is_some()
+                    {
+                        panic!(
+                            "duplicate name {} for device {} on bus {}",
+                            name, d.device, bus
+                        )
+                    }
+                }
+
+                if let Some(rails) =
+                    d.pmbus.as_ref().and_then(|p| p.rails.as_ref())
+                {
+                    if rails.len() == count {
+                        for (rail, id) in rails.iter().zip(&ids) {
+                            if rail.len() == 0 {
+                                continue;
+                            }
+
+                            byrail.insert((rail, kind), *id);
+                        }
+                    }
+                }
+            }
+        }
+
+        write!(
+            &mut self.output,
+            r##"
+    pub mod sensors {{
+        #[allow(unused_imports)]
+        use task_sensor_api::{{SensorId, SensorKind}};
+
+        #[allow(dead_code)]
+        pub const NUM_SENSORS: usize = {};
+
+        #[allow(dead_code)]
+        pub const SENSOR_KINDS: [SensorKind; {}] = ["##,
+            kinds.len(),
+            kinds.len(),
+        )?;
+
+        for variant in &kinds {
+            write!(
+                &mut self.output,
+                r##"
+            SensorKind::{},"##,
+                variant
+            )?;
+        }
+
+        writeln!(&mut self.output, "\n        ];")?;
+
+        for ((device, kind), ids) in &bydevice {
+            write!(
+                &mut self.output,
+                r##"
+        #[allow(dead_code)]
+        pub const {}_{}_SENSORS: [SensorId; {}] = {};
+"##,
+                device.to_uppercase(),
+                kind.to_uppercase(),
+                ids.len(),
+                sensor_ids(ids),
+            )?;
+        }
+
+        for ((device, bus, name, kind), ids) in &byname {
+            let prefix = format!("{}_{}_{}_{}", device, bus, name, kind);
+
+            if ids.len() == 1 {
+                write!(
+                    &mut self.output,
+                    r##"
+        #[allow(dead_code)]
+        pub const {}_SENSOR: SensorId = SensorId({});
+"##,
+                    prefix.to_uppercase(),
+                    ids[0]
+                )?;
+            } else {
+                write!(
+                    &mut self.output,
+                    r##"
+        #[allow(dead_code)]
+        pub const {}_SENSORS: [SensorId; {}] = {};
+"##,
+                    prefix.to_uppercase(),
+                    ids.len(),
+                    sensor_ids(ids),
+                )?;
+            }
+        }
+
+        for ((rail, kind), id) in &byrail {
+            write!(
+                &mut self.output,
+                r##"
+        #[allow(dead_code)]
+        pub const {}_{}_SENSOR: SensorId = SensorId({});
+"##,
+                rail.to_uppercase(),
+                kind.to_uppercase(),
+                id
+            )?;
+        }
+
+        writeln!(&mut self.output, "    }}")?;
+        Ok(())
+    }
+
+    pub fn generate_inventory(&mut self) -> Result<()> {
+        let len = self.devices.len();
+
+        write!(
+            &mut self.output,
+            r##"
+    pub mod inventory {{
+        #[allow(unused_imports)]
+        use drv_i2c_api::{{I2cDevice, Controller, PortIndex}};
+        use userlib::TaskId;
+
+        /// A device, as described in `[config.i2c.devices]`.
+        #[allow(dead_code)]
+        pub struct Device {{
+            pub device: &'static str,
+            pub description: &'static str,
+            pub refdes: Option<&'static str>,
+            pub removable: bool,
+        }}
+
+        #[allow(dead_code)]
+        pub const NUM_DEVICES: usize = {len};
+
+        #[allow(dead_code)]
+        pub const DEVICES: [Device; {len}] = ["##,
+            len = len,
+        )?;
+
+        for d in &self.devices {
+            write!(
+                &mut self.output,
+                r##"
+            Device {{
+                device: {:?},
+                description: {:?},
+                refdes: {},
+                removable: {},
+            }},"##,
+                d.device,
+                d.description,
+                match &d.refdes {
+                    Some(refdes) => format!("Some({:?})", refdes),
+                    None => "None".to_string(),
+                },
+                d.removable,
+            )?;
+        }
+
+        write!(
+            &mut self.output,
+            r##"
+        ];
+
+        /// Returns each device in `DEVICES`, in the same order. Unlike the
+        /// devices in `devices`, these are behind their muxes and segments.
+        #[allow(dead_code, unused_variables)]
+        pub fn i2c_devices(task: TaskId) -> [I2cDevice; {}] {{
+            ["##,
+            len
+        )?;
+
+        for d in &self.devices {
+            let out = self.generate_device_on(d, &self.generate_segment(d));
+            write!(&mut self.output, "{},", out)?;
+        }
+
+        writeln!(
+            &mut self.output,
+            r##"
+            ]
+        }}
+    }}"##
+        )?;
+
+        Ok(())
+    }
+
     pub fn generate_ports(&mut self) -> Result<()> {
         writeln!(
             &mut self.output,
@@ -802,6 +1112,15 @@ impl ConfigGenerator {
     }
 }
 
+fn sensor_ids(ids: &[usize]) -> String {
+    let ids = ids
+        .iter()
+        .map(|id| format!("SensorId({})", id))
+        .collect::<Vec<_>>();
+
+    format!("[{}]", ids.join(", "))
+}
+
 pub fn codegen(disposition: Disposition, artifact: Artifact) -> Result<()> {
     use std::io::Write;
 
@@ -841,6 +1160,15 @@ pub fn codegen(disposition: Disposition, artifact: Artifact) -> Result<()> {
         Disposition::Devices => {
             g.generate_devices()?;
             g.generate_pmbus()?;
+            g.generate_sensors()?;
+        }
+
+        Disposition::Sensors => {
+            g.generate_sensors()?;
+        }
+
+        Disposition::Inventory => {
+            g.generate_inventory()?;
         }
     }
 
@@ -0,0 +1,979 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Code generation for IPC interfaces.
+//!
+//! An IPC interface is described by a TOML file (by convention, in the
+//! top-level `idl` directory) that looks something like this:
+//!
+//! ```toml
+//! name = "UserLeds"
+//! description = "Driver for dev board user LEDs."
+//!
+//! [ops.led_on]
+//! description = "Turns an LED on by index."
+//! args = { index = "usize" }
+//! error = "LedError"
+//!
+//! [errors.LedError]
+//! description = "Errors returned by the user LEDs driver."
+//! codes = { NoSuchLed = 2 }
+//! ```
+//!
+//! From this, build scripts generate two stubs, to be `include!`d from
+//! `OUT_DIR`:
+//!
+//! - The *client stub*, from `client_stub`, belongs in the interface's API
+//!   crate. It contains the error types, and a handle type named after the
+//!   interface (here, `UserLeds`) with a method for each operation.
+//!
+//! - The *server stub*, from `server_stub`, contains a trait (here,
+//!   `UserLedsServer`) with a method for each operation, and a `dispatch`
+//!   function that receives a message, checks it against the interface, and
+//!   hands it to the trait. The server stub refers to the error types by name,
+//!   so it should be included in a module that imports them from the API
+//!   crate.
+//!
+//! Both stubs refer to `userlib`, `zerocopy` and `num_traits`, which the
+//! including crate must depend on.
+//!
+//! # Operations
+//!
+//! Each entry in `ops` describes an operation. Operation codes are assigned in
+//! order, starting at 1, so new operations should be added at the end. Fields:
+//!
+//! - `description`: becomes the doc comment on the client and server methods.
+//!
+//! - `args`: names and types of the arguments sent in the message. They're
+//!   packed together without padding, so their types must implement
+//!   `zerocopy::AsBytes` and `zerocopy::FromBytes` -- in practice, integers
+//!   and arrays of them.
+//!
+//! - `leases`: names of buffers lent to the server, each with `read = true`
+//!   and/or `write = true` according to what the server may do with it, and
+//!   optionally a `max-len` in bytes. Clients pass a `&[u8]` (for `read` only)
+//!   or a `&mut [u8]`. Servers get an `hl::Borrow`, whose attributes and
+//!   length `dispatch` has already checked.
+//!
+//! - `reply`: type of the reply on success, which must also be `AsBytes` and
+//!   `FromBytes`. Defaults to `()`.
+//!
+//! - `error`: name of the error type, from `errors`. Operations without one
+//!   can't fail.
+//!
+//! - `idempotent`: whether the operation can safely be repeated, which is the
+//!   default. If the server restarts during an idempotent operation, the
+//!   client sends it again; otherwise, the error type must have a
+//!   `ServerRestarted` variant, which is returned instead.
+//!
+//! - `deferred-reply`: whether the server may reply after its method returns,
+//!   for operations that wait on something (like an interrupt). The method is
+//!   given the `hl::Caller` to reply through, rather than the caller's task
+//!   ID and its leases, which it can get from the `Caller`. If it returns an
+//!   error, `dispatch` replies with that; otherwise, replying is up to the
+//!   server. Defaults to false. This makes no difference to clients.
+//!
+//! # Errors
+//!
+//! Each entry in `errors` becomes a `#[repr(u32)]` enum, whose variants and
+//! response codes are given by `codes`. Codes must be between 2 and `0xffff`:
+//! 0 means success, and 1 means that the server didn't understand the message.
+//!
+//! Messages that don't match the interface -- unknown operations, the wrong
+//! size, the wrong number or kind of leases -- get response code 1, and
+//! `dispatch` doesn't bother the trait with them. Clients generated from the
+//! same interface never send such a message, so they treat that code, like
+//! any other they don't expect, as cause to panic.
+
+use anyhow::{anyhow, bail, Context, Result};
+use convert_case::{Case, Casing};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Interface {
+    /// Interface name, in `UpperCamelCase`.
+    name: String,
+    description: String,
+    ops: IndexMap<String, Operation>,
+    #[serde(default)]
+    errors: IndexMap<String, ErrorType>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Operation {
+    description: String,
+    #[serde(default)]
+    args: IndexMap<String, String>,
+    #[serde(default)]
+    leases: IndexMap<String, Lease>,
+    reply: Option<String>,
+    error: Option<String>,
+    #[serde(default = "idempotent_default")]
+    idempotent: bool,
+    #[serde(default)]
+    deferred_reply: bool,
+}
+
+fn idempotent_default() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Lease {
+    #[serde(default)]
+    read: bool,
+    #[serde(default)]
+    write: bool,
+    max_len: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ErrorType {
+    description: String,
+    codes: IndexMap<String, u32>,
+}
+
+/// Response code for messages that don't match the interface, which matches
+/// what `hl::recv` sends for unknown operations.
+const BAD_MESSAGE: u32 = 1;
+
+/// Names that can't be used for arguments, because the generated code uses
+/// them for something else.
+const RESERVED: &[&str] = &[
+    "self", "caller", "args", "leases", "reply", "task", "rc", "len", "g",
+    "info", "result", "server", "msg", "op",
+];
+
+impl Interface {
+    fn load(source: &Path) -> Result<Self> {
+        println!("cargo:rerun-if-changed={}", source.display());
+        let text = fs::read_to_string(source)
+            .with_context(|| format!("reading {}", source.display()))?;
+        let iface: Self = toml::from_str(&text)
+            .with_context(|| format!("parsing {}", source.display()))?;
+        iface
+            .validate()
+            .with_context(|| format!("checking {}", source.display()))?;
+        Ok(iface)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !is_camel(&self.name) {
+            bail!("interface name {} should be UpperCamelCase", self.name);
+        }
+        if self.ops.is_empty() {
+            bail!("interface has no operations");
+        }
+
+        for (name, op) in &self.ops {
+            if !is_snake(name) {
+                bail!("operation name {} should be snake_case", name);
+            }
+            for arg in op.args.keys().chain(op.leases.keys()) {
+                if !is_snake(arg) {
+                    bail!("{}: argument {} should be snake_case", name, arg);
+                }
+                if RESERVED.contains(&arg.as_str()) {
+                    bail!("{}: argument name {} is reserved", name, arg);
+                }
+            }
+            if let Some(arg) =
+                op.args.keys().find(|a| op.leases.contains_key(*a))
+            {
+                bail!("{}: {} is both an argument and a lease", name, arg);
+            }
+            for (lease_name, lease) in &op.leases {
+                if !lease.read && !lease.write {
+                    bail!(
+                        "{}: lease {} must allow read, write, or both",
+                        name,
+                        lease_name
+                    );
+                }
+            }
+            if op.leases.len() > 255 {
+                bail!("{}: too many leases", name);
+            }
+
+            match &op.error {
+                Some(error) => {
+                    let ty = self.errors.get(error).ok_or_else(|| {
+                        anyhow!("{}: unknown error type {}", name, error)
+                    })?;
+                    if !op.idempotent
+                        && !ty.codes.contains_key("ServerRestarted")
+                    {
+                        bail!(
+                            "{}: not idempotent, so {} needs a \
+                             ServerRestarted variant",
+                            name,
+                            error
+                        );
+                    }
+                }
+                None if !op.idempotent => {
+                    bail!(
+                        "{}: not idempotent, so it needs an error type",
+                        name
+                    );
+                }
+                None => (),
+            }
+        }
+
+        for (name, ty) in &self.errors {
+            if !is_camel(name) {
+                bail!("error type name {} should be UpperCamelCase", name);
+            }
+            if ty.codes.is_empty() {
+                bail!("error type {} has no variants", name);
+            }
+            let mut seen = vec![];
+            for (variant, &code) in &ty.codes {
+                if !is_camel(variant) {
+                    bail!("{}::{} should be UpperCamelCase", name, variant);
+                }
+                if !(BAD_MESSAGE + 1..=0xffff).contains(&code) {
+                    bail!(
+                        "{}::{}: code {} is out of range (2..=0xffff)",
+                        name,
+                        variant,
+                        code
+                    );
+                }
+                if seen.contains(&code) {
+                    bail!("{}::{}: code {} is used twice", name, variant, code);
+                }
+                seen.push(code);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn op_enum(&self) -> String {
+        format!("{}Operation", self.name)
+    }
+
+    fn args_struct(&self, op: &str) -> String {
+        format!("{}{}Args", self.name, op.to_case(Case::Pascal))
+    }
+}
+
+impl Operation {
+    fn reply(&self) -> &str {
+        self.reply.as_deref().unwrap_or("()")
+    }
+
+    /// Returns the type of the message: the operation's arguments struct, or
+    /// `()` if it has no arguments.
+    fn message(&self, iface: &Interface, name: &str) -> String {
+        if self.args.is_empty() {
+            "()".to_string()
+        } else {
+            iface.args_struct(name)
+        }
+    }
+}
+
+fn is_camel(s: &str) -> bool {
+    s.to_case(Case::Pascal) == s
+}
+
+fn is_snake(s: &str) -> bool {
+    s.to_case(Case::Snake) == s
+}
+
+/// Writes `text` out as a doc comment, with each line indented by `indent`.
+fn doc(out: &mut String, indent: &str, text: &str) -> Result<()> {
+    for line in text.trim().lines() {
+        if line.trim().is_empty() {
+            writeln!(out, "{}///", indent)?;
+        } else {
+            writeln!(out, "{}/// {}", indent, line.trim_end())?;
+        }
+    }
+    Ok(())
+}
+
+/// Generates the definitions that client and server stubs share: the
+/// operation enum, and the argument structs.
+fn generate_common(out: &mut String, iface: &Interface) -> Result<()> {
+    writeln!(
+        out,
+        "/// Operation codes for the `{}` interface.",
+        iface.name
+    )?;
+    writeln!(
+        out,
+        "#[derive(Copy, Clone, Debug, Eq, PartialEq, userlib::FromPrimitive)]"
+    )?;
+    writeln!(out, "pub enum {} {{", iface.op_enum())?;
+    for (i, name) in iface.ops.keys().enumerate() {
+        writeln!(out, "    {} = {},", name.to_case(Case::Pascal), i + 1)?;
+    }
+    writeln!(out, "}}")?;
+
+    for (name, op) in &iface.ops {
+        if op.args.is_empty() {
+            continue;
+        }
+        writeln!(out)?;
+        writeln!(out, "/// Arguments to `{}::{}`.", iface.name, name)?;
+        writeln!(
+            out,
+            "#[derive(Copy, Clone, zerocopy::AsBytes, zerocopy::FromBytes)]"
+        )?;
+        writeln!(out, "#[repr(C, packed)]")?;
+        writeln!(out, "struct {} {{", iface.args_struct(name))?;
+        for (arg, ty) in &op.args {
+            writeln!(out, "    {}: {},", arg, ty)?;
+        }
+        writeln!(out, "}}")?;
+    }
+
+    Ok(())
+}
+
+fn generate_errors(out: &mut String, iface: &Interface) -> Result<()> {
+    for (name, ty) in &iface.errors {
+        writeln!(out)?;
+        doc(out, "", &ty.description)?;
+        writeln!(out, "#[derive(Copy, Clone, Debug, Eq, PartialEq)]")?;
+        writeln!(out, "#[repr(u32)]")?;
+        writeln!(out, "pub enum {} {{", name)?;
+        for (variant, code) in &ty.codes {
+            writeln!(out, "    {} = {},", variant, code)?;
+        }
+        writeln!(out, "}}")?;
+        writeln!(out)?;
+        writeln!(out, "impl From<{}> for u32 {{", name)?;
+        writeln!(out, "    fn from(e: {}) -> Self {{", name)?;
+        writeln!(out, "        e as u32")?;
+        writeln!(out, "    }}")?;
+        writeln!(out, "}}")?;
+        writeln!(out)?;
+        writeln!(out, "impl core::convert::TryFrom<u32> for {} {{", name)?;
+        writeln!(out, "    type Error = ();")?;
+        writeln!(out)?;
+        writeln!(out, "    fn try_from(code: u32) -> Result<Self, ()> {{")?;
+        writeln!(out, "        match code {{")?;
+        for (variant, code) in &ty.codes {
+            writeln!(out, "            {} => Ok(Self::{}),", code, variant)?;
+        }
+        writeln!(out, "            _ => Err(()),")?;
+        writeln!(out, "        }}")?;
+        writeln!(out, "    }}")?;
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+fn generate_client(iface: &Interface) -> Result<String> {
+    let mut out = String::new();
+    generate_common(&mut out, iface)?;
+    generate_errors(&mut out, iface)?;
+
+    writeln!(out)?;
+    doc(&mut out, "", &iface.description)?;
+    writeln!(out, "///")?;
+    writeln!(
+        out,
+        "/// Client handle for the `{}` interface, which keeps track of the",
+        iface.name
+    )?;
+    writeln!(out, "/// server's generation as it restarts.")?;
+    writeln!(out, "#[derive(Clone, Debug)]")?;
+    writeln!(
+        out,
+        "pub struct {}(core::cell::Cell<userlib::TaskId>);",
+        iface.name
+    )?;
+    writeln!(out)?;
+    writeln!(out, "impl From<userlib::TaskId> for {} {{", iface.name)?;
+    writeln!(out, "    fn from(t: userlib::TaskId) -> Self {{")?;
+    writeln!(out, "        Self(core::cell::Cell::new(t))")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    writeln!(out, "impl {} {{", iface.name)?;
+
+    for (i, (name, op)) in iface.ops.iter().enumerate() {
+        if i != 0 {
+            writeln!(out)?;
+        }
+        generate_client_op(&mut out, iface, name, op)?;
+    }
+
+    writeln!(out, "}}")?;
+    Ok(out)
+}
+
+fn generate_client_op(
+    out: &mut String,
+    iface: &Interface,
+    name: &str,
+    op: &Operation,
+) -> Result<()> {
+    let reply = op.reply();
+
+    doc(out, "    ", &op.description)?;
+    write!(out, "    pub fn {}(&self", name)?;
+    for (arg, ty) in &op.args {
+        write!(out, ", {}: {}", arg, ty)?;
+    }
+    for (lease_name, lease) in &op.leases {
+        let ty = if lease.write { "&mut [u8]" } else { "&[u8]" };
+        write!(out, ", {}: {}", lease_name, ty)?;
+    }
+    match &op.error {
+        Some(error) => writeln!(out, ") -> Result<{}, {}> {{", reply, error)?,
+        None if reply == "()" => writeln!(out, ") {{")?,
+        None => writeln!(out, ") -> {} {{", reply)?,
+    }
+
+    if !op.args.is_empty() {
+        let fields = op.args.keys().cloned().collect::<Vec<_>>();
+        writeln!(
+            out,
+            "        let args = {} {{ {} }};",
+            iface.args_struct(name),
+            fields.join(", ")
+        )?;
+    }
+    if op.leases.is_empty() {
+        writeln!(out, "        let leases: [userlib::Lease<'_>; 0] = [];")?;
+    } else {
+        writeln!(out, "        let leases = [")?;
+        for (lease_name, lease) in &op.leases {
+            let ctor = match (lease.read, lease.write) {
+                (true, true) => "read_write",
+                (false, true) => "write_only",
+                _ => "read_only",
+            };
+            writeln!(
+                out,
+                "            userlib::Lease::{}({}),",
+                ctor, lease_name
+            )?;
+        }
+        writeln!(out, "        ];")?;
+    }
+    writeln!(
+        out,
+        "        let mut reply = <{} as zerocopy::FromBytes>::new_zeroed();",
+        reply
+    )?;
+
+    // Idempotent operations are sent again if the server restarts, so they go
+    // in a loop; the rest return an error.
+    let indent = if op.idempotent {
+        writeln!(out, "        loop {{")?;
+        "            "
+    } else {
+        "        "
+    };
+    let ret = if op.idempotent { "return " } else { "" };
+    let end = if op.idempotent { ";" } else { "" };
+
+    writeln!(out, "{}let task = self.0.get();", indent)?;
+    writeln!(out, "{}let (rc, len) = userlib::sys_send(", indent)?;
+    writeln!(out, "{}    task,", indent)?;
+    writeln!(
+        out,
+        "{}    {}::{} as u16,",
+        indent,
+        iface.op_enum(),
+        name.to_case(Case::Pascal)
+    )?;
+    let args = if op.args.is_empty() { "&()" } else { "&args" };
+    writeln!(out, "{}    zerocopy::AsBytes::as_bytes({}),", indent, args)?;
+    writeln!(
+        out,
+        "{}    zerocopy::AsBytes::as_bytes_mut(&mut reply),",
+        indent
+    )?;
+    writeln!(out, "{}    &leases,", indent)?;
+    writeln!(out, "{});", indent)?;
+    writeln!(
+        out,
+        "{}if let Some(g) = userlib::extract_new_generation(rc) {{",
+        indent
+    )?;
+    writeln!(
+        out,
+        "{}    self.0.set(userlib::TaskId::for_index_and_gen(task.index(), g));",
+        indent
+    )?;
+    match &op.error {
+        _ if op.idempotent => writeln!(out, "{}    continue;", indent)?,
+        Some(error) => writeln!(
+            out,
+            "{}    return Err({}::ServerRestarted);",
+            indent, error
+        )?,
+        None => unreachable!(),
+    }
+    writeln!(out, "{}}}", indent)?;
+    writeln!(out, "{}if rc != 0 {{", indent)?;
+    match &op.error {
+        Some(error) => {
+            writeln!(
+                out,
+                "{}    match <{} as core::convert::TryFrom<u32>>::try_from(rc) {{",
+                indent, error
+            )?;
+            writeln!(out, "{}        Ok(e) => return Err(e),", indent)?;
+            writeln!(out, "{}        Err(()) => panic!(),", indent)?;
+            writeln!(out, "{}    }}", indent)?;
+        }
+        None => writeln!(out, "{}    panic!();", indent)?,
+    }
+    writeln!(out, "{}}}", indent)?;
+    writeln!(
+        out,
+        "{}assert_eq!(len, core::mem::size_of::<{}>());",
+        indent, reply
+    )?;
+    match &op.error {
+        Some(_) => writeln!(out, "{}{}Ok(reply){}", indent, ret, end)?,
+        None if reply == "()" => {
+            if op.idempotent {
+                writeln!(out, "{}return;", indent)?;
+            }
+        }
+        None => writeln!(out, "{}{}reply{}", indent, ret, end)?,
+    }
+
+    if op.idempotent {
+        writeln!(out, "        }}")?;
+    }
+    writeln!(out, "    }}")?;
+    Ok(())
+}
+
+fn generate_server(iface: &Interface) -> Result<String> {
+    let mut out = String::new();
+    generate_common(&mut out, iface)?;
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "/// Size of the buffer `dispatch` needs to receive any `{}` message.",
+        iface.name
+    )?;
+    writeln!(out, "pub const INCOMING_SIZE: usize = {{")?;
+    writeln!(out, "    let sizes = [")?;
+    for (name, op) in &iface.ops {
+        writeln!(
+            out,
+            "        core::mem::size_of::<{}>(),",
+            op.message(iface, name)
+        )?;
+    }
+    writeln!(out, "    ];")?;
+    writeln!(out, "    let mut max = 0;")?;
+    writeln!(out, "    let mut i = 0;")?;
+    writeln!(out, "    while i < sizes.len() {{")?;
+    writeln!(out, "        if sizes[i] > max {{")?;
+    writeln!(out, "            max = sizes[i];")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "        i += 1;")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    max")?;
+    writeln!(out, "}};")?;
+
+    writeln!(out)?;
+    doc(&mut out, "", &iface.description)?;
+    writeln!(out, "///")?;
+    writeln!(
+        out,
+        "/// Server side of the `{}` interface. Each method is given the \
+         caller's",
+        iface.name
+    )?;
+    writeln!(
+        out,
+        "/// task ID, and its result is sent back as the reply once it \
+         returns."
+    )?;
+    writeln!(out, "pub trait {}Server {{", iface.name)?;
+    for (name, op) in &iface.ops {
+        doc(&mut out, "    ", &op.description)?;
+        if op.deferred_reply {
+            writeln!(out, "    ///")?;
+            writeln!(
+                out,
+                "    /// Unless this returns an error, it's up to the server to \
+                 reply"
+            )?;
+            writeln!(out, "    /// through `caller`, now or later.")?;
+            write!(
+                out,
+                "    fn {}(&mut self, caller: userlib::hl::Caller<{}>",
+                name,
+                op.reply()
+            )?;
+        } else {
+            write!(out, "    fn {}(&mut self, caller: userlib::TaskId", name)?;
+        }
+        for (arg, ty) in &op.args {
+            write!(out, ", {}: {}", arg, ty)?;
+        }
+        if !op.deferred_reply {
+            for lease_name in op.leases.keys() {
+                write!(out, ", {}: userlib::hl::Borrow<'_>", lease_name)?;
+            }
+        }
+        match &op.error {
+            Some(error) if op.deferred_reply => {
+                writeln!(out, ") -> Result<(), {}>;", error)?
+            }
+            Some(error) => {
+                writeln!(out, ") -> Result<{}, {}>;", op.reply(), error)?
+            }
+            None if op.deferred_reply || op.reply() == "()" => {
+                writeln!(out, ");")?
+            }
+            None => writeln!(out, ") -> {};", op.reply())?,
+        }
+        writeln!(out)?;
+    }
+    writeln!(
+        out,
+        "    /// Returns the notifications that `dispatch` should accept. By \
+         default,"
+    )?;
+    writeln!(out, "    /// it accepts none.")?;
+    writeln!(out, "    fn notification_mask(&self) -> u32 {{")?;
+    writeln!(out, "        0")?;
+    writeln!(out, "    }}")?;
+    writeln!(out)?;
+    writeln!(out, "    /// Handles notifications received by `dispatch`.")?;
+    writeln!(
+        out,
+        "    fn handle_notification(&mut self, _bits: u32) {{}}"
+    )?;
+    writeln!(out, "}}")?;
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "/// Receives a message or notification, and handles it with `server`."
+    )?;
+    writeln!(
+        out,
+        "pub fn dispatch<S: {}Server>(buffer: &mut [u8; INCOMING_SIZE], \
+         server: &mut S) {{",
+        iface.name
+    )?;
+    writeln!(out, "    let mask = server.notification_mask();")?;
+    writeln!(out, "    userlib::hl::recv(")?;
+    writeln!(out, "        &mut buffer[..],")?;
+    writeln!(out, "        mask,")?;
+    writeln!(out, "        server,")?;
+    writeln!(
+        out,
+        "        |server, bits| server.handle_notification(bits),"
+    )?;
+    writeln!(
+        out,
+        "        |server, op: {}, msg| -> Result<(), u32> {{",
+        iface.op_enum()
+    )?;
+    writeln!(out, "            match op {{")?;
+    for (name, op) in &iface.ops {
+        generate_server_op(&mut out, iface, name, op)?;
+    }
+    writeln!(out, "            }}")?;
+    writeln!(out, "            Ok(())")?;
+    writeln!(out, "        }},")?;
+    writeln!(out, "    );")?;
+    writeln!(out, "}}")?;
+
+    Ok(out)
+}
+
+fn generate_server_op(
+    out: &mut String,
+    iface: &Interface,
+    name: &str,
+    op: &Operation,
+) -> Result<()> {
+    const INDENT: &str = "                    ";
+
+    writeln!(
+        out,
+        "                {}::{} => {{",
+        iface.op_enum(),
+        name.to_case(Case::Pascal)
+    )?;
+    let pattern = if op.args.is_empty() { "()" } else { "args" };
+    writeln!(out, "{}let (&{}, caller) = msg", INDENT, pattern)?;
+    writeln!(
+        out,
+        "{}    .fixed_with_leases::<{}, {}>({})",
+        INDENT,
+        op.message(iface, name),
+        op.reply(),
+        op.leases.len()
+    )?;
+    writeln!(out, "{}    .ok_or({}u32)?;", INDENT, BAD_MESSAGE)?;
+
+    for (i, (lease_name, lease)) in op.leases.iter().enumerate() {
+        writeln!(out, "{}let {} = caller.borrow({});", INDENT, lease_name, i)?;
+        writeln!(
+            out,
+            "{}let info = {}.info().ok_or({}u32)?;",
+            INDENT, lease_name, BAD_MESSAGE
+        )?;
+        let mut checks = vec![];
+        if lease.read {
+            checks.push(
+                "!info.attributes.contains(userlib::LeaseAttributes::READ)"
+                    .to_string(),
+            );
+        }
+        if lease.write {
+            checks.push(
+                "!info.attributes.contains(userlib::LeaseAttributes::WRITE)"
+                    .to_string(),
+            );
+        }
+        if let Some(max) = lease.max_len {
+            checks.push(format!("info.len > {}", max));
+        }
+        writeln!(out, "{}if {} {{", INDENT, checks.join(" || "))?;
+        writeln!(out, "{}    return Err({});", INDENT, BAD_MESSAGE)?;
+        writeln!(out, "{}}}", INDENT)?;
+    }
+
+    if op.deferred_reply {
+        // The leases have been checked, and the server can borrow them again
+        // from the caller, which it now owns.
+        write!(out, "{}server.{}(caller", INDENT, name)?;
+        for arg in op.args.keys() {
+            write!(out, ", args.{}", arg)?;
+        }
+        match &op.error {
+            Some(_) => writeln!(out, ").map_err(u32::from)?;")?,
+            None => writeln!(out, ");")?,
+        }
+        writeln!(out, "                }}")?;
+        return Ok(());
+    }
+
+    // An infallible operation with nothing to return just gets an empty
+    // reply.
+    let unit = op.error.is_none() && op.reply() == "()";
+    let bind = if unit { "" } else { "let result = " };
+    write!(out, "{}{}server.{}(caller.task_id()", INDENT, bind, name)?;
+    for arg in op.args.keys() {
+        write!(out, ", args.{}", arg)?;
+    }
+    for lease_name in op.leases.keys() {
+        write!(out, ", {}", lease_name)?;
+    }
+    writeln!(out, ");")?;
+    match &op.error {
+        Some(_) => {
+            writeln!(out, "{}match result {{", INDENT)?;
+            writeln!(out, "{}    Ok(r) => caller.reply(r),", INDENT)?;
+            writeln!(out, "{}    Err(e) => caller.reply_fail(e),", INDENT)?;
+            writeln!(out, "{}}}", INDENT)?;
+        }
+        None if unit => writeln!(out, "{}caller.reply(());", INDENT)?,
+        None => writeln!(out, "{}caller.reply(result);", INDENT)?,
+    }
+    writeln!(out, "                }}")?;
+    Ok(())
+}
+
+fn write_stub(source: &str, stub_name: &str, text: String) -> Result<()> {
+    let out = Path::new(&env::var("OUT_DIR")?).join(stub_name);
+    let header = format!(
+        "// Generated from {} by build-idl; do not edit.\n\n",
+        Path::new(source).display()
+    );
+    fs::write(&out, header + &text)
+        .with_context(|| format!("writing {}", out.display()))?;
+    Ok(())
+}
+
+/// Generates the client stub for the interface described by `source` (a path
+/// relative to the crate being built), and writes it to `stub_name` in
+/// `OUT_DIR`.
+pub fn client_stub(source: &str, stub_name: &str) -> Result<()> {
+    let iface = Interface::load(Path::new(source))?;
+    write_stub(source, stub_name, generate_client(&iface)?)
+}
+
+/// Generates the server stub for the interface described by `source` (a path
+/// relative to the crate being built), and writes it to `stub_name` in
+/// `OUT_DIR`.
+pub fn server_stub(source: &str, stub_name: &str) -> Result<()> {
+    let iface = Interface::load(Path::new(source))?;
+    write_stub(source, stub_name, generate_server(&iface)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An interface with one of each kind of operation.
+    const FIXTURE: &str = r#"
+        name = "Widget"
+        description = "A widget."
+
+        [ops.ping]
+        description = "Does nothing."
+
+        [ops.read]
+        description = "Reads a register."
+        args = { reg = "u8" }
+        reply = "u32"
+        error = "WidgetError"
+
+        [ops.write]
+        description = "Writes a register."
+        args = { reg = "u8", value = "u32" }
+        error = "WidgetError"
+        idempotent = false
+
+        [ops.wait]
+        description = "Waits for an interrupt, then fills in a buffer."
+        leases = { buf = { write = true, max-len = 16 } }
+        reply = "usize"
+        error = "WidgetError"
+        deferred-reply = true
+
+        [errors.WidgetError]
+        description = "Widget errors."
+        codes = { Busy = 2, ServerRestarted = 3 }
+    "#;
+
+    fn parse(text: &str) -> Result<Interface> {
+        let iface: Interface = toml::from_str(text)?;
+        iface.validate()?;
+        Ok(iface)
+    }
+
+    /// Returns the part of `text` from `start` up to and including the next
+    /// `end`.
+    fn section<'a>(text: &'a str, start: &str, end: &str) -> &'a str {
+        let from = text
+            .find(start)
+            .unwrap_or_else(|| panic!("{:?} not found in:\n{}", start, text));
+        let len = text[from..]
+            .find(end)
+            .unwrap_or_else(|| panic!("{:?} not found after {:?}", end, start));
+        &text[from..from + len + end.len()]
+    }
+
+    #[test]
+    fn operations_are_numbered_in_order() {
+        let iface = parse(FIXTURE).unwrap();
+        let mut common = String::new();
+        generate_common(&mut common, &iface).unwrap();
+        assert!(common.contains(
+            "pub enum WidgetOperation {\n    \
+             Ping = 1,\n    Read = 2,\n    Write = 3,\n    Wait = 4,\n}"
+        ));
+
+        // Both stubs use the same enum.
+        let client = generate_client(&iface).unwrap();
+        let server = generate_server(&iface).unwrap();
+        assert!(client.starts_with(&common));
+        assert!(server.starts_with(&common));
+        assert!(client.contains("WidgetOperation::Write as u16"));
+        assert!(server.contains("WidgetOperation::Write => {"));
+    }
+
+    #[test]
+    fn idempotent_operations_retry() {
+        let client = generate_client(&parse(FIXTURE).unwrap()).unwrap();
+        let read = section(&client, "pub fn read(", "\n    }\n");
+        assert!(read.contains("-> Result<u32, WidgetError> {"));
+        assert!(read.contains("loop {"));
+        assert!(read.contains(
+            "self.0.set(userlib::TaskId::for_index_and_gen(task.index(), g));\n\
+             \x20               continue;"
+        ));
+        assert!(!read.contains("ServerRestarted"));
+
+        let ping = section(&client, "pub fn ping(", "\n    }\n");
+        assert!(ping.contains("loop {"));
+        assert!(ping.contains("continue;"));
+    }
+
+    #[test]
+    fn non_idempotent_operations_report_restart() {
+        let client = generate_client(&parse(FIXTURE).unwrap()).unwrap();
+        let write = section(&client, "pub fn write(", "\n    }\n");
+        assert!(write.contains("(&self, reg: u8, value: u32)"));
+        assert!(!write.contains("loop {"));
+        assert!(!write.contains("continue;"));
+        assert!(write.contains("return Err(WidgetError::ServerRestarted);"));
+    }
+
+    #[test]
+    fn non_idempotent_operations_need_server_restarted() {
+        let missing = FIXTURE.replace(", ServerRestarted = 3", "");
+        let err = parse(&missing).unwrap_err().to_string();
+        assert!(err.contains("ServerRestarted"), "{}", err);
+
+        let infallible = FIXTURE.replace("error = \"WidgetError\"\n", "");
+        let err = parse(&infallible).unwrap_err().to_string();
+        assert!(err.contains("needs an error type"), "{}", err);
+
+        // Without the non-idempotent operation, neither is needed.
+        let idempotent = missing.replace("idempotent = false", "");
+        parse(&idempotent).unwrap();
+    }
+
+    #[test]
+    fn deferred_reply_gets_caller() {
+        const ARM_END: &str = "\n                }\n";
+        let server = generate_server(&parse(FIXTURE).unwrap()).unwrap();
+
+        // The method gets the `Caller` in place of the task ID and leases,
+        // and has no reply of its own.
+        assert!(server.contains(
+            "fn wait(&mut self, caller: userlib::hl::Caller<usize>) \
+             -> Result<(), WidgetError>;"
+        ));
+        assert!(server.contains(
+            "fn read(&mut self, caller: userlib::TaskId, reg: u8) \
+             -> Result<u32, WidgetError>;"
+        ));
+
+        // `dispatch` still checks the lease, but leaves replying to the
+        // server unless it fails.
+        let wait = section(&server, "WidgetOperation::Wait => {", ARM_END);
+        assert!(wait.contains(".fixed_with_leases::<(), usize>(1)"));
+        assert!(wait.contains("info.len > 16"));
+        assert!(wait.contains("server.wait(caller).map_err(u32::from)?;"));
+        assert!(!wait.contains("caller.reply"));
+
+        let read = section(&server, "WidgetOperation::Read => {", ARM_END);
+        assert!(read.contains("server.read(caller.task_id(), args.reg);"));
+        assert!(read.contains("Err(e) => caller.reply_fail(e),"));
+
+        // Clients can't tell the difference.
+        let client = generate_client(&parse(FIXTURE).unwrap()).unwrap();
+        let wait = section(&client, "pub fn wait(", "\n    }\n");
+        assert!(wait.contains("(&self, buf: &mut [u8]) -> Result<usize"));
+        assert!(wait.contains("userlib::Lease::write_only(buf),"));
+        assert!(wait.contains("loop {"));
+    }
+}
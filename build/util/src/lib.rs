@@ -50,3 +50,19 @@ pub fn config<T: DeserializeOwned>() -> Result<T> {
     println!("cargo:rerun-if-env-changed=HUBRIS_APP_CONFIG");
     Ok(rval)
 }
+
+///
+/// Pulls the task-specific configuration (the `config` table of the task
+/// being built, in `app.toml`) for purposes of a build task.  Unlike the
+/// app-wide configuration, it's perfectly normal for a task to have no
+/// configuration at all, in which case this returns `Ok(None)`; it will fail
+/// only if the configuration is present but can't parse as a `T`.
+///
+pub fn task_config<T: DeserializeOwned>() -> Result<Option<T>> {
+    println!("cargo:rerun-if-env-changed=HUBRIS_TASK_CONFIG");
+    match env::var("HUBRIS_TASK_CONFIG") {
+        Ok(config) => Ok(Some(toml::from_slice(config.as_bytes())?)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -17,7 +17,7 @@ use indexmap::IndexMap;
 use path_slash::PathBufExt;
 
 use crate::{
-    elf, task_slot, Config, LoadSegment, Output, Peripheral, Signing,
+    elf, task_slot, Config, LoadSegment, Output, Peripheral, Shared, Signing,
     Supervisor, Task,
 };
 
@@ -95,7 +95,8 @@ pub fn package(
     let starting_memories = memories.clone();
 
     // Allocate memories.
-    let allocs = allocate_all(&toml.kernel, &toml.tasks, &mut memories)?;
+    let allocs =
+        allocate_all(&toml.kernel, &toml.tasks, &toml.shared, &mut memories)?;
 
     println!("Used:");
     for (name, new_range) in &memories {
@@ -106,6 +107,7 @@ pub fn package(
     let mut infofile = File::create(out.join("allocations.txt"))?;
     writeln!(infofile, "kernel: {:#x?}", allocs.kernel)?;
     writeln!(infofile, "tasks: {:#x?}", allocs.tasks)?;
+    writeln!(infofile, "shared: {:#x?}", allocs.shared)?;
     drop(infofile);
 
     // Build each task.
@@ -266,10 +268,19 @@ pub fn package(
         }
         let task_toml = &toml.tasks[name];
 
+        // Shared regions this task can get at.
+        let shared = toml
+            .shared
+            .iter()
+            .filter(|(_, region)| region.tasks().any(|(t, _)| t == name))
+            .map(|(region, _)| (region.as_str(), allocs.shared[region].clone()))
+            .collect::<Vec<_>>();
+
         generate_task_linker_script(
             "memory.x",
             &allocs.tasks[name],
             Some(&task_toml.sections),
+            &shared,
             task_toml.stacksize.or(toml.stacksize).ok_or_else(|| {
                 anyhow!(
                     "{}: no stack size specified and there is no default",
@@ -328,6 +339,8 @@ pub fn package(
         &toml.peripherals,
         toml.supervisor.as_ref(),
         &allocs.tasks,
+        &toml.shared,
+        &allocs.shared,
         toml.stacksize,
         &toml.outputs,
         &entry_points,
@@ -740,6 +753,7 @@ fn generate_task_linker_script(
     name: &str,
     map: &BTreeMap<String, Range<u32>>,
     sections: Option<&IndexMap<String, String>>,
+    shared: &[(&str, Range<u32>)],
     stacksize: u32,
 ) -> Result<()> {
     // Put the linker script somewhere the linker can find it
@@ -779,6 +793,10 @@ fn generate_task_linker_script(
 
         emit(&mut linkscr, &name, start, end - start)?;
     }
+    for (name, range) in shared {
+        let name = format!("SHARED_{}", name.to_ascii_uppercase());
+        emit(&mut linkscr, &name, range.start, range.end - range.start)?;
+    }
     writeln!(linkscr, "}}")?;
 
     // The task may have defined additional section-to-memory mappings.
@@ -792,6 +810,19 @@ fn generate_task_linker_script(
         writeln!(linkscr, "}} INSERT BEFORE .got")?;
     }
 
+    // Each shared region gets a section of its own, `.shared_<name>`, so that
+    // every task using it can place a static there.
+    if !shared.is_empty() {
+        writeln!(linkscr, "SECTIONS {{")?;
+        for (name, _) in shared {
+            let section = format!("shared_{}", name);
+            writeln!(linkscr, "  .{} (NOLOAD) : ALIGN(4) {{", section)?;
+            writeln!(linkscr, "    *(.{} .{}.*);", section, section)?;
+            writeln!(linkscr, "  }} > {}", section.to_ascii_uppercase())?;
+        }
+        writeln!(linkscr, "}} INSERT BEFORE .got")?;
+    }
+
     Ok(())
 }
 
@@ -998,12 +1029,51 @@ fn build(
     Ok(())
 }
 
+/// Works out where each task's stack lives, by repeating the allocation that
+/// `package` does for the same config. Stacks sit at the bottom of each task's
+/// RAM allocation. The result maps task name to stack address range.
+pub fn task_stacks(toml: &Config) -> Result<IndexMap<String, Range<u32>>> {
+    let mut memories = IndexMap::new();
+    for (name, out) in &toml.outputs {
+        let end = out.address.checked_add(out.size).ok_or_else(|| {
+            anyhow!(
+                "output {}: address {:08x} size {:x} would overflow",
+                name,
+                out.address,
+                out.size
+            )
+        })?;
+        memories.insert(name.clone(), out.address..end);
+    }
+    let allocs =
+        allocate_all(&toml.kernel, &toml.tasks, &toml.shared, &mut memories)?;
+
+    let mut stacks = IndexMap::new();
+    for (name, task) in &toml.tasks {
+        let stacksize = task.stacksize.or(toml.stacksize).ok_or_else(|| {
+            anyhow!("{}: no stack size specified and there is no default", name)
+        })?;
+        let base = allocs.tasks[name]["ram"].start;
+        stacks.insert(name.clone(), base..base + stacksize);
+    }
+    Ok(stacks)
+}
+
 #[derive(Debug, Clone, Default)]
 struct Allocations {
     /// Map from memory-name to address-range
     kernel: BTreeMap<String, Range<u32>>,
     /// Map from task-name to memory-name to address-range
     tasks: BTreeMap<String, BTreeMap<String, Range<u32>>>,
+    /// Map from shared-region-name to address-range
+    shared: BTreeMap<String, Range<u32>>,
+}
+
+/// Something asking for memory from `allocate_all`, other than the kernel.
+#[derive(Copy, Clone, Debug)]
+enum Requester<'a> {
+    Task(&'a str),
+    Shared(&'a str),
 }
 
 /// Allocates address space from all regions for the kernel and all tasks.
@@ -1043,6 +1113,7 @@ struct Allocations {
 fn allocate_all(
     kernel: &crate::Kernel,
     tasks: &IndexMap<String, crate::Task>,
+    shared: &IndexMap<String, Shared>,
     free: &mut IndexMap<String, Range<u32>>,
 ) -> Result<Allocations> {
     // Collect all allocation requests into queues, one per memory type, indexed
@@ -1052,8 +1123,9 @@ fn allocate_all(
     // We keep kernel and task requests separate so we can always service the
     // kernel first.
     //
-    // The task map is: memory name -> allocation size -> queue of task name.
-    // The kernel map is: memory name -> allocation size
+    // The task map is: memory name -> allocation size -> queue of requester
+    // (a task, or a shared region). The kernel map is: memory name ->
+    // allocation size
     let kernel_requests = &kernel.requires;
     for (name, &amt) in kernel_requests {
         if !amt.is_power_of_two() {
@@ -1062,7 +1134,7 @@ fn allocate_all(
         }
     }
 
-    let mut task_requests: BTreeMap<&str, BTreeMap<u32, VecDeque<&str>>> =
+    let mut task_requests: BTreeMap<&str, BTreeMap<u32, VecDeque<Requester>>> =
         BTreeMap::new();
 
     for (name, task) in tasks {
@@ -1076,10 +1148,42 @@ fn allocate_all(
                 .or_default()
                 .entry(amt)
                 .or_default()
-                .push_back(name.as_str());
+                .push_back(Requester::Task(name.as_str()));
         }
     }
 
+    for (name, region) in shared {
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("shared region {}: name must be alphanumeric or _", name);
+        }
+        if !region.size.is_power_of_two() {
+            bail!(
+                "shared region {}: size {} is not a power of two.",
+                name,
+                region.size
+            );
+        }
+        if !free.contains_key(&region.memory) {
+            bail!("shared region {}: no memory named {}", name, region.memory);
+        }
+        let mut seen = vec![];
+        for (task, _) in region.tasks() {
+            if !tasks.contains_key(task) {
+                bail!("shared region {}: unknown task {}", name, task);
+            }
+            if seen.contains(&task) {
+                bail!("shared region {}: task {} listed twice", name, task);
+            }
+            seen.push(task);
+        }
+        task_requests
+            .entry(region.memory.as_str())
+            .or_default()
+            .entry(region.size)
+            .or_default()
+            .push_back(Requester::Shared(name.as_str()));
+    }
+
     // Okay! Do memory types one by one, fitting kernel first.
     let mut allocs = Allocations::default();
     for (region, avail) in free {
@@ -1087,7 +1191,7 @@ fn allocate_all(
         let mut t_reqs = task_requests.get_mut(region.as_str());
 
         fn reqs_map_not_empty(
-            om: &Option<&mut BTreeMap<u32, VecDeque<&str>>>,
+            om: &Option<&mut BTreeMap<u32, VecDeque<Requester>>>,
         ) -> bool {
             om.iter()
                 .flat_map(|map| map.values())
@@ -1121,31 +1225,19 @@ fn allocate_all(
 
             if let Some(t_reqs) = t_reqs.as_mut() {
                 for (&sz, q) in t_reqs.range_mut(..=align).rev() {
-                    if let Some(task) = q.pop_front() {
+                    if let Some(requester) = q.pop_front() {
                         // We can pack an equal or smaller one in.
-                        allocs
-                            .tasks
-                            .entry(task.to_string())
-                            .or_default()
-                            .insert(
-                                region.to_string(),
-                                allocate_one(region, sz, avail)?,
-                            );
+                        let range = allocate_one(region, sz, avail)?;
+                        allocs.insert(requester, region, range);
                         continue 'fitloop;
                     }
                 }
 
                 for (&sz, q) in t_reqs.range_mut(align + 1..) {
-                    if let Some(task) = q.pop_front() {
+                    if let Some(requester) = q.pop_front() {
                         // We've gotta use a larger one.
-                        allocs
-                            .tasks
-                            .entry(task.to_string())
-                            .or_default()
-                            .insert(
-                                region.to_string(),
-                                allocate_one(region, sz, avail)?,
-                            );
+                        let range = allocate_one(region, sz, avail)?;
+                        allocs.insert(requester, region, range);
                         continue 'fitloop;
                     }
                 }
@@ -1161,6 +1253,28 @@ fn allocate_all(
     Ok(allocs)
 }
 
+impl Allocations {
+    /// Records `range`, allocated from memory `region`, for `requester`.
+    fn insert(
+        &mut self,
+        requester: Requester,
+        region: &str,
+        range: Range<u32>,
+    ) {
+        match requester {
+            Requester::Task(task) => {
+                self.tasks
+                    .entry(task.to_string())
+                    .or_default()
+                    .insert(region.to_string(), range);
+            }
+            Requester::Shared(name) => {
+                self.shared.insert(name.to_string(), range);
+            }
+        }
+    }
+}
+
 fn allocate_one(
     region: &str,
     size: u32,
@@ -1228,6 +1342,8 @@ fn make_descriptors(
     peripherals: &IndexMap<String, Peripheral>,
     supervisor: Option<&Supervisor>,
     task_allocations: &BTreeMap<String, BTreeMap<String, Range<u32>>>,
+    shared: &IndexMap<String, Shared>,
+    shared_allocations: &BTreeMap<String, Range<u32>>,
     stacksize: Option<u32>,
     outputs: &IndexMap<String, Output>,
     entry_points: &HashMap<String, u32>,
@@ -1300,6 +1416,43 @@ fn make_descriptors(
         });
     }
 
+    // Shared regions come next. Each gets up to two descriptors, one for the
+    // tasks that can write it and one for those that can only read it. This
+    // maps each task using a shared region to the index of its descriptor.
+    let mut shared_index: IndexMap<&str, Vec<usize>> = IndexMap::new();
+
+    for (name, region) in shared.iter() {
+        let range = &shared_allocations[name];
+        let out = &outputs[&region.memory];
+
+        for writable in [true, false] {
+            let mut users =
+                region.tasks().filter(|&(_, w)| w == writable).peekable();
+            if users.peek().is_none() {
+                continue;
+            }
+
+            let mut attributes = abi::RegionAttributes::READ;
+            if writable {
+                attributes |= abi::RegionAttributes::WRITE;
+            }
+            if out.dma {
+                attributes |= abi::RegionAttributes::DMA;
+            }
+
+            for (task, _) in users {
+                shared_index.entry(task).or_default().push(regions.len());
+            }
+
+            regions.push(abi::RegionDesc {
+                base: range.start,
+                size: range.end - range.start,
+                attributes,
+                reserved_zero: 0,
+            });
+        }
+    }
+
     // The remaining regions are allocated to tasks on a first-come first-serve
     // basis.
     for (i, (name, task)) in tasks.iter().enumerate() {
@@ -1312,15 +1465,22 @@ fn make_descriptors(
         }
 
         // Regions are referenced by index into the table we just generated.
-        // Each task has up to 8, chosen from its 'requires' and 'uses' keys.
+        // Each task has up to 8, chosen from its 'requires' and 'uses' keys
+        // and the shared regions it's been given.
         let mut task_regions = [0; 8];
 
-        if task.uses.len() + task.requires.len() > 8 {
+        let task_shared = shared_index
+            .get(name.as_str())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if task.uses.len() + task.requires.len() + task_shared.len() > 8 {
             panic!(
-                "task {} uses {} peripherals and {} memories (too many)",
+                "task {} uses {} peripherals, {} memories, and {} shared \
+                 regions (too many)",
                 name,
                 task.uses.len(),
-                task.requires.len()
+                task.requires.len(),
+                task_shared.len()
             );
         }
 
@@ -1355,12 +1515,16 @@ fn make_descriptors(
             });
         }
 
-        // For peripherals referenced by the task, we don't need to allocate
-        // _new_ regions, since we did them all in advance. Just record the
-        // entries for the TaskDesc.
+        // For peripherals and shared regions referenced by the task, we don't
+        // need to allocate _new_ regions, since we did them all in advance.
+        // Just record the entries for the TaskDesc.
+        for (j, &region) in task_shared.iter().enumerate() {
+            task_regions[allocs.len() + j] = region as u8;
+        }
+        let first_peripheral = allocs.len() + task_shared.len();
         for (j, peripheral_name) in task.uses.iter().enumerate() {
             if let Some(&peripheral) = peripheral_index.get(&peripheral_name) {
-                task_regions[allocs.len() + j] = peripheral as u8;
+                task_regions[first_peripheral + j] = peripheral as u8;
             } else {
                 bail!(
                     "Could not find peripheral `{}` referenced by task `{}`.",
@@ -1375,6 +1539,17 @@ fn make_descriptors(
             flags |= abi::TaskFlags::START_AT_BOOT;
         }
 
+        if name.len() > abi::TASK_NAME_LEN {
+            bail!(
+                "task name `{}` is too long ({} bytes, max {})",
+                name,
+                name.len(),
+                abi::TASK_NAME_LEN
+            );
+        }
+        let mut task_name = [0; abi::TASK_NAME_LEN];
+        task_name[..name.len()].copy_from_slice(name.as_bytes());
+
         task_descs.push(abi::TaskDesc {
             regions: task_regions,
             entry_point: entry_points[name],
@@ -1382,6 +1557,7 @@ fn make_descriptors(
                 + task.stacksize.unwrap_or(stacksize.unwrap()),
             priority: task.priority,
             flags,
+            name: task_name,
         });
 
         // Interrupts.
@@ -1454,6 +1630,13 @@ fn make_descriptors(
         words.push(tdesc.initial_stack);
         words.push(tdesc.priority);
         words.push(tdesc.flags.bits());
+
+        // Name, packed little-endian
+        for chunk in tdesc.name.chunks(4) {
+            words.push(u32::from_le_bytes([
+                chunk[0], chunk[1], chunk[2], chunk[3],
+            ]));
+        }
     }
 
     // Flatten interrupt response records.
@@ -19,6 +19,7 @@ mod flash;
 mod gdb;
 mod humility;
 mod license;
+mod stack;
 mod task_slot;
 mod test;
 
@@ -88,6 +89,14 @@ enum Xtask {
         options: Vec<String>,
     },
 
+    /// Reads each task's stack from an attached target using `humility`, and
+    /// reports the deepest stack usage since the task was started. The image
+    /// on the target must match the one built from `cfg`.
+    StackUsage {
+        /// Path to the image configuration file, in TOML.
+        cfg: PathBuf,
+    },
+
     /// Runs `xtask dist`, `xtask flash` and then `humility test`
     Test {
         /// Path to the image configuration file, in TOML.
@@ -164,6 +173,8 @@ struct Config {
     peripherals: IndexMap<String, Peripheral>,
     #[serde(default)]
     extratext: IndexMap<String, Peripheral>,
+    #[serde(default)]
+    shared: IndexMap<String, Shared>,
     supervisor: Option<Supervisor>,
     #[serde(default)]
     config: Option<toml::Value>,
@@ -306,6 +317,33 @@ struct Peripheral {
     size: u32,
 }
 
+/// A region of memory shared between specific tasks, from `[shared.<name>]`
+/// in `app.toml`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Shared {
+    /// Output (e.g. `ram`) to allocate the region from.
+    memory: String,
+    size: u32,
+    /// Tasks that can read and write the region.
+    #[serde(default)]
+    read_write: Vec<String>,
+    /// Tasks that can only read it.
+    #[serde(default)]
+    read_only: Vec<String>,
+}
+
+impl Shared {
+    /// Lists the tasks that can access the region, with whether they can
+    /// write it.
+    fn tasks(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.read_write
+            .iter()
+            .map(|t| (t.as_str(), true))
+            .chain(self.read_only.iter().map(|t| (t.as_str(), false)))
+    }
+}
+
 struct LoadSegment {
     source_file: PathBuf,
     data: Vec<u8>,
@@ -411,6 +449,9 @@ fn main() -> Result<()> {
         Xtask::Humility { cfg, options } => {
             humility::run(&cfg, &options)?;
         }
+        Xtask::StackUsage { cfg } => {
+            stack::run(&cfg)?;
+        }
         Xtask::Test {
             cfg,
             noflash,
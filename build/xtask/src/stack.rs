@@ -0,0 +1,121 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reports how much of each task's stack has been used on an attached target.
+//!
+//! The kernel paints each task's stack with `abi::STACK_PAINT` whenever it
+//! starts the task. We read the stacks back through Humility and look for the
+//! lowest word that has been overwritten.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+use crate::{dist, Config};
+
+/// Number of bytes we ask Humility for at a time. Stacks are scanned from the
+/// bottom up, stopping at the first overwritten word, so a stack with modest
+/// headroom only takes a read or two.
+const CHUNK: u32 = 256;
+
+pub fn run(cfg: &Path) -> Result<()> {
+    let cfg_contents = std::fs::read(&cfg)?;
+    let toml: Config = toml::from_slice(&cfg_contents)?;
+
+    let mut archive = PathBuf::from("target");
+    archive.push(&toml.name);
+    archive.push("dist");
+    archive.push(format!("build-{}.zip", &toml.name));
+
+    let stacks = dist::task_stacks(&toml)?;
+
+    println!(
+        "{:16} {:>10} {:>6} {:>6} {:>4}",
+        "TASK", "BASE", "SIZE", "USED", "USE%"
+    );
+    for (name, stack) in &stacks {
+        let size = stack.end - stack.start;
+        let used = size - untouched_bytes(&archive, stack.start, size)?;
+        println!(
+            "{:16} {:#010x} {:>6} {:>6} {:>3}%",
+            name,
+            stack.start,
+            size,
+            used,
+            used * 100 / size.max(1)
+        );
+    }
+
+    Ok(())
+}
+
+/// Counts the bytes at the bottom of the stack at `base` that still hold the
+/// paint pattern.
+fn untouched_bytes(archive: &Path, base: u32, size: u32) -> Result<u32> {
+    let mut offset = 0;
+    while offset < size {
+        let len = CHUNK.min(size - offset);
+        let words = readmem(archive, base + offset, len)?;
+        if words.len() * 4 != len as usize {
+            bail!(
+                "asked humility for {} bytes at {:#x}, got {} words",
+                len,
+                base + offset,
+                words.len()
+            );
+        }
+        for word in words {
+            if word != abi::STACK_PAINT {
+                return Ok(offset);
+            }
+            offset += 4;
+        }
+    }
+    Ok(size)
+}
+
+/// Reads `len` bytes of target memory starting at `addr`, as words.
+fn readmem(archive: &Path, addr: u32, len: u32) -> Result<Vec<u32>> {
+    let mut humility = Command::new("humility");
+    humility
+        .arg("-a")
+        .arg(archive)
+        .arg("readmem")
+        .arg("-w")
+        .arg(format!("{:#x}", addr))
+        .arg(len.to_string());
+
+    let output = humility
+        .output()
+        .with_context(|| format!("failed to run humility ({:?})", humility))?;
+
+    if !output.status.success() {
+        bail!(
+            "humility readmem failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    // Data rows look like `0x20001000 | baddcafe baddcafe ...`; everything
+    // else (the column header, mostly) is ignored.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut words = vec![];
+    for line in stdout.lines() {
+        let mut fields = line.splitn(2, '|');
+        let (row, data) = match (fields.next(), fields.next()) {
+            (Some(row), Some(data)) => (row, data),
+            _ => continue,
+        };
+        if !row.trim().starts_with("0x") {
+            continue;
+        }
+        for word in data.split_whitespace() {
+            words.push(u32::from_str_radix(word, 16).with_context(|| {
+                format!("unexpected humility output: {:?}", line)
+            })?);
+        }
+    }
+    Ok(words)
+}
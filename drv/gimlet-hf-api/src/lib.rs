@@ -28,6 +28,8 @@ pub enum Operation {
 pub enum HfError {
     WriteEnableFailed = 1,
     ServerRestarted = 2,
+    /// DMA failed partway through a read or write.
+    DmaError = 3,
 }
 
 impl From<HfError> for u32 {
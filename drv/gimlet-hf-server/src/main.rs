@@ -12,6 +12,7 @@
 
 use userlib::*;
 
+use drv_stm32h7_dma as dma;
 use drv_stm32h7_gpio_api as gpio_api;
 use drv_stm32h7_qspi::Qspi;
 use drv_stm32h7_rcc_api as rcc_api;
@@ -30,6 +31,7 @@ task_slot!(RCC, rcc_driver);
 task_slot!(GPIO, gpio_driver);
 
 const QSPI_IRQ: u32 = 1;
+const MDMA_IRQ: u32 = 2;
 
 #[export_name = "main"]
 fn main() -> ! {
@@ -39,8 +41,14 @@ fn main() -> ! {
     rcc_driver.enable_clock(rcc_api::Peripheral::QuadSpi);
     rcc_driver.leave_reset(rcc_api::Peripheral::QuadSpi);
 
+    // We only use MDMA channel 0, so it's ours alone. The controller isn't
+    // reset, in case anyone else is using other channels.
+    rcc_driver.enable_clock(rcc_api::Peripheral::Mdma);
+
     let reg = unsafe { &*device::QUADSPI::ptr() };
-    let qspi = Qspi::new(reg, QSPI_IRQ);
+    let mut qspi = Qspi::new(reg, QSPI_IRQ);
+    let mdma = unsafe { &*device::MDMA::ptr() };
+    qspi.enable_dma(dma::Channel::new(mdma, 0), MDMA_IRQ);
     // Board specific goo
     cfg_if::cfg_if! {
         if #[cfg(target_board = "gimlet-1")] {
@@ -357,11 +365,12 @@ fn main() -> ! {
                     .read_fully_at(0, &mut block[..info.len])
                     .ok_or(InternalHfError::BadLease)?;
 
-                // Now we can't fail.
-
                 set_and_check_write_enable(&qspi)?;
-                qspi.page_program(addr, &block[..info.len]);
+                let programmed = qspi
+                    .page_program(addr, &block[..info.len])
+                    .map_err(|_| HfError::DmaError);
                 poll_for_write_complete(&qspi);
+                programmed?;
                 caller.reply(());
                 Ok::<_, InternalHfError>(())
             }
@@ -380,7 +389,8 @@ fn main() -> ! {
                     return Err(InternalHfError::BadLease);
                 }
 
-                qspi.read_memory(addr, &mut block[..info.len]);
+                qspi.read_memory(addr, &mut block[..info.len])
+                    .map_err(|_| HfError::DmaError)?;
 
                 // Throw away an error here since it means the caller's
                 // wandered off
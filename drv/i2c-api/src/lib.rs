@@ -19,6 +19,24 @@
 //! - The segment on the multiplexer, if a multiplexer is specified
 //! - The address of the device itself
 //!
+//! # Transactions
+//!
+//! Each of the [`I2cDevice`] operations is a separate message to the I2C
+//! server, which selects the device's port and mux segment before each one.
+//! To perform several transfers in one message -- and so without any other
+//! traffic on the bus between them -- build a [`Transaction`] and pass it to
+//! [`I2cDevice::transaction`].
+//!
+//! # Packet error checking
+//!
+//! SMBus devices may support packet error checking (PEC): a CRC-8 over every
+//! byte of a transfer (including the address bytes), sent by the controller
+//! after the last byte of a write and by the device after the last byte of a
+//! read. If a device is marked as using PEC -- with `pec = true` in its entry
+//! in `[config.i2c.devices]`, or with [`I2cDevice::with_pec`] -- the server
+//! generates the PEC for each of its writes and checks it on each of its
+//! reads, failing with [`ResponseCode::PecMismatch`] if it's wrong.
+//!
 
 #![no_std]
 
@@ -30,10 +48,82 @@ use userlib::*;
 pub enum Op {
     WriteRead = 1,
     WriteReadBlock = 2,
+    Transaction = 3,
+    BlockProcessCall = 4,
+    Scan = 5,
+}
+
+/// The kind of each transfer in a [`Transaction`].
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq)]
+#[repr(u8)]
+pub enum TransferKind {
+    /// A write and/or a read of a fixed length, as with [`Op::WriteRead`]
+    WriteRead = 1,
+    /// A write and/or an SMBus block read, as with [`Op::WriteReadBlock`]
+    WriteReadBlock = 2,
+}
+
+/// Length of the header of each transfer in a [`Transaction`]; see
+/// [`TransferHeader`].
+pub const TRANSFER_HEADER_LEN: usize = 6;
+
+///
+/// The header of each transfer in a [`Transaction`], which is followed in the
+/// transaction's buffer by the transfer's write data and then room for its
+/// read data. The client fills in the kind, address and lengths; the server
+/// fills in the rest as it performs the transfer.
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TransferHeader {
+    pub kind: TransferKind,
+    pub address: u8,
+    pub wlen: usize,
+    pub rlen: usize,
+    /// Number of bytes actually read. For a block read, this can be less than
+    /// `rlen`, which is the room left for it.
+    pub nread: usize,
+    /// Whether the server has performed the transfer.
+    pub done: bool,
+}
+
+impl TransferHeader {
+    /// Decodes a header, returning `None` if it's malformed.
+    pub fn from_bytes(bytes: [u8; TRANSFER_HEADER_LEN]) -> Option<Self> {
+        Some(Self {
+            kind: TransferKind::from_u8(bytes[0])?,
+            address: bytes[1],
+            wlen: bytes[2] as usize,
+            rlen: bytes[3] as usize,
+            nread: bytes[4] as usize,
+            done: bytes[5] != 0,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; TRANSFER_HEADER_LEN] {
+        [
+            self.kind as u8,
+            self.address,
+            self.wlen as u8,
+            self.rlen as u8,
+            self.nread as u8,
+            self.done as u8,
+        ]
+    }
+
+    /// Offset of the transfer's read data from the start of its header.
+    pub fn read_offset(&self) -> usize {
+        TRANSFER_HEADER_LEN + self.wlen
+    }
+
+    /// Length of the whole transfer, header included -- and so the offset of
+    /// the next transfer from the start of this one's header.
+    pub fn len(&self) -> usize {
+        self.read_offset() + self.rlen
+    }
 }
 
 /// The response code returned from the I2C controller (or from the kernel in
-/// the case of [`ResponseCode::Dead`]).  These response codes pretty specific,
+/// the case of [`ResponseCode::Dead`] and [`ResponseCode::TimedOut`]).  These response codes pretty specific,
 /// not because the caller is expected to necessarily handle them differently,
 /// but to give upstack software some modicum of context surrounding the error.
 #[derive(Copy, Clone, Debug, FromPrimitive, PartialEq)]
@@ -81,6 +171,10 @@ pub enum ResponseCode {
     BusLockedMux = 20,
     /// I2C controller appeared to be locked and was reset
     ControllerLocked = 21,
+    /// Packet error code (PEC) did not match the data read from the device
+    PecMismatch = 22,
+    /// Server didn't reply within the device's timeout (from the kernel)
+    TimedOut = TIMED_OUT,
 }
 
 ///
@@ -177,9 +271,13 @@ pub struct I2cDevice {
     pub port: PortIndex,
     pub segment: Option<(Mux, Segment)>,
     pub address: u8,
+    /// The device uses SMBus packet error checking
+    pub pec: bool,
+    /// Ticks to wait for the server to reply to each operation, if bounded
+    pub timeout: Option<u32>,
 }
 
-type I2cMessage = (u8, Controller, PortIndex, Option<(Mux, Segment)>);
+type I2cMessage = (u8, Controller, PortIndex, Option<(Mux, Segment)>, bool);
 
 pub trait Marshal<T> {
     fn marshal(&self) -> T;
@@ -188,8 +286,8 @@ pub trait Marshal<T> {
         Self: Sized;
 }
 
-impl Marshal<[u8; 4]> for I2cMessage {
-    fn marshal(&self) -> [u8; 4] {
+impl Marshal<[u8; 5]> for I2cMessage {
+    fn marshal(&self) -> [u8; 5] {
         [
             self.0,
             self.1 as u8,
@@ -200,9 +298,10 @@ impl Marshal<[u8; 4]> for I2cMessage {
                 }
                 None => 0,
             },
+            self.4 as u8,
         ]
     }
-    fn unmarshal(val: &[u8; 4]) -> Result<Self, ResponseCode> {
+    fn unmarshal(val: &[u8; 5]) -> Result<Self, ResponseCode> {
         Ok((
             val[0],
             Controller::from_u8(val[1]).ok_or(ResponseCode::BadController)?,
@@ -217,6 +316,11 @@ impl Marshal<[u8; 4]> for I2cMessage {
                         .ok_or(ResponseCode::BadSegment)?,
                 ))
             },
+            match val[4] {
+                0 => false,
+                1 => true,
+                _ => return Err(ResponseCode::BadArg),
+            },
         ))
     }
 }
@@ -240,6 +344,186 @@ impl core::fmt::Display for I2cDevice {
     }
 }
 
+///
+/// A sequence of transfers for the I2C server to perform in a single message,
+/// without any other traffic on the bus (or change of mux segment) between
+/// them. Each transfer is a write, a read, or a write followed by a read
+/// with a repeated start, to any address on the same bus.
+///
+/// The transfers are encoded into a caller-provided buffer, each as a
+/// [`TransferHeader`] followed by its write data and then room for its read
+/// data. The server fills in the read data and the end of the header, leaving
+/// the rest alone -- so a transaction can be performed again.
+///
+pub struct Transaction<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    count: usize,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            count: 0,
+        }
+    }
+
+    ///
+    /// Adds a write of `wbuf` to `address`, followed by a read of `rlen`
+    /// bytes, returning the transfer's index. Either the write or the read
+    /// can be empty, but not both; neither can be more than 255 bytes. Fails
+    /// with [`ResponseCode::BadArg`] if the transfer doesn't fit in the
+    /// transaction's buffer.
+    ///
+    pub fn write_read(
+        &mut self,
+        address: u8,
+        wbuf: &[u8],
+        rlen: usize,
+    ) -> Result<usize, ResponseCode> {
+        self.add(TransferKind::WriteRead, address, wbuf, rlen)
+    }
+
+    ///
+    /// Like [`Transaction::write_read`], but performs an SMBus block read, of
+    /// at most `rlen` bytes (not including the count).
+    ///
+    pub fn write_read_block(
+        &mut self,
+        address: u8,
+        wbuf: &[u8],
+        rlen: usize,
+    ) -> Result<usize, ResponseCode> {
+        self.add(TransferKind::WriteReadBlock, address, wbuf, rlen)
+    }
+
+    /// Adds a write of `wbuf` to `address`, returning the transfer's index.
+    pub fn write(
+        &mut self,
+        address: u8,
+        wbuf: &[u8],
+    ) -> Result<usize, ResponseCode> {
+        self.write_read(address, wbuf, 0)
+    }
+
+    /// Adds a read of `rlen` bytes from `address`, returning the transfer's
+    /// index.
+    pub fn read(
+        &mut self,
+        address: u8,
+        rlen: usize,
+    ) -> Result<usize, ResponseCode> {
+        self.write_read(address, &[], rlen)
+    }
+
+    fn add(
+        &mut self,
+        kind: TransferKind,
+        address: u8,
+        wbuf: &[u8],
+        rlen: usize,
+    ) -> Result<usize, ResponseCode> {
+        let wlen = wbuf.len();
+
+        if (wlen == 0 && rlen == 0) || wlen > 255 || rlen > 255 {
+            return Err(ResponseCode::BadArg);
+        }
+
+        let header = TransferHeader {
+            kind,
+            address,
+            wlen,
+            rlen,
+            nread: 0,
+            done: false,
+        };
+
+        let start = self.len;
+        let end = start + header.len();
+
+        if end > self.buf.len() {
+            return Err(ResponseCode::BadArg);
+        }
+
+        let (header_buf, rest) =
+            self.buf[start..end].split_at_mut(TRANSFER_HEADER_LEN);
+        header_buf.copy_from_slice(&header.to_bytes());
+        rest[..wlen].copy_from_slice(wbuf);
+
+        self.len = end;
+        self.count += 1;
+        Ok(self.count - 1)
+    }
+
+    /// Returns the number of transfers in the transaction.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the header of transfer `index`, and its offset in the buffer.
+    fn header(&self, index: usize) -> Option<(TransferHeader, usize)> {
+        let mut pos = 0;
+
+        for i in 0..self.count {
+            let mut bytes = [0u8; TRANSFER_HEADER_LEN];
+            bytes.copy_from_slice(&self.buf[pos..pos + TRANSFER_HEADER_LEN]);
+
+            // We wrote this header, so it's well-formed.
+            let header = TransferHeader::from_bytes(bytes)?;
+
+            if i == index {
+                return Some((header, pos));
+            }
+
+            pos += header.len();
+        }
+
+        None
+    }
+
+    ///
+    /// Returns the data read by transfer `index`, once the transaction has
+    /// been performed. This is empty for a transfer that the server didn't
+    /// get to, because an earlier one failed.
+    ///
+    pub fn read_data(&self, index: usize) -> Option<&[u8]> {
+        let (header, pos) = self.header(index)?;
+        let rpos = pos + header.read_offset();
+        Some(&self.buf[rpos..rpos + header.nread])
+    }
+
+    ///
+    /// Returns whether transfer `index` was performed, and so -- if the
+    /// transaction failed -- whether it was this transfer or a later one that
+    /// failed.
+    ///
+    pub fn done(&self, index: usize) -> bool {
+        self.header(index).map_or(false, |(header, _)| header.done)
+    }
+
+    /// Clears what the server filled in for each transfer, so that nothing is
+    /// left over from a previous run of the transaction.
+    fn clear_results(&mut self) {
+        for index in 0..self.count {
+            if let Some((header, pos)) = self.header(index) {
+                let header = TransferHeader {
+                    nread: 0,
+                    done: false,
+                    ..header
+                };
+                self.buf[pos..pos + TRANSFER_HEADER_LEN]
+                    .copy_from_slice(&header.to_bytes());
+            }
+        }
+    }
+}
+
 impl I2cDevice {
     ///
     /// Return a new [`I2cDevice`], given a 5-tuple identifying a device plus
@@ -259,6 +543,30 @@ impl I2cDevice {
             port: port,
             segment: segment,
             address: address,
+            pec: false,
+            timeout: None,
+        }
+    }
+
+    ///
+    /// Marks the device as using SMBus packet error checking: a PEC will be
+    /// sent with every write to the device, and checked on every read.
+    ///
+    pub fn with_pec(self) -> Self {
+        Self { pec: true, ..self }
+    }
+
+    ///
+    /// Bounds how long each operation on the device waits for the I2C
+    /// server: if the server hasn't replied within `ticks`, the operation
+    /// fails with [`ResponseCode::TimedOut`].  The operation may or may not
+    /// have been performed, and the server won't take another one from this
+    /// task until it's done with it.
+    ///
+    pub fn with_timeout(self, ticks: u32) -> Self {
+        Self {
+            timeout: Some(ticks),
+            ..self
         }
     }
 
@@ -276,10 +584,96 @@ impl I2cDevice {
             port: PortIndex(0),
             segment: None,
             address: 0,
+            pec: false,
+            timeout: None,
+        }
+    }
+
+    /// Sends `op` about this device to the I2C server.
+    fn send(
+        &self,
+        op: Op,
+        response: &mut usize,
+        leases: &[Lease<'_>],
+    ) -> Result<(), ResponseCode> {
+        let code = send(
+            self.task,
+            op,
+            &Marshal::marshal(&(
+                self.address,
+                self.controller,
+                self.port,
+                self.segment,
+                self.pec,
+            )),
+            response,
+            leases,
+            self.timeout,
+        );
+
+        if code != 0 {
+            Err(ResponseCode::from_u32(code)
+                .ok_or(ResponseCode::BadResponse)?)
+        } else {
+            Ok(())
         }
     }
 }
 
+///
+/// Probes every address on the specified bus, other than the reserved ones,
+/// by reading a byte from it.  Returns a bitmap of the addresses that
+/// acknowledged: bit `n` is set if there is a device at address `n`.  Note
+/// that if a mux segment is specified, this will also find the muxes -- and
+/// any devices on any other enabled segments.  As with
+/// [`I2cDevice::with_timeout`], a `timeout` bounds the wait for the server.
+///
+pub fn scan(
+    task: TaskId,
+    controller: Controller,
+    port: PortIndex,
+    segment: Option<(Mux, Segment)>,
+    timeout: Option<u32>,
+) -> Result<u128, ResponseCode> {
+    let mut present = 0_u128;
+    let mut response = 0_usize;
+
+    let code = send(
+        task,
+        Op::Scan,
+        &Marshal::marshal(&(0, controller, port, segment, false)),
+        &mut response,
+        &[Lease::from(present.as_bytes_mut())],
+        timeout,
+    );
+
+    if code != 0 {
+        Err(ResponseCode::from_u32(code).ok_or(ResponseCode::BadResponse)?)
+    } else {
+        Ok(present)
+    }
+}
+
+/// Sends `op` to the I2C server, giving up after `timeout` ticks if one is
+/// specified, and returns the response code.
+fn send(
+    task: TaskId,
+    op: Op,
+    msg: &[u8],
+    response: &mut usize,
+    leases: &[Lease<'_>],
+    timeout: Option<u32>,
+) -> u32 {
+    let response = response.as_bytes_mut();
+    let (code, _) = match timeout {
+        Some(ticks) => {
+            sys_send_timeout(task, op as u16, msg, response, leases, ticks)
+        }
+        None => sys_send(task, op as u16, msg, response, leases),
+    };
+    code
+}
+
 impl From<ResponseCode> for u32 {
     fn from(rc: ResponseCode) -> Self {
         rc as u32
@@ -311,25 +705,13 @@ impl I2cDevice {
         let mut val = V::default();
         let mut response = 0_usize;
 
-        let (code, _) = sys_send(
-            self.task,
-            Op::WriteRead as u16,
-            &Marshal::marshal(&(
-                self.address,
-                self.controller,
-                self.port,
-                self.segment,
-            )),
-            response.as_bytes_mut(),
+        self.send(
+            Op::WriteRead,
+            &mut response,
             &[Lease::from(reg.as_bytes()), Lease::from(val.as_bytes_mut())],
-        );
+        )?;
 
-        if code != 0 {
-            Err(ResponseCode::from_u32(code)
-                .ok_or(ResponseCode::BadResponse)?)
-        } else {
-            Ok(val)
-        }
+        Ok(val)
     }
 
     ///
@@ -344,25 +726,13 @@ impl I2cDevice {
     ) -> Result<usize, ResponseCode> {
         let mut response = 0_usize;
 
-        let (code, _) = sys_send(
-            self.task,
-            Op::WriteRead as u16,
-            &Marshal::marshal(&(
-                self.address,
-                self.controller,
-                self.port,
-                self.segment,
-            )),
-            response.as_bytes_mut(),
+        self.send(
+            Op::WriteRead,
+            &mut response,
             &[Lease::from(reg.as_bytes()), Lease::from(buf)],
-        );
+        )?;
 
-        if code != 0 {
-            Err(ResponseCode::from_u32(code)
-                .ok_or(ResponseCode::BadResponse)?)
-        } else {
-            Ok(response)
-        }
+        Ok(response)
     }
 
     ///
@@ -379,25 +749,41 @@ impl I2cDevice {
     ) -> Result<usize, ResponseCode> {
         let mut response = 0_usize;
 
-        let (code, _) = sys_send(
-            self.task,
-            Op::WriteReadBlock as u16,
-            &Marshal::marshal(&(
-                self.address,
-                self.controller,
-                self.port,
-                self.segment,
-            )),
-            response.as_bytes_mut(),
+        self.send(
+            Op::WriteReadBlock,
+            &mut response,
             &[Lease::from(reg.as_bytes()), Lease::from(buf)],
-        );
+        )?;
 
-        if code != 0 {
-            Err(ResponseCode::from_u32(code)
-                .ok_or(ResponseCode::BadResponse)?)
-        } else {
-            Ok(response)
-        }
+        Ok(response)
+    }
+
+    ///
+    /// Performs an SMBus block process call: writes the register, followed
+    /// by a block (that is, a byte count and then the contents of `wbuf`),
+    /// and then -- with a repeated start -- performs a block read into
+    /// `rbuf`, returning the number of bytes read.  As with [`read_block`],
+    /// neither byte count appears in the buffers.
+    ///
+    pub fn block_process_call<R: AsBytes>(
+        &self,
+        reg: R,
+        wbuf: &[u8],
+        rbuf: &mut [u8],
+    ) -> Result<usize, ResponseCode> {
+        let mut response = 0_usize;
+
+        self.send(
+            Op::BlockProcessCall,
+            &mut response,
+            &[
+                Lease::from(reg.as_bytes()),
+                Lease::from(wbuf),
+                Lease::from(rbuf),
+            ],
+        )?;
+
+        Ok(response)
     }
 
     ///
@@ -414,25 +800,13 @@ impl I2cDevice {
         let mut val = V::default();
         let mut response = 0_usize;
 
-        let (code, _) = sys_send(
-            self.task,
-            Op::WriteRead as u16,
-            &Marshal::marshal(&(
-                self.address,
-                self.controller,
-                self.port,
-                self.segment,
-            )),
-            response.as_bytes_mut(),
+        self.send(
+            Op::WriteRead,
+            &mut response,
             &[Lease::from(&empty[0..0]), Lease::from(val.as_bytes_mut())],
-        );
+        )?;
 
-        if code != 0 {
-            Err(ResponseCode::from_u32(code)
-                .ok_or(ResponseCode::BadResponse)?)
-        } else {
-            Ok(val)
-        }
+        Ok(val)
     }
 
     ///
@@ -444,25 +818,13 @@ impl I2cDevice {
         let empty = [0u8; 1];
         let mut response = 0_usize;
 
-        let (code, _) = sys_send(
-            self.task,
-            Op::WriteRead as u16,
-            &Marshal::marshal(&(
-                self.address,
-                self.controller,
-                self.port,
-                self.segment,
-            )),
-            response.as_bytes_mut(),
+        self.send(
+            Op::WriteRead,
+            &mut response,
             &[Lease::from(&empty[0..0]), Lease::from(buf)],
-        );
+        )?;
 
-        if code != 0 {
-            Err(ResponseCode::from_u32(code)
-                .ok_or(ResponseCode::BadResponse)?)
-        } else {
-            Ok(response)
-        }
+        Ok(response)
     }
 
     ///
@@ -473,24 +835,119 @@ impl I2cDevice {
         let empty = [0u8; 1];
         let mut response = 0_usize;
 
-        let (code, _) = sys_send(
-            self.task,
-            Op::WriteRead as u16,
-            &Marshal::marshal(&(
-                self.address,
-                self.controller,
-                self.port,
-                self.segment,
-            )),
-            response.as_bytes_mut(),
+        self.send(
+            Op::WriteRead,
+            &mut response,
             &[Lease::from(buffer), Lease::from(&empty[0..0])],
-        );
+        )?;
 
-        if code != 0 {
-            Err(ResponseCode::from_u32(code)
-                .ok_or(ResponseCode::BadResponse)?)
-        } else {
-            Ok(())
+        Ok(())
+    }
+
+    ///
+    /// Performs the transfers of `txn` on this device's bus -- that is, its
+    /// controller, port, and mux segment; each transfer has its own address,
+    /// which need not be this device's. The transfers are performed in
+    /// order, stopping at the first to fail. If this device uses PEC, so
+    /// does each of the transfers.
+    ///
+    pub fn transaction(
+        &self,
+        txn: &mut Transaction,
+    ) -> Result<(), ResponseCode> {
+        let mut response = 0_usize;
+
+        txn.clear_results();
+
+        self.send(
+            Op::Transaction,
+            &mut response,
+            &[Lease::from(&mut txn.buf[..txn.len])],
+        )?;
+
+        Ok(())
+    }
+}
+
+//
+// These are run with `cargo test -p drv-i2c-api --lib` on the host.
+//
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays the server's part in performing `txn`, using the same header
+    /// accessors it does: each device sends the corresponding entry of
+    /// `reads`, until the transfers run out or a `None` (a NACK) stops the
+    /// transaction.
+    fn respond(txn: &mut Transaction, reads: &[Option<&[u8]>]) {
+        let mut pos = 0;
+
+        for data in reads {
+            let mut bytes = [0u8; TRANSFER_HEADER_LEN];
+            bytes.copy_from_slice(&txn.buf[pos..pos + TRANSFER_HEADER_LEN]);
+            let header = TransferHeader::from_bytes(bytes).unwrap();
+
+            let data = match data {
+                Some(data) => data,
+                None => return,
+            };
+
+            let rpos = pos + header.read_offset();
+            txn.buf[rpos..rpos + data.len()].copy_from_slice(data);
+
+            let header = TransferHeader {
+                nread: data.len(),
+                done: true,
+                ..header
+            };
+            txn.buf[pos..pos + TRANSFER_HEADER_LEN]
+                .copy_from_slice(&header.to_bytes());
+            pos += header.len();
         }
     }
+
+    #[test]
+    fn block_read_then_fixed_read() {
+        let mut buf = [0u8; 32];
+        let mut txn = Transaction::new(&mut buf);
+
+        let block = txn.write_read_block(0x24, &[0x9a], 8).unwrap();
+        let fixed = txn.write_read(0x24, &[0x8b], 2).unwrap();
+        assert_eq!(txn.len(), 2);
+
+        // A short block read mustn't throw off where the next transfer is.
+        respond(&mut txn, &[Some(&[1, 2, 3]), Some(&[0x34, 0x12])]);
+        assert_eq!(txn.read_data(block), Some(&[1, 2, 3][..]));
+        assert_eq!(txn.read_data(fixed), Some(&[0x34, 0x12][..]));
+        assert_eq!(txn.read_data(2), None);
+
+        // Nor should it leave less room for the block read the next time.
+        txn.clear_results();
+        assert_eq!(txn.read_data(block), Some(&[][..]));
+        respond(
+            &mut txn,
+            &[Some(&[1, 2, 3, 4, 5, 6, 7, 8]), Some(&[0x78, 0x56])],
+        );
+        assert_eq!(txn.read_data(block), Some(&[1, 2, 3, 4, 5, 6, 7, 8][..]));
+        assert_eq!(txn.read_data(fixed), Some(&[0x78, 0x56][..]));
+    }
+
+    #[test]
+    fn failed_transfer_is_not_done() {
+        let mut buf = [0u8; 32];
+        let mut txn = Transaction::new(&mut buf);
+
+        let page = txn.write(0x60, &[0x00, 1]).unwrap();
+        let vout = txn.write_read(0x60, &[0x8b], 2).unwrap();
+
+        respond(&mut txn, &[Some(&[]), None]);
+        assert!(txn.done(page));
+        assert!(!txn.done(vout));
+        assert_eq!(txn.read_data(vout), Some(&[][..]));
+
+        // Performing it again starts from scratch.
+        txn.clear_results();
+        assert!(!txn.done(page));
+    }
 }
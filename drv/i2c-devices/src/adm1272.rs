@@ -4,9 +4,12 @@
 
 //! Driver for the ADM1272 hot-swap controller
 
+use crate::pmbus_device::{self, PmbusDevice};
+use crate::pmbus_status::{Limit, PmbusStatus};
 use drv_i2c_api::*;
 use num_traits::float::FloatCore;
 use pmbus::commands::*;
+use pmbus::units;
 use ringbuf::*;
 use userlib::units::*;
 
@@ -17,6 +20,7 @@ pub enum Error {
     BadData { cmd: u8 },
     InvalidData { err: pmbus::Error },
     InvalidConfig,
+    UnsupportedLimit,
 }
 
 impl From<pmbus::Error> for Error {
@@ -25,13 +29,39 @@ impl From<pmbus::Error> for Error {
     }
 }
 
-#[allow(dead_code)]
+impl From<pmbus_device::Error> for Error {
+    fn from(err: pmbus_device::Error) -> Self {
+        match err {
+            pmbus_device::Error::BadRead { cmd, code } => {
+                Error::BadRead { cmd, code }
+            }
+            pmbus_device::Error::BadWrite { cmd, code } => {
+                Error::BadWrite { cmd, code }
+            }
+            pmbus_device::Error::BadData { cmd } => Error::BadData { cmd },
+            pmbus_device::Error::InvalidData { err } => {
+                Error::InvalidData { err }
+            }
+        }
+    }
+}
+
 struct Coefficients {
     voltage: pmbus::Coefficients,
     current: pmbus::Coefficients,
     power: pmbus::Coefficients,
 }
 
+//
+// Temperature coefficients don't depend on the mode; these are from Table 10
+// of the ADM1272 datasheet.
+//
+const TEMPERATURE: pmbus::Coefficients = pmbus::Coefficients {
+    m: 42,
+    b: 31880,
+    R: -1,
+};
+
 pub struct Adm1272 {
     /// Underlying I2C device
     device: I2cDevice,
@@ -209,25 +239,92 @@ impl Adm1272 {
         }
     }
 
-    pub fn read_vin(&mut self) -> Result<Volts, Error> {
+    pub fn peak_iout(&mut self) -> Result<Amperes, Error> {
+        let iout = pmbus_read!(self.device, adm1272::PEAK_IOUT)?;
+        Ok(Amperes(iout.get(&self.load_coefficients()?.current)?.0))
+    }
+}
+
+//
+// The ADM1272 encodes everything in DIRECT format, with coefficients that
+// depend on its configuration, so we use its own commands rather than the
+// standard ones.
+//
+impl PmbusDevice for Adm1272 {
+    type Error = Error;
+
+    fn device(&self) -> &I2cDevice {
+        &self.device
+    }
+
+    //
+    // The ADM1272 only samples the voltages once asked to, so we make sure
+    // that it is before reading them.
+    //
+    fn read_vin(&mut self) -> Result<Volts, Error> {
         self.enable_vin_sampling()?;
         let vin = pmbus_read!(self.device, adm1272::READ_VIN)?;
         Ok(Volts(vin.get(&self.load_coefficients()?.voltage)?.0))
     }
 
-    pub fn read_vout(&mut self) -> Result<Volts, Error> {
+    fn read_vout(&mut self) -> Result<Volts, Error> {
         self.enable_vout_sampling()?;
         let vout = pmbus_read!(self.device, adm1272::READ_VOUT)?;
         Ok(Volts(vout.get(&self.load_coefficients()?.voltage)?.0))
     }
 
-    pub fn read_iout(&mut self) -> Result<Amperes, Error> {
+    fn read_iout(&mut self) -> Result<Amperes, Error> {
         let iout = pmbus_read!(self.device, adm1272::READ_IOUT)?;
         Ok(Amperes(iout.get(&self.load_coefficients()?.current)?.0))
     }
 
-    pub fn peak_iout(&mut self) -> Result<Amperes, Error> {
-        let iout = pmbus_read!(self.device, adm1272::PEAK_IOUT)?;
-        Ok(Amperes(iout.get(&self.load_coefficients()?.current)?.0))
+    fn read_pin(&mut self) -> Result<Watts, Error> {
+        let pin = pmbus_read!(self.device, adm1272::READ_PIN)?;
+        Ok(Watts(pin.get(&self.load_coefficients()?.power)?.0))
+    }
+
+    fn read_temperature(&mut self) -> Result<Celsius, Error> {
+        let temp = pmbus_read!(self.device, adm1272::READ_TEMPERATURE_1)?;
+        Ok(Celsius(temp.get(&TEMPERATURE)?.0))
+    }
+}
+
+impl PmbusStatus for Adm1272 {
+    //
+    // Of the limits we know, the ADM1272 has only the output warning limits
+    // and the overtemperature ones.
+    //
+    fn set_limit(&mut self, limit: Limit) -> Result<(), Error> {
+        let coefficients = self.load_coefficients()?;
+        let (voltage, current) = (coefficients.voltage, coefficients.current);
+
+        match limit {
+            Limit::VoutOvWarn(v) => {
+                let mut data = adm1272::VOUT_OV_WARN_LIMIT::CommandData(0);
+                data.set(&voltage, units::Volts(v.0))?;
+                pmbus_write!(self.device, adm1272::VOUT_OV_WARN_LIMIT, data)
+            }
+            Limit::VoutUvWarn(v) => {
+                let mut data = adm1272::VOUT_UV_WARN_LIMIT::CommandData(0);
+                data.set(&voltage, units::Volts(v.0))?;
+                pmbus_write!(self.device, adm1272::VOUT_UV_WARN_LIMIT, data)
+            }
+            Limit::IoutOcWarn(i) => {
+                let mut data = adm1272::IOUT_OC_WARN_LIMIT::CommandData(0);
+                data.set(&current, units::Amperes(i.0))?;
+                pmbus_write!(self.device, adm1272::IOUT_OC_WARN_LIMIT, data)
+            }
+            Limit::OtFault(t) => {
+                let mut data = adm1272::OT_FAULT_LIMIT::CommandData(0);
+                data.set(&TEMPERATURE, units::Celsius(t.0))?;
+                pmbus_write!(self.device, adm1272::OT_FAULT_LIMIT, data)
+            }
+            Limit::OtWarn(t) => {
+                let mut data = adm1272::OT_WARN_LIMIT::CommandData(0);
+                data.set(&TEMPERATURE, units::Celsius(t.0))?;
+                pmbus_write!(self.device, adm1272::OT_WARN_LIMIT, data)
+            }
+            _ => Err(Error::UnsupportedLimit),
+        }
     }
 }
@@ -2,15 +2,20 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! Driver for the ISL68224 power controller
+//!
+//! Everything we need is standard PMBus, provided by [`PmbusDevice`]; each
+//! rail is a separate page.
+
+use crate::pmbus_device::PmbusDevice;
+use crate::pmbus_status::PmbusStatus;
 use drv_i2c_api::*;
-use pmbus::commands::isl68224::*;
-use pmbus::*;
-use userlib::units::*;
+
+pub use crate::pmbus_device::Error;
 
 pub struct Isl68224 {
     device: I2cDevice,
     rail: u8,
-    mode: Option<pmbus::VOutModeCommandData>,
 }
 
 impl core::fmt::Display for Isl68224 {
@@ -19,68 +24,25 @@ impl core::fmt::Display for Isl68224 {
     }
 }
 
-#[derive(Debug)]
-pub enum Error {
-    BadRead { cmd: u8, code: ResponseCode },
-    BadWrite { cmd: u8, code: ResponseCode },
-    BadData { cmd: u8 },
-    InvalidData { err: pmbus::Error },
-}
-
-impl From<pmbus::Error> for Error {
-    fn from(err: pmbus::Error) -> Self {
-        Error::InvalidData { err: err }
-    }
-}
-
 impl Isl68224 {
     pub fn new(device: &I2cDevice, rail: u8) -> Self {
         Isl68224 {
             device: *device,
             rail: rail,
-            mode: None,
         }
     }
+}
 
-    fn read_mode(&mut self) -> Result<pmbus::VOutModeCommandData, Error> {
-        Ok(match self.mode {
-            None => {
-                let mode = pmbus_read!(self.device, commands::VOUT_MODE)?;
-                self.mode = Some(mode);
-                mode
-            }
-            Some(mode) => mode,
-        })
-    }
-
-    fn set_rail(&mut self) -> Result<(), Error> {
-        let page = PAGE::CommandData(self.rail);
-        pmbus_write!(self.device, PAGE, page)
-    }
-
-    pub fn turn_off(&mut self) -> Result<(), Error> {
-        self.set_rail()?;
-        let mut operation = pmbus_read!(self.device, OPERATION)?;
-        operation.set_on_off_state(OPERATION::OnOffState::Off);
-        pmbus_write!(self.device, OPERATION, operation)
-    }
-
-    pub fn turn_on(&mut self) -> Result<(), Error> {
-        self.set_rail()?;
-        let mut operation = pmbus_read!(self.device, OPERATION)?;
-        operation.set_on_off_state(OPERATION::OnOffState::On);
-        pmbus_write!(self.device, OPERATION, operation)
-    }
+impl PmbusDevice for Isl68224 {
+    type Error = Error;
 
-    pub fn read_vout(&mut self) -> Result<Volts, Error> {
-        self.set_rail()?;
-        let vout = pmbus_read!(self.device, READ_VOUT)?;
-        Ok(Volts(vout.get(self.read_mode()?)?.0))
+    fn device(&self) -> &I2cDevice {
+        &self.device
     }
 
-    pub fn read_iout(&mut self) -> Result<Amperes, Error> {
-        self.set_rail()?;
-        let iout = pmbus_read!(self.device, READ_IOUT)?;
-        Ok(Amperes(iout.get()?.0))
+    fn page(&self) -> Option<u8> {
+        Some(self.rail)
     }
 }
+
+impl PmbusStatus for Isl68224 {}
@@ -14,6 +14,8 @@
 //! - [`max31790`]: MAX31790 fan controller
 //! - [`mcp9808`]: MCP9808 temperature sensor
 //! - [`pct2075`]: PCT2075 temperature sensor
+//! - [`pmbus_device`]: support common to PMBus devices
+//! - [`pmbus_status`]: status, faults, and limits common to PMBus devices
 //! - [`raa229618`]: RAA229618 power controller
 //! - [`tmp116`]: TMP116 temperature sensor
 //! - [`tps546b24a`]: TPS546B24A buck converter
@@ -88,6 +90,58 @@ macro_rules! pmbus_write {
     }};
 }
 
+//
+// Like `pmbus_read!` and `pmbus_write!`, but for a
+// [`pmbus_device::PmbusDevice`]: the command is performed with its
+// `transfer`, which first selects the device's page (if it has one) in the
+// same transaction. Errors are [`pmbus_device::Error`]s.
+//
+macro_rules! pmbus_device_read {
+    ($device:expr, $dev:ident::$cmd:ident) => {{
+        let cmd = $dev::$cmd::CommandData::code();
+        let mut data = [0u8; $dev::$cmd::CommandData::len()];
+
+        match $device.transfer(&[cmd], &mut data) {
+            Err(err) => Err(err),
+            Ok(_) => match $dev::$cmd::CommandData::from_slice(&data) {
+                Some(data) => Ok(data),
+                None => Err($crate::pmbus_device::Error::BadData { cmd }),
+            },
+        }
+    }};
+
+    ($device:expr, $cmd:ident) => {{
+        let cmd = $cmd::CommandData::code();
+        let mut data = [0u8; $cmd::CommandData::len()];
+
+        match $device.transfer(&[cmd], &mut data) {
+            Err(err) => Err(err),
+            Ok(_) => match $cmd::CommandData::from_slice(&data) {
+                Some(data) => Ok(data),
+                None => Err($crate::pmbus_device::Error::BadData { cmd }),
+            },
+        }
+    }};
+}
+
+macro_rules! pmbus_device_write {
+    ($device:expr, $dev:ident::$cmd:ident, $data:expr) => {{
+        let mut payload = [0u8; $dev::$cmd::CommandData::len() + 1];
+        payload[0] = $dev::$cmd::CommandData::code();
+        $data.to_slice(&mut payload[1..]);
+
+        $device.transfer(&payload, &mut [])
+    }};
+
+    ($device:expr, $cmd:ident, $data:expr) => {{
+        let mut payload = [0u8; $cmd::CommandData::len() + 1];
+        payload[0] = $cmd::CommandData::code();
+        $data.to_slice(&mut payload[1..]);
+
+        $device.transfer(&payload, &mut [])
+    }};
+}
+
 pub trait TempSensor<T> {
     fn read_temperature(&self) -> Result<userlib::units::Celsius, T>;
 }
@@ -100,6 +154,8 @@ pub mod max31790;
 pub mod max6634;
 pub mod mcp9808;
 pub mod pct2075;
+pub mod pmbus_device;
+pub mod pmbus_status;
 pub mod raa229618;
 pub mod tmp116;
 pub mod tps546b24a;
@@ -34,8 +34,11 @@ enum Frequency {
     F25000Hz = 0b1011,
 }
 
-#[allow(dead_code)]
-enum SpinUp {
+/// How a fan is started from a stop: if enabled, the fan is driven at 100%
+/// duty cycle until its tach reports it spinning, or for at most the given
+/// time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SpinUp {
     NoSpinUp = 0b00,
     HalfSecond = 0b01,
     OneSecond = 0b10,
@@ -174,10 +177,15 @@ pub enum Error {
     BadRead16 { reg: Register, code: ResponseCode },
     BadWrite { reg: Register, code: ResponseCode },
     IllegalFan,
+    IllegalDuty,
+    IllegalRpm,
+    IllegalPulses,
 }
 
 pub struct Max31790 {
     pub device: I2cDevice,
+    /// Tach pulses per revolution of each fan
+    pulses: [u8; MAX_FANS as usize],
 }
 
 impl core::fmt::Display for Max31790 {
@@ -228,6 +236,58 @@ impl Fan {
     fn pwm_target(&self) -> Register {
         self.register(Register::PWMOut1TargetDutyCycleMSB, 1)
     }
+
+    fn tach_target(&self) -> Register {
+        self.register(Register::Tach1TargetCountMSB, 1)
+    }
+}
+
+/// The set of fans that have faulted, as read from the fan fault status
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FanFaults(u8);
+
+impl FanFaults {
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, fan: Fan) -> bool {
+        self.0 & (1 << fan.0) != 0
+    }
+}
+
+//
+// The tach count is somewhat misnamed: it is in fact the number of 8192 Hz
+// clock cycles counted in a configurable number of pulses of the tach.  (It
+// would be more aptly named a pulse count.) The number of pulses (NP) per
+// revolution of the fan is specific to the fan, but is generally two for the
+// DC brushless fans we care about.  The number of pulses of the tach measured
+// is called the Speed Range (SR) and defaults to 4; we leave it there.
+//
+// So to get from the tach count to the time per revolution:
+//
+//                    count * NP
+//                t = ----------
+//                    8192 * SR
+//
+// And to get from there to RPM, we want to divide 60 by t:
+//
+//                   60 * 8192 * SR
+//   RPM = 60 / t =  --------------
+//                     count * NP
+//
+// Which also gets us from a target RPM to a target count, by swapping RPM
+// and count.
+//
+const TACH_POR_VALUE: u32 = 0b111_1111_1111;
+const SR: u32 = 4;
+const DEFAULT_NP: u8 = 2;
+const FREQ: u32 = 8192;
+
+/// Converts between RPM and tach counts, for a fan with `np` pulses per
+/// revolution.
+fn convert_tach(np: u8, val: u32) -> u32 {
+    (60 * FREQ * SR) / (val * u32::from(np))
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -236,6 +296,7 @@ enum Trace {
     Read16(Register, [u8; 2]),
     ReadError(Register, ResponseCode),
     Write(Register, u8),
+    Write16(Register, [u8; 2]),
     WriteError(Register, u8, ResponseCode),
     None,
 }
@@ -305,9 +366,38 @@ fn write_reg(
     }
 }
 
+fn write_reg16(
+    device: &I2cDevice,
+    register: Register,
+    val: [u8; 2],
+) -> Result<(), Error> {
+    let rval = device.write(&[register as u8, val[0], val[1]]);
+
+    match rval {
+        Ok(_) => {
+            ringbuf_entry!(Trace::Write16(register, val));
+            Ok(())
+        }
+        Err(code) => {
+            ringbuf_entry!(Trace::WriteError(register, val[0], code));
+            Err(Error::BadWrite {
+                reg: register,
+                code: code,
+            })
+        }
+    }
+}
+
+/// PWM duty cycle, as a percentage
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PwmDuty(pub u8);
+
 impl Max31790 {
     pub fn new(device: &I2cDevice) -> Self {
-        Self { device: *device }
+        Self {
+            device: *device,
+            pulses: [DEFAULT_NP; MAX_FANS as usize],
+        }
     }
 
     pub fn initialize(&self) -> Result<(), Error> {
@@ -332,43 +422,126 @@ impl Max31790 {
         Ok(())
     }
 
+    /// Sets the number of tach pulses per revolution of the specified fan,
+    /// which is a property of the fan (and is 2 by default). This is used
+    /// to convert between tach counts and RPM.
+    pub fn set_pulses_per_revolution(
+        &mut self,
+        fan: Fan,
+        pulses: u8,
+    ) -> Result<(), Error> {
+        if pulses == 0 {
+            return Err(Error::IllegalPulses);
+        }
+
+        self.pulses[fan.0 as usize] = pulses;
+        Ok(())
+    }
+
     /// Determines the rotations per minute based on the tach count
     pub fn fan_rpm(&self, fan: Fan) -> Result<Rpm, Error> {
         let val = read_reg16(&self.device, fan.tach_count())?;
-
-        //
-        // The tach count is somewhat misnamed: it is in fact the number of
-        // 8192 Hz clock cycles counted in a configurable number of pulses of
-        // the tach.  (It would be more aptly named a pulse count.) The number
-        // of pulses (NP) per revolution of the fan is specific to the fan,
-        // but is generally two for the DC brushless fans we care about.  The
-        // number of pulses of the tach measured is called the Speed Range
-        // (SR) and defaults to 4.
-        //
-        // So to get from the tach count to the time per revolution:
-        //
-        //                    count * NP
-        //                t = ----------
-        //                    8192 * SR
-        //
-        // And to get from there to RPM, we want to divide 60 by t:
-        //
-        //                   60 * 8192 * SR
-        //   RPM = 60 / t =  --------------
-        //                     count * NP
-        //
         let count = ((val[0] as u32) << 3) | (val[1] >> 5) as u32;
 
-        const TACH_POR_VALUE: u32 = 0b111_1111_1111;
-        const SR: u32 = 4;
-        const NP: u32 = 2;
-        const FREQ: u32 = 8192;
-
         if count == TACH_POR_VALUE {
             Ok(Rpm(0))
         } else {
-            let rpm = (60 * FREQ * SR) / (count * NP);
+            let rpm = convert_tach(self.pulses[fan.0 as usize], count);
             Ok(Rpm(rpm as u16))
         }
     }
+
+    /// Reads the configuration for the specified fan, applies `f` to it, and
+    /// writes it back if it changed.
+    fn update_configuration(
+        &self,
+        fan: Fan,
+        f: impl FnOnce(&mut FanConfiguration),
+    ) -> Result<(), Error> {
+        let reg = fan.configuration();
+        let orig = read_reg8(&self.device, reg)?;
+        let mut config = FanConfiguration(orig);
+        f(&mut config);
+
+        if config.0 != orig {
+            write_reg(&self.device, reg, config.0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the spin-up behavior for the specified fan
+    pub fn set_spinup(&self, fan: Fan, spinup: SpinUp) -> Result<(), Error> {
+        self.update_configuration(fan, |config| {
+            config.set_spinup(spinup as u8);
+        })
+    }
+
+    /// Sets the target RPM for the specified fan, putting it into RPM mode:
+    /// the controller will then adjust the fan's duty cycle to maintain that
+    /// speed. The slowest speed that can be expressed depends on the number
+    /// of pulses per revolution; with the default of 2, it's about 480 RPM.
+    pub fn set_rpm(&self, fan: Fan, rpm: Rpm) -> Result<(), Error> {
+        if rpm.0 == 0 {
+            return Err(Error::IllegalRpm);
+        }
+
+        let count = convert_tach(self.pulses[fan.0 as usize], rpm.0.into());
+
+        if count == 0 || count >= TACH_POR_VALUE {
+            return Err(Error::IllegalRpm);
+        }
+
+        //
+        // Like the tach count, the target count is an 11-bit value split
+        // across the MSB register and the top three bits of the LSB.
+        //
+        write_reg16(
+            &self.device,
+            fan.tach_target(),
+            [(count >> 3) as u8, ((count & 0b111) << 5) as u8],
+        )?;
+
+        self.update_configuration(fan, |config| config.set_rpm(true))
+    }
+
+    /// Sets the target PWM duty cycle for the specified fan, putting it into
+    /// PWM mode if it was in RPM mode.
+    pub fn set_pwm(&self, fan: Fan, duty: PwmDuty) -> Result<(), Error> {
+        if duty.0 > 100 {
+            return Err(Error::IllegalDuty);
+        }
+
+        self.update_configuration(fan, |config| config.set_rpm(false))?;
+
+        //
+        // The target duty cycle is a 9-bit value spread across two
+        // registers: the MSB holds the top eight bits, and the top bit of the
+        // LSB holds the bottom bit.
+        //
+        let val = (duty.0 as u16 * 511) / 100;
+
+        write_reg16(
+            &self.device,
+            fan.pwm_target(),
+            [(val >> 1) as u8, ((val & 1) << 7) as u8],
+        )
+    }
+
+    /// Returns the set of fans whose tachs have reported a fault: the fan
+    /// isn't spinning, or (in RPM mode) can't reach its target speed. Fault
+    /// bits are sticky; see `clear_fan_faults`.
+    pub fn fan_faults(&self) -> Result<FanFaults, Error> {
+        let status = read_reg8(&self.device, Register::FanFaultStatus1)?;
+        Ok(FanFaults(status & ((1 << MAX_FANS) - 1)))
+    }
+
+    /// Clears the specified fan faults. A fault that persists will be
+    /// reported again at the next tach measurement.
+    pub fn clear_fan_faults(&self, faults: FanFaults) -> Result<(), Error> {
+        let status = read_reg8(&self.device, Register::FanFaultStatus1)?;
+
+        // Fault bits are cleared by writing zero to them.
+        write_reg(&self.device, Register::FanFaultStatus1, status & !faults.0)
+    }
 }
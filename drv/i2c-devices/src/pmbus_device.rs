@@ -0,0 +1,168 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Support common to PMBus devices
+//!
+//! Most of what a PMBus device does is defined by the PMBus specification
+//! rather than its datasheet: selecting a rail with PAGE, turning it on and
+//! off with OPERATION, and reading its voltages, currents, power and
+//! temperature, all in one of a handful of standard data formats.
+//! [`PmbusDevice`] provides all of that, given the underlying I2C device and
+//! -- for devices with several rails -- the page to select. The commands and
+//! their formats are those of the `pmbus` crate: output voltages are in
+//! ULINEAR16, as given by VOUT_MODE, and everything else is in LINEAR11.
+//! Devices that encode things differently (for example, in DIRECT format)
+//! override the methods for the values concerned, using their own commands
+//! from `pmbus::commands`.
+//!
+//! Device-specific commands are still best read and written with the
+//! `pmbus_read!` and `pmbus_write!` macros -- or `pmbus_device_read!` and
+//! `pmbus_device_write!`, for a device with pages.
+
+use drv_i2c_api::*;
+use pmbus::commands::*;
+use userlib::units::*;
+
+/// Errors common to PMBus devices. A device with errors of its own has an
+/// error type that these convert into.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    BadRead { cmd: u8, code: ResponseCode },
+    BadWrite { cmd: u8, code: ResponseCode },
+    BadData { cmd: u8 },
+    InvalidData { err: pmbus::Error },
+}
+
+impl From<pmbus::Error> for Error {
+    fn from(err: pmbus::Error) -> Self {
+        Error::InvalidData { err: err }
+    }
+}
+
+/// A PMBus device (or, for a device with several rails, one of its rails).
+pub trait PmbusDevice {
+    /// Errors from the device. A value that the `pmbus` crate can't encode
+    /// or decode -- for example, a voltage out of range for VOUT_MODE -- is
+    /// reported as the [`pmbus::Error`] it gives.
+    type Error: From<Error> + From<pmbus::Error> + core::fmt::Debug;
+
+    fn device(&self) -> &I2cDevice;
+
+    /// The page to select before each command, for a device with several
+    /// rails. Devices without pages don't implement this.
+    fn page(&self) -> Option<u8> {
+        None
+    }
+
+    /// Performs a command: a write of `wbuf` (the command code and any data)
+    /// and a read into `rbuf`, which may be empty. For a device with pages,
+    /// this is preceded by selecting our page, in the same transaction -- so
+    /// that nothing else can select another page in between.
+    ///
+    /// A failure is reported against the command (as a write, if there's
+    /// nothing to read), or against PAGE if selecting our page failed.
+    fn transfer(&self, wbuf: &[u8], rbuf: &mut [u8]) -> Result<(), Error> {
+        let device = self.device();
+        let mut buf = [0u8; 32];
+        let mut txn = Transaction::new(&mut buf);
+
+        let cmd = wbuf[0];
+        let is_read = !rbuf.is_empty();
+        let failed = |code| match is_read {
+            true => Error::BadRead { cmd, code },
+            false => Error::BadWrite { cmd, code },
+        };
+
+        let page = match self.page() {
+            Some(page) => Some(
+                txn.write(device.address, &[PAGE::CommandData::code(), page])
+                    .map_err(failed)?,
+            ),
+            None => None,
+        };
+
+        let index = txn
+            .write_read(device.address, wbuf, rbuf.len())
+            .map_err(failed)?;
+
+        if let Err(code) = device.transaction(&mut txn) {
+            return Err(match page {
+                Some(page) if !txn.done(page) => Error::BadWrite {
+                    cmd: PAGE::CommandData::code(),
+                    code,
+                },
+                _ => failed(code),
+            });
+        }
+
+        // The read is of a fixed length, so anything else is the server's
+        // mistake.
+        match txn.read_data(index) {
+            Some(data) if data.len() == rbuf.len() => {
+                rbuf.copy_from_slice(data);
+                Ok(())
+            }
+            _ => Err(failed(ResponseCode::BadResponse)),
+        }
+    }
+
+    /// Reads VOUT_MODE, which gives the format of output voltages.
+    fn vout_mode(&self) -> Result<pmbus::VOutModeCommandData, Self::Error> {
+        Ok(pmbus_device_read!(self, VOUT_MODE)?)
+    }
+
+    fn turn_on(&self) -> Result<(), Self::Error> {
+        let mut operation = pmbus_device_read!(self, OPERATION)?;
+        operation.set_on_off_state(OPERATION::OnOffState::On);
+        Ok(pmbus_device_write!(self, OPERATION, operation)?)
+    }
+
+    fn turn_off(&self) -> Result<(), Self::Error> {
+        let mut operation = pmbus_device_read!(self, OPERATION)?;
+        operation.set_on_off_state(OPERATION::OnOffState::Off);
+        Ok(pmbus_device_write!(self, OPERATION, operation)?)
+    }
+
+    /// Sets the output voltage.
+    fn set_vout(&mut self, vout: Volts) -> Result<(), Self::Error> {
+        let mut data = VOUT_COMMAND::CommandData(0);
+        data.set(self.vout_mode()?, pmbus::units::Volts(vout.0))?;
+        Ok(pmbus_device_write!(self, VOUT_COMMAND, data)?)
+    }
+
+    fn read_vin(&mut self) -> Result<Volts, Self::Error> {
+        let vin = pmbus_device_read!(self, READ_VIN)?;
+        Ok(Volts(vin.get()?.0))
+    }
+
+    fn read_vout(&mut self) -> Result<Volts, Self::Error> {
+        let vout = pmbus_device_read!(self, READ_VOUT)?;
+        Ok(Volts(vout.get(self.vout_mode()?)?.0))
+    }
+
+    fn read_iin(&mut self) -> Result<Amperes, Self::Error> {
+        let iin = pmbus_device_read!(self, READ_IIN)?;
+        Ok(Amperes(iin.get()?.0))
+    }
+
+    fn read_iout(&mut self) -> Result<Amperes, Self::Error> {
+        let iout = pmbus_device_read!(self, READ_IOUT)?;
+        Ok(Amperes(iout.get()?.0))
+    }
+
+    fn read_pin(&mut self) -> Result<Watts, Self::Error> {
+        let pin = pmbus_device_read!(self, READ_PIN)?;
+        Ok(Watts(pin.get()?.0))
+    }
+
+    fn read_pout(&mut self) -> Result<Watts, Self::Error> {
+        let pout = pmbus_device_read!(self, READ_POUT)?;
+        Ok(Watts(pout.get()?.0))
+    }
+
+    fn read_temperature(&mut self) -> Result<Celsius, Self::Error> {
+        let temp = pmbus_device_read!(self, READ_TEMPERATURE_1)?;
+        Ok(Celsius(temp.get()?.0))
+    }
+}
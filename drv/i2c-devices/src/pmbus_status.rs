@@ -0,0 +1,227 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Status, faults, and fault limits common to PMBus devices
+//!
+//! These are defined by part II of the PMBus specification, and are provided
+//! by [`PmbusStatus`], which each [`PmbusDevice`] implements. Devices needn't
+//! implement every status bit or limit -- see their datasheets -- and will
+//! generally NACK a write to a limit they don't have.
+
+use crate::pmbus_device::PmbusDevice;
+use bitfield::bitfield;
+use pmbus::commands::*;
+use userlib::units::*;
+
+bitfield! {
+    /// Summary of the device's status. The low byte is also known as
+    /// STATUS_BYTE.
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct StatusWord(u16);
+    impl Debug;
+    pub vout, _: 15;
+    pub iout_pout, _: 14;
+    pub input, _: 13;
+    pub mfr_specific, _: 12;
+    pub power_good_n, _: 11;
+    pub fans, _: 10;
+    pub other, _: 9;
+    pub unknown, _: 8;
+    pub busy, _: 7;
+    pub off, _: 6;
+    pub vout_ov_fault, _: 5;
+    pub iout_oc_fault, _: 4;
+    pub vin_uv_fault, _: 3;
+    pub temperature, _: 2;
+    pub cml, _: 1;
+    pub none_of_the_above, _: 0;
+}
+
+impl StatusWord {
+    /// Returns true if any warning or fault is flagged -- that is, anything
+    /// other than the output being off, not (yet) good, or the device being
+    /// busy.
+    pub fn has_faults(&self) -> bool {
+        self.0 & !(1 << 11 | 1 << 7 | 1 << 6) != 0
+    }
+}
+
+bitfield! {
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct StatusVout(u8);
+    impl Debug;
+    pub ov_fault, _: 7;
+    pub ov_warn, _: 6;
+    pub uv_warn, _: 5;
+    pub uv_fault, _: 4;
+    pub max_min_warn, _: 3;
+    pub ton_max_fault, _: 2;
+    pub toff_max_warn, _: 1;
+    pub tracking_error, _: 0;
+}
+
+bitfield! {
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct StatusIout(u8);
+    impl Debug;
+    pub oc_fault, _: 7;
+    pub oc_lv_fault, _: 6;
+    pub oc_warn, _: 5;
+    pub uc_fault, _: 4;
+    pub current_share_fault, _: 3;
+    pub power_limiting, _: 2;
+    pub pout_op_fault, _: 1;
+    pub pout_op_warn, _: 0;
+}
+
+bitfield! {
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct StatusTemperature(u8);
+    impl Debug;
+    pub ot_fault, _: 7;
+    pub ot_warn, _: 6;
+    pub ut_warn, _: 5;
+    pub ut_fault, _: 4;
+}
+
+/// A device's status, with the details of whichever of output voltage,
+/// output current, and temperature have something to report.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Faults {
+    pub word: StatusWord,
+    pub vout: Option<StatusVout>,
+    pub iout: Option<StatusIout>,
+    pub temperature: Option<StatusTemperature>,
+}
+
+/// A warning or fault limit, with the value to set it to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Limit {
+    VoutOvFault(Volts),
+    VoutOvWarn(Volts),
+    VoutUvWarn(Volts),
+    VoutUvFault(Volts),
+    IoutOcFault(Amperes),
+    IoutOcWarn(Amperes),
+    OtFault(Celsius),
+    OtWarn(Celsius),
+    UtWarn(Celsius),
+    UtFault(Celsius),
+}
+
+/// Status, faults, and limits for a PMBus device (or, for a device with
+/// several rails, one of its rails). Everything but [`set_limit`] is the
+/// same for every device.
+///
+/// [`set_limit`]: PmbusStatus::set_limit
+pub trait PmbusStatus: PmbusDevice {
+    fn status_word(&self) -> Result<StatusWord, Self::Error> {
+        Ok(StatusWord(pmbus_device_read!(self, STATUS_WORD)?.0))
+    }
+
+    fn status_vout(&self) -> Result<StatusVout, Self::Error> {
+        Ok(StatusVout(pmbus_device_read!(self, STATUS_VOUT)?.0))
+    }
+
+    fn status_iout(&self) -> Result<StatusIout, Self::Error> {
+        Ok(StatusIout(pmbus_device_read!(self, STATUS_IOUT)?.0))
+    }
+
+    fn status_temperature(&self) -> Result<StatusTemperature, Self::Error> {
+        Ok(StatusTemperature(
+            pmbus_device_read!(self, STATUS_TEMPERATURE)?.0,
+        ))
+    }
+
+    /// Reads STATUS_WORD, and then the status registers for whatever it
+    /// says needs a closer look.
+    fn faults(&self) -> Result<Faults, Self::Error> {
+        let word = self.status_word()?;
+
+        Ok(Faults {
+            word,
+            vout: match word.vout() {
+                true => Some(self.status_vout()?),
+                false => None,
+            },
+            iout: match word.iout_pout() {
+                true => Some(self.status_iout()?),
+                false => None,
+            },
+            temperature: match word.temperature() {
+                true => Some(self.status_temperature()?),
+                false => None,
+            },
+        })
+    }
+
+    /// Clears all latched warnings and faults. Any whose conditions persist
+    /// will be flagged again.
+    fn clear_faults(&self) -> Result<(), Self::Error> {
+        Ok(self.transfer(&[CLEAR_FAULTS::CommandData::code()], &mut [])?)
+    }
+
+    /// Sets a limit, in the standard formats: ULINEAR16 (as given by
+    /// VOUT_MODE) for output voltages, and LINEAR11 for everything else.
+    /// Devices that encode their limits differently implement this
+    /// themselves.
+    fn set_limit(&mut self, limit: Limit) -> Result<(), Self::Error> {
+        use pmbus::units;
+
+        match limit {
+            Limit::VoutOvFault(v) => {
+                let mut data = VOUT_OV_FAULT_LIMIT::CommandData(0);
+                data.set(self.vout_mode()?, units::Volts(v.0))?;
+                pmbus_device_write!(self, VOUT_OV_FAULT_LIMIT, data)
+            }
+            Limit::VoutOvWarn(v) => {
+                let mut data = VOUT_OV_WARN_LIMIT::CommandData(0);
+                data.set(self.vout_mode()?, units::Volts(v.0))?;
+                pmbus_device_write!(self, VOUT_OV_WARN_LIMIT, data)
+            }
+            Limit::VoutUvWarn(v) => {
+                let mut data = VOUT_UV_WARN_LIMIT::CommandData(0);
+                data.set(self.vout_mode()?, units::Volts(v.0))?;
+                pmbus_device_write!(self, VOUT_UV_WARN_LIMIT, data)
+            }
+            Limit::VoutUvFault(v) => {
+                let mut data = VOUT_UV_FAULT_LIMIT::CommandData(0);
+                data.set(self.vout_mode()?, units::Volts(v.0))?;
+                pmbus_device_write!(self, VOUT_UV_FAULT_LIMIT, data)
+            }
+            Limit::IoutOcFault(i) => {
+                let mut data = IOUT_OC_FAULT_LIMIT::CommandData(0);
+                data.set(units::Amperes(i.0))?;
+                pmbus_device_write!(self, IOUT_OC_FAULT_LIMIT, data)
+            }
+            Limit::IoutOcWarn(i) => {
+                let mut data = IOUT_OC_WARN_LIMIT::CommandData(0);
+                data.set(units::Amperes(i.0))?;
+                pmbus_device_write!(self, IOUT_OC_WARN_LIMIT, data)
+            }
+            Limit::OtFault(t) => {
+                let mut data = OT_FAULT_LIMIT::CommandData(0);
+                data.set(units::Celsius(t.0))?;
+                pmbus_device_write!(self, OT_FAULT_LIMIT, data)
+            }
+            Limit::OtWarn(t) => {
+                let mut data = OT_WARN_LIMIT::CommandData(0);
+                data.set(units::Celsius(t.0))?;
+                pmbus_device_write!(self, OT_WARN_LIMIT, data)
+            }
+            Limit::UtWarn(t) => {
+                let mut data = UT_WARN_LIMIT::CommandData(0);
+                data.set(units::Celsius(t.0))?;
+                pmbus_device_write!(self, UT_WARN_LIMIT, data)
+            }
+            Limit::UtFault(t) => {
+                let mut data = UT_FAULT_LIMIT::CommandData(0);
+                data.set(units::Celsius(t.0))?;
+                pmbus_device_write!(self, UT_FAULT_LIMIT, data)
+            }
+        }?;
+
+        Ok(())
+    }
+}
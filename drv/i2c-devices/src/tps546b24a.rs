@@ -3,14 +3,17 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Driver for the TPS546B24A buck converter
+//!
+//! Everything we need is standard PMBus, provided by [`PmbusDevice`].
 
+use crate::pmbus_device::PmbusDevice;
+use crate::pmbus_status::PmbusStatus;
 use drv_i2c_api::*;
-use pmbus::commands::*;
-use userlib::units::*;
+
+pub use crate::pmbus_device::Error;
 
 pub struct Tps546b24a {
     device: I2cDevice,
-    mode: Option<pmbus::VOutModeCommandData>,
 }
 
 impl core::fmt::Display for Tps546b24a {
@@ -19,46 +22,18 @@ impl core::fmt::Display for Tps546b24a {
     }
 }
 
-#[derive(Debug)]
-pub enum Error {
-    BadRead { cmd: u8, code: ResponseCode },
-    BadWrite { cmd: u8, code: ResponseCode },
-    BadData { cmd: u8 },
-    InvalidData { err: pmbus::Error },
-}
-
-impl From<pmbus::Error> for Error {
-    fn from(err: pmbus::Error) -> Self {
-        Error::InvalidData { err: err }
-    }
-}
-
 impl Tps546b24a {
     pub fn new(device: &I2cDevice) -> Self {
-        Tps546b24a {
-            device: *device,
-            mode: None,
-        }
+        Tps546b24a { device: *device }
     }
+}
 
-    fn read_mode(&mut self) -> Result<pmbus::VOutModeCommandData, Error> {
-        Ok(match self.mode {
-            None => {
-                let mode = pmbus_read!(self.device, VOUT_MODE)?;
-                self.mode = Some(mode);
-                mode
-            }
-            Some(mode) => mode,
-        })
-    }
+impl PmbusDevice for Tps546b24a {
+    type Error = Error;
 
-    pub fn read_vout(&mut self) -> Result<Volts, Error> {
-        let vout = pmbus_read!(self.device, tps546b24a::READ_VOUT)?;
-        Ok(Volts(vout.get(self.read_mode()?)?.0))
-    }
-
-    pub fn read_iout(&mut self) -> Result<Amperes, Error> {
-        let iout = pmbus_read!(self.device, tps546b24a::READ_IOUT)?;
-        Ok(Amperes(iout.get()?.0))
+    fn device(&self) -> &I2cDevice {
+        &self.device
     }
 }
+
+impl PmbusStatus for Tps546b24a {}
@@ -4,44 +4,48 @@
 
 //! A driver for the LPC55 U(S)ART.
 //!
-//! This driver is currently configured to run at 9600. We could potentially
-//! run faster but 9600 works so nicely with the clocks...
+//! This driver starts out running at 9600, which divides nicely into our
+//! 12MHz clock, but can be reconfigured. We don't (yet) know which pins would
+//! carry CTS and RTS, so hardware flow control isn't supported.
 //!
-//! # IPC protocol
+//! Received bytes are buffered as they arrive; when the buffer fills, we
+//! leave them in the FIFO until a `read` makes room.
 //!
-//! ## `write` (1)
-//!
-//! Sends the contents of lease #0. Returns when completed.
+//! Our IPC protocol is defined in `idl/usart.toml`.
 
 #![no_std]
 #![no_main]
 
 use drv_lpc55_gpio_api::*;
 use drv_lpc55_syscon_api::*;
+use drv_usart_api::*;
 use lpc55_pac as device;
 use userlib::*;
-use zerocopy::AsBytes;
 
 task_slot!(SYSCON, syscon_driver);
 
-const OP_WRITE: u32 = 1;
-
 task_slot!(GPIO, gpio_driver);
 
-#[repr(u32)]
-enum ResponseCode {
-    Success = 0,
-    BadOp = 1,
-    BadArg = 2,
-    Busy = 3,
+mod idl {
+    use drv_usart_api::{ConfigureRequest, UsartError};
+
+    include!(concat!(env!("OUT_DIR"), "/server_stub.rs"));
 }
 
+/// Our clock, from which we derive our baud rate.
+const CLOCK_HZ: u32 = 12_000_000;
+
 struct Transmit {
-    task: TaskId,
+    caller: hl::Caller<()>,
     len: usize,
     pos: usize,
 }
 
+struct Receive {
+    caller: hl::Caller<u32>,
+    len: usize,
+}
+
 #[export_name = "main"]
 fn main() -> ! {
     // Turn the actual peripheral on so that we can interact with it.
@@ -61,25 +65,171 @@ fn main() -> ! {
     // Set USART mode
     flexcomm.pselid.write(|w| w.persel().usart());
 
-    usart.fifocfg.modify(|_, w| w.enabletx().enabled());
+    usart
+        .fifocfg
+        .modify(|_, w| w.enabletx().enabled().enablerx().enabled());
 
     // We actually get interrupts from the FIFO
-    // Trigger when the FIFO is empty for now
-    usart
-        .fifotrig
-        .modify(|_, w| unsafe { w.txlvl().bits(0).txlvlena().enabled() });
+    // Trigger when the TX FIFO is empty, or the RX FIFO isn't
+    usart.fifotrig.modify(|_, w| unsafe {
+        w.txlvl()
+            .bits(0)
+            .txlvlena()
+            .enabled()
+            .rxlvl()
+            .bits(0)
+            .rxlvlena()
+            .enabled()
+    });
+
+    configure(
+        usart,
+        &Config {
+            baud: 9600,
+            ..Config::DEFAULT
+        },
+    )
+    .unwrap();
+
+    // Start listening for received bytes.
+    usart.fifointenset.write(|w| w.rxlvl().set_bit());
+
+    sys_irq_control(1, true);
+
+    // Field messages.
+    let mut buffer = [0; idl::INCOMING_SIZE];
+    let mut server = ServerImpl {
+        usart,
+        tx: None,
+        rx: RxBuffer::new(),
+        reader: None,
+    };
+
+    loop {
+        idl::dispatch(&mut buffer, &mut server);
+    }
+}
+
+struct ServerImpl {
+    usart: &'static device::usart0::RegisterBlock,
+    tx: Option<Transmit>,
+    rx: RxBuffer,
+    reader: Option<Receive>,
+}
+
+impl idl::UsartServer for ServerImpl {
+    fn write(&mut self, caller: hl::Caller<()>) -> Result<(), UsartError> {
+        // Deny incoming writes if we're already running one.
+        if self.tx.is_some() {
+            return Err(UsartError::Busy);
+        }
+
+        // `dispatch` has checked the lease count and that the lease is
+        // readable.
+        let len = caller.borrow(0).info().ok_or(UsartError::BadArg)?.len;
+
+        // Okay! Begin a transfer!
+        self.tx = Some(Transmit {
+            caller,
+            pos: 0,
+            len,
+        });
+
+        self.usart.intenset.modify(|_, w| w.txidleen().set_bit());
+
+        // We'll do the rest as interrupts arrive.
+        Ok(())
+    }
+
+    fn read(&mut self, caller: hl::Caller<u32>) -> Result<(), UsartError> {
+        // Deny incoming reads if someone is already waiting -- unless they
+        // aren't any more.
+        if let Some(reader) = &self.reader {
+            let pending = reader.caller.task_id();
+            if !read_is_stale(pending, caller.task_id()) {
+                return Err(UsartError::Busy);
+            }
+        }
+
+        let len = caller.borrow(0).info().ok_or(UsartError::BadArg)?.len;
+        if len == 0 {
+            return Err(UsartError::BadArg);
+        }
+
+        self.reader = Some(Receive { caller, len });
+
+        // If we've already got something, this replies now; otherwise, we'll
+        // reply when something arrives.
+        self.complete_read();
+        Ok(())
+    }
+
+    fn configure(
+        &mut self,
+        _: TaskId,
+        request: ConfigureRequest,
+    ) -> Result<(), UsartError> {
+        let config = request.config().ok_or(UsartError::BadArg)?;
+
+        // Reconfiguring disables the USART, which would garble a write in
+        // progress.
+        if self.tx.is_some() {
+            return Err(UsartError::Busy);
+        }
+
+        configure(self.usart, &config)
+    }
+
+    fn notification_mask(&self) -> u32 {
+        1
+    }
+
+    fn handle_notification(&mut self, bits: u32) {
+        if bits & 1 != 0 {
+            // Handling an interrupt. To allow for spurious interrupts, check
+            // the individual conditions we care about, and unconditionally
+            // re-enable the IRQ at the end of the handler.
+            if self.tx.is_some() && self.usart.stat.read().txidle().bit() {
+                // Transmit in progress and TX empty. Time to send something.
+                self.step_transmit();
+            }
 
-    // This puts us at 9600 baud because it divides nicely with the
-    // 12mhz clock
-    usart.brg.write(|w| unsafe { w.brgval().bits(0x7c) });
-    usart.osr.write(|w| unsafe { w.osrval().bits(0x9) });
+            self.step_receive();
+            self.complete_read();
 
-    // 8N1 configuration
+            sys_irq_control(1, true);
+        }
+    }
+}
+
+fn configure(
+    usart: &device::usart0::RegisterBlock,
+    config: &Config,
+) -> Result<(), UsartError> {
+    if config.flow_control != FlowControl::None {
+        return Err(UsartError::Unsupported);
+    }
+
+    let (osrval, brgval) = divisors(config.baud).ok_or(UsartError::BadArg)?;
+
+    let paritysel = match config.parity {
+        Parity::None => 0,
+        Parity::Even => 2,
+        Parity::Odd => 3,
+    };
+
+    // The USART must be disabled while we change its configuration.
+    usart.cfg.modify(|_, w| w.enable().disabled());
+
+    usart.brg.write(|w| unsafe { w.brgval().bits(brgval) });
+    usart.osr.write(|w| unsafe { w.osrval().bits(osrval) });
+
+    // 8 data bits, with parity (if any) in addition
     usart.cfg.write(|w| unsafe {
         w.paritysel()
-            .bits(0)
+            .bits(paritysel)
             .stoplen()
-            .bit(false)
+            .bit(config.stop_bits == StopBits::Two)
             .datalen()
             .bits(1)
             .loop_()
@@ -92,96 +242,45 @@ fn main() -> ! {
             .enabled()
     });
 
-    // USART side yet, so this won't trigger notifications yet.
-    sys_irq_control(1, true);
+    Ok(())
+}
 
-    // Field messages.
-    let mask = 1;
-    let mut tx: Option<Transmit> = None;
+/// Works out the OSR and BRG values for `baud`. Our clock is divided by both
+/// the oversample rate (5 to 16) and the baud rate generator (1 to 65536), so
+/// we pick whichever pair gets closest, preferring more oversampling. Returns
+/// `None` if nothing gets within 2%.
+fn divisors(baud: u32) -> Option<(u8, u16)> {
+    if baud == 0 || baud > CLOCK_HZ / 5 {
+        return None;
+    }
 
-    loop {
-        let msginfo = sys_recv_open(&mut [], mask);
-        if msginfo.sender == TaskId::KERNEL {
-            if msginfo.operation & 1 != 0 {
-                // Handling an interrupt. To allow for spurious interrupts,
-                // check the individual conditions we care about, and
-                // unconditionally re-enable the IRQ at the end of the handler.
-                if let Some(txs) = tx.as_mut() {
-                    // Transmit in progress, check to see if TX is empty.
-                    if usart.stat.read().txidle().bit() {
-                        // TX register empty. Time to send something.
-                        if step_transmit(&usart, txs) {
-                            tx = None;
-                            // This is a write to clear register
-                            usart.intenclr.write(|w| w.txidleclr().set_bit());
-                        }
-                    }
-                }
-
-                sys_irq_control(1, true);
-            }
+    let mut best: Option<(u32, u32, u32)> = None;
+
+    for osr in (5..=16).rev() {
+        let brg = (CLOCK_HZ + (osr * baud / 2)) / (osr * baud);
+
+        if brg == 0 || brg > 0x1_0000 {
+            continue;
+        }
+
+        let actual = CLOCK_HZ / (osr * brg);
+        let error = if actual > baud {
+            actual - baud
         } else {
-            match msginfo.operation {
-                OP_WRITE => {
-                    // Deny incoming writes if we're already running one.
-                    if tx.is_some() {
-                        sys_reply(
-                            msginfo.sender,
-                            ResponseCode::Busy as u32,
-                            &[],
-                        );
-                        continue;
-                    }
-
-                    // Check the lease count and characteristics.
-                    if msginfo.lease_count != 1 {
-                        sys_reply(
-                            msginfo.sender,
-                            ResponseCode::BadArg as u32,
-                            &[],
-                        );
-                        continue;
-                    }
-
-                    let len = match sys_borrow_info(msginfo.sender, 0) {
-                        None => {
-                            sys_reply(
-                                msginfo.sender,
-                                ResponseCode::BadArg as u32,
-                                &[],
-                            );
-                            continue;
-                        }
-                        Some(info)
-                            if !info
-                                .attributes
-                                .contains(LeaseAttributes::READ) =>
-                        {
-                            sys_reply(
-                                msginfo.sender,
-                                ResponseCode::BadArg as u32,
-                                &[],
-                            );
-                            continue;
-                        }
-                        Some(info) => info.len,
-                    };
-
-                    // Okay! Begin a transfer!
-                    tx = Some(Transmit {
-                        task: msginfo.sender,
-                        pos: 0,
-                        len,
-                    });
-
-                    usart.intenset.modify(|_, w| w.txidleen().set_bit());
-
-                    // We'll do the rest as interrupts arrive.
-                }
-                _ => sys_reply(msginfo.sender, ResponseCode::BadOp as u32, &[]),
-            }
+            baud - actual
+        };
+
+        if best.map_or(true, |(e, _, _)| error < e) {
+            best = Some((error, osr, brg));
         }
     }
+
+    match best {
+        Some((error, osr, brg)) if error <= baud / 50 => {
+            Some(((osr - 1) as u8, (brg - 1) as u16))
+        }
+        _ => None,
+    }
 }
 
 fn turn_on_flexcomm() {
@@ -222,29 +321,88 @@ fn muck_with_gpios() {
         .unwrap();
 }
 
-fn step_transmit(
-    usart: &device::usart0::RegisterBlock,
-    txs: &mut Transmit,
-) -> bool {
-    let mut byte = 0u8;
-    let (rc, len) = sys_borrow_read(txs.task, 0, txs.pos, byte.as_bytes_mut());
-    if rc != 0 || len != 1 {
-        sys_reply(txs.task, ResponseCode::BadArg as u32, &[]);
-        true
-    } else {
-        // Stuff byte into transmitter.
-        //
-        // This is marked as unsafe for reasons I don't quite understand?
-        unsafe {
-            usart.fifowr.write(|w| w.bits(byte as u32));
+impl ServerImpl {
+    fn step_transmit(&mut self) {
+        let txs = if let Some(txs) = &mut self.tx {
+            txs
+        } else {
+            return;
+        };
+
+        let byte = txs.caller.borrow(0).read_at::<u8>(txs.pos);
+
+        if let Some(byte) = byte {
+            // Stuff byte into transmitter.
+            //
+            // This is marked as unsafe for reasons I don't quite understand?
+            unsafe {
+                self.usart.fifowr.write(|w| w.bits(byte as u32));
+            }
+
+            txs.pos += 1;
+            if txs.pos == txs.len {
+                self.end_transmission().reply(());
+            }
+        } else {
+            self.end_transmission().reply_fail(UsartError::BadArg);
+        }
+    }
+
+    fn end_transmission(&mut self) -> hl::Caller<()> {
+        // This is a write to clear register.
+        self.usart.intenclr.write(|w| w.txidleclr().set_bit());
+        self.tx.take().unwrap().caller
+    }
+
+    fn step_receive(&mut self) {
+        let usart = self.usart;
+
+        // If we've lost a byte, there's no-one to tell, but the overflow has
+        // to be cleared. This is a write to clear bit.
+        if usart.fifostat.read().rxerr().bit() {
+            usart.fifostat.write(|w| w.rxerr().set_bit());
+        }
+
+        while usart.fifostat.read().rxnotempty().bit() {
+            if self.rx.is_full() {
+                // Leave the rest in the FIFO, and stop listening until a read
+                // makes room. This is a write to clear register.
+                usart.fifointenclr.write(|w| w.rxlvl().set_bit());
+                break;
+            }
+
+            self.rx.push(usart.fiford.read().rxdata().bits() as u8);
+        }
+    }
+
+    fn complete_read(&mut self) {
+        if self.rx.is_empty() {
+            return;
         }
 
-        txs.pos += 1;
-        if txs.pos == txs.len {
-            sys_reply(txs.task, ResponseCode::Success as u32, &[]);
-            true
+        let reader = if let Some(reader) = self.reader.take() {
+            reader
+        } else {
+            return;
+        };
+
+        let mut data = [0; RX_BUFFER_SIZE];
+        let n = self
+            .rx
+            .pop_into(&mut data[..usize::min(reader.len, RX_BUFFER_SIZE)]);
+
+        // We've made room, so make sure we're listening.
+        self.usart.fifointenset.write(|w| w.rxlvl().set_bit());
+
+        if reader
+            .caller
+            .borrow(0)
+            .write_fully_at(0, &data[..n])
+            .is_some()
+        {
+            reader.caller.reply(n as u32);
         } else {
-            false
+            reader.caller.reply_fail(UsartError::BadArg);
         }
     }
 }
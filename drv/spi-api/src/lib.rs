@@ -81,6 +81,9 @@ pub enum SpiError {
     ///
     /// This is almost certainly a programming error on the client side.
     BadDevice = 16,
+
+    /// DMA failed partway through the transfer, which was abandoned.
+    DmaError = 17,
 }
 
 impl From<SpiError> for u32 {
@@ -4,11 +4,11 @@
 
 //! A driver for the STM32F4 U(S)ART.
 //!
-//! # IPC protocol
+//! Received bytes are buffered as they arrive; when the buffer fills, we stop
+//! taking bytes from the USART until a `read` makes room, which (with flow
+//! control enabled) deasserts RTS.
 //!
-//! ## `write` (1)
-//!
-//! Sends the contents of lease #0. Returns when completed.
+//! Our IPC protocol is defined in `idl/usart.toml`.
 
 #![no_std]
 #![no_main]
@@ -19,28 +19,16 @@ use stm32f4::stm32f407 as device;
 #[cfg(feature = "stm32f3")]
 use stm32f3::stm32f303 as device;
 
+use drv_usart_api::*;
 use userlib::*;
 use zerocopy::AsBytes;
 
 task_slot!(RCC, rcc_driver);
 
-#[derive(Copy, Clone, Debug, FromPrimitive)]
-enum Operation {
-    Write = 1,
-}
+mod idl {
+    use drv_usart_api::{ConfigureRequest, UsartError};
 
-#[repr(u32)]
-enum ResponseCode {
-    BadArg = 2,
-    Busy = 3,
-}
-
-// TODO: it is super unfortunate to have to write this by hand, but deriving
-// ToPrimitive makes us check at runtime whether the value fits
-impl From<ResponseCode> for u32 {
-    fn from(rc: ResponseCode) -> Self {
-        rc as u32
-    }
+    include!(concat!(env!("OUT_DIR"), "/server_stub.rs"));
 }
 
 struct Transmit {
@@ -49,6 +37,11 @@ struct Transmit {
     pos: usize,
 }
 
+struct Receive {
+    caller: hl::Caller<u32>,
+    len: usize,
+}
+
 #[export_name = "main"]
 fn main() -> ! {
     // Turn the actual peripheral on so that we can interact with it.
@@ -61,33 +54,15 @@ fn main() -> ! {
     // concern. Were it literally a static, we could just reference it.
     let usart = unsafe { &*device::USART2::ptr() };
 
-    // The UART has clock and is out of reset, but isn't actually on until we:
-    usart.cr1.write(|w| w.ue().enabled());
-    // Work out our baud rate divisor.
-    const BAUDRATE: u32 = 115_200;
+    // The UART has clock and is out of reset, but isn't actually on until we
+    // configure it.
+    configure(usart, &Config::DEFAULT).unwrap();
 
-    #[cfg(feature = "stm32f3")]
-    {
-        const CLOCK_HZ: u32 = 8_000_000;
-        usart
-            .brr
-            .write(|w| w.brr().bits((CLOCK_HZ / BAUDRATE) as u16));
-    }
-
-    #[cfg(feature = "stm32f4")]
-    {
-        const CLOCK_HZ: u32 = 16_000_000;
-        const CYCLES_PER_BIT: u32 = (CLOCK_HZ + (BAUDRATE / 2)) / BAUDRATE;
-        usart.brr.write(|w| {
-            w.div_mantissa()
-                .bits((CYCLES_PER_BIT >> 4) as u16)
-                .div_fraction()
-                .bits(CYCLES_PER_BIT as u8 & 0xF)
-        });
-    }
-
-    // Enable the transmitter.
-    usart.cr1.modify(|_, w| w.te().enabled());
+    // Enable the transmitter and receiver, and OR the RX register not empty
+    // signal into the USART interrupt.
+    usart
+        .cr1
+        .modify(|_, w| w.te().enabled().re().enabled().rxneie().enabled());
 
     turn_on_gpioa();
 
@@ -102,81 +77,204 @@ fn main() -> ! {
         .modify(|_, w| w.moder2().alternate().moder3().alternate());
     gpioa.afrl.modify(|_, w| w.afrl2().af7().afrl3().af7());
 
-    // Turn on our interrupt. We haven't enabled any interrupt sources at the
-    // USART side yet, so this won't trigger notifications yet.
+    // Turn on our interrupt.
     sys_irq_control(1, true);
 
     // Field messages.
-    let mask = 1;
-    let mut tx: Option<Transmit> = None;
+    let mut buffer = [0; idl::INCOMING_SIZE];
+    let mut server = ServerImpl {
+        usart,
+        gpioa,
+        tx: None,
+        rx: RxBuffer::new(),
+        reader: None,
+    };
 
     loop {
-        hl::recv(
-            // Buffer (none required)
-            &mut [],
-            // Notification mask
-            mask,
-            // State to pass through to whichever closure below gets run
-            &mut tx,
-            // Notification handler
-            |txref, bits| {
-                if bits & 1 != 0 {
-                    // Handling an interrupt. To allow for spurious interrupts,
-                    // check the individual conditions we care about, and
-                    // unconditionally re-enable the IRQ at the end of the handler.
-
-                    #[cfg(feature = "stm32f3")]
-                    let txe = usart.isr.read().txe().bit();
-                    #[cfg(feature = "stm32f4")]
-                    let txe = usart.sr.read().txe().bit();
-                    if txe {
-                        // TX register empty. Do we need to send something?
-                        step_transmit(&usart, txref);
-                    }
-
-                    sys_irq_control(1, true);
-                }
-            },
-            // Message handler
-            |txref, op, msg| match op {
-                Operation::Write => {
-                    // Validate lease count and buffer sizes first.
-                    let ((), caller) =
-                        msg.fixed_with_leases(1).ok_or(ResponseCode::BadArg)?;
-
-                    // Deny incoming writes if we're already running one.
-                    if txref.is_some() {
-                        return Err(ResponseCode::Busy);
-                    }
-
-                    let borrow = caller.borrow(0);
-                    let info = borrow.info().ok_or(ResponseCode::BadArg)?;
-                    // Provide feedback to callers if they fail to provide a
-                    // readable lease (otherwise we'd fail accessing the borrow
-                    // later, which is a defection case and we won't reply at
-                    // all).
-                    if !info.attributes.contains(LeaseAttributes::READ) {
-                        return Err(ResponseCode::BadArg);
-                    }
-
-                    // Okay! Begin a transfer!
-                    *txref = Some(Transmit {
-                        caller,
-                        pos: 0,
-                        len: info.len,
-                    });
-
-                    // OR the TX register empty signal into the USART interrupt.
-                    usart.cr1.modify(|_, w| w.txeie().enabled());
-
-                    // We'll do the rest as interrupts arrive.
-                    Ok(())
-                }
-            },
-        );
+        idl::dispatch(&mut buffer, &mut server);
     }
 }
 
+struct ServerImpl {
+    usart: &'static device::usart1::RegisterBlock,
+    gpioa: &'static device::gpioa::RegisterBlock,
+    tx: Option<Transmit>,
+    rx: RxBuffer,
+    reader: Option<Receive>,
+}
+
+impl idl::UsartServer for ServerImpl {
+    fn write(&mut self, caller: hl::Caller<()>) -> Result<(), UsartError> {
+        // Deny incoming writes if we're already running one.
+        if self.tx.is_some() {
+            return Err(UsartError::Busy);
+        }
+
+        // `dispatch` has checked that the lease is readable, so we won't
+        // fail to access it later (which would be a defection case, and we
+        // wouldn't reply at all) unless the caller goes away.
+        let len = caller.borrow(0).info().ok_or(UsartError::BadArg)?.len;
+
+        // Okay! Begin a transfer!
+        self.tx = Some(Transmit {
+            caller,
+            pos: 0,
+            len,
+        });
+
+        // OR the TX register empty signal into the USART interrupt.
+        self.usart.cr1.modify(|_, w| w.txeie().enabled());
+
+        // We'll do the rest as interrupts arrive.
+        Ok(())
+    }
+
+    fn read(&mut self, caller: hl::Caller<u32>) -> Result<(), UsartError> {
+        // Deny incoming reads if someone is already waiting -- unless they
+        // aren't any more.
+        if let Some(reader) = &self.reader {
+            let pending = reader.caller.task_id();
+            if !read_is_stale(pending, caller.task_id()) {
+                return Err(UsartError::Busy);
+            }
+        }
+
+        let len = caller.borrow(0).info().ok_or(UsartError::BadArg)?.len;
+        if len == 0 {
+            return Err(UsartError::BadArg);
+        }
+
+        self.reader = Some(Receive { caller, len });
+
+        // If we've already got something, this replies now; otherwise, we'll
+        // reply when something arrives.
+        self.complete_read();
+        Ok(())
+    }
+
+    fn configure(
+        &mut self,
+        _: TaskId,
+        request: ConfigureRequest,
+    ) -> Result<(), UsartError> {
+        let config = request.config().ok_or(UsartError::BadArg)?;
+
+        // Reconfiguring disables the USART, which would garble a write in
+        // progress.
+        if self.tx.is_some() {
+            return Err(UsartError::Busy);
+        }
+
+        configure(self.usart, &config)?;
+
+        if config.flow_control == FlowControl::RtsCts {
+            // We're using PA0/1 for CTS/RTS, where USART2 is again selected
+            // by Alternate Function 7.
+            self.gpioa
+                .moder
+                .modify(|_, w| w.moder0().alternate().moder1().alternate());
+            self.gpioa.afrl.modify(|_, w| w.afrl0().af7().afrl1().af7());
+        }
+
+        Ok(())
+    }
+
+    fn notification_mask(&self) -> u32 {
+        1
+    }
+
+    fn handle_notification(&mut self, bits: u32) {
+        if bits & 1 != 0 {
+            // Handling an interrupt. To allow for spurious interrupts, check
+            // the individual conditions we care about, and unconditionally
+            // re-enable the IRQ at the end of the handler.
+
+            #[cfg(feature = "stm32f3")]
+            let (rxne, ore, txe) = {
+                let isr = self.usart.isr.read();
+                (isr.rxne().bit(), isr.ore().bit(), isr.txe().bit())
+            };
+            #[cfg(feature = "stm32f4")]
+            let (rxne, ore, txe) = {
+                let sr = self.usart.sr.read();
+                (sr.rxne().bit(), sr.ore().bit(), sr.txe().bit())
+            };
+
+            if rxne || ore {
+                // Something to receive (or lost).
+                self.step_receive();
+            }
+
+            if txe {
+                // TX register empty. Do we need to send something?
+                self.step_transmit();
+            }
+
+            sys_irq_control(1, true);
+        }
+    }
+}
+
+fn configure(
+    usart: &device::usart1::RegisterBlock,
+    config: &Config,
+) -> Result<(), UsartError> {
+    // Work out our baud rate divisor.
+    #[cfg(feature = "stm32f3")]
+    const CLOCK_HZ: u32 = 8_000_000;
+    #[cfg(feature = "stm32f4")]
+    const CLOCK_HZ: u32 = 16_000_000;
+
+    let cycles_per_bit = (CLOCK_HZ + (config.baud / 2)) / config.baud;
+
+    // We oversample by 16, so need at least 16 cycles per bit.
+    if cycles_per_bit < 16 || cycles_per_bit > u32::from(u16::MAX) {
+        return Err(UsartError::BadArg);
+    }
+
+    let parity = config.parity != Parity::None;
+    let flow_control = config.flow_control == FlowControl::RtsCts;
+
+    // Most of the configuration can only be changed while the USART is
+    // disabled.
+    usart.cr1.modify(|_, w| w.ue().disabled());
+
+    #[cfg(feature = "stm32f3")]
+    usart.brr.write(|w| w.brr().bits(cycles_per_bit as u16));
+
+    #[cfg(feature = "stm32f4")]
+    usart.brr.write(|w| {
+        w.div_mantissa()
+            .bits((cycles_per_bit >> 4) as u16)
+            .div_fraction()
+            .bits(cycles_per_bit as u8 & 0xF)
+    });
+
+    // The parity bit is the last bit of the word, so with parity we need
+    // 9-bit words to keep 8 data bits.
+    usart.cr1.modify(|_, w| {
+        w.m()
+            .bit(parity)
+            .pce()
+            .bit(parity)
+            .ps()
+            .bit(config.parity == Parity::Odd)
+    });
+
+    usart.cr2.modify(|_, w| match config.stop_bits {
+        StopBits::One => w.stop().stop1(),
+        StopBits::Two => w.stop().stop2(),
+    });
+
+    usart
+        .cr3
+        .modify(|_, w| w.rtse().bit(flow_control).ctse().bit(flow_control));
+
+    usart.cr1.modify(|_, w| w.ue().enabled());
+
+    Ok(())
+}
+
 fn turn_on_usart() {
     let rcc_driver = RCC.get_task_id();
 
@@ -232,33 +330,99 @@ fn turn_on_gpioa() {
     assert_eq!(code, 0);
 }
 
-fn step_transmit(
-    usart: &device::usart1::RegisterBlock,
-    tx: &mut Option<Transmit>,
-) {
-    // Clearer than just using replace:
-    fn end_transmission(
-        usart: &device::usart1::RegisterBlock,
-        state: &mut Option<Transmit>,
-    ) -> hl::Caller<()> {
-        usart.cr1.modify(|_, w| w.txeie().disabled());
-        core::mem::replace(state, None).unwrap().caller
+impl ServerImpl {
+    fn step_transmit(&mut self) {
+        let txs = if let Some(txs) = &mut self.tx {
+            txs
+        } else {
+            return;
+        };
+
+        let byte = txs.caller.borrow(0).read_at::<u8>(txs.pos);
+
+        if let Some(byte) = byte {
+            // Stuff byte into transmitter.
+            #[cfg(feature = "stm32f3")]
+            self.usart.tdr.write(|w| w.tdr().bits(u16::from(byte)));
+            #[cfg(feature = "stm32f4")]
+            self.usart.dr.write(|w| w.dr().bits(u16::from(byte)));
+
+            txs.pos += 1;
+            if txs.pos == txs.len {
+                self.end_transmission().reply(());
+            }
+        } else {
+            self.end_transmission().reply_fail(UsartError::BadArg);
+        }
+    }
+
+    fn end_transmission(&mut self) -> hl::Caller<()> {
+        self.usart.cr1.modify(|_, w| w.txeie().disabled());
+        self.tx.take().unwrap().caller
     }
 
-    let txs = if let Some(txs) = tx { txs } else { return };
+    fn step_receive(&mut self) {
+        let usart = self.usart;
 
-    if let Some(byte) = txs.caller.borrow(0).read_at::<u8>(txs.pos) {
-        // Stuff byte into transmitter.
+        // If we've lost a byte, there's no-one to tell, but the overrun has
+        // to be cleared or it'll keep interrupting us. (On the F4, reading
+        // the data register below does this.)
         #[cfg(feature = "stm32f3")]
-        usart.tdr.write(|w| w.tdr().bits(u16::from(byte)));
+        if usart.isr.read().ore().bit() {
+            usart.icr.write(|w| w.orecf().set_bit());
+        }
+
+        #[cfg(feature = "stm32f3")]
+        let rxne = usart.isr.read().rxne().bit();
         #[cfg(feature = "stm32f4")]
-        usart.dr.write(|w| w.dr().bits(u16::from(byte)));
+        let rxne = usart.sr.read().rxne().bit();
+
+        if rxne {
+            if self.rx.is_full() {
+                // Leave the byte where it is, and stop listening until a read
+                // makes room.
+                usart.cr1.modify(|_, w| w.rxneie().disabled());
+            } else {
+                #[cfg(feature = "stm32f3")]
+                let byte = usart.rdr.read().rdr().bits() as u8;
+                #[cfg(feature = "stm32f4")]
+                let byte = usart.dr.read().dr().bits() as u8;
+
+                self.rx.push(byte);
+            }
+        }
+
+        self.complete_read();
+    }
+
+    fn complete_read(&mut self) {
+        if self.rx.is_empty() {
+            return;
+        }
 
-        txs.pos += 1;
-        if txs.pos == txs.len {
-            end_transmission(usart, tx).reply(());
+        let reader = if let Some(reader) = self.reader.take() {
+            reader
+        } else {
+            return;
+        };
+
+        let mut data = [0; RX_BUFFER_SIZE];
+        let n = self
+            .rx
+            .pop_into(&mut data[..usize::min(reader.len, RX_BUFFER_SIZE)]);
+
+        // We've made room, so make sure we're listening.
+        self.usart.cr1.modify(|_, w| w.rxneie().enabled());
+
+        if reader
+            .caller
+            .borrow(0)
+            .write_fully_at(0, &data[..n])
+            .is_some()
+        {
+            reader.caller.reply(n as u32);
+        } else {
+            reader.caller.reply_fail(UsartError::BadArg);
         }
-    } else {
-        end_transmission(usart, tx).reply_fail(ResponseCode::BadArg);
     }
 }
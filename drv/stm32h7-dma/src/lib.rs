@@ -0,0 +1,388 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Low-level drivers for the STM32H7 DMA controllers, for use by peripheral
+//! drivers that want to move data without the CPU.
+//!
+//! The H7 has three kinds of DMA controller:
+//!
+//! - DMA1 and DMA2, which have eight streams each. Which peripheral request
+//!   drives a stream is chosen through DMAMUX1. These sit in the D2 domain and
+//!   *can't reach the TCMs*, which is where our task RAM lives; the memory side
+//!   of a transfer needs to be elsewhere. In our apps, that's an output marked
+//!   `dma` in the `app.toml` (which the kernel maps uncached, since DMA doesn't
+//!   see the data cache), with a buffer placed in it through the task's
+//!   `sections`. `Stream` drives these.
+//!
+//! - BDMA, which serves the D3 peripherals (e.g. SPI6). We don't support it
+//!   yet.
+//!
+//! - MDMA, which can reach all memory, TCMs included, but can only be
+//!   triggered by a handful of peripherals -- QUADSPI among them. `Channel`
+//!   drives these.
+//!
+//! Each driver assumes that its caller has sole use of the stream or channel
+//! it's given; nothing arbitrates between tasks, so apps need to make sure
+//! their tasks don't overlap. The controllers' clocks must be on.
+//!
+//! Transfers here are always a byte at a time on both sides. Streams and
+//! channels raise their interrupts on errors, and (if asked to) when they're
+//! done; callers route those to a notification in their `app.toml`, sleep
+//! until it arrives, and then check with `is_done`. A DMA1/DMA2 stream has an
+//! IRQ of its own, while MDMA has one IRQ for all channels.
+
+#![no_std]
+
+#[cfg(feature = "h7b3")]
+use stm32h7::stm32h7b3 as device;
+
+#[cfg(feature = "h743")]
+use stm32h7::stm32h743 as device;
+
+#[cfg(feature = "h753")]
+use stm32h7::stm32h753 as device;
+
+use core::sync::atomic::{fence, Ordering};
+use vcell::VolatileCell;
+
+/// Which way a transfer goes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    PeripheralToMemory,
+    MemoryToPeripheral,
+}
+
+/// Things that can go wrong in a transfer. Any of these indicates a bug --
+/// typically, a buffer the controller can't reach.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DmaError {
+    /// The controller got a bus error.
+    TransferError,
+    /// The peripheral asked for data faster than the stream could keep up
+    /// (DMA1/DMA2 only).
+    DirectModeError,
+    /// The stream's FIFO overran or underran, because the memory side
+    /// couldn't keep up (DMA1/DMA2 only).
+    FifoError,
+}
+
+/// A stream on DMA1 or DMA2, with its DMAMUX1 channel.
+pub struct Stream {
+    dma: &'static device::dma1::RegisterBlock,
+    dmamux: &'static device::dmamux1::RegisterBlock,
+    /// Stream number on its controller, 0-7.
+    index: usize,
+}
+
+// Bits in the stream configuration register, DMA_SxCR.
+const CR_EN: u32 = 1 << 0;
+const CR_DMEIE: u32 = 1 << 1;
+const CR_TEIE: u32 = 1 << 2;
+const CR_TCIE: u32 = 1 << 4;
+const CR_DIR_M2P: u32 = 0b01 << 6;
+const CR_MINC: u32 = 1 << 10;
+
+// Bits in the stream FIFO control register, DMA_SxFCR.
+const FCR_FEIE: u32 = 1 << 7;
+
+// Per-stream bits in DMA_LISR/HISR, and the clear registers, before shifting
+// into position for a given stream.
+const ISR_FEIF: u32 = 1 << 0;
+const ISR_DMEIF: u32 = 1 << 2;
+const ISR_TEIF: u32 = 1 << 3;
+const ISR_HTIF: u32 = 1 << 4;
+const ISR_TCIF: u32 = 1 << 5;
+const ISR_ALL: u32 = ISR_FEIF | ISR_DMEIF | ISR_TEIF | ISR_HTIF | ISR_TCIF;
+
+impl Stream {
+    /// Sets up stream `index` of `dma` (which must be DMA1 or DMA2) to be
+    /// driven by DMAMUX1 request `request`, which names the peripheral and
+    /// direction (e.g. 40 for SPI2 TX); see the DMAMUX1 request table in the
+    /// reference manual.
+    pub fn new(
+        dma: &'static device::dma1::RegisterBlock,
+        dmamux: &'static device::dmamux1::RegisterBlock,
+        index: usize,
+        request: u8,
+    ) -> Self {
+        assert!(index < 8);
+        let stream = Self { dma, dmamux, index };
+        stream.stop();
+
+        // DMAMUX1 channels 0-7 go to DMA1, and 8-15 to DMA2.
+        let is_dma2 = core::ptr::eq(dma, device::DMA2::ptr());
+        let channel = index + if is_dma2 { 8 } else { 0 };
+        dmamux.ccr[channel].write(|w| unsafe { w.dmareq_id().bits(request) });
+
+        stream
+    }
+
+    /// Starts moving `len` bytes between the data register at `peripheral`
+    /// and the buffer at `memory`. `len` must be nonzero.
+    ///
+    /// The stream's interrupt fires on any error, and, if `notify_done` is
+    /// set, once the transfer is done. Until the stream is stopped, its
+    /// interrupt stays asserted after either.
+    ///
+    /// # Safety
+    ///
+    /// Until the transfer is done (as reported by `is_done`) or stopped, the
+    /// controller reads or writes `len` bytes at `memory`, behind the
+    /// compiler's back. The caller must keep that memory alive and otherwise
+    /// untouched until then.
+    pub unsafe fn start(
+        &self,
+        direction: Direction,
+        peripheral: u32,
+        memory: u32,
+        len: u16,
+        notify_done: bool,
+    ) {
+        assert!(len != 0);
+        let st = &self.dma.st[self.index];
+
+        // The stream must be off before it can be configured.
+        self.stop();
+
+        st.par.write(|w| w.bits(peripheral));
+        st.m0ar.write(|w| w.bits(memory));
+        st.ndtr.write(|w| w.bits(u32::from(len)));
+        // Direct mode (no FIFO), since both sides are bytes.
+        st.fcr.write(|w| w.bits(FCR_FEIE));
+
+        let mut cr = CR_MINC | CR_TEIE | CR_DMEIE;
+        if notify_done {
+            cr |= CR_TCIE;
+        }
+        if direction == Direction::MemoryToPeripheral {
+            cr |= CR_DIR_M2P;
+        }
+        st.cr.write(|w| w.bits(cr));
+
+        // Make sure anything we've put in the buffer is there before the
+        // controller goes looking.
+        fence(Ordering::SeqCst);
+
+        st.cr.write(|w| w.bits(cr | CR_EN));
+    }
+
+    /// Checks whether the transfer has finished.
+    pub fn is_done(&self) -> Result<bool, DmaError> {
+        let status = self.status();
+        if status & ISR_TEIF != 0 {
+            return Err(DmaError::TransferError);
+        }
+        if status & ISR_DMEIF != 0 {
+            return Err(DmaError::DirectModeError);
+        }
+        if status & ISR_FEIF != 0 {
+            return Err(DmaError::FifoError);
+        }
+        let done = status & ISR_TCIF != 0;
+        if done {
+            // Make sure we don't look at the buffer before the controller
+            // has finished with it.
+            fence(Ordering::SeqCst);
+        }
+        Ok(done)
+    }
+
+    /// Stops the stream, abandoning any transfer in progress, and clears its
+    /// status.
+    pub fn stop(&self) {
+        let st = &self.dma.st[self.index];
+        st.cr.modify(|r, w| unsafe { w.bits(r.bits() & !CR_EN) });
+        while st.cr.read().bits() & CR_EN != 0 {
+            // The stream finishes its current access before turning off.
+        }
+        self.clear_status();
+    }
+
+    /// Returns this stream's bits from the interrupt status registers,
+    /// shifted down to the bottom.
+    fn status(&self) -> u32 {
+        let isr = if self.index < 4 {
+            self.dma.lisr.read().bits()
+        } else {
+            self.dma.hisr.read().bits()
+        };
+        (isr >> self.status_shift()) & ISR_ALL
+    }
+
+    fn clear_status(&self) {
+        let bits = ISR_ALL << self.status_shift();
+        if self.index < 4 {
+            self.dma.lifcr.write(|w| unsafe { w.bits(bits) });
+        } else {
+            self.dma.hifcr.write(|w| unsafe { w.bits(bits) });
+        }
+    }
+
+    /// Each status register holds four streams' bits, at these offsets.
+    fn status_shift(&self) -> u32 {
+        [0, 6, 16, 22][self.index % 4]
+    }
+}
+
+/// A channel on the MDMA controller.
+pub struct Channel {
+    regs: &'static ChannelRegisters,
+}
+
+/// The registers for one MDMA channel.
+///
+/// svd2rust gives each MDMA channel its own set of uniquely named registers,
+/// so there's no way to pick a channel by number through the `stm32h7` crate.
+/// Instead, we lay out one channel's worth here, and find channel `n` at
+/// `0x40 * (n + 1)` bytes past the start of the controller.
+#[repr(C)]
+struct ChannelRegisters {
+    isr: VolatileCell<u32>,
+    ifcr: VolatileCell<u32>,
+    esr: VolatileCell<u32>,
+    cr: VolatileCell<u32>,
+    tcr: VolatileCell<u32>,
+    bndtr: VolatileCell<u32>,
+    sar: VolatileCell<u32>,
+    dar: VolatileCell<u32>,
+    brur: VolatileCell<u32>,
+    lar: VolatileCell<u32>,
+    tbr: VolatileCell<u32>,
+    _reserved: VolatileCell<u32>,
+    mar: VolatileCell<u32>,
+    mdr: VolatileCell<u32>,
+}
+
+// Bits in MDMA_CxCR.
+const MDMA_CR_EN: u32 = 1 << 0;
+const MDMA_CR_TEIE: u32 = 1 << 1;
+const MDMA_CR_CTCIE: u32 = 1 << 2;
+
+// Bits in MDMA_CxTCR.
+const MDMA_TCR_SINC_INCREMENT: u32 = 0b10 << 0;
+const MDMA_TCR_DINC_INCREMENT: u32 = 0b10 << 2;
+const MDMA_TCR_TLEN_SHIFT: u32 = 18;
+
+// Bits in MDMA_CxTBR.
+const MDMA_TBR_SBUS_AHB: u32 = 1 << 16;
+const MDMA_TBR_DBUS_AHB: u32 = 1 << 17;
+
+// Bits in MDMA_CxISR and MDMA_CxIFCR.
+const MDMA_ISR_TEIF: u32 = 1 << 0;
+const MDMA_ISR_CTCIF: u32 = 1 << 1;
+const MDMA_ISR_ALL: u32 = 0b1_1111;
+
+impl Channel {
+    /// Picks out channel `index` of `mdma`.
+    pub fn new(
+        mdma: &'static device::mdma::RegisterBlock,
+        index: usize,
+    ) -> Self {
+        assert!(index < 16);
+        let base = mdma as *const _ as usize + 0x40 * (index + 1);
+        // Safety: the channel registers are where we say they are, and live as
+        // long as the controller does.
+        let regs = unsafe { &*(base as *const ChannelRegisters) };
+        let channel = Self { regs };
+        channel.stop();
+        channel
+    }
+
+    /// Starts moving `len` bytes between the data register at `peripheral`
+    /// and the buffer at `memory`. The transfer is paced by MDMA trigger
+    /// `trigger` (e.g. 22 for the QUADSPI FIFO threshold; see the MDMA request
+    /// table in the reference manual), and each time it fires, up to
+    /// `burst` bytes are moved. `len` must be nonzero.
+    ///
+    /// The MDMA interrupt fires once the transfer is done, or on an error, and
+    /// stays asserted until the channel is stopped.
+    ///
+    /// # Safety
+    ///
+    /// As for `Stream::start`: the controller owns `len` bytes at `memory`
+    /// until the transfer is done or stopped. On top of that, the memory must
+    /// not be cached, since MDMA doesn't see the data cache. (The TCMs are
+    /// never cached.)
+    pub unsafe fn start(
+        &self,
+        direction: Direction,
+        trigger: u8,
+        peripheral: u32,
+        memory: u32,
+        len: u32,
+        burst: u8,
+    ) {
+        assert!(len != 0 && len <= 0x1_0000);
+        assert!(burst != 0 && burst <= 128);
+        assert!(trigger < 64);
+
+        self.stop();
+
+        let (source, dest) = match direction {
+            Direction::PeripheralToMemory => (peripheral, memory),
+            Direction::MemoryToPeripheral => (memory, peripheral),
+        };
+        // Only the memory side moves.
+        let increment = match direction {
+            Direction::PeripheralToMemory => MDMA_TCR_DINC_INCREMENT,
+            Direction::MemoryToPeripheral => MDMA_TCR_SINC_INCREMENT,
+        };
+
+        // Each trigger moves one buffer of `burst` bytes; the whole thing is a
+        // single block of `len` bytes, and no linked list.
+        self.regs
+            .tcr
+            .set(increment | u32::from(burst - 1) << MDMA_TCR_TLEN_SHIFT);
+        self.regs.bndtr.set(len);
+        self.regs.sar.set(source);
+        self.regs.dar.set(dest);
+        self.regs.brur.set(0);
+        self.regs.lar.set(0);
+        self.regs.mar.set(0);
+        self.regs.mdr.set(0);
+
+        // The TCMs are only reachable through the AHB port; everything else
+        // goes through AXI.
+        let mut tbr = u32::from(trigger);
+        if is_tcm(source) {
+            tbr |= MDMA_TBR_SBUS_AHB;
+        }
+        if is_tcm(dest) {
+            tbr |= MDMA_TBR_DBUS_AHB;
+        }
+        self.regs.tbr.set(tbr);
+
+        fence(Ordering::SeqCst);
+
+        self.regs.cr.set(MDMA_CR_TEIE | MDMA_CR_CTCIE | MDMA_CR_EN);
+    }
+
+    /// Checks whether the transfer has finished.
+    pub fn is_done(&self) -> Result<bool, DmaError> {
+        let status = self.regs.isr.get();
+        if status & MDMA_ISR_TEIF != 0 {
+            return Err(DmaError::TransferError);
+        }
+        let done = status & MDMA_ISR_CTCIF != 0;
+        if done {
+            fence(Ordering::SeqCst);
+        }
+        Ok(done)
+    }
+
+    /// Stops the channel, abandoning any transfer in progress, and clears its
+    /// status.
+    pub fn stop(&self) {
+        self.regs.cr.set(self.regs.cr.get() & !MDMA_CR_EN);
+        while self.regs.cr.get() & MDMA_CR_EN != 0 {
+            // Wait for the current access to finish.
+        }
+        self.regs.ifcr.set(MDMA_ISR_ALL);
+    }
+}
+
+/// Checks whether `addr` is in ITCM or DTCM.
+fn is_tcm(addr: u32) -> bool {
+    addr < 0x0001_0000 || (0x2000_0000..0x2002_0000).contains(&addr)
+}
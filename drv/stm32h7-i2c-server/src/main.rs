@@ -141,6 +141,138 @@ fn reset_if_needed(
     });
 }
 
+///
+/// Performs the transfers of a transaction (see [`Transaction`]), encoded in
+/// `lease`, stopping at the first to fail. Returns the number of transfers.
+///
+fn transaction(
+    controller: &I2cController,
+    lease: &hl::Borrow,
+    pec: bool,
+    ctrl: &I2cControl,
+) -> Result<usize, ResponseCode> {
+    let info = lease.info().ok_or(ResponseCode::BadArg)?;
+
+    if !info
+        .attributes
+        .contains(LeaseAttributes::READ | LeaseAttributes::WRITE)
+    {
+        return Err(ResponseCode::BadArg);
+    }
+
+    let mut pos = 0;
+    let mut count = 0;
+
+    while pos < info.len {
+        let header = TransferHeader::from_bytes(
+            lease.read_at(pos).ok_or(ResponseCode::BadArg)?,
+        )
+        .ok_or(ResponseCode::BadArg)?;
+
+        let addr = header.address;
+        let (wlen, rlen) = (header.wlen, header.rlen);
+
+        if let Some(_) = ReservedAddress::from_u8(addr) {
+            return Err(ResponseCode::ReservedAddress);
+        }
+
+        if wlen == 0 && rlen == 0 {
+            return Err(ResponseCode::BadArg);
+        }
+
+        if pec && (wlen == 255 || rlen == 255) {
+            // No room for the PEC
+            return Err(ResponseCode::BadArg);
+        }
+
+        let wpos = pos + TRANSFER_HEADER_LEN;
+        let rpos = pos + header.read_offset();
+
+        if pos + header.len() > info.len {
+            return Err(ResponseCode::BadArg);
+        }
+
+        let mut nread = 0;
+
+        controller.write_read_pec(
+            addr,
+            wlen,
+            |i| lease.read_at(wpos + i),
+            match header.kind {
+                TransferKind::WriteRead => ReadLength::Fixed(rlen),
+                TransferKind::WriteReadBlock => ReadLength::Variable,
+            },
+            |i, byte| {
+                // A block read can't overrun the room it was given.
+                if i >= rlen {
+                    return None;
+                }
+
+                if i + 1 > nread {
+                    nread = i + 1;
+                }
+
+                lease.write_at(rpos + i, byte)
+            },
+            pec,
+            ctrl,
+        )?;
+
+        // Let the caller know that we got this far, and how much the device
+        // actually sent -- which for a block read can be less than the room
+        // it left.
+        let done = TransferHeader {
+            nread,
+            done: true,
+            ..header
+        };
+        lease
+            .write_at(pos, done.to_bytes())
+            .ok_or(ResponseCode::BadArg)?;
+
+        pos += header.len();
+        count += 1;
+    }
+
+    if count == 0 {
+        return Err(ResponseCode::BadArg);
+    }
+
+    Ok(count)
+}
+
+///
+/// Probes every address that isn't reserved by reading a byte from it,
+/// returning a bitmap of the addresses that acknowledged.
+///
+fn scan(
+    controller: &I2cController,
+    ctrl: &I2cControl,
+) -> Result<u128, ResponseCode> {
+    let mut present = 0;
+
+    for addr in 0..128 {
+        if let Some(_) = ReservedAddress::from_u8(addr) {
+            continue;
+        }
+
+        match controller.write_read(
+            addr,
+            0,
+            |_| None,
+            ReadLength::Fixed(1),
+            |_, _| Some(()),
+            ctrl,
+        ) {
+            Ok(_) => present |= 1 << addr,
+            Err(ResponseCode::NoDevice) => {}
+            Err(code) => return Err(code),
+        }
+    }
+
+    Ok(present)
+}
+
 type PortMap = FixedMap<Controller, PortIndex, 8>;
 type MuxMap = FixedMap<Mux, Segment, 4>;
 
@@ -162,7 +294,7 @@ fn main() -> ! {
     configure_controllers(&controllers);
 
     // Field messages.
-    let mut buffer = [0; 4];
+    let mut buffer = [0; 5];
 
     let ctrl = I2cControl {
         enable: |notification| {
@@ -177,15 +309,26 @@ fn main() -> ! {
 
     loop {
         hl::recv_without_notification(&mut buffer, |op, msg| match op {
-            Op::WriteRead | Op::WriteReadBlock => {
+            Op::WriteRead
+            | Op::WriteReadBlock
+            | Op::BlockProcessCall
+            | Op::Transaction
+            | Op::Scan => {
+                let nleases = match op {
+                    Op::Transaction | Op::Scan => 1,
+                    Op::BlockProcessCall => 3,
+                    _ => 2,
+                };
+
                 let (payload, caller) = msg
-                    .fixed_with_leases::<[u8; 4], usize>(2)
+                    .fixed_with_leases::<[u8; 5], usize>(nleases)
                     .ok_or(ResponseCode::BadArg)?;
 
-                let (addr, controller, port, mux) =
+                let (addr, controller, port, mux, pec) =
                     Marshal::unmarshal(payload)?;
 
-                if let Some(_) = ReservedAddress::from_u8(addr) {
+                // A scan has no address of its own.
+                if op != Op::Scan && ReservedAddress::from_u8(addr).is_some() {
                     return Err(ResponseCode::ReservedAddress);
                 }
 
@@ -209,6 +352,43 @@ fn main() -> ! {
                     }
                 }
 
+                if op == Op::Scan {
+                    let lease = caller.borrow(0);
+
+                    return match scan(controller, &ctrl) {
+                        Err(code) => {
+                            reset_if_needed(
+                                code, controller, port, &muxes, mux,
+                            );
+                            Err(code)
+                        }
+                        Ok(present) => {
+                            lease
+                                .write_at(0, present)
+                                .ok_or(ResponseCode::BadArg)?;
+                            caller.reply(present.count_ones() as usize);
+                            Ok(())
+                        }
+                    };
+                }
+
+                if op == Op::Transaction {
+                    let lease = caller.borrow(0);
+
+                    return match transaction(controller, &lease, pec, &ctrl) {
+                        Err(code) => {
+                            reset_if_needed(
+                                code, controller, port, &muxes, mux,
+                            );
+                            Err(code)
+                        }
+                        Ok(count) => {
+                            caller.reply(count);
+                            Ok(())
+                        }
+                    };
+                }
+
                 let wbuf = caller.borrow(0);
                 let winfo = wbuf.info().ok_or(ResponseCode::BadArg)?;
 
@@ -216,10 +396,34 @@ fn main() -> ! {
                     return Err(ResponseCode::BadArg);
                 }
 
-                let rbuf = caller.borrow(1);
+                //
+                // For a block process call, the write is followed by a block:
+                // a byte count, and then the contents of the next lease.
+                //
+                let (block, rbuf) = if op == Op::BlockProcessCall {
+                    (Some(caller.borrow(1)), caller.borrow(2))
+                } else {
+                    (None, caller.borrow(1))
+                };
+
+                let blen = match &block {
+                    Some(block) => {
+                        let binfo = block.info().ok_or(ResponseCode::BadArg)?;
+
+                        if !binfo.attributes.contains(LeaseAttributes::READ) {
+                            return Err(ResponseCode::BadArg);
+                        }
+
+                        Some(binfo.len)
+                    }
+                    None => None,
+                };
+
+                let wlen = winfo.len + blen.map_or(0, |len| len + 1);
+
                 let rinfo = rbuf.info().ok_or(ResponseCode::BadArg)?;
 
-                if winfo.len == 0 && rinfo.len == 0 {
+                if wlen == 0 && rinfo.len == 0 {
                     // We must have either a write OR a read -- while perhaps
                     // valid to support both being zero as a way of testing an
                     // address for a NACK, it's not a mode that we (currently)
@@ -227,18 +431,34 @@ fn main() -> ! {
                     return Err(ResponseCode::BadArg);
                 }
 
-                if winfo.len > 255 || rinfo.len > 255 {
+                if wlen > 255 || rinfo.len > 255 {
                     // For now, we don't support writing or reading more than
                     // 255 bytes.
                     return Err(ResponseCode::BadArg);
                 }
 
+                if pec
+                    && (wlen == 255
+                        || (op == Op::WriteRead && rinfo.len == 255))
+                {
+                    // With PEC, we also need room for it.
+                    return Err(ResponseCode::BadArg);
+                }
+
                 let mut nread = 0;
 
-                match controller.write_read(
+                match controller.write_read_pec(
                     addr,
-                    winfo.len,
-                    |pos| wbuf.read_at(pos),
+                    wlen,
+                    |pos| {
+                        if pos < winfo.len {
+                            wbuf.read_at(pos)
+                        } else if pos == winfo.len {
+                            blen.map(|len| len as u8)
+                        } else {
+                            block.as_ref()?.read_at(pos - winfo.len - 1)
+                        }
+                    },
                     if op == Op::WriteRead {
                         ReadLength::Fixed(rinfo.len)
                     } else {
@@ -251,6 +471,7 @@ fn main() -> ! {
 
                         rbuf.write_at(pos, byte)
                     },
+                    pec,
                     &ctrl,
                 ) {
                     Err(code) => {
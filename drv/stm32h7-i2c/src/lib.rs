@@ -139,6 +139,24 @@ enum Trace {
 
 ringbuf!(Trace, 48, Trace::None);
 
+///
+/// Updates an SMBus packet error code -- a CRC-8 with a polynomial of
+/// x^8 + x^2 + x + 1 -- with another byte.
+///
+fn crc8(crc: u8, byte: u8) -> u8 {
+    let mut crc = crc ^ byte;
+
+    for _ in 0..8 {
+        crc = if crc & 0x80 != 0 {
+            (crc << 1) ^ 0x07
+        } else {
+            crc << 1
+        };
+    }
+
+    crc
+}
+
 impl<'a> I2cMux<'_> {
     /// A convenience routine to translate an error induced by in-band
     /// management into one that can be returned to a caller
@@ -378,31 +396,66 @@ impl<'a> I2cController<'a> {
     /// the device can support longer buffers, and the implementation could
     /// be extended in the future to allow them.
     pub fn write_read(
+        &self,
+        addr: u8,
+        wlen: usize,
+        getbyte: impl Fn(usize) -> Option<u8>,
+        rlen: ReadLength,
+        putbyte: impl FnMut(usize, u8) -> Option<()>,
+        ctrl: &I2cControl,
+    ) -> Result<(), drv_i2c_api::ResponseCode> {
+        self.write_read_pec(addr, wlen, getbyte, rlen, putbyte, false, ctrl)
+    }
+
+    /// Like [`write_read`], but if `pec` is set, performs SMBus packet error
+    /// checking: a PEC byte covering every byte of the write and the read
+    /// (including the address bytes) is sent after a write alone, or is
+    /// received -- and checked -- after the read, failing with `PecMismatch`
+    /// if it's wrong.  With PEC, the lengths (including the length of a
+    /// variable read) must be less than 255 bytes, to leave room for it.
+    pub fn write_read_pec(
         &self,
         addr: u8,
         wlen: usize,
         getbyte: impl Fn(usize) -> Option<u8>,
         mut rlen: ReadLength,
         mut putbyte: impl FnMut(usize, u8) -> Option<()>,
+        pec: bool,
         ctrl: &I2cControl,
     ) -> Result<(), drv_i2c_api::ResponseCode> {
+        let extra = pec as usize;
+
         // Assert our preconditions as described above
         assert!(wlen > 0 || rlen != ReadLength::Fixed(0));
-        assert!(wlen <= 255);
+        assert!(wlen + extra <= 255);
 
         if let ReadLength::Fixed(rlen) = rlen {
-            assert!(rlen <= 255);
+            assert!(rlen + extra <= 255);
         }
 
+        // Our PEC so far (which we maintain whether we're using it or not)
+        let mut crc = 0;
+        let mut pec_ok = true;
+
         let i2c = self.registers;
         let notification = self.notification;
 
         self.wait_until_notbusy()?;
 
         if wlen > 0 {
+            //
+            // The PEC comes at the end of the whole transfer: we only send it
+            // after the write if there's no read to follow.
+            //
+            let extra = if rlen == ReadLength::Fixed(0) {
+                extra
+            } else {
+                0
+            };
+
             #[rustfmt::skip]
             i2c.cr2.modify(|_, w| { w
-                .nbytes().bits(wlen as u8)
+                .nbytes().bits((wlen + extra) as u8)
                 .autoend().clear_bit()
                 .add10().clear_bit()
                 .sadd().bits((addr << 1).into())
@@ -410,9 +463,11 @@ impl<'a> I2cController<'a> {
                 .start().set_bit()
             });
 
+            crc = crc8(crc, addr << 1);
+
             let mut pos = 0;
 
-            while pos < wlen {
+            while pos < wlen + extra {
                 loop {
                     let isr = i2c.isr.read();
                     ringbuf_entry!(Trace::WriteISR(isr.bits()));
@@ -440,9 +495,15 @@ impl<'a> I2cController<'a> {
                     (ctrl.enable)(notification);
                 }
 
-                // Get a single byte.
-                let byte =
-                    getbyte(pos).ok_or(drv_i2c_api::ResponseCode::BadArg)?;
+                // Get a single byte -- or, after the last, our PEC.
+                let byte = if pos < wlen {
+                    let byte = getbyte(pos)
+                        .ok_or(drv_i2c_api::ResponseCode::BadArg)?;
+                    crc = crc8(crc, byte);
+                    byte
+                } else {
+                    crc
+                };
 
                 // And send it!
                 i2c.txdr.write(|w| w.txdata().bits(byte));
@@ -489,7 +550,7 @@ impl<'a> I2cController<'a> {
             if let ReadLength::Fixed(rlen) = rlen {
                 #[rustfmt::skip]
                 i2c.cr2.modify(|_, w| { w
-                    .nbytes().bits(rlen as u8)
+                    .nbytes().bits((rlen + extra) as u8)
                     .autoend().clear_bit()
                     .add10().clear_bit()
                     .sadd().bits((addr << 1).into())
@@ -509,11 +570,13 @@ impl<'a> I2cController<'a> {
                 });
             }
 
+            crc = crc8(crc, (addr << 1) | 1);
+
             let mut pos = 0;
 
             loop {
                 if let ReadLength::Fixed(rlen) = rlen {
-                    if pos >= rlen {
+                    if pos >= rlen + extra {
                         break;
                     }
                 }
@@ -549,16 +612,33 @@ impl<'a> I2cController<'a> {
                 let byte: u8 = i2c.rxdr.read().rxdata().bits();
 
                 if rlen == ReadLength::Variable {
+                    if pec && byte == 255 {
+                        // No room for the PEC after the block.  We're in the
+                        // middle of a reload with no way to end the transfer
+                        // cleanly, so reset the controller to release the bus
+                        // rather than leaving it held.
+                        self.reset();
+                        return Err(drv_i2c_api::ResponseCode::BadArg);
+                    }
+
                     #[rustfmt::skip]
                     i2c.cr2.modify(|_, w| { w
-                        .nbytes().bits(byte)
+                        .nbytes().bits(byte + extra as u8)
                         .reload().clear_bit()
                     });
 
+                    crc = crc8(crc, byte);
                     rlen = ReadLength::Fixed(byte.into());
                     continue;
                 }
 
+                if rlen == ReadLength::Fixed(pos) {
+                    // This is the PEC rather than data -- and the last byte.
+                    pec_ok = byte == crc;
+                    break;
+                }
+
+                crc = crc8(crc, byte);
                 putbyte(pos, byte).ok_or(drv_i2c_api::ResponseCode::BadArg)?;
                 pos += 1;
             }
@@ -593,6 +673,10 @@ impl<'a> I2cController<'a> {
         //
         i2c.cr2.modify(|_, w| w.stop().set_bit());
 
+        if !pec_ok {
+            return Err(drv_i2c_api::ResponseCode::PecMismatch);
+        }
+
         Ok(())
     }
 
@@ -3,6 +3,13 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! STM32H7 QSPI low-level driver crate.
+//!
+//! By default, data is moved through the controller's FIFO by the CPU. If
+//! given an MDMA channel with `Qspi::enable_dma`, longer transfers are moved
+//! by MDMA instead. MDMA doesn't see the data cache, so buffers handed to
+//! those transfers must be in uncached memory -- which task RAM, living in
+//! DTCM, is. The MDMA interrupt must be routed to the task too, on a
+//! notification of its own.
 
 #![no_std]
 
@@ -14,16 +21,22 @@ use stm32h7::stm32h743 as device;
 #[cfg(feature = "h753")]
 use stm32h7::stm32h753 as device;
 
+use drv_stm32h7_dma::{Channel, Direction, DmaError};
 use userlib::{sys_irq_control, sys_recv_closed, TaskId};
 use zerocopy::AsBytes;
 
 const FIFO_SIZE: usize = 32;
 const FIFO_THRESH: usize = 16;
 
+/// MDMA trigger for the QUADSPI FIFO threshold flag.
+const MDMA_FIFO_TRIGGER: u8 = 22;
+
 /// Wrapper for a reference to the register block.
 pub struct Qspi {
     reg: &'static device::quadspi::RegisterBlock,
     interrupt: u32,
+    /// MDMA channel, and the notification for the MDMA interrupt.
+    dma: Option<(Channel, u32)>,
 }
 
 enum Command {
@@ -50,7 +63,18 @@ impl Qspi {
         reg: &'static device::quadspi::RegisterBlock,
         interrupt: u32,
     ) -> Self {
-        Self { reg, interrupt }
+        Self {
+            reg,
+            interrupt,
+            dma: None,
+        }
+    }
+
+    /// Has MDMA `channel` move the data for transfers too long to fit in the
+    /// FIFO in one go. The MDMA controller must have its clock enabled, and
+    /// its interrupt must be routed to notification `interrupt`.
+    pub fn enable_dma(&mut self, channel: Channel, interrupt: u32) {
+        self.dma = Some((channel, interrupt));
     }
 
     /// Sets up the QSPI controller with some canned settings.
@@ -91,26 +115,33 @@ impl Qspi {
     /// This can be used to get basic details of the chip, and also to detect
     /// whether a chip is attached at all.
     pub fn read_id(&self, buf: &mut [u8; 20]) {
-        self.read_impl(Command::ReadId, None, buf)
+        // This fits in the FIFO, so doesn't use DMA, so can't fail.
+        self.read_impl(Command::ReadId, None, buf).unwrap()
     }
 
     /// Reads the Status register.
     pub fn read_status(&self) -> u8 {
         let mut status = 0u8;
-        self.read_impl(Command::ReadStatusReg, None, status.as_bytes_mut());
+        self.read_impl(Command::ReadStatusReg, None, status.as_bytes_mut())
+            .unwrap();
         status
     }
 
     /// Reads from flash storage starting at `address` and continuing for
-    /// `data.len()` bytes, depositing the bytes into `data`.
-    pub fn read_memory(&self, address: u32, data: &mut [u8]) {
-        self.read_impl(Command::Read, Some(address), data);
+    /// `data.len()` bytes, depositing the bytes into `data`. This fails only
+    /// if DMA does, in which case `data` holds garbage.
+    pub fn read_memory(
+        &self,
+        address: u32,
+        data: &mut [u8],
+    ) -> Result<(), DmaError> {
+        self.read_impl(Command::Read, Some(address), data)
     }
 
     /// Sets the Write Enable Latch on the flash chip, allowing a write/erase
     /// command sent immediately after to succeed.
     pub fn write_enable(&self) {
-        self.write_impl(Command::WriteEnable, None, &[])
+        self.write_impl(Command::WriteEnable, None, &[]).unwrap()
     }
 
     /// Performs a bulk erase of the chip. Note that this may take a rather long
@@ -120,7 +151,7 @@ impl Qspi {
     ///
     /// Erasing a NAND flash chip resets all bits to 1.
     pub fn bulk_erase(&self) {
-        self.write_impl(Command::BulkErase, None, &[])
+        self.write_impl(Command::BulkErase, None, &[]).unwrap()
     }
 
     /// Erases the 64kiB sector containing `addr`.
@@ -131,6 +162,7 @@ impl Qspi {
     /// Erasing a sector of a NAND flash chip resets all bits to 1.
     pub fn sector_erase(&self, addr: u32) {
         self.write_impl(Command::SectorErase, Some(addr), &[])
+            .unwrap()
     }
 
     /// Writes `data` into flash memory beginning at `addr`.
@@ -143,12 +175,21 @@ impl Qspi {
     /// It is sometimes (rarely) useful to deliberately overwrite data using
     /// this routine, to update information without erasing -- but of course it
     /// can only clear bits.
-    pub fn page_program(&self, addr: u32, data: &[u8]) {
+    ///
+    /// This fails only if DMA does, in which case some unknown prefix of
+    /// `data` may have been written.
+    pub fn page_program(&self, addr: u32, data: &[u8]) -> Result<(), DmaError> {
         self.write_impl(Command::PageProgram, Some(addr), data)
     }
 
-    /// Internal implementation of writes.
-    fn write_impl(&self, command: Command, addr: Option<u32>, data: &[u8]) {
+    /// Internal implementation of writes. This can only fail if it uses DMA,
+    /// which it doesn't without data.
+    fn write_impl(
+        &self,
+        command: Command,
+        addr: Option<u32>,
+        data: &[u8],
+    ) -> Result<(), DmaError> {
         if !data.is_empty() {
             self.set_transfer_length(data.len());
         }
@@ -156,6 +197,23 @@ impl Qspi {
         // Clear flags we'll use later.
         self.reg.fcr.write(|w| w.ctcf().set_bit());
 
+        let dma = self.dma_for(data.len());
+        if let Some((channel, _)) = dma {
+            // Safety: `data` outlives the transfer, since we don't return
+            // until we've stopped the channel.
+            unsafe {
+                channel.start(
+                    Direction::MemoryToPeripheral,
+                    MDMA_FIFO_TRIGGER,
+                    self.dr_address(),
+                    data.as_ptr() as u32,
+                    data.len() as u32,
+                    FIFO_THRESH as u8,
+                );
+            }
+            self.reg.cr.modify(|_, w| w.dmaen().set_bit());
+        }
+
         // Note: if we aren't using an address, this write will kick things off.
         // Otherwise it's the AR write below.
         #[rustfmt::skip]
@@ -182,6 +240,29 @@ impl Qspi {
             self.reg.ar.write(|w| unsafe { w.address().bits(addr) });
         }
 
+        // With DMA, there's nothing for us to do but wait for completion.
+        if dma.is_none() {
+            self.write_fifo(data);
+        }
+
+        // We're now interested in transfer complete, not FIFO ready.
+        self.reg
+            .cr
+            .modify(|_, w| w.ftie().clear_bit().tcie().set_bit());
+        let done = match dma {
+            Some(dma) => self.wait_for_dma(dma),
+            None => {
+                self.wait_for_completion();
+                Ok(())
+            }
+        };
+        self.reg.cr.modify(|_, w| w.tcie().clear_bit());
+
+        done
+    }
+
+    /// Feeds `data` into the FIFO as the controller sends it.
+    fn write_fifo(&self, data: &[u8]) {
         // We're going to update this slice in place as we send data by lopping
         // off the front.
         let mut data = data;
@@ -225,23 +306,16 @@ impl Qspi {
             // Just loop back around to the fast path to avoid duplicating code
             // here.
         }
-
-        // We're now interested in transfer complete, not FIFO ready.
-        self.reg
-            .cr
-            .modify(|_, w| w.ftie().clear_bit().tcie().set_bit());
-        while self.is_busy() {
-            // Unmask our interrupt.
-            sys_irq_control(self.interrupt, true);
-            // And wait for it to arrive.
-            let _rm = sys_recv_closed(&mut [], self.interrupt, TaskId::KERNEL)
-                .unwrap();
-        }
-        self.reg.cr.modify(|_, w| w.tcie().clear_bit());
     }
 
-    /// Internal implementation of reads.
-    fn read_impl(&self, command: Command, addr: Option<u32>, out: &mut [u8]) {
+    /// Internal implementation of reads. This can only fail if it uses DMA,
+    /// which it doesn't for anything that fits in the FIFO.
+    fn read_impl(
+        &self,
+        command: Command,
+        addr: Option<u32>,
+        out: &mut [u8],
+    ) -> Result<(), DmaError> {
         assert!(!out.is_empty());
 
         self.set_transfer_length(out.len());
@@ -250,6 +324,23 @@ impl Qspi {
         // hanging around from some previous transfer -- ensure this:
         self.reg.fcr.write(|w| w.ctcf().set_bit());
 
+        let dma = self.dma_for(out.len());
+        if let Some((channel, _)) = dma {
+            // Safety: `out` outlives the transfer, since we don't return until
+            // we've stopped the channel.
+            unsafe {
+                channel.start(
+                    Direction::PeripheralToMemory,
+                    MDMA_FIFO_TRIGGER,
+                    self.dr_address(),
+                    out.as_mut_ptr() as u32,
+                    out.len() as u32,
+                    FIFO_THRESH as u8,
+                );
+            }
+            self.reg.cr.modify(|_, w| w.dmaen().set_bit());
+        }
+
         #[rustfmt::skip]
         self.reg.ccr.write(|w| unsafe {
             w
@@ -274,6 +365,47 @@ impl Qspi {
             self.reg.ar.write(|w| unsafe { w.address().bits(addr) });
         }
 
+        // With DMA, there's nothing for us to do but wait for completion.
+        if dma.is_none() {
+            self.read_fifo(out);
+        }
+
+        // There's a chance we race BUSY clearing here, because we've seen it
+        // happen in the wild, no matter what the reference manual might
+        // suggest. Waiting for transfer complete seems to be good enough,
+        // though the relationship between BUSY and TC is not documented.
+        self.reg
+            .cr
+            .modify(|_, w| w.ftie().clear_bit().tcie().set_bit());
+        let done = match dma {
+            Some(dma) => self.wait_for_dma(dma),
+            None => {
+                self.wait_for_completion();
+                Ok(())
+            }
+        };
+
+        // Clean up by disabling our interrupt sources.
+        self.reg
+            .cr
+            .modify(|_, w| w.ftie().clear_bit().tcie().clear_bit());
+
+        done
+    }
+
+    /// Sleeps until the controller is no longer busy.
+    fn wait_for_completion(&self) {
+        while self.is_busy() {
+            // Unmask our interrupt.
+            sys_irq_control(self.interrupt, true);
+            // And wait for it to arrive.
+            let _rm = sys_recv_closed(&mut [], self.interrupt, TaskId::KERNEL)
+                .unwrap();
+        }
+    }
+
+    /// Drains the FIFO into `out` as the controller receives data.
+    fn read_fifo(&self, out: &mut [u8]) {
         // We're going to shorten this slice by lopping off the front as we
         // perform transfers.
         let mut out = out;
@@ -324,26 +456,70 @@ impl Qspi {
 
             // next!
         }
+    }
 
-        // There's a chance we race BUSY clearing here, because we've seen it
-        // happen in the wild, no matter what the reference manual might
-        // suggest. Waiting for transfer complete seems to be good enough,
-        // though the relationship between BUSY and TC is not documented.
-        self.reg
-            .cr
-            .modify(|_, w| w.ftie().clear_bit().tcie().set_bit());
-        while self.is_busy() {
-            // Unmask our interrupt.
-            sys_irq_control(self.interrupt, true);
-            // And wait for it to arrive.
-            let _rm = sys_recv_closed(&mut [], self.interrupt, TaskId::KERNEL)
-                .unwrap();
-        }
+    /// Decides whether a transfer of `len` bytes should use DMA, returning
+    /// the channel and its notification if so. Anything that fits in the FIFO
+    /// isn't worth it.
+    fn dma_for(&self, len: usize) -> Option<(&Channel, u32)> {
+        self.dma
+            .as_ref()
+            .filter(|_| len > FIFO_SIZE)
+            .map(|(channel, interrupt)| (channel, *interrupt))
+    }
 
-        // Clean up by disabling our interrupt sources.
-        self.reg
-            .cr
-            .modify(|_, w| w.ftie().clear_bit().tcie().clear_bit());
+    /// Sleeps until both the controller and the DMA channel are done with a
+    /// transfer, and then wraps it up. (On reads, the channel may still be
+    /// moving the last bytes received after the controller is done.)
+    ///
+    /// If the channel fails, the controller would wait forever for it, so
+    /// the transfer is aborted instead. That means we were handed a buffer
+    /// MDMA can't reach, which is a bug.
+    fn wait_for_dma(
+        &self,
+        (channel, dma_interrupt): (&Channel, u32),
+    ) -> Result<(), DmaError> {
+        let done = loop {
+            let dma_done = match channel.is_done() {
+                Ok(done) => done,
+                Err(e) => {
+                    self.reg.cr.modify(|_, w| w.abort().set_bit());
+                    while self.reg.cr.read().abort().bit() {
+                        // The abort takes effect once the controller is idle.
+                    }
+                    break Err(e);
+                }
+            };
+            let busy = self.is_busy();
+            if dma_done && !busy {
+                break Ok(());
+            }
+
+            // The channel's interrupt stays asserted once it's done, so only
+            // listen for it until then.
+            if busy {
+                sys_irq_control(self.interrupt, true);
+            }
+            if !dma_done {
+                sys_irq_control(dma_interrupt, true);
+            }
+            let _rm = sys_recv_closed(
+                &mut [],
+                self.interrupt | dma_interrupt,
+                TaskId::KERNEL,
+            )
+            .unwrap();
+        };
+
+        self.reg.cr.modify(|_, w| w.dmaen().clear_bit());
+        channel.stop();
+        done
+    }
+
+    /// Address of the Data Register, for the peripheral side of a DMA
+    /// transfer.
+    fn dr_address(&self) -> u32 {
+        &self.reg.dr as *const _ as u32
     }
 
     fn set_transfer_length(&self, len: usize) {
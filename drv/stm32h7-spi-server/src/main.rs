@@ -7,6 +7,24 @@
 //! Currently this hardcodes the clock rate.
 //!
 //! See the `spi-api` crate for the protocol being implemented here.
+//!
+//! By default, data is moved through the controller's FIFOs by the CPU, one
+//! byte at a time. With the `dma` feature, it's moved by DMA1 or DMA2 instead,
+//! in chunks staged through buffers in the `.dma_buffer` section. Those
+//! controllers can't reach the TCMs, so the app must map that section into
+//! memory they can reach, which should be an output marked `dma` so the
+//! kernel maps it uncached:
+//!
+//! ```toml
+//! requires = {flash = 16384, ram = 4096, sram = 2048}
+//! sections = {dma_buffer = "sram"}
+//! uses = ["spi2", "dma1", "dmamux1"]
+//! interrupts = {36 = 1, 13 = 2, 14 = 2}
+//! ```
+//!
+//! The streams each controller uses are fixed below; see `DMA_CONFIG`. Both
+//! streams' interrupts must go to notification bit 1, alongside the
+//! controller's on bit 0.
 
 #![no_std]
 #![no_main]
@@ -35,14 +53,22 @@ task_slot!(GPIO, gpio_driver);
 #[derive(Copy, Clone, PartialEq)]
 enum Trace {
     Start(Operation, (usize, usize)),
+    #[cfg(not(feature = "dma"))]
     Tx(usize, u8),
+    #[cfg(not(feature = "dma"))]
     Rx(usize, u8),
+    #[cfg(feature = "dma")]
+    Dma(usize, usize),
+    #[cfg(feature = "dma")]
+    DmaError(drv_stm32h7_dma::DmaError),
     None,
 }
 
 ringbuf!(Trace, 64, Trace::None);
 
 const IRQ_MASK: u32 = 1;
+#[cfg(feature = "dma")]
+const DMA_IRQ_MASK: u32 = 2;
 
 #[derive(Copy, Clone, Debug)]
 struct LockState {
@@ -60,6 +86,10 @@ fn main() -> ! {
 
     rcc_driver.enable_clock(CONFIG.peripheral);
     rcc_driver.leave_reset(CONFIG.peripheral);
+    // The DMA controller may be shared with other tasks, so we just make sure
+    // it's on rather than resetting it.
+    #[cfg(feature = "dma")]
+    rcc_driver.enable_clock(DMA_CONFIG.peripheral);
     let mut spi = spi_core::Spi::from(registers);
 
     // This should correspond to '0' in the standard SPI parlance
@@ -334,35 +364,6 @@ fn main() -> ! {
                         current_mux_index = device.mux_index;
                     }
 
-                    // Make sure SPI is on.
-                    spi.enable(xfer_len.0 as u16);
-
-                    // Load transfer count and start the state machine. At this
-                    // point we _have_ to move the specified number of bytes
-                    // through (or explicitly cancel, but we don't).
-                    spi.start();
-
-                    // As you might expect, we will work from byte 0 to the end
-                    // of each buffer. There are two complications:
-                    //
-                    // 1. Transmit and receive can be at different positions --
-                    //    transmit will tend to lead receive, because the SPI
-                    //    unit contains FIFOs.
-                    //
-                    // 2. We're only keeping track of position in the buffers
-                    //    we're using: both tx and rx are `Option<(Borrow,
-                    //    usize)>`.
-
-                    // Tack a position field onto whichever borrows actually
-                    // exist.
-                    let mut tx = data_src.map(|borrow| (borrow, 0));
-                    let mut rx = data_dst.map(|borrow| (borrow, 0));
-
-                    // Enable interrupt on the conditions we're interested in.
-                    spi.enable_transfer_interrupts();
-
-                    spi.clear_eot();
-
                     // We're doing this! Check if we need to control CS.
                     let cs_override = lock_holder.is_some();
                     if !cs_override {
@@ -371,98 +372,16 @@ fn main() -> ! {
                             .unwrap();
                     }
 
-                    // While work remains, we'll attempt to move up to one byte
-                    // in each direction, sleeping if we can do neither.
-                    while tx.is_some() || rx.is_some() {
-                        // Entering RECV to check for interrupts is not free, so
-                        // we only do it if we've filled the TX FIFO and emptied
-                        // the RX and repeating this loop would just burn power
-                        // and CPU. If there is any potential value to repeating
-                        // the loop immediately, we'll set this flag.
-                        let mut made_progress = false;
-
-                        if let Some((tx_data, tx_pos)) = &mut tx {
-                            while spi.can_tx_frame() {
-                                // If our position is less than our tx len,
-                                // transfer a byte from caller to TX FIFO --
-                                // otherwise put a dummy byte on the wire
-                                let byte: u8 = if *tx_pos < xfer_len.1 {
-                                    tx_data
-                                        .read_at(*tx_pos)
-                                        .ok_or(SpiError::BadSourceByte)?
-                                } else {
-                                    0u8
-                                };
-
-                                ringbuf_entry!(Trace::Tx(*tx_pos, byte));
-                                spi.send8(byte);
-                                *tx_pos += 1;
-                                made_progress = true;
-
-                                // If we have _just_ finished...
-                                if *tx_pos == xfer_len.0 {
-                                    // We will finish transmitting well before
-                                    // we're done receiving, so stop getting
-                                    // interrupt notifications for transmit
-                                    // space available during that time.
-                                    spi.disable_can_tx_interrupt();
-                                    tx = None;
-                                    break;
-                                }
-                            }
-                        }
-
-                        if let Some((rx_data, rx_pos)) = &mut rx {
-                            if spi.can_rx_byte() {
-                                // Transfer byte from RX FIFO to caller.
-                                let r = spi.recv8();
-                                rx_data
-                                    .write_at(*rx_pos, r)
-                                    .ok_or(SpiError::BadSinkByte)?;
-                                ringbuf_entry!(Trace::Rx(*rx_pos, r));
-                                *rx_pos += 1;
-
-                                if *rx_pos == xfer_len.0 {
-                                    rx = None;
-                                }
-
-                                made_progress = true;
-                            }
-                        }
-
-                        if !made_progress {
-                            // Allow the controller interrupt to post to our
-                            // notification set.
-                            sys_irq_control(IRQ_MASK, true);
-                            // Wait for our notification set to get, well, set.
-                            sys_recv_closed(&mut [], IRQ_MASK, TaskId::KERNEL)
-                                .expect("kernel died?");
-                        }
-                    }
-
-                    // Wait for the final EOT interrupt to ensure we're really
-                    // done before returning to the client
-                    loop {
-                        sys_irq_control(IRQ_MASK, true);
-                        sys_recv_closed(&mut [], IRQ_MASK, TaskId::KERNEL)
-                            .expect("kernel died?");
-
-                        if spi.check_eot() {
-                            spi.clear_eot();
-                            break;
-                        }
-                    }
-
-                    // Wrap up the transfer and restore things to a reasonable
-                    // state.
-                    spi.end();
+                    let result =
+                        transfer(&mut spi, data_src, data_dst, xfer_len);
 
-                    // Deassert (set) CS.
+                    // Deassert (set) CS, even if the transfer failed.
                     if !cs_override {
                         gpio_driver
                             .set_reset(device.cs.port, device.cs.pin_mask, 0)
                             .unwrap();
                     }
+                    result?;
 
                     // As we're done with the borrows, we can now resume the
                     // caller.
@@ -481,6 +400,274 @@ fn main() -> ! {
     }
 }
 
+/// Moves `xfer_len.0` bytes through the SPI controller, sending bytes from
+/// `data_src` (followed by zeros, past `xfer_len.1`) and storing what comes
+/// back in `data_dst`.
+#[cfg(not(feature = "dma"))]
+fn transfer(
+    spi: &mut spi_core::Spi,
+    data_src: Option<hl::Borrow<'_>>,
+    data_dst: Option<hl::Borrow<'_>>,
+    xfer_len: (usize, usize),
+) -> Result<(), SpiError> {
+    // Make sure SPI is on.
+    spi.enable(xfer_len.0 as u16);
+
+    // Load transfer count and start the state machine. At this point we _have_
+    // to move the specified number of bytes through (or explicitly cancel, but
+    // we don't).
+    spi.start();
+
+    // As you might expect, we will work from byte 0 to the end of each buffer.
+    // There are two complications:
+    //
+    // 1. Transmit and receive can be at different positions -- transmit will
+    //    tend to lead receive, because the SPI unit contains FIFOs.
+    //
+    // 2. We're only keeping track of position in the buffers we're using: both
+    //    tx and rx are `Option<(Borrow, usize)>`.
+
+    // Tack a position field onto whichever borrows actually exist.
+    let mut tx = data_src.map(|borrow| (borrow, 0));
+    let mut rx = data_dst.map(|borrow| (borrow, 0));
+
+    // Enable interrupt on the conditions we're interested in.
+    spi.enable_transfer_interrupts();
+
+    spi.clear_eot();
+
+    // While work remains, we'll attempt to move up to one byte in each
+    // direction, sleeping if we can do neither.
+    while tx.is_some() || rx.is_some() {
+        // Entering RECV to check for interrupts is not free, so we only do it
+        // if we've filled the TX FIFO and emptied the RX and repeating this
+        // loop would just burn power and CPU. If there is any potential value
+        // to repeating the loop immediately, we'll set this flag.
+        let mut made_progress = false;
+
+        if let Some((tx_data, tx_pos)) = &mut tx {
+            while spi.can_tx_frame() {
+                // If our position is less than our tx len, transfer a byte
+                // from caller to TX FIFO -- otherwise put a dummy byte on the
+                // wire
+                let byte: u8 = if *tx_pos < xfer_len.1 {
+                    tx_data.read_at(*tx_pos).ok_or(SpiError::BadSourceByte)?
+                } else {
+                    0u8
+                };
+
+                ringbuf_entry!(Trace::Tx(*tx_pos, byte));
+                spi.send8(byte);
+                *tx_pos += 1;
+                made_progress = true;
+
+                // If we have _just_ finished...
+                if *tx_pos == xfer_len.0 {
+                    // We will finish transmitting well before we're done
+                    // receiving, so stop getting interrupt notifications for
+                    // transmit space available during that time.
+                    spi.disable_can_tx_interrupt();
+                    tx = None;
+                    break;
+                }
+            }
+        }
+
+        if let Some((rx_data, rx_pos)) = &mut rx {
+            if spi.can_rx_byte() {
+                // Transfer byte from RX FIFO to caller.
+                let r = spi.recv8();
+                rx_data.write_at(*rx_pos, r).ok_or(SpiError::BadSinkByte)?;
+                ringbuf_entry!(Trace::Rx(*rx_pos, r));
+                *rx_pos += 1;
+
+                if *rx_pos == xfer_len.0 {
+                    rx = None;
+                }
+
+                made_progress = true;
+            }
+        }
+
+        if !made_progress {
+            // Allow the controller interrupt to post to our notification set.
+            sys_irq_control(IRQ_MASK, true);
+            // Wait for our notification set to get, well, set.
+            sys_recv_closed(&mut [], IRQ_MASK, TaskId::KERNEL)
+                .expect("kernel died?");
+        }
+    }
+
+    // Wait for the final EOT interrupt to ensure we're really done before
+    // returning to the client
+    wait_for_eot(spi);
+
+    // Wrap up the transfer and restore things to a reasonable state.
+    spi.end();
+
+    Ok(())
+}
+
+/// Size of each of the DMA staging buffers, and thus the most we'll move in
+/// one go. Together, they account for the `dma_buffer` memory our tasks
+/// require.
+#[cfg(feature = "dma")]
+const DMA_BUFFER_SIZE: usize = 1024;
+
+#[cfg(feature = "dma")]
+#[link_section = ".dma_buffer"]
+static mut DMA_TX_BUFFER: [u8; DMA_BUFFER_SIZE] = [0; DMA_BUFFER_SIZE];
+
+#[cfg(feature = "dma")]
+#[link_section = ".dma_buffer"]
+static mut DMA_RX_BUFFER: [u8; DMA_BUFFER_SIZE] = [0; DMA_BUFFER_SIZE];
+
+/// Moves `xfer_len.0` bytes through the SPI controller, sending bytes from
+/// `data_src` (followed by zeros, past `xfer_len.1`) and storing what comes
+/// back in `data_dst`.
+///
+/// This version has DMA do the work, a chunk at a time: we copy a chunk of the
+/// source lease into `DMA_TX_BUFFER`, run it through the controller while
+/// DMA fills `DMA_RX_BUFFER`, and copy that out to the sink lease. We always
+/// run both directions, since the controller needs something to send in order
+/// to receive, and it's simpler to throw away what we receive than to not
+/// receive it.
+#[cfg(feature = "dma")]
+fn transfer(
+    spi: &mut spi_core::Spi,
+    data_src: Option<hl::Borrow<'_>>,
+    data_dst: Option<hl::Borrow<'_>>,
+    xfer_len: (usize, usize),
+) -> Result<(), SpiError> {
+    use drv_stm32h7_dma::Direction;
+
+    let (tx_stream, rx_stream) = DMA_CONFIG.streams();
+
+    // Safety: these are only touched here, and we're not reentrant.
+    let (tx_buf, rx_buf) = unsafe { (&mut DMA_TX_BUFFER, &mut DMA_RX_BUFFER) };
+
+    // Only this much of the transfer comes from the source lease.
+    let src_len = if data_src.is_some() { xfer_len.1 } else { 0 };
+
+    let mut pos = 0;
+    while pos < xfer_len.0 {
+        let len = (xfer_len.0 - pos).min(DMA_BUFFER_SIZE);
+        ringbuf_entry!(Trace::Dma(pos, len));
+
+        let tx_chunk = &mut tx_buf[..len];
+        let from_src = src_len.saturating_sub(pos).min(len);
+        if let Some(src) = &data_src {
+            src.read_fully_at(pos, &mut tx_chunk[..from_src])
+                .ok_or(SpiError::BadSourceByte)?;
+        }
+        for byte in &mut tx_chunk[from_src..] {
+            *byte = 0;
+        }
+
+        // The controller is particular about the order in which all this
+        // happens; see `Spi::enable_rx_dma`.
+        spi.enable_rx_dma();
+        // Safety: the buffers are ours alone, and we stop both streams before
+        // looking at them again.
+        unsafe {
+            rx_stream.start(
+                Direction::PeripheralToMemory,
+                spi.rxdr_address(),
+                rx_buf.as_mut_ptr() as u32,
+                len as u16,
+                true,
+            );
+            tx_stream.start(
+                Direction::MemoryToPeripheral,
+                spi.txdr_address(),
+                tx_buf.as_ptr() as u32,
+                len as u16,
+                false,
+            );
+        }
+        spi.enable_tx_dma();
+        spi.enable(len as u16);
+        spi.start();
+        spi.enable_eot_interrupt();
+
+        let done = wait_for_dma(spi, &tx_stream, &rx_stream);
+
+        spi.end();
+        spi.disable_dma();
+        tx_stream.stop();
+        rx_stream.stop();
+
+        done?;
+
+        if let Some(dst) = &data_dst {
+            dst.write_fully_at(pos, &rx_buf[..len])
+                .ok_or(SpiError::BadSinkByte)?;
+        }
+
+        pos += len;
+    }
+
+    Ok(())
+}
+
+/// Sleeps until the controller reports the end of the transfer, and the RX
+/// stream has moved the last byte into memory -- or until either stream
+/// fails, in which case the controller would wait forever for data that isn't
+/// coming. A DMA error means our buffers are somewhere the controller can't
+/// reach, or it couldn't keep up; either way, the transfer is abandoned.
+#[cfg(feature = "dma")]
+fn wait_for_dma(
+    spi: &mut spi_core::Spi,
+    tx_stream: &drv_stm32h7_dma::Stream,
+    rx_stream: &drv_stm32h7_dma::Stream,
+) -> Result<(), SpiError> {
+    let mut eot = false;
+    loop {
+        // Of the TX stream, which finishes first, all we want to know is
+        // whether it's failed.
+        let rx_done =
+            tx_stream.is_done().and(rx_stream.is_done()).map_err(|e| {
+                ringbuf_entry!(Trace::DmaError(e));
+                SpiError::DmaError
+            })?;
+
+        if !eot && spi.check_eot() {
+            spi.clear_eot();
+            eot = true;
+        }
+
+        if eot && rx_done {
+            return Ok(());
+        }
+
+        // The RX stream's interrupt stays asserted once it's done, so only
+        // listen for it until then.
+        if !eot {
+            sys_irq_control(IRQ_MASK, true);
+        }
+        if !rx_done {
+            sys_irq_control(DMA_IRQ_MASK, true);
+        }
+        sys_recv_closed(&mut [], IRQ_MASK | DMA_IRQ_MASK, TaskId::KERNEL)
+            .expect("kernel died?");
+    }
+}
+
+/// Sleeps until the controller reports the end of the transfer.
+#[cfg(not(feature = "dma"))]
+fn wait_for_eot(spi: &mut spi_core::Spi) {
+    loop {
+        sys_irq_control(IRQ_MASK, true);
+        sys_recv_closed(&mut [], IRQ_MASK, TaskId::KERNEL)
+            .expect("kernel died?");
+
+        if spi.check_eot() {
+            spi.clear_eot();
+            break;
+        }
+    }
+}
+
 fn deactivate_mux_option(opt: &SpiMuxOption, gpio: &gpio_api::Gpio) {
     // Drive all output pins low.
     for &(pins, _af) in opt.outputs {
@@ -998,3 +1185,88 @@ cfg_if::cfg_if! {
         compile_error!("unsupported board-controller combination");
     }
 }
+
+/// Which DMA streams serve our controller, when built with the `dma` feature.
+///
+/// The streams are fixed per controller (rather than per board) so that any
+/// combination of our servers can use DMA at once without colliding. SPI6
+/// hangs off BDMA, which we don't support.
+#[cfg(feature = "dma")]
+struct DmaConfig {
+    /// DMA1 or DMA2, which share a type.
+    controller: *const device::dma1::RegisterBlock,
+    /// Name for the DMA controller as far as the RCC is concerned.
+    peripheral: rcc_api::Peripheral,
+    /// Stream number and DMAMUX1 request for transmit.
+    tx: (usize, u8),
+    /// Stream number and DMAMUX1 request for receive.
+    rx: (usize, u8),
+}
+
+#[cfg(feature = "dma")]
+impl DmaConfig {
+    /// Returns the transmit and receive streams, set up to be driven by our
+    /// controller.
+    fn streams(&self) -> (drv_stm32h7_dma::Stream, drv_stm32h7_dma::Stream) {
+        use drv_stm32h7_dma::Stream;
+
+        // Safety: these need to match peripherals in our task's `uses` list.
+        let controller = unsafe { &*self.controller };
+        let dmamux = unsafe { &*device::DMAMUX1::ptr() };
+        (
+            Stream::new(controller, dmamux, self.tx.0, self.tx.1),
+            Stream::new(controller, dmamux, self.rx.0, self.rx.1),
+        )
+    }
+}
+
+#[cfg(feature = "dma")]
+cfg_if::cfg_if! {
+    if #[cfg(feature = "spi1")] {
+        const DMA_CONFIG: DmaConfig = DmaConfig {
+            controller: device::DMA1::ptr(),
+            peripheral: rcc_api::Peripheral::Dma1,
+            tx: (0, 38),
+            rx: (1, 37),
+        };
+    } else if #[cfg(feature = "spi2")] {
+        const DMA_CONFIG: DmaConfig = DmaConfig {
+            controller: device::DMA1::ptr(),
+            peripheral: rcc_api::Peripheral::Dma1,
+            tx: (2, 40),
+            rx: (3, 39),
+        };
+    } else if #[cfg(feature = "spi3")] {
+        const DMA_CONFIG: DmaConfig = DmaConfig {
+            controller: device::DMA1::ptr(),
+            peripheral: rcc_api::Peripheral::Dma1,
+            tx: (4, 62),
+            rx: (5, 61),
+        };
+    } else if #[cfg(feature = "spi4")] {
+        const DMA_CONFIG: DmaConfig = DmaConfig {
+            controller: device::DMA1::ptr(),
+            peripheral: rcc_api::Peripheral::Dma1,
+            tx: (6, 84),
+            rx: (7, 83),
+        };
+    } else if #[cfg(feature = "spi5")] {
+        const DMA_CONFIG: DmaConfig = DmaConfig {
+            controller: device::DMA2::ptr(),
+            peripheral: rcc_api::Peripheral::Dma2,
+            tx: (0, 86),
+            rx: (1, 85),
+        };
+    } else if #[cfg(feature = "spi6")] {
+        compile_error!("SPI6 is served by BDMA, which isn't supported");
+    } else if #[cfg(feature = "standalone")] {
+        const DMA_CONFIG: DmaConfig = DmaConfig {
+            controller: device::DMA1::ptr(),
+            peripheral: rcc_api::Peripheral::Dma1,
+            tx: (0, 38),
+            rx: (1, 37),
+        };
+    } else {
+        compile_error!("unsupported controller for DMA");
+    }
+}
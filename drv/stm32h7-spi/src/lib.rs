@@ -250,6 +250,48 @@ impl Spi {
         self.reg.ier.modify(|_, w| w.txpie().clear_bit());
     }
 
+    /// Enables only the end-of-transfer interrupt, for use when DMA is moving
+    /// the data.
+    pub fn enable_eot_interrupt(&mut self) {
+        self.reg.ier.write(|w| w.eotie().set_bit());
+    }
+
+    /// Has the SPI request DMA service whenever there's data in the RX FIFO.
+    ///
+    /// Per the reference manual, when using DMA in both directions, this must
+    /// be done before the RX stream is started, which must happen before the
+    /// TX stream is started and `enable_tx_dma` is called, all before
+    /// `enable`.
+    pub fn enable_rx_dma(&mut self) {
+        self.reg.cfg1.modify(|_, w| w.rxdmaen().set_bit());
+    }
+
+    /// Has the SPI request DMA service whenever there's room in the TX FIFO.
+    /// See `enable_rx_dma` for ordering.
+    pub fn enable_tx_dma(&mut self) {
+        self.reg.cfg1.modify(|_, w| w.txdmaen().set_bit());
+    }
+
+    /// Turns off DMA requests in both directions. This should be done after
+    /// `end`.
+    pub fn disable_dma(&mut self) {
+        self.reg
+            .cfg1
+            .modify(|_, w| w.rxdmaen().clear_bit().txdmaen().clear_bit());
+    }
+
+    /// Address of the TX data register, for the peripheral side of a DMA
+    /// transfer.
+    pub fn txdr_address(&self) -> u32 {
+        &self.reg.txdr as *const _ as u32
+    }
+
+    /// Address of the RX data register, for the peripheral side of a DMA
+    /// transfer.
+    pub fn rxdr_address(&self) -> u32 {
+        &self.reg.rxdr as *const _ as u32
+    }
+
     pub fn check_eot(&self) -> bool {
         self.reg.sr.read().eot().is_completed()
     }
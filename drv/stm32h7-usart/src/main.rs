@@ -4,11 +4,11 @@
 
 //! A driver for the STM32H7 U(S)ART.
 //!
-//! # IPC protocol
+//! Received bytes are buffered as they arrive; when the buffer fills, we stop
+//! taking bytes from the USART until a `read` makes room, which (with flow
+//! control enabled) deasserts RTS.
 //!
-//! ## `write` (1)
-//!
-//! Sends the contents of lease #0. Returns when completed.
+//! Our IPC protocol is defined in `idl/usart.toml`.
 
 #![no_std]
 #![no_main]
@@ -22,28 +22,16 @@ use stm32h7::stm32h753 as device;
 #[cfg(feature = "h7b3")]
 use stm32h7::stm32h7b3 as device;
 
+use drv_usart_api::*;
 use userlib::*;
 
 task_slot!(RCC, rcc_driver);
 task_slot!(GPIO, gpio_driver);
 
-#[derive(Copy, Clone, Debug, FromPrimitive)]
-enum Operation {
-    Write = 1,
-}
-
-#[repr(u32)]
-enum ResponseCode {
-    BadArg = 2,
-    Busy = 3,
-}
+mod idl {
+    use drv_usart_api::{ConfigureRequest, UsartError};
 
-// TODO: it is super unfortunate to have to write this by hand, but deriving
-// ToPrimitive makes us check at runtime whether the value fits
-impl From<ResponseCode> for u32 {
-    fn from(rc: ResponseCode) -> Self {
-        rc as u32
-    }
+    include!(concat!(env!("OUT_DIR"), "/server_stub.rs"));
 }
 
 struct Transmit {
@@ -52,6 +40,11 @@ struct Transmit {
     pos: usize,
 }
 
+struct Receive {
+    caller: hl::Caller<u32>,
+    len: usize,
+}
+
 #[export_name = "main"]
 fn main() -> ! {
     // Turn the actual peripheral on so that we can interact with it.
@@ -67,8 +60,143 @@ fn main() -> ! {
     #[cfg(any(feature = "h743", feature = "h753"))]
     let usart = unsafe { &*device::USART3::ptr() };
 
-    // The UART has clock and is out of reset, but isn't actually on until we:
-    usart.cr1.write(|w| w.ue().enabled());
+    // The UART has clock and is out of reset, but isn't actually on until we
+    // configure it.
+    configure(usart, &Config::DEFAULT).unwrap();
+
+    // Enable the transmitter and receiver, and OR the RX register not empty
+    // signal into the USART interrupt.
+    usart
+        .cr1
+        .modify(|_, w| w.te().enabled().re().enabled().rxneie().enabled());
+
+    configure_pins();
+
+    // Turn on our interrupt.
+    sys_irq_control(1, true);
+
+    // Field messages.
+    let mut buffer = [0; idl::INCOMING_SIZE];
+    let mut server = ServerImpl {
+        usart,
+        tx: None,
+        rx: RxBuffer::new(),
+        reader: None,
+    };
+
+    loop {
+        idl::dispatch(&mut buffer, &mut server);
+    }
+}
+
+struct ServerImpl {
+    usart: &'static device::usart1::RegisterBlock,
+    tx: Option<Transmit>,
+    rx: RxBuffer,
+    reader: Option<Receive>,
+}
+
+impl idl::UsartServer for ServerImpl {
+    fn write(&mut self, caller: hl::Caller<()>) -> Result<(), UsartError> {
+        // Deny incoming writes if we're already running one.
+        if self.tx.is_some() {
+            return Err(UsartError::Busy);
+        }
+
+        // `dispatch` has checked that the lease is readable, so we won't
+        // fail to access it later (which would be a defection case, and we
+        // wouldn't reply at all) unless the caller goes away.
+        let len = caller.borrow(0).info().ok_or(UsartError::BadArg)?.len;
+
+        // Okay! Begin a transfer!
+        self.tx = Some(Transmit {
+            caller,
+            pos: 0,
+            len,
+        });
+
+        // OR the TX register empty signal into the USART interrupt.
+        self.usart.cr1.modify(|_, w| w.txeie().enabled());
+
+        // We'll do the rest as interrupts arrive.
+        Ok(())
+    }
+
+    fn read(&mut self, caller: hl::Caller<u32>) -> Result<(), UsartError> {
+        // Deny incoming reads if someone is already waiting -- unless they
+        // aren't any more.
+        if let Some(reader) = &self.reader {
+            let pending = reader.caller.task_id();
+            if !read_is_stale(pending, caller.task_id()) {
+                return Err(UsartError::Busy);
+            }
+        }
+
+        let len = caller.borrow(0).info().ok_or(UsartError::BadArg)?.len;
+        if len == 0 {
+            return Err(UsartError::BadArg);
+        }
+
+        self.reader = Some(Receive { caller, len });
+
+        // If we've already got something, this replies now; otherwise, we'll
+        // reply when something arrives.
+        self.complete_read();
+        Ok(())
+    }
+
+    fn configure(
+        &mut self,
+        _: TaskId,
+        request: ConfigureRequest,
+    ) -> Result<(), UsartError> {
+        let config = request.config().ok_or(UsartError::BadArg)?;
+
+        // Reconfiguring disables the USART, which would garble a write in
+        // progress.
+        if self.tx.is_some() {
+            return Err(UsartError::Busy);
+        }
+
+        configure(self.usart, &config)?;
+
+        if config.flow_control == FlowControl::RtsCts {
+            configure_flow_control_pins();
+        }
+
+        Ok(())
+    }
+
+    fn notification_mask(&self) -> u32 {
+        1
+    }
+
+    fn handle_notification(&mut self, bits: u32) {
+        if bits & 1 != 0 {
+            // Handling an interrupt. To allow for spurious interrupts, check
+            // the individual conditions we care about, and unconditionally
+            // re-enable the IRQ at the end of the handler.
+            let isr = self.usart.isr.read();
+
+            if isr.rxne().bit() || isr.ore().bit() {
+                // Something to receive (or lost).
+                self.step_receive();
+            }
+
+            if isr.txe().bit() {
+                // TX register empty. Do we need to send something?
+                self.step_transmit();
+            }
+
+            sys_irq_control(1, true);
+        }
+    }
+}
+
+fn configure(
+    usart: &device::usart1::RegisterBlock,
+    config: &Config,
+) -> Result<(), UsartError> {
     // Work out our baud rate divisor.
     // TODO: this module should _not_ know our clock rate. That's a hack.
     #[cfg(feature = "h7b3")]
@@ -76,84 +204,45 @@ fn main() -> ! {
     #[cfg(any(feature = "h743", feature = "h753"))]
     const CLOCK_HZ: u32 = 200_000_000;
 
-    const BAUDRATE: u32 = 115_200;
-    const CYCLES_PER_BIT: u32 = (CLOCK_HZ + (BAUDRATE / 2)) / BAUDRATE;
-    usart.brr.write(|w| w.brr().bits(CYCLES_PER_BIT as u16));
+    let cycles_per_bit = (CLOCK_HZ + (config.baud / 2)) / config.baud;
 
-    // Enable the UART and transmitter.
-    usart.cr1.modify(|_, w| w.ue().enabled().te().enabled());
+    // We oversample by 16, so need at least 16 cycles per bit.
+    if cycles_per_bit < 16 || cycles_per_bit > u32::from(u16::MAX) {
+        return Err(UsartError::BadArg);
+    }
 
-    configure_pins();
+    let parity = config.parity != Parity::None;
+    let flow_control = config.flow_control == FlowControl::RtsCts;
 
-    // Turn on our interrupt. We haven't enabled any interrupt sources at the
-    // USART side yet, so this won't trigger notifications yet.
-    sys_irq_control(1, true);
+    // Most of the configuration can only be changed while the USART is
+    // disabled.
+    usart.cr1.modify(|_, w| w.ue().disabled());
 
-    // Field messages.
-    let mask = 1;
-    let mut tx: Option<Transmit> = None;
+    usart.brr.write(|w| w.brr().bits(cycles_per_bit as u16));
 
-    loop {
-        hl::recv(
-            // Buffer (none required)
-            &mut [],
-            // Notification mask
-            mask,
-            // State to pass through to whichever closure below gets run
-            &mut tx,
-            // Notification handler
-            |txref, bits| {
-                if bits & 1 != 0 {
-                    // Handling an interrupt. To allow for spurious interrupts,
-                    // check the individual conditions we care about, and
-                    // unconditionally re-enable the IRQ at the end of the handler.
-
-                    if usart.isr.read().txe().bit() {
-                        // TX register empty. Do we need to send something?
-                        step_transmit(&usart, txref);
-                    }
-
-                    sys_irq_control(1, true);
-                }
-            },
-            // Message handler
-            |txref, op, msg| match op {
-                Operation::Write => {
-                    // Validate lease count and buffer sizes first.
-                    let ((), caller) =
-                        msg.fixed_with_leases(1).ok_or(ResponseCode::BadArg)?;
-
-                    // Deny incoming writes if we're already running one.
-                    if txref.is_some() {
-                        return Err(ResponseCode::Busy);
-                    }
-
-                    let borrow = caller.borrow(0);
-                    let info = borrow.info().ok_or(ResponseCode::BadArg)?;
-                    // Provide feedback to callers if they fail to provide a
-                    // readable lease (otherwise we'd fail accessing the borrow
-                    // later, which is a defection case and we won't reply at
-                    // all).
-                    if !info.attributes.contains(LeaseAttributes::READ) {
-                        return Err(ResponseCode::BadArg);
-                    }
-
-                    // Okay! Begin a transfer!
-                    *txref = Some(Transmit {
-                        caller,
-                        pos: 0,
-                        len: info.len,
-                    });
-
-                    // OR the TX register empty signal into the USART interrupt.
-                    usart.cr1.modify(|_, w| w.txeie().enabled());
-
-                    // We'll do the rest as interrupts arrive.
-                    Ok(())
-                }
-            },
-        );
-    }
+    // The parity bit is the last bit of the word, so with parity we need
+    // 9-bit words to keep 8 data bits.
+    usart.cr1.modify(|_, w| {
+        w.m0()
+            .bit(parity)
+            .pce()
+            .bit(parity)
+            .ps()
+            .bit(config.parity == Parity::Odd)
+    });
+
+    usart.cr2.modify(|_, w| match config.stop_bits {
+        StopBits::One => w.stop().stop1(),
+        StopBits::Two => w.stop().stop2(),
+    });
+
+    usart
+        .cr3
+        .modify(|_, w| w.rtse().bit(flow_control).ctse().bit(flow_control));
+
+    usart.cr1.modify(|_, w| w.ue().enabled());
+
+    Ok(())
 }
 
 fn turn_on_usart() {
@@ -193,32 +282,109 @@ fn configure_pins() {
         .unwrap();
 }
 
-fn step_transmit(
-    usart: &device::usart1::RegisterBlock,
-    tx: &mut Option<Transmit>,
-) {
-    // Clearer than just using replace:
-    fn end_transmission(
-        usart: &device::usart1::RegisterBlock,
-        state: &mut Option<Transmit>,
-    ) -> hl::Caller<()> {
-        usart.cr1.modify(|_, w| w.txeie().disabled());
-        core::mem::replace(state, None).unwrap().caller
+fn configure_flow_control_pins() {
+    use drv_stm32h7_gpio_api::*;
+
+    let gpio_driver = GPIO.get_task_id();
+    let gpio_driver = Gpio::from(gpio_driver);
+
+    // TODO these are really board configs, not SoC configs!
+    #[cfg(feature = "h7b3")]
+    const CTS_RTS_MASK: PinSet = Port::A.pin(11).and_pin(12);
+    #[cfg(any(feature = "h743", feature = "h753"))]
+    const CTS_RTS_MASK: PinSet = Port::D.pin(11).and_pin(12);
+
+    gpio_driver
+        .configure_alternate(
+            CTS_RTS_MASK,
+            OutputType::PushPull,
+            Speed::High,
+            Pull::None,
+            Alternate::AF7,
+        )
+        .unwrap();
+}
+
+impl ServerImpl {
+    fn step_transmit(&mut self) {
+        let txs = if let Some(txs) = &mut self.tx {
+            txs
+        } else {
+            return;
+        };
+
+        let byte = txs.caller.borrow(0).read_at::<u8>(txs.pos);
+
+        if let Some(byte) = byte {
+            // Stuff byte into transmitter.
+            self.usart
+                .tdr
+                .write(|w| unsafe { w.tdr().bits(u16::from(byte)) });
+
+            txs.pos += 1;
+            if txs.pos == txs.len {
+                self.end_transmission().reply(());
+            }
+        } else {
+            self.end_transmission().reply_fail(UsartError::BadArg);
+        }
+    }
+
+    fn end_transmission(&mut self) -> hl::Caller<()> {
+        self.usart.cr1.modify(|_, w| w.txeie().disabled());
+        self.tx.take().unwrap().caller
     }
 
-    let txs = if let Some(txs) = tx { txs } else { return };
+    fn step_receive(&mut self) {
+        let usart = self.usart;
+
+        // If we've lost a byte, there's no-one to tell, but the overrun has
+        // to be cleared or it'll keep interrupting us.
+        if usart.isr.read().ore().bit() {
+            usart.icr.write(|w| w.orecf().set_bit());
+        }
 
-    if let Some(byte) = txs.caller.borrow(0).read_at::<u8>(txs.pos) {
-        // Stuff byte into transmitter.
-        usart
-            .tdr
-            .write(|w| unsafe { w.tdr().bits(u16::from(byte)) });
+        if usart.isr.read().rxne().bit() {
+            if self.rx.is_full() {
+                // Leave the byte where it is, and stop listening until a read
+                // makes room.
+                usart.cr1.modify(|_, w| w.rxneie().disabled());
+            } else {
+                self.rx.push(usart.rdr.read().rdr().bits() as u8);
+            }
+        }
+
+        self.complete_read();
+    }
+
+    fn complete_read(&mut self) {
+        if self.rx.is_empty() {
+            return;
+        }
 
-        txs.pos += 1;
-        if txs.pos == txs.len {
-            end_transmission(usart, tx).reply(());
+        let reader = if let Some(reader) = self.reader.take() {
+            reader
+        } else {
+            return;
+        };
+
+        let mut data = [0; RX_BUFFER_SIZE];
+        let n = self
+            .rx
+            .pop_into(&mut data[..usize::min(reader.len, RX_BUFFER_SIZE)]);
+
+        // We've made room, so make sure we're listening.
+        self.usart.cr1.modify(|_, w| w.rxneie().enabled());
+
+        if reader
+            .caller
+            .borrow(0)
+            .write_fully_at(0, &data[..n])
+            .is_some()
+        {
+            reader.caller.reply(n as u32);
+        } else {
+            reader.caller.reply_fail(UsartError::BadArg);
         }
-    } else {
-        end_transmission(usart, tx).reply_fail(ResponseCode::BadArg);
     }
 }
@@ -0,0 +1,175 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Client API for the USART drivers
+//!
+//! All of our USART drivers speak the same protocol, which is defined in
+//! `idl/usart.toml`; the client is generated from it. This crate also has the
+//! types that the drivers share.
+
+#![no_std]
+
+use byteorder::LittleEndian;
+use userlib::*;
+use zerocopy::{AsBytes, FromBytes, Unaligned, U32};
+
+include!(concat!(env!("OUT_DIR"), "/client_stub.rs"));
+
+#[derive(Copy, Clone, Debug, FromPrimitive, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Parity {
+    None = 0,
+    Even = 1,
+    Odd = 2,
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, Eq, PartialEq)]
+#[repr(u8)]
+pub enum StopBits {
+    One = 0,
+    Two = 1,
+}
+
+#[derive(Copy, Clone, Debug, FromPrimitive, Eq, PartialEq)]
+#[repr(u8)]
+pub enum FlowControl {
+    None = 0,
+    /// Hardware flow control: we only transmit while CTS is asserted, and
+    /// deassert RTS while we have no room to receive.
+    RtsCts = 1,
+}
+
+/// A USART configuration. We always use 8 data bits; parity, if any, is in
+/// addition to them.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Config {
+    pub baud: u32,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl Config {
+    /// 115200 8N1, without flow control.
+    pub const DEFAULT: Self = Self {
+        baud: 115_200,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+        flow_control: FlowControl::None,
+    };
+}
+
+/// The argument to `configure`.
+#[derive(Copy, Clone, AsBytes, FromBytes, Unaligned)]
+#[repr(C)]
+pub struct ConfigureRequest {
+    pub baud: U32<LittleEndian>,
+    pub parity: u8,
+    pub stop_bits: u8,
+    pub flow_control: u8,
+}
+
+impl From<Config> for ConfigureRequest {
+    fn from(config: Config) -> Self {
+        Self {
+            baud: U32::new(config.baud),
+            parity: config.parity as u8,
+            stop_bits: config.stop_bits as u8,
+            flow_control: config.flow_control as u8,
+        }
+    }
+}
+
+impl ConfigureRequest {
+    /// Decodes the request, returning `None` if any of it is invalid.
+    pub fn config(&self) -> Option<Config> {
+        let baud = self.baud.get();
+
+        if baud == 0 {
+            return None;
+        }
+
+        Some(Config {
+            baud,
+            parity: Parity::from_u8(self.parity)?,
+            stop_bits: StopBits::from_u8(self.stop_bits)?,
+            flow_control: FlowControl::from_u8(self.flow_control)?,
+        })
+    }
+}
+
+/// Size of a driver's receive buffer.
+pub const RX_BUFFER_SIZE: usize = 128;
+
+/// A ring buffer of received bytes, filled by a driver's interrupt handler
+/// and drained by `read`.
+pub struct RxBuffer {
+    buf: [u8; RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RxBuffer {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; RX_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == RX_BUFFER_SIZE
+    }
+
+    /// Adds a byte, returning false (and dropping it) if we're full.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            false
+        } else {
+            self.buf[(self.head + self.len) % RX_BUFFER_SIZE] = byte;
+            self.len += 1;
+            true
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            None
+        } else {
+            let byte = self.buf[self.head];
+            self.head = (self.head + 1) % RX_BUFFER_SIZE;
+            self.len -= 1;
+            Some(byte)
+        }
+    }
+
+    /// Moves as many bytes as will fit into `dest`, returning how many.
+    pub fn pop_into(&mut self, dest: &mut [u8]) -> usize {
+        let mut n = 0;
+
+        for slot in dest.iter_mut() {
+            match self.pop() {
+                Some(byte) => *slot = byte,
+                None => break,
+            }
+            n += 1;
+        }
+
+        n
+    }
+}
+
+/// Checks whether a driver still holding a `read` from `pending` should give
+/// it up for a new one from `caller`, rather than returning `Busy`. This is
+/// the case if the two are the same task -- which can't have a read pending
+/// from its current incarnation while sending another -- or if `pending` has
+/// since been restarted. Either way, nobody is waiting for the old read.
+pub fn read_is_stale(pending: TaskId, caller: TaskId) -> bool {
+    pending.index() == caller.index() || sys_refresh_task_id(pending) != pending
+}
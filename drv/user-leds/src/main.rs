@@ -7,37 +7,18 @@
 //! We assume that there are two user LEDs available, numbered 0 and 1. The
 //! precise assignment of these to a particular dev board varies.
 //!
-//! # IPC protocol
-//!
-//! ## `led_on` (1)
-//!
-//! Turns an LED on by index.
-//!
-//! Request message format: single `u32` giving LED index.
-//!
-//! ## `led_off` (2)
-//!
-//! Turns an LED off by index.
-//!
-//! Request message format: single `u32` giving LED index.
-//!
-//! ## `led_toggle` (3)
-//!
-//! Toggles an LED by index.
-//!
-//! Request message format: single `u32` giving LED index.
+//! Our IPC protocol is defined in `idl/user-leds.toml`.
 
 #![no_std]
 #![no_main]
 
+use drv_user_leds_api::LedError;
 use userlib::*;
-use zerocopy::AsBytes;
 
-#[derive(FromPrimitive)]
-enum Op {
-    On = 1,
-    Off = 2,
-    Toggle = 3,
+mod idl {
+    use drv_user_leds_api::LedError;
+
+    include!(concat!(env!("OUT_DIR"), "/server_stub.rs"));
 }
 
 cfg_if::cfg_if! {
@@ -70,47 +51,39 @@ cfg_if::cfg_if! {
     }
 }
 
-#[repr(u32)]
-enum ResponseCode {
-    BadArg = 2,
+#[export_name = "main"]
+fn main() -> ! {
+    enable_led_pins();
+
+    let mut buffer = [0; idl::INCOMING_SIZE];
+    let mut server = ServerImpl;
+    loop {
+        idl::dispatch(&mut buffer, &mut server);
+    }
 }
 
-impl From<ResponseCode> for u32 {
-    fn from(rc: ResponseCode) -> Self {
-        rc as u32
+struct ServerImpl;
+
+impl ServerImpl {
+    fn led(index: usize) -> Result<Led, LedError> {
+        Led::from_usize(index).ok_or(LedError::NoSuchLed)
     }
 }
 
-#[export_name = "main"]
-fn main() -> ! {
-    enable_led_pins();
+impl idl::UserLedsServer for ServerImpl {
+    fn led_on(&mut self, _: TaskId, index: usize) -> Result<(), LedError> {
+        led_on(Self::led(index)?);
+        Ok(())
+    }
 
-    // Field messages.
-    // Ensure our buffer is aligned properly for a u32 by declaring it as one.
-    let mut buffer = 0u32;
-    loop {
-        hl::recv_without_notification(
-            buffer.as_bytes_mut(),
-            |op, msg| -> Result<(), ResponseCode> {
-                // Every incoming message uses the same payload type and
-                // response type: it's always u32 -> (). So we can do the
-                // check-and-convert here:
-                let (msg, caller) =
-                    msg.fixed::<u32, ()>().ok_or(ResponseCode::BadArg)?;
-
-                // Every incoming message has the same permitted range, as well.
-                let led = Led::from_u32(*msg).ok_or(ResponseCode::BadArg)?;
-
-                match op {
-                    Op::On => led_on(led),
-                    Op::Off => led_off(led),
-                    Op::Toggle => led_toggle(led),
-                }
-
-                caller.reply(());
-                Ok(())
-            },
-        );
+    fn led_off(&mut self, _: TaskId, index: usize) -> Result<(), LedError> {
+        led_off(Self::led(index)?);
+        Ok(())
+    }
+
+    fn led_toggle(&mut self, _: TaskId, index: usize) -> Result<(), LedError> {
+        led_toggle(Self::led(index)?);
+        Ok(())
     }
 }
 
@@ -143,6 +116,8 @@ macro_rules! gpio {
 
 #[cfg(any(feature = "stm32f3", feature = "stm32f4"))]
 fn enable_led_pins() {
+    use zerocopy::AsBytes;
+
     // This assumes an STM32F4DISCOVERY board, where the LEDs are on D12 and
     // D13 OR an STM32F3DISCOVERY board, where the LEDs are on E8 and E9.
 
@@ -20,6 +20,19 @@ pub const REGIONS_PER_TASK: usize = 8;
 
 pub const TASK_ID_INDEX_BITS: usize = 10;
 
+/// Number of bytes reserved for a task's name in its `TaskDesc`. Names shorter
+/// than this are padded with NUL bytes.
+pub const TASK_NAME_LEN: usize = 16;
+
+/// Pattern that the kernel paints over each task's stack when the task is
+/// (re)initialized, so that the deepest point the stack has reached can be
+/// found later by looking for the first word that no longer matches.
+pub const STACK_PAINT: u32 = 0xbaddcafe;
+
+/// Number of bytes of a task's panic message that the kernel keeps for later
+/// retrieval. Longer messages are truncated.
+pub const PANIC_MESSAGE_LEN: usize = 64;
+
 /// Names a particular incarnation of a task.
 ///
 /// A `TaskId` combines two fields, a task index (which can be predicted at
@@ -78,7 +91,9 @@ impl TaskId {
 }
 
 /// Type used to track generation numbers.
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Default)]
+#[derive(
+    Copy, Clone, Debug, Eq, PartialEq, Default, Serialize, Deserialize,
+)]
 #[repr(transparent)]
 pub struct Generation(u8);
 
@@ -106,7 +121,17 @@ impl From<u8> for Generation {
 /// keep us from confusing ourselves on whether `>` means numerically greater /
 /// less important, or more important / numerically smaller.
 #[derive(
-    Copy, Clone, Debug, Eq, PartialEq, FromBytes, AsBytes, Unaligned, Default,
+    Copy,
+    Clone,
+    Debug,
+    Eq,
+    PartialEq,
+    FromBytes,
+    AsBytes,
+    Unaligned,
+    Default,
+    Serialize,
+    Deserialize,
 )]
 #[repr(transparent)]
 pub struct Priority(pub u8);
@@ -172,6 +197,9 @@ pub struct TaskDesc {
     pub priority: u32,
     /// Collection of boolean flags controlling task behavior.
     pub flags: TaskFlags,
+    /// Name of the task, as given in the application config, padded with NUL
+    /// bytes. This is informational; the kernel doesn't use it.
+    pub name: [u8; TASK_NAME_LEN],
 }
 
 bitflags::bitflags! {
@@ -234,6 +262,37 @@ bitflags::bitflags! {
     }
 }
 
+/// Description of one of a task's memory regions, as reported by the kernel.
+/// This mirrors `RegionDesc` in a form that can be sent over IPC.
+#[derive(
+    Copy, Clone, Debug, Eq, PartialEq, Default, Serialize, Deserialize,
+)]
+pub struct RegionInfo {
+    /// Address of start of region.
+    pub base: u32,
+    /// Size of region, in bytes.
+    pub size: u32,
+    /// Bits of the region's `RegionAttributes`.
+    pub attributes: u32,
+}
+
+impl RegionInfo {
+    /// Decodes the region's attributes.
+    pub fn attributes(&self) -> RegionAttributes {
+        RegionAttributes::from_bits_truncate(self.attributes)
+    }
+}
+
+impl From<&RegionDesc> for RegionInfo {
+    fn from(r: &RegionDesc) -> Self {
+        Self {
+            base: r.base,
+            size: r.size,
+            attributes: r.attributes.bits(),
+        }
+    }
+}
+
 /// Description of one interrupt response.
 #[derive(Clone, Debug, FromBytes)]
 #[repr(C)]
@@ -297,6 +356,20 @@ pub const fn extract_new_generation(code: u32) -> Option<Generation> {
 /// Response code returned by the kernel if a lender has defected.
 pub const DEFECT: u32 = 1;
 
+/// Response code returned by the kernel from `SEND_TIMEOUT` if the recipient
+/// didn't reply before the timeout expired. This is taken from the top of the
+/// code space, just below the dead codes, where it's unlikely to collide with
+/// application-defined codes.
+pub const TIMED_OUT: u32 = 0xffff_fe00;
+
+/// Response code returned by the kernel from `ASYNC_SEND` if the recipient
+/// wasn't waiting to receive the message. Like `TIMED_OUT`, this sits just
+/// below the dead codes.
+pub const WOULD_BLOCK: u32 = 0xffff_fe01;
+
+/// Largest timeout, in ticks, that can be passed to `SEND_TIMEOUT`.
+pub const MAX_SEND_TIMEOUT: u32 = (1 << 24) - 1;
+
 /// State used to make scheduling decisions.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub enum TaskState {
@@ -349,6 +422,36 @@ impl Default for TaskState {
     }
 }
 
+/// Summary of a task's configuration and current state, as reported by the
+/// kernel to tasks that want to discover the system layout.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TaskInfo {
+    /// Name of the task, padded with NUL bytes. See `TaskInfo::name`.
+    pub name: [u8; TASK_NAME_LEN],
+    /// Current priority of the task.
+    pub priority: Priority,
+    /// Current generation number of the task.
+    pub generation: Generation,
+    /// Current state of the task.
+    pub state: TaskState,
+    /// The task's region table. Unused slots typically refer to a region that
+    /// grants no access.
+    pub regions: [RegionInfo; REGIONS_PER_TASK],
+}
+
+impl TaskInfo {
+    /// Returns the task's name as a string, without padding. If the name isn't
+    /// valid UTF-8 (which would indicate a malformed image), returns `None`.
+    pub fn name(&self) -> Option<&str> {
+        let len = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).ok()
+    }
+}
+
 /// Scheduler parameters for a healthy task.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub enum SchedState {
@@ -450,6 +553,8 @@ pub enum FaultSource {
 }
 
 /// Enumeration of syscall numbers.
+///
+/// If you add a syscall here, remember to bump `SYSNUM_COUNT`.
 #[repr(u32)]
 pub enum Sysnum {
     Send = 0,
@@ -464,6 +569,8 @@ pub enum Sysnum {
     GetTimer = 9,
     RefreshTaskId = 10,
     Post = 11,
+    AsyncSend = 12,
+    SendTimeout = 13,
 }
 
 /// We're using an explicit `TryFrom` impl for `Sysnum` instead of
@@ -486,7 +593,60 @@ impl core::convert::TryFrom<u32> for Sysnum {
             9 => Ok(Self::GetTimer),
             10 => Ok(Self::RefreshTaskId),
             11 => Ok(Self::Post),
+            12 => Ok(Self::AsyncSend),
+            13 => Ok(Self::SendTimeout),
             _ => Err(()),
         }
     }
 }
+
+/// Number of defined syscalls. Syscall numbers are dense, so this is also one
+/// more than the largest valid `Sysnum`.
+pub const SYSNUM_COUNT: usize = 14;
+
+/// Execution statistics the kernel maintains for each task.
+///
+/// These counters are cumulative across task restarts, so that a task that is
+/// repeatedly faulting and being restarted still shows up as busy. All counters
+/// wrap on overflow.
+#[derive(
+    Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize,
+)]
+#[repr(C)]
+pub struct TaskStats {
+    /// Number of kernel ticks that arrived while this task was running. This is
+    /// a statistical sample of CPU time, not an exact measure: a task that
+    /// reliably yields just before each tick will appear to use no time.
+    pub run_ticks: u64,
+    /// Number of times this task has been switched in by the scheduler.
+    pub context_switches: u32,
+    /// Number of times this task has invoked each syscall, indexed by
+    /// `Sysnum`. Bad syscall numbers are not counted here (they're faults).
+    pub syscalls: [u32; SYSNUM_COUNT],
+    /// Number of times this task has faulted, including double faults and
+    /// faults injected by another task.
+    pub faults: u32,
+}
+
+impl TaskStats {
+    /// Total number of syscalls made by this task.
+    pub fn total_syscalls(&self) -> u32 {
+        self.syscalls
+            .iter()
+            .fold(0u32, |total, &n| total.wrapping_add(n))
+    }
+}
+
+/// Stack usage of a task, as reported by the kernel.
+#[derive(
+    Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize,
+)]
+pub struct StackUsage {
+    /// Size of the task's stack, in bytes.
+    pub size: u32,
+    /// Deepest extent of the stack observed since the task was last
+    /// initialized, in bytes. This is derived from `STACK_PAINT` and so can
+    /// under-report by a word if the task happens to store the paint pattern
+    /// at the edge of its stack.
+    pub max_depth: u32,
+}
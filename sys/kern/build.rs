@@ -8,7 +8,11 @@ use std::io::Write;
 use std::path::PathBuf;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    build_util::expose_m_profile();
+    // The simulator (see `arch/sim.rs`) runs on the host and has no M-profile
+    // architecture version to expose.
+    if env::var("CARGO_CFG_TARGET_ARCH").unwrap() == "arm" {
+        build_util::expose_m_profile();
+    }
 
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     let mut const_file = File::create(out.join("consts.rs")).unwrap();
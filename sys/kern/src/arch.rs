@@ -25,6 +25,10 @@ cfg_if::cfg_if! {
         #[macro_use]
         pub mod arm_m;
         pub use arm_m::*;
+    } else if #[cfg(unix)] {
+        #[macro_use]
+        pub mod sim;
+        pub use sim::*;
     } else {
         compile_error!("support for this architecture not implemented");
     }
@@ -15,6 +15,16 @@
 //! to maintain `TICKS`, but has the upside that we don't need special SoC
 //! support for timing.
 //!
+//! With the `tickless` feature, we instead stretch each timer period out to
+//! the next deadline any task is waiting on (or as far as the 24-bit counter
+//! will go, if none is), so that an idle system isn't woken just to count.
+//! `TICKS` then holds the time of the most recent interrupt, `PERIOD_END` the
+//! time at which the current period ends, and `now` works out the time in
+//! between from the counter. Periods always end on tick boundaries, so
+//! timestamps mean the same thing in either mode. Each time the counter is
+//! reprogrammed, the few cycles spent doing it are lost, so a tickless kernel's
+//! clock runs slightly slow relative to the CPU clock.
+//!
 //! # Notes on ARM-M interrupts
 //!
 //! For performance and (believe it or not) simplicity, this implementation uses
@@ -336,30 +346,6 @@ pub fn reinitialize(task: &mut task::Task) {
     // Ok. Generate a uslice for the task's starting stack frame.
     let mut frame_uslice: USlice<ExtendedExceptionFrame> =
         USlice::from_raw(initial_stack as usize - frame_size, 1).unwrap();
-    // Before we set our frame, find the region that contains our initial stack
-    // pointer, and zap the region from the base to the stack pointer with a
-    // distinct (and storied) pattern.
-    for region in task.region_table().iter() {
-        if initial_stack < region.base {
-            continue;
-        }
-
-        if initial_stack > region.base + region.size {
-            continue;
-        }
-
-        let mut uslice: USlice<u32> = USlice::from_raw(
-            region.base as usize,
-            (initial_stack as usize - frame_size - region.base as usize) >> 2,
-        )
-        .unwrap();
-
-        let zap = task.try_write(&mut uslice).unwrap();
-        for word in zap.iter_mut() {
-            *word = 0xbaddcafe;
-        }
-    }
-
     let descriptor = task.descriptor();
     let frame = &mut task.try_write(&mut frame_uslice).unwrap()[0];
 
@@ -632,8 +618,17 @@ pub fn start_first_task(tick_divisor: u32, task: &task::Task) -> ! {
     unsafe {
         // Configure the timer.
         let syst = &*cortex_m::peripheral::SYST::ptr();
-        // Program reload value.
+        // Program reload value. Without the tickless feature, this is one
+        // tick; with it, the first period is as long as possible, since no task
+        // can have a deadline yet.
+        #[cfg(not(feature = "tickless"))]
         syst.rvr.write(tick_divisor - 1);
+        #[cfg(feature = "tickless")]
+        {
+            let period = max_period_ticks(tick_divisor);
+            syst.rvr.write(period * tick_divisor - 1);
+            PERIOD_END = u64::from(period);
+        }
         // Clear current value.
         syst.cvr.write(0);
         // Enable counter and interrupt.
@@ -802,28 +797,185 @@ pub fn with_irq_table<R>(body: impl FnOnce(&[abi::Interrupt]) -> R) -> R {
     body(table)
 }
 
-/// Records the address of `task` as the current user task.
+/// Records the address of `tasks[next]` as the current user task, charging
+/// the task it replaces for the time it ran.
+///
+/// # Safety
+///
+/// This records a pointer that aliases `tasks[next]`. As long as you don't read
+/// that pointer except at syscall entry, you'll be okay.
+pub unsafe fn set_current_task(tasks: &mut [task::Task], next: usize) {
+    let ptr = NonNull::from(&mut tasks[next]);
+    if CURRENT_TASK_PTR != Some(ptr) {
+        if let Some(current) = CURRENT_TASK_PTR {
+            let idx = (current.as_ptr() as usize - tasks.as_ptr() as usize)
+                / core::mem::size_of::<task::Task>();
+            charge_run_time(&mut tasks[idx], now());
+        }
+        tasks[next].record_context_switch();
+    }
+    CURRENT_TASK_PTR = Some(ptr);
+}
+
+/// Charges `task`, which has been running since the last charge, for the
+/// ticks that have passed since then, up to `now`.
 ///
 /// # Safety
 ///
-/// This records a pointer that aliases `task`. As long as you don't read that
-/// pointer except at syscall entry, you'll be okay.
-pub unsafe fn set_current_task(task: &mut task::Task) {
-    CURRENT_TASK_PTR = Some(NonNull::from(task));
+/// This must be called from the kernel, where nothing else can be touching
+/// `CHARGED_UNTIL`.
+unsafe fn charge_run_time(task: &mut task::Task, now: Timestamp) {
+    let now = u64::from(now);
+    task.charge_ticks(now - CHARGED_UNTIL);
+    CHARGED_UNTIL = now;
 }
 
 /// Reads the tick counter.
+#[cfg(not(feature = "tickless"))]
 pub fn now() -> Timestamp {
     Timestamp::from(unsafe { TICKS })
 }
 
+/// Reads the tick counter.
+///
+/// This works out how far we are through the current timer period from the
+/// SysTick counter. It must be called from the kernel, where SysTick can't
+/// preempt us (though it can become pending).
+#[cfg(feature = "tickless")]
+pub fn now() -> Timestamp {
+    use cortex_m::peripheral::{SCB, SYST};
+
+    // Safety: we're in the kernel, so nothing else is touching these.
+    let (end, divisor) = unsafe { (PERIOD_END, CLOCK_FREQ_KHZ) };
+    loop {
+        if SCB::is_pendst_pending() {
+            // The period has ended, and the handler will account for it as
+            // soon as we're done. Until then, it's the end of the period.
+            return Timestamp::from(end);
+        }
+        let remaining = SYST::get_current();
+        // If the counter expired between our two reads, the value we got may
+        // be from the next period. A zero without a pending interrupt means
+        // the counter has just been written and hasn't reloaded yet. In either
+        // case, try again.
+        if remaining != 0 && !SCB::is_pendst_pending() {
+            let ticks_left = (remaining + divisor - 1) / divisor;
+            return Timestamp::from(end - u64::from(ticks_left));
+        }
+    }
+}
+
 /// Kernel global for tracking the current timestamp, measured in ticks.
 ///
 /// This is a mutable `u64` instead of an `AtomicU64` because ARMv7-M doesn't
 /// have any 64-bit atomic operations. So, we access it carefully from
 /// non-preemptible contexts.
+///
+/// With the `tickless` feature, this is the time of the most recent SysTick
+/// interrupt, which may be some way in the past; use `now` instead.
 static mut TICKS: u64 = 0;
 
+/// Time up to which the current task has been charged for running; see
+/// `charge_run_time`. Charging at each context switch, rather than a tick at a
+/// time, keeps this right when (with the `tickless` feature) a SysTick period
+/// spans many ticks and many tasks.
+static mut CHARGED_UNTIL: u64 = 0;
+
+/// With the `tickless` feature, the timestamp at which the current SysTick
+/// period ends. This is only ever moved earlier by `wake_by`, and later by the
+/// SysTick handler.
+#[cfg(feature = "tickless")]
+static mut PERIOD_END: u64 = 0;
+
+/// Shortest SysTick period we'll program, in cycles. This needs to be
+/// comfortably longer than it takes to reprogram the counter and get back to
+/// user code, or we'd take an interrupt we weren't ready for.
+#[cfg(feature = "tickless")]
+const MIN_PERIOD_CYCLES: u32 = 512;
+
+/// Longest SysTick period that fits the 24-bit counter, in whole ticks.
+#[cfg(feature = "tickless")]
+fn max_period_ticks(tick_divisor: u32) -> u32 {
+    0x00ff_ffff / tick_divisor
+}
+
+/// Notes that some task has a deadline at `deadline`, so that the timer can be
+/// made to interrupt by then.
+///
+/// Without the `tickless` feature, we get an interrupt every tick anyway, so
+/// there's nothing to do.
+#[cfg(not(feature = "tickless"))]
+pub fn wake_by(_deadline: Timestamp) {}
+
+/// Notes that some task has a deadline at `deadline`, so that the timer can be
+/// made to interrupt by then.
+///
+/// If the current period ends after `deadline`, we cut it short, ending it on
+/// the first tick boundary at or after `deadline` (or a little later, if that's
+/// too soon to arrange). This must be called from the kernel.
+#[cfg(feature = "tickless")]
+pub fn wake_by(deadline: Timestamp) {
+    use cortex_m::peripheral::scb::{Exception, VectActive};
+    use cortex_m::peripheral::{SCB, SYST};
+
+    // If we're in the SysTick handler, or it's about to run, it'll program
+    // the next period with this deadline in mind.
+    if SCB::vect_active() == VectActive::Exception(Exception::SysTick)
+        || SCB::is_pendst_pending()
+    {
+        return;
+    }
+
+    // Safety: we're in the kernel, so nothing else is touching these.
+    let (end, divisor) = unsafe { (PERIOD_END, CLOCK_FREQ_KHZ) };
+    let deadline = u64::from(deadline);
+    if deadline >= end {
+        return;
+    }
+
+    let remaining = SYST::get_current();
+    if remaining < MIN_PERIOD_CYCLES {
+        // The period is nearly over anyway.
+        return;
+    }
+
+    // Find the next tick boundary, and how many cycles away it is. The counter
+    // reaches zero at `end`, and each multiple of `divisor` below that is a
+    // tick earlier.
+    let mut ticks_left = remaining / divisor;
+    let mut cycles = remaining % divisor;
+    if cycles == 0 {
+        // We're right on a boundary; aim for the next one.
+        ticks_left -= 1;
+        cycles = divisor;
+    }
+    let boundary = end - u64::from(ticks_left);
+
+    let mut new_end = boundary;
+    if deadline > boundary {
+        // This can't overflow: the whole thing is shorter than the period
+        // we're already in.
+        cycles += (deadline - boundary) as u32 * divisor;
+        new_end = deadline;
+    }
+    while cycles < MIN_PERIOD_CYCLES {
+        cycles += divisor;
+        new_end += 1;
+    }
+    if new_end >= end {
+        return;
+    }
+
+    // Safety: as above, we have exclusive access to the timer and its state.
+    // Writing the current value restarts the count from the new reload value.
+    unsafe {
+        let syst = &*SYST::ptr();
+        syst.rvr.write(cycles - 1);
+        syst.cvr.write(0);
+        PERIOD_END = new_end;
+    }
+}
+
 /// Handler that gets linked into the vector table for the System Tick Timer
 /// overflow interrupt. (Name is dictated by the `cortex_m` crate.)
 #[allow(non_snake_case)]
@@ -834,11 +986,24 @@ pub unsafe extern "C" fn SysTick() {
     // code. As a result, we can manufacture exclusive references to various
     // bits of kernel state.
     let ticks = &mut TICKS;
-    with_task_table(|tasks| safe_sys_tick_handler(ticks, tasks));
+    with_task_table(|tasks| {
+        let current = CURRENT_TASK_PTR
+            .expect("irq before kernel started?")
+            .as_ptr();
+        let idx = (current as usize - tasks.as_ptr() as usize)
+            / core::mem::size_of::<task::Task>();
+        safe_sys_tick_handler(ticks, idx, tasks)
+    });
 }
 
 /// The meat of the systick handler, after we do the unsafe things.
-fn safe_sys_tick_handler(ticks: &mut u64, tasks: &mut [task::Task]) {
+///
+/// `current` is the index of the task that was interrupted by the tick.
+fn safe_sys_tick_handler(
+    ticks: &mut u64,
+    current: usize,
+    tasks: &mut [task::Task],
+) {
     // Advance the kernel's notion of time.
     // This increment is not expected to overflow in a working system, since it
     // would indicate that 2^64 ticks have passed, and ticks are expected to be
@@ -846,15 +1011,36 @@ fn safe_sys_tick_handler(ticks: &mut u64, tasks: &mut [task::Task]) {
     // However, we do not use wrapping add here because, if we _do_ overflow due
     // to e.g. memory corruption, we'd rather panic and reboot than attempt to
     // limp forward.
-    *ticks += 1;
+    #[cfg(not(feature = "tickless"))]
+    {
+        *ticks += 1;
+    }
+    // In tickless mode, the period that just ended may have been many ticks
+    // long; it ended at `PERIOD_END`.
+    #[cfg(feature = "tickless")]
+    {
+        // Safety: we're in the SysTick handler, so nothing else is touching
+        // this.
+        *ticks = unsafe { PERIOD_END };
+    }
     // Now, give up mutable access to *ticks so there's no chance of a
     // double-increment due to bugs below.
     let now = Timestamp::from(*ticks);
     drop(ticks);
 
+    // Charge whoever was running for the time since it was last charged.
+    // Safety: we're in the SysTick handler, so nothing else is touching
+    // `CHARGED_UNTIL`.
+    unsafe {
+        charge_run_time(&mut tasks[current], now);
+    }
+
     // Process any timers.
     let switch = task::process_timers(tasks, now);
 
+    #[cfg(feature = "tickless")]
+    program_next_period(now, task::next_deadline(tasks));
+
     // If any timers fired, we need to defer a context switch, because the entry
     // sequence to this ISR doesn't save state correctly for efficiency.
     if switch != task::NextTask::Same {
@@ -862,6 +1048,54 @@ fn safe_sys_tick_handler(ticks: &mut u64, tasks: &mut [task::Task]) {
     }
 }
 
+/// Programs the SysTick period following the one that ended at `now`, so that
+/// it ends at `deadline` (or as late as it can, if there's no deadline or it's
+/// too far off).
+#[cfg(feature = "tickless")]
+fn program_next_period(now: Timestamp, deadline: Option<Timestamp>) {
+    use cortex_m::peripheral::{SCB, SYST};
+
+    // Safety: we're in the SysTick handler, so nothing else is touching these.
+    let divisor = unsafe { CLOCK_FREQ_KHZ };
+    let syst = unsafe { &*SYST::ptr() };
+
+    let now = u64::from(now);
+    let max = max_period_ticks(divisor);
+    let ticks = match deadline {
+        Some(d) => u64::from(d).saturating_sub(now).clamp(1, u64::from(max)),
+        None => u64::from(max),
+    } as u32;
+
+    // The counter reloaded when the last period ended, and has been counting
+    // since. Take those cycles out of the new period, so that it still ends
+    // on a tick boundary.
+    let reload = syst.rvr.read();
+    let current = SYST::get_current();
+    let used = if current == 0 {
+        0
+    } else {
+        reload - current + 1
+    };
+
+    let mut end = now + u64::from(ticks);
+    let mut cycles = (ticks * divisor).saturating_sub(used);
+    while cycles < MIN_PERIOD_CYCLES {
+        cycles += divisor;
+        end += 1;
+    }
+
+    // Safety: as above, we have exclusive access to the timer and its state.
+    // Writing the current value restarts the count from the new reload value;
+    // if the old period somehow expired while we were in here, we've now
+    // accounted for it, so we discard the interrupt.
+    unsafe {
+        syst.rvr.write(cycles - 1);
+        syst.cvr.write(0);
+        PERIOD_END = end;
+    }
+    SCB::clear_pendst();
+}
+
 fn pend_context_switch_from_isr() {
     // This sets the bit to pend a PendSV interrupt. PendSV will happen after
     // the current ISR (and any chained ISRs) returns, and perform the context
@@ -919,9 +1153,8 @@ unsafe extern "C" fn pendsv_entry() {
             / core::mem::size_of::<task::Task>();
 
         let next = task::select(idx, tasks);
-        let next = &mut tasks[next];
-        apply_memory_protection(next);
-        set_current_task(next);
+        apply_memory_protection(&tasks[next]);
+        set_current_task(tasks, next);
     });
 }
 
@@ -1012,6 +1245,11 @@ pub fn enable_irq(n: u32) {
     }
 }
 
+/// Resets the whole system, as though the reset pin had been asserted.
+pub fn reset() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
 #[repr(u8)]
 #[allow(dead_code)]
 enum FaultType {
@@ -1267,9 +1505,8 @@ unsafe extern "C" fn handle_fault(
             panic!("attempt to return to Task #{} after fault", idx);
         }
 
-        let next = &mut tasks[next];
-        apply_memory_protection(next);
-        set_current_task(next);
+        apply_memory_protection(&tasks[next]);
+        set_current_task(tasks, next);
     });
 }
 
@@ -0,0 +1,715 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Architecture support for running the kernel as a hosted simulation.
+//!
+//! This backend lets the portable parts of the kernel -- syscalls, kernel IPC,
+//! scheduling, timers -- run as an ordinary process on a (32-bit) host OS, such
+//! as `i686-unknown-linux-gnu`. It exists so that changes to the kernel and to
+//! supervisory tasks can be exercised without hardware or an emulator.
+//!
+//! This is the kernel's half of the simulator. Tasks reach it through the
+//! host syscall stubs in `userlib::sim`, once a harness has passed `syscall`
+//! to `userlib::sim::set_syscall_handler`. The harness builds an app image in
+//! host memory, registers the tasks' entry functions with
+//! `set_entry_points`, and calls `start_kernel`; `tests/sim.rs` is an example,
+//! and is run in CI on `i686-unknown-linux-gnu`.
+//!
+//! Only tasks written for the harness run this way. Building `test-suite` or
+//! an app's tasks for the host -- each is a separate `no_std` binary, found
+//! through linker sections -- and loading them into one process is not
+//! supported.
+//!
+//! # Tasks as threads
+//!
+//! Each task is run by a host thread, which is spawned the first time the task
+//! is scheduled after (re)initialization. The thread calls the task's entry
+//! function, as registered with `set_entry_points`; the `entry_point` in the
+//! task's descriptor is not used. The task's region table must describe
+//! memory that the host process actually owns, and the declared stack region
+//! is not used for execution, since the thread runs on its own host stack.
+//!
+//! The kernel is non-preemptive, and we preserve that by guarding all kernel
+//! state with a single lock. Only the task that the scheduler has selected is
+//! allowed to enter the kernel; any other task thread that tries to make a
+//! syscall waits until it is selected again. This means a preempted task may
+//! keep computing in parallel with its replacement, but because tasks only
+//! interact through the kernel, nobody can observe this.
+//!
+//! When a task is restarted, its old thread (if any) is left parked. The next
+//! time it wakes, it notices that it belongs to a previous incarnation of the
+//! task and unwinds quietly.
+//!
+//! # Simulated MPU
+//!
+//! There's no MPU to stop a host thread from scribbling over memory it doesn't
+//! own. Kernel accesses to task memory are still validated against the region
+//! table by `Task::try_read`/`try_write`, exactly as on hardware. For user
+//! accesses, a harness (or a simulated peripheral) can call
+//! `check_user_access`, which applies the rules the real MPU would and delivers
+//! a `MemoryAccess` fault to the current task if they're violated.
+//!
+//! # Simulated timer
+//!
+//! A dedicated host thread plays the role of the system tick timer. It wakes
+//! every `tick_divisor` microseconds, advances `TICKS`, and processes timers,
+//! just like the `SysTick` handler on ARM-M.
+//!
+//! # Simulated interrupts
+//!
+//! Hardware interrupts can be injected with `raise_irq`, which honors the
+//! enable state maintained by `enable_irq`/`disable_irq` and then follows the
+//! same notification path as a real interrupt.
+
+use core::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::app;
+use crate::task;
+use crate::time::Timestamp;
+use abi::{FaultInfo, FaultSource};
+
+/// Log things from kernel context. On the simulator, this just goes to the
+/// host's standard error, regardless of the `klog-*` features.
+macro_rules! klog {
+    ($s:expr) => {
+        std::eprintln!($s);
+    };
+    ($s:expr, $($tt:tt)*) => {
+        std::eprintln!($s, $($tt)*);
+    };
+}
+
+macro_rules! uassert {
+    ($cond : expr) => {
+        if !$cond {
+            panic!("Assertion failed!");
+        }
+    };
+}
+
+macro_rules! uassert_eq {
+    ($cond1 : expr, $cond2 : expr) => {
+        if !($cond1 == $cond2) {
+            panic!("Assertion failed!");
+        }
+    };
+}
+
+/// As on ARM-M, we use a global to record the task table position and extent.
+/// These are only touched with the kernel lock held.
+static mut TASK_TABLE_BASE: Option<NonNull<task::Task>> = None;
+static mut TASK_TABLE_SIZE: usize = 0;
+
+/// Interrupt table position and extent.
+static mut IRQ_TABLE_BASE: Option<NonNull<abi::Interrupt>> = None;
+static mut IRQ_TABLE_SIZE: usize = 0;
+
+/// The task currently holding the simulated CPU.
+static mut CURRENT_TASK_PTR: Option<NonNull<task::Task>> = None;
+
+/// Kernel global for tracking the current timestamp, measured in ticks. Only
+/// accessed with the kernel lock held.
+static mut TICKS: u64 = 0;
+
+/// Time up to which the current task has been charged for running, as on
+/// ARM-M. Only accessed with the kernel lock held.
+static mut CHARGED_UNTIL: u64 = 0;
+
+/// Entry functions for each task, indexed like the task table.
+static mut ENTRY_POINTS: &[TaskEntry] = &[];
+
+/// Shared simulator state, created by `start_first_task`.
+static mut SIM: Option<&'static Sim> = None;
+
+/// Source of task incarnation numbers. These are distinct from the task
+/// generation, which wraps quickly; we use them to recognize threads left
+/// over from before a restart.
+static NEXT_INCARNATION: AtomicU32 = AtomicU32::new(1);
+
+/// Enable bits for simulated interrupts, 32 per word like the NVIC.
+static IRQ_ENABLED: [AtomicU32; 16] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+std::thread_local! {
+    /// Identity of the task run by this host thread, as (index, incarnation).
+    /// `None` on threads that aren't running a task.
+    static THIS_TASK: core::cell::Cell<Option<(usize, u32)>> =
+        core::cell::Cell::new(None);
+}
+
+struct Sim {
+    /// The kernel lock. Whoever holds this is "in the kernel."
+    kernel: Mutex<()>,
+    /// Signaled whenever the current task changes, so that parked task
+    /// threads can check whether it's their turn.
+    resched: Condvar,
+}
+
+/// Marker payload used to unwind threads belonging to a dead incarnation of a
+/// task.
+struct Defunct;
+
+/// Simulated volatile registers. These take the place of `r4`-`r11` on ARM-M,
+/// and are filled in from the arguments of `syscall`.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct SavedState {
+    regs: [u32; 8],
+    /// Nominal stack pointer, recorded for debugging and `stack_pointer`.
+    sp: u32,
+    /// Incarnation of the task this state belongs to, assigned by
+    /// `reinitialize`.
+    incarnation: u32,
+    /// Whether a host thread has been spawned for this incarnation.
+    spawned: bool,
+}
+
+/// Map the simulated registers to (architecture-independent) syscall argument
+/// and return slots.
+impl task::ArchState for SavedState {
+    fn stack_pointer(&self) -> u32 {
+        self.sp
+    }
+
+    /// Reads syscall argument register 0.
+    fn arg0(&self) -> u32 {
+        self.regs[0]
+    }
+    fn arg1(&self) -> u32 {
+        self.regs[1]
+    }
+    fn arg2(&self) -> u32 {
+        self.regs[2]
+    }
+    fn arg3(&self) -> u32 {
+        self.regs[3]
+    }
+    fn arg4(&self) -> u32 {
+        self.regs[4]
+    }
+    fn arg5(&self) -> u32 {
+        self.regs[5]
+    }
+    fn arg6(&self) -> u32 {
+        self.regs[6]
+    }
+
+    fn syscall_descriptor(&self) -> u32 {
+        self.regs[7]
+    }
+
+    /// Writes syscall return argument 0.
+    fn ret0(&mut self, x: u32) {
+        self.regs[0] = x
+    }
+    fn ret1(&mut self, x: u32) {
+        self.regs[1] = x
+    }
+    fn ret2(&mut self, x: u32) {
+        self.regs[2] = x
+    }
+    fn ret3(&mut self, x: u32) {
+        self.regs[3] = x
+    }
+    fn ret4(&mut self, x: u32) {
+        self.regs[4] = x
+    }
+    fn ret5(&mut self, x: u32) {
+        self.regs[5] = x
+    }
+}
+
+/// The entry function of a task run by the simulator.
+pub type TaskEntry = extern "C" fn() -> !;
+
+/// Records `entries` as the entry functions of the tasks, in task index order.
+/// This stands in for the entry points in the task descriptors, which can't
+/// hold the address of a function in the host process.
+///
+/// # Safety
+///
+/// This must be called before `start_kernel`, and only once.
+pub unsafe fn set_entry_points(entries: &'static [TaskEntry]) {
+    uassert!(ENTRY_POINTS.is_empty());
+    ENTRY_POINTS = entries;
+}
+
+/// Records `tasks` as the system-wide task table.
+///
+/// If a task table has already been set, panics.
+///
+/// # Safety
+///
+/// This stashes a copy of `tasks` without revoking your right to access it,
+/// which is a potential aliasing violation if you call `with_task_table`. So
+/// don't do that. The normal kernel entry sequences avoid this issue.
+pub unsafe fn set_task_table(tasks: &mut [task::Task]) {
+    let prev_task_table = core::mem::replace(
+        &mut TASK_TABLE_BASE,
+        Some(NonNull::from(&mut tasks[0])),
+    );
+    // Catch double-uses of this function.
+    uassert_eq!(prev_task_table, None);
+    // Record length as well.
+    TASK_TABLE_SIZE = tasks.len();
+}
+
+pub unsafe fn set_irq_table(irqs: &[abi::Interrupt]) {
+    let prev_table = core::mem::replace(
+        &mut IRQ_TABLE_BASE,
+        Some(NonNull::new_unchecked(irqs.as_ptr() as *mut abi::Interrupt)),
+    );
+    // Catch double-uses of this function.
+    uassert_eq!(prev_table, None);
+    // Record length as well.
+    IRQ_TABLE_SIZE = irqs.len();
+}
+
+pub fn reinitialize(task: &mut task::Task) {
+    // Giving the task a fresh incarnation orphans any thread still running
+    // the old one; it will notice and exit the next time it wakes.
+    let initial_stack = task.descriptor().initial_stack;
+    *task.save_mut() = SavedState {
+        sp: initial_stack,
+        incarnation: NEXT_INCARNATION.fetch_add(1, Ordering::Relaxed),
+        ..SavedState::default()
+    };
+}
+
+/// There's no MPU to program on the simulator, so this is a no-op. Accesses
+/// are checked against the current task's region table on demand by
+/// `check_user_access`.
+pub fn apply_memory_protection(_task: &task::Task) {}
+
+/// Starts the simulation. On the simulator there are no machine cycles, so
+/// `tick_divisor` is taken to be the tick period in microseconds.
+pub fn start_first_task(tick_divisor: u32, task: &task::Task) -> ! {
+    let sim: &'static Sim = Box::leak(Box::new(Sim {
+        kernel: Mutex::new(()),
+        resched: Condvar::new(),
+    }));
+
+    {
+        let _guard = sim.kernel.lock().unwrap();
+        // Safety: no task threads exist yet, and we hold the kernel lock in
+        // any case.
+        unsafe {
+            SIM = Some(sim);
+            CURRENT_TASK_PTR = Some(NonNull::from(task));
+            with_task_table(|tasks| {
+                let idx = task_index(tasks, task);
+                spawn_if_needed(idx, &mut tasks[idx]);
+            });
+        }
+    }
+
+    let period = Duration::from_micros(u64::from(tick_divisor.max(1)));
+    std::thread::Builder::new()
+        .name("systick".into())
+        .spawn(move || loop {
+            std::thread::sleep(period);
+            sys_tick(sim);
+        })
+        .expect("can't spawn tick thread");
+
+    // The thread that booted the kernel has nothing more to do.
+    loop {
+        std::thread::park();
+    }
+}
+
+/// Makes a syscall on behalf of the task running on the calling thread.
+///
+/// `args` and `nr` are placed in the task's simulated registers just as the
+/// ARM-M `SVCall` sequence places them in `r4`-`r11`, and the result registers
+/// are returned once the task is next scheduled.
+///
+/// # Panics
+///
+/// If called from a thread that isn't running a task.
+pub fn syscall(nr: u32, args: [u32; 7]) -> [u32; 6] {
+    let (idx, incarnation) = THIS_TASK
+        .with(|t| t.get())
+        .expect("syscall from outside a task");
+    let sim = sim();
+
+    let guard = wait_for_cpu(sim, idx, incarnation);
+    // Safety: we hold the kernel lock, so nobody else has the task table.
+    unsafe {
+        let task_ptr = with_task_table(|tasks| {
+            let save = tasks[idx].save_mut();
+            save.regs[..7].copy_from_slice(&args);
+            save.regs[7] = nr;
+            &mut tasks[idx] as *mut task::Task
+        });
+        crate::syscalls::syscall_entry(nr, task_ptr);
+    }
+    sim.resched.notify_all();
+
+    // We may have blocked; either way, wait until we're selected again before
+    // reading our results.
+    let guard = wait_for_cpu_with(sim, guard, idx, incarnation);
+    // Safety: we hold the kernel lock.
+    let mut rets = [0; 6];
+    unsafe {
+        with_task_table(|tasks| {
+            rets.copy_from_slice(&tasks[idx].save().regs[..6]);
+        });
+    }
+    drop(guard);
+    rets
+}
+
+/// Checks an access by the current task against its region table, applying
+/// the rules that the ARM-M MPU would. Unlike kernel accesses, user code is
+/// allowed to touch device and DMA memory it has been granted.
+///
+/// If the access is denied, the task is faulted with `MemoryAccess` and this
+/// function does not return.
+pub fn check_user_access(address: usize, len: usize, write: bool) {
+    let (idx, incarnation) = THIS_TASK
+        .with(|t| t.get())
+        .expect("access check from outside a task");
+    let sim = sim();
+    let guard = wait_for_cpu(sim, idx, incarnation);
+
+    let needed = if write {
+        app::RegionAttributes::WRITE
+    } else {
+        app::RegionAttributes::READ
+    };
+    let end = address.checked_add(len);
+    // Safety: we hold the kernel lock.
+    let allowed = unsafe {
+        with_task_table(|tasks| {
+            tasks[idx].region_table().iter().any(|region| {
+                let region_end = region.base as usize + region.size as usize;
+                region.attributes.contains(needed)
+                    && region.base as usize <= address
+                    && end.map(|e| e <= region_end).unwrap_or(false)
+            })
+        })
+    };
+    if allowed {
+        return;
+    }
+
+    // Safety: we hold the kernel lock.
+    unsafe {
+        with_task_table(|tasks| {
+            let fault = FaultInfo::MemoryAccess {
+                address: Some(address as u32),
+                source: FaultSource::User,
+            };
+            let next = match task::force_fault(tasks, idx, fault) {
+                task::NextTask::Specific(i) => i,
+                task::NextTask::Other | task::NextTask::Same => {
+                    task::select(idx, tasks)
+                }
+            };
+            apply_memory_protection(&tasks[next]);
+            set_current_task(tasks, next);
+        });
+    }
+    sim.resched.notify_all();
+    // We're faulted, so this only returns through a restart, which unwinds.
+    let _guard = wait_for_cpu_with(sim, guard, idx, incarnation);
+    unreachable!();
+}
+
+/// Injects hardware interrupt `n`. If the interrupt is enabled and routed to a
+/// task, it is disabled and the task is notified, as on real hardware.
+///
+/// # Panics
+///
+/// If `n` is enabled but not routed to any task.
+pub fn raise_irq(n: u32) {
+    let sim = sim();
+    let _guard = sim.kernel.lock().unwrap();
+    let word = &IRQ_ENABLED[(n / 32) as usize];
+    if word.load(Ordering::Relaxed) & (1 << (n % 32)) == 0 {
+        return;
+    }
+
+    // Safety: we hold the kernel lock.
+    let switch = unsafe {
+        with_task_table(|tasks| {
+            with_irq_table(|irqs| {
+                for entry in irqs {
+                    if entry.irq == n {
+                        disable_irq(n);
+                        let n = task::NotificationSet(entry.notification);
+                        return Ok(tasks[entry.task as usize].post(n));
+                    }
+                }
+                Err(())
+            })
+        })
+    };
+    match switch {
+        Ok(true) => reschedule(sim),
+        Ok(false) => (),
+        Err(_) => panic!("unhandled IRQ {}", n),
+    }
+}
+
+/// Manufacture a mutable/exclusive reference to the task table from thin air
+/// and hand it to `body`. This bypasses borrow checking and should only be used
+/// at kernel entry points, then passed around.
+///
+/// Because the lifetime of the reference passed into `body` is anonymous, the
+/// reference can't easily be stored, which is deliberate.
+///
+/// # Safety
+///
+/// You can use this safely at kernel entry points, exactly once, with the
+/// kernel lock held, to create a reference to the task table.
+pub unsafe fn with_task_table<R>(
+    body: impl FnOnce(&mut [task::Task]) -> R,
+) -> R {
+    let tasks = core::slice::from_raw_parts_mut(
+        TASK_TABLE_BASE.expect("kernel not started").as_mut(),
+        TASK_TABLE_SIZE,
+    );
+    body(tasks)
+}
+
+/// Manufacture a shared reference to the interrupt action table from thin air
+/// and hand it to `body`. This bypasses borrow checking and should only be used
+/// at kernel entry points, then passed around.
+///
+/// Because the lifetime of the reference passed into `body` is anonymous, the
+/// reference can't easily be stored, which is deliberate.
+pub fn with_irq_table<R>(body: impl FnOnce(&[abi::Interrupt]) -> R) -> R {
+    // Safety: as long as a legit pointer was stored in IRQ_TABLE_BASE, or no
+    // pointer has been stored, we can do this safely.
+    let table = unsafe {
+        core::slice::from_raw_parts(
+            IRQ_TABLE_BASE.expect("kernel not started").as_ptr(),
+            IRQ_TABLE_SIZE,
+        )
+    };
+    body(table)
+}
+
+/// Records the address of `tasks[next]` as the current user task, charging
+/// the task it replaces for the time it ran, and spawning a thread to run it if
+/// this incarnation doesn't have one yet.
+///
+/// # Safety
+///
+/// This records a pointer that aliases `tasks[next]`. As long as you don't read
+/// that pointer except with the kernel lock held, you'll be okay.
+pub unsafe fn set_current_task(tasks: &mut [task::Task], next: usize) {
+    let ptr = NonNull::from(&mut tasks[next]);
+    if CURRENT_TASK_PTR != Some(ptr) {
+        if let Some(current) = CURRENT_TASK_PTR {
+            let idx = task_index(tasks, current.as_ref());
+            charge_run_time(&mut tasks[idx], now());
+        }
+        tasks[next].record_context_switch();
+    }
+    CURRENT_TASK_PTR = Some(ptr);
+    spawn_if_needed(next, &mut tasks[next]);
+}
+
+/// Charges `task`, which has been running since the last charge, for the
+/// ticks that have passed since then, up to `now`.
+///
+/// # Safety
+///
+/// The caller must hold the kernel lock.
+unsafe fn charge_run_time(task: &mut task::Task, now: Timestamp) {
+    let now = u64::from(now);
+    task.charge_ticks(now - CHARGED_UNTIL);
+    CHARGED_UNTIL = now;
+}
+
+/// Reads the tick counter.
+pub fn now() -> Timestamp {
+    Timestamp::from(unsafe { TICKS })
+}
+
+/// Notes that some task has a deadline at `_deadline`. The simulated tick is
+/// always periodic, so there's nothing to reprogram.
+pub fn wake_by(_deadline: Timestamp) {}
+
+pub fn disable_irq(n: u32) {
+    IRQ_ENABLED[(n / 32) as usize]
+        .fetch_and(!(1 << (n % 32)), Ordering::Relaxed);
+}
+
+pub fn enable_irq(n: u32) {
+    IRQ_ENABLED[(n / 32) as usize].fetch_or(1 << (n % 32), Ordering::Relaxed);
+}
+
+/// "Resets the system." There's no way to reboot the simulation in place, so we
+/// end the process and leave a restart to whatever harness is running it.
+pub fn reset() -> ! {
+    eprintln!("kernel: system reset requested");
+    std::process::exit(0)
+}
+
+fn sim() -> &'static Sim {
+    // Safety: SIM is written once, before any thread that could call this
+    // exists.
+    unsafe { SIM.expect("kernel not started") }
+}
+
+fn task_index(tasks: &[task::Task], task: &task::Task) -> usize {
+    (task as *const task::Task as usize - tasks.as_ptr() as usize)
+        / core::mem::size_of::<task::Task>()
+}
+
+/// The simulated equivalent of the `SysTick` handler.
+fn sys_tick(sim: &Sim) {
+    let _guard = sim.kernel.lock().unwrap();
+    // Safety: we hold the kernel lock, which stands in for the non-preemptive
+    // interrupt priority on ARM-M.
+    let switch = unsafe {
+        TICKS += 1;
+        let now = Timestamp::from(TICKS);
+        with_task_table(|tasks| {
+            let current =
+                CURRENT_TASK_PTR.expect("tick before kernel started?");
+            let idx = task_index(tasks, current.as_ref());
+            charge_run_time(&mut tasks[idx], now);
+            task::process_timers(tasks, now)
+        })
+    };
+    if switch != task::NextTask::Same {
+        reschedule(sim);
+    }
+}
+
+/// Picks a new task and wakes the task threads so it can run. This is what
+/// `PendSV` does on ARM-M. Must be called with the kernel lock held.
+fn reschedule(sim: &Sim) {
+    // Safety: our caller holds the kernel lock.
+    unsafe {
+        with_task_table(|tasks| {
+            let current = CURRENT_TASK_PTR.expect("irq before kernel started?");
+            let idx = task_index(tasks, current.as_ref());
+            let next = task::select(idx, tasks);
+            apply_memory_protection(&tasks[next]);
+            set_current_task(tasks, next);
+        });
+    }
+    sim.resched.notify_all();
+}
+
+/// Spawns a host thread for the task at `idx` unless its current incarnation
+/// already has one. Must be called with the kernel lock held.
+fn spawn_if_needed(idx: usize, task: &mut task::Task) {
+    if task.save().spawned {
+        return;
+    }
+    task.save_mut().spawned = true;
+    let incarnation = task.save().incarnation;
+    // Safety: ENTRY_POINTS is only written before the kernel starts.
+    let entry = *unsafe { ENTRY_POINTS }
+        .get(idx)
+        .expect("no entry point for task");
+
+    std::thread::Builder::new()
+        .name(format!("task{}.{}", idx, incarnation))
+        .spawn(move || task_thread(idx, incarnation, entry))
+        .expect("can't spawn task thread");
+}
+
+fn task_thread(idx: usize, incarnation: u32, entry: TaskEntry) {
+    THIS_TASK.with(|t| t.set(Some((idx, incarnation))));
+
+    let result = std::panic::catch_unwind(|| {
+        drop(wait_for_cpu(sim(), idx, incarnation));
+        entry()
+    });
+
+    if let Err(payload) = result {
+        if payload.is::<Defunct>() {
+            // We were running an incarnation that has since been restarted.
+            return;
+        }
+        // A Rust panic escaped the task without going through the panic
+        // syscall. Treat it as one.
+        let sim = sim();
+        let guard = sim.kernel.lock().unwrap();
+        // Safety: we hold the kernel lock.
+        unsafe {
+            with_task_table(|tasks| {
+                if tasks[idx].save().incarnation != incarnation {
+                    return;
+                }
+                let next = match task::force_fault(tasks, idx, FaultInfo::Panic)
+                {
+                    task::NextTask::Specific(i) => i,
+                    task::NextTask::Other | task::NextTask::Same => {
+                        task::select(idx, tasks)
+                    }
+                };
+                apply_memory_protection(&tasks[next]);
+                set_current_task(tasks, next);
+            });
+        }
+        drop(guard);
+        sim.resched.notify_all();
+    }
+}
+
+/// Takes the kernel lock and waits until the task at `idx` is selected to run.
+/// If the task is restarted while we wait, unwinds the calling thread.
+fn wait_for_cpu(
+    sim: &'static Sim,
+    idx: usize,
+    incarnation: u32,
+) -> MutexGuard<'static, ()> {
+    let guard = sim.kernel.lock().unwrap();
+    wait_for_cpu_with(sim, guard, idx, incarnation)
+}
+
+fn wait_for_cpu_with(
+    sim: &'static Sim,
+    mut guard: MutexGuard<'static, ()>,
+    idx: usize,
+    incarnation: u32,
+) -> MutexGuard<'static, ()> {
+    loop {
+        // Safety: we hold the kernel lock.
+        let (current, alive) = unsafe {
+            with_task_table(|tasks| {
+                let current = CURRENT_TASK_PTR
+                    .map(|p| task_index(tasks, p.as_ref()))
+                    .expect("kernel not started");
+                (current, tasks[idx].save().incarnation == incarnation)
+            })
+        };
+        if !alive {
+            drop(guard);
+            std::panic::resume_unwind(Box::new(Defunct));
+        }
+        if current == idx {
+            return guard;
+        }
+        guard = sim.resched.wait(guard).unwrap();
+    }
+}
@@ -4,7 +4,10 @@
 
 //! Implementation of IPC operations on the virtual kernel task.
 
-use abi::{FaultInfo, SchedState, TaskState, UsageError};
+use abi::{
+    FaultInfo, RegionInfo, SchedState, TaskInfo, TaskState, UsageError,
+    PANIC_MESSAGE_LEN, REGIONS_PER_TASK,
+};
 
 use crate::err::UserError;
 use crate::task::{current_id, ArchState, NextTask, Task};
@@ -27,6 +30,12 @@ pub fn handle_kernel_message(
         1 => read_task_status(tasks, caller, maybe_message?, maybe_response?),
         2 => restart_task(tasks, caller, maybe_message?),
         3 => fault_task(tasks, caller, maybe_message?),
+        4 => read_task_count(tasks, caller, maybe_response?),
+        5 => read_task_info(tasks, caller, maybe_message?, maybe_response?),
+        6 => read_task_stats(tasks, caller, maybe_message?, maybe_response?),
+        7 => read_stack_usage(tasks, caller, maybe_message?, maybe_response?),
+        8 => reset(caller),
+        9 => read_panic_message(tasks, caller, maybe_message?, maybe_response?),
         _ => {
             // Task has sent an unknown message to the kernel. That's bad.
             return Err(UserError::Unrecoverable(FaultInfo::SyscallUsage(
@@ -115,6 +124,10 @@ fn restart_task(
     // example. So, make a pass over the task table and unblock anyone who was
     // expecting useful work from the now-defunct task.
     for (i, task) in tasks.iter_mut().enumerate() {
+        // Anything sent to the old incarnation is gone, so there's no reply
+        // to wait for before sending to the new one.
+        task.forget_abandoned(old_id);
+
         // Just to make this a little easier to think about, don't check either
         // of the tasks involved in the restart operation. Neither should be
         // affected anyway.
@@ -191,3 +204,135 @@ fn fault_task(
 
     Ok(NextTask::Same)
 }
+
+fn read_task_count(
+    tasks: &mut [Task],
+    caller: usize,
+    response: USlice<u8>,
+) -> Result<NextTask, UserError> {
+    let count = tasks.len() as u32;
+    let response_len =
+        serialize_response(&mut tasks[caller], response, &count)?;
+    tasks[caller]
+        .save_mut()
+        .set_send_response_and_length(0, response_len);
+    Ok(NextTask::Same)
+}
+
+fn read_task_info(
+    tasks: &mut [Task],
+    caller: usize,
+    message: USlice<u8>,
+    response: USlice<u8>,
+) -> Result<NextTask, UserError> {
+    let index: u32 = deserialize_message(&tasks[caller], message)?;
+    if index as usize >= tasks.len() {
+        return Err(UserError::Unrecoverable(FaultInfo::SyscallUsage(
+            UsageError::TaskOutOfRange,
+        )));
+    }
+    let other = &tasks[index as usize];
+    let mut regions = [RegionInfo::default(); REGIONS_PER_TASK];
+    for (info, &region) in regions.iter_mut().zip(other.region_table()) {
+        *info = RegionInfo::from(region);
+    }
+    let info = TaskInfo {
+        name: other.descriptor().name,
+        priority: other.priority(),
+        generation: other.generation(),
+        state: *other.state(),
+        regions,
+    };
+
+    let response_len = serialize_response(&mut tasks[caller], response, &info)?;
+    tasks[caller]
+        .save_mut()
+        .set_send_response_and_length(0, response_len);
+    Ok(NextTask::Same)
+}
+
+fn read_task_stats(
+    tasks: &mut [Task],
+    caller: usize,
+    message: USlice<u8>,
+    response: USlice<u8>,
+) -> Result<NextTask, UserError> {
+    let index: u32 = deserialize_message(&tasks[caller], message)?;
+    if index as usize >= tasks.len() {
+        return Err(UserError::Unrecoverable(FaultInfo::SyscallUsage(
+            UsageError::TaskOutOfRange,
+        )));
+    }
+    let stats = *tasks[index as usize].stats();
+    let response_len =
+        serialize_response(&mut tasks[caller], response, &stats)?;
+    tasks[caller]
+        .save_mut()
+        .set_send_response_and_length(0, response_len);
+    Ok(NextTask::Same)
+}
+
+fn read_stack_usage(
+    tasks: &mut [Task],
+    caller: usize,
+    message: USlice<u8>,
+    response: USlice<u8>,
+) -> Result<NextTask, UserError> {
+    let index: u32 = deserialize_message(&tasks[caller], message)?;
+    if index as usize >= tasks.len() {
+        return Err(UserError::Unrecoverable(FaultInfo::SyscallUsage(
+            UsageError::TaskOutOfRange,
+        )));
+    }
+    let usage = tasks[index as usize].stack_usage();
+    let response_len =
+        serialize_response(&mut tasks[caller], response, &usage)?;
+    tasks[caller]
+        .save_mut()
+        .set_send_response_and_length(0, response_len);
+    Ok(NextTask::Same)
+}
+
+/// Copies out a task's panic message. This isn't `ssmarshal`-encoded: the
+/// response is just the bytes of the message, truncated to fit the caller's
+/// buffer.
+fn read_panic_message(
+    tasks: &mut [Task],
+    caller: usize,
+    message: USlice<u8>,
+    mut response: USlice<u8>,
+) -> Result<NextTask, UserError> {
+    let index: u32 = deserialize_message(&tasks[caller], message)?;
+    if index as usize >= tasks.len() {
+        return Err(UserError::Unrecoverable(FaultInfo::SyscallUsage(
+            UsageError::TaskOutOfRange,
+        )));
+    }
+
+    // The caller may be asking about itself, so copy the message out before
+    // we borrow its response buffer.
+    let mut msg = [0; PANIC_MESSAGE_LEN];
+    let src = tasks[index as usize].panic_message();
+    let msg = &mut msg[..src.len()];
+    msg.copy_from_slice(src);
+
+    let buf = tasks[caller].try_write(&mut response)?;
+    let len = msg.len().min(buf.len());
+    buf[..len].copy_from_slice(&msg[..len]);
+    tasks[caller]
+        .save_mut()
+        .set_send_response_and_length(0, len);
+    Ok(NextTask::Same)
+}
+
+/// Resets the entire system. Because this takes down every task at once, we
+/// only honor it from the supervisor.
+fn reset(caller: usize) -> Result<NextTask, UserError> {
+    if caller != 0 {
+        return Err(UserError::Unrecoverable(FaultInfo::SyscallUsage(
+            UsageError::IllegalTask,
+        )));
+    }
+
+    crate::arch::reset()
+}
@@ -24,7 +24,8 @@ use crate::task::{self, Task};
 ///   accessible to any task.
 /// - `tick_divisor`: a platform-specific way of converting "machine ticks" into
 ///   "kernel ticks." On ARM M-profile, this is CPU cycles per tick, where a
-///   tick is typically a millisecond.
+///   tick is typically a millisecond. On the host simulator, it's the tick
+///   period in microseconds.
 ///
 /// # Safety
 ///
@@ -175,8 +176,10 @@ fn safe_start_kernel(
 
     uassert!(tasks.len() != 0); // tasks must exist for this to work.
 
-    // With that done, set up initial register state etc.
+    // With that done, paint the stacks so we can measure their use later, and
+    // set up initial register state etc.
     for task in tasks.iter_mut() {
+        task.paint_stack();
         crate::arch::reinitialize(task);
     }
 
@@ -65,11 +65,11 @@ pub unsafe extern "C" fn syscall_entry(nr: u32, task: *mut Task) {
             // If we're returning to the same task, we're done!
             NextTask::Same => (),
 
-            NextTask::Specific(i) => switch_to(&mut tasks[i]),
+            NextTask::Specific(i) => switch_to(tasks, i),
 
             NextTask::Other => {
                 let next = task::select(idx, tasks);
-                switch_to(&mut tasks[next])
+                switch_to(tasks, next)
             }
         }
     })
@@ -78,8 +78,12 @@ pub unsafe extern "C" fn syscall_entry(nr: u32, task: *mut Task) {
 /// Factored out of `syscall_entry` to encapsulate the bits that don't need
 /// unsafe.
 fn safe_syscall_entry(nr: u32, current: usize, tasks: &mut [Task]) -> NextTask {
+    if let Ok(sysnum) = Sysnum::try_from(nr) {
+        tasks[current].record_syscall(sysnum);
+    }
+
     let res = match Sysnum::try_from(nr) {
-        Ok(Sysnum::Send) => send(tasks, current),
+        Ok(Sysnum::Send) | Ok(Sysnum::SendTimeout) => send(tasks, current),
         Ok(Sysnum::Recv) => recv(tasks, current).map_err(UserError::from),
         Ok(Sysnum::Reply) => reply(tasks, current).map_err(UserError::from),
         Ok(Sysnum::SetTimer) => Ok(set_timer(&mut tasks[current], arch::now())),
@@ -91,6 +95,7 @@ fn safe_syscall_entry(nr: u32, current: usize, tasks: &mut [Task]) -> NextTask {
         Ok(Sysnum::GetTimer) => Ok(get_timer(&mut tasks[current], arch::now())),
         Ok(Sysnum::RefreshTaskId) => refresh_task_id(tasks, current),
         Ok(Sysnum::Post) => post(tasks, current),
+        Ok(Sysnum::AsyncSend) => async_send(tasks, current),
         Err(_) => {
             // Bogus syscall number! That's a fault.
             Err(FaultInfo::SyscallUsage(UsageError::BadSyscallNumber).into())
@@ -108,7 +113,7 @@ fn safe_syscall_entry(nr: u32, current: usize, tasks: &mut [Task]) -> NextTask {
     }
 }
 
-/// Implementation of the SEND IPC primitive.
+/// Implementation of the SEND and SEND_TIMEOUT IPC primitives.
 ///
 /// `caller` is a valid task index (i.e. not directly from user code).
 ///
@@ -116,8 +121,17 @@ fn safe_syscall_entry(nr: u32, current: usize, tasks: &mut [Task]) -> NextTask {
 ///
 /// If `caller` is out of range for `tasks`.
 fn send(tasks: &mut [Task], caller: usize) -> Result<NextTask, UserError> {
-    // Extract callee.
-    let callee_id = tasks[caller].save().as_send_args().callee();
+    // Extract callee and timeout.
+    let args = tasks[caller].save().as_send_args();
+    let callee_id = args.callee();
+    let timeout = args.timeout();
+    drop(args);
+
+    // Arm (or, for a plain SEND, disarm) the timeout. If it expires while
+    // we're blocked below, `process_timers` will unblock us.
+    let deadline =
+        timeout.map(|t| Timestamp::from(u64::from(arch::now()) + u64::from(t)));
+    tasks[caller].set_send_deadline(deadline);
 
     // Check IPC filter - TODO
     // Open question: should out-of-range task IDs be handled by faulting below,
@@ -134,7 +148,9 @@ fn send(tasks: &mut [Task], caller: usize) -> Result<NextTask, UserError> {
     // Check for ready peer.
     let mut next_task = NextTask::Same;
     let caller_id = current_id(tasks, caller);
-    if tasks[callee].state().can_accept_message_from(caller_id) {
+    if tasks[callee].state().can_accept_message_from(caller_id)
+        && !tasks[caller].has_abandoned(callee_id)
+    {
         // Callee is waiting in receive -- either an open receive, or a
         // closed receive from just us. Either way, we can directly deliver the
         // message and switch tasks...unless either task was naughty, in which
@@ -165,6 +181,96 @@ fn send(tasks: &mut [Task], caller: usize) -> Result<NextTask, UserError> {
     return Ok(NextTask::Other.combine(next_task));
 }
 
+/// Implementation of the ASYNC_SEND IPC primitive.
+///
+/// This delivers a message only if the recipient is already waiting to receive
+/// it, and never blocks the caller: there's no reply, and no leases. This lets
+/// a task send to a less trusted (e.g. lower priority) task without putting
+/// itself at that task's mercy.
+///
+/// `caller` is a valid task index (i.e. not directly from user code).
+///
+/// # Panics
+///
+/// If `caller` is out of range for `tasks`.
+fn async_send(
+    tasks: &mut [Task],
+    caller: usize,
+) -> Result<NextTask, UserError> {
+    let callee_id = tasks[caller].save().as_send_args().callee();
+
+    // The kernel never blocks senders anyway, and its messages all have
+    // responses, so there's no sense in sending to it this way.
+    if callee_id == TaskId::KERNEL {
+        return Err(UserError::Unrecoverable(FaultInfo::SyscallUsage(
+            UsageError::BadKernelMessage,
+        )));
+    }
+
+    let callee = task::check_task_id_against_table(tasks, callee_id)?;
+
+    let caller_id = current_id(tasks, caller);
+    if !tasks[callee].state().can_accept_message_from(caller_id)
+        || tasks[caller].has_abandoned(callee_id)
+    {
+        return Err(UserError::Recoverable(abi::WOULD_BLOCK, NextTask::Same));
+    }
+
+    // Collect the message. Only the first three SEND arguments are
+    // meaningful to us; the rest are ignored.
+    let send_args = tasks[caller].save().as_send_args();
+    let op = send_args.operation();
+    let src_slice = send_args.message()?;
+    drop(send_args);
+
+    let recv_args = tasks[callee].save().as_recv_args();
+    let dest_slice = recv_args.buffer();
+    drop(recv_args);
+    let dest_slice = match dest_slice {
+        Ok(slice) => slice,
+        Err(e) => {
+            // The recipient set up a bogus receive buffer, so it's faulted
+            // and doesn't get the message.
+            let hint =
+                task::force_fault(tasks, callee, FaultInfo::SyscallUsage(e));
+            return Err(UserError::Recoverable(abi::WOULD_BLOCK, hint));
+        }
+    };
+
+    let amount_copied =
+        match safe_copy(tasks, caller, src_slice, callee, dest_slice) {
+            Ok(n) => n,
+            Err(interact) => {
+                // Fault whichever task misbehaved. If it was the recipient, the
+                // message wasn't delivered.
+                let hint = interact.apply_to_dst(tasks, callee)?;
+                return Err(UserError::Recoverable(abi::WOULD_BLOCK, hint));
+            }
+        };
+
+    // Tell the recipient that there's nothing to reply into and nothing to
+    // borrow. If it replies anyway, `reply` will find that we're not waiting
+    // and discard it.
+    tasks[callee].save_mut().set_recv_result(
+        caller_id,
+        u32::from(op),
+        amount_copied,
+        0,
+        0,
+    );
+    tasks[callee].set_healthy_state(SchedState::Runnable);
+    tasks[caller].save_mut().set_send_response_and_length(0, 0);
+
+    // As with `post`, only switch if the recipient outranks us.
+    let caller_p = tasks[caller].priority();
+    let callee_p = tasks[callee].priority();
+    if callee_p.is_more_important_than(caller_p) {
+        Ok(NextTask::Specific(callee))
+    } else {
+        Ok(NextTask::Same)
+    }
+}
+
 /// Implementation of the RECV IPC primitive.
 ///
 /// `caller` is a valid task index (i.e. not directly from user code).
@@ -206,7 +312,9 @@ fn recv(tasks: &mut [Task], caller: usize) -> Result<NextTask, UserError> {
         // First possibility: that task you're asking about is DEAD.
         let sender_idx = task::check_task_id_against_table(tasks, sender_id)?;
         // Second possibility: task has a message for us.
-        if tasks[sender_idx].state().is_sending_to(caller_id) {
+        if tasks[sender_idx].state().is_sending_to(caller_id)
+            && !tasks[sender_idx].has_abandoned(caller_id)
+        {
             // Oh hello sender!
             match deliver(tasks, sender_idx, caller) {
                 Ok(_) => {
@@ -243,7 +351,7 @@ fn recv(tasks: &mut [Task], caller: usize) -> Result<NextTask, UserError> {
 
         // Is anyone blocked waiting to send to us?
         while let Some(sender) = task::priority_scan(last, tasks, |t| {
-            t.state().is_sending_to(caller_id)
+            t.state().is_sending_to(caller_id) && !t.has_abandoned(caller_id)
         }) {
             // Oh hello sender!
             match deliver(tasks, sender, caller) {
@@ -295,6 +403,13 @@ fn reply(tasks: &mut [Task], caller: usize) -> Result<NextTask, FaultInfo> {
         Ok(x) => x,
     };
 
+    if tasks[callee].forget_abandoned(caller_id) {
+        // This answers a message the callee gave up on when its SEND timed
+        // out, not whatever it's doing now. Discard it; now that we've
+        // answered, we can be given the callee's next message.
+        return Ok(NextTask::Same);
+    }
+
     if tasks[callee].state()
         != &TaskState::Healthy(SchedState::InReply(caller_id))
     {
@@ -522,6 +637,13 @@ fn borrow_lease(
 
     let caller_id = current_id(tasks, caller);
 
+    if tasks[lender].has_abandoned(caller_id) {
+        // The lender gave up on the message its leases came with when its SEND
+        // timed out, so as far as we're concerned, that message is dead.
+        let code = abi::dead_response_code(tasks[lender].generation());
+        return Err(UserError::Recoverable(code, NextTask::Same));
+    }
+
     // Check state of lender and range of lease table.
     if tasks[lender].state()
         != &TaskState::Healthy(SchedState::InReply(caller_id))
@@ -584,14 +706,14 @@ fn borrow_lease(
     }
 }
 
-/// Performs the architecture-specific bookkeeping to activate `task` on next
-/// return to user. This should be done "on our way out" to user code, toward
-/// the end of the syscall routine.
+/// Performs the architecture-specific bookkeeping to activate `tasks[next]` on
+/// next return to user. This should be done "on our way out" to user code,
+/// toward the end of the syscall routine.
 ///
 /// Note that this does *not* magically run user code. This is not Unix `swtch`.
-unsafe fn switch_to(task: &mut Task) {
-    arch::apply_memory_protection(task);
-    arch::set_current_task(task);
+unsafe fn switch_to(tasks: &mut [Task], next: usize) {
+    arch::apply_memory_protection(&tasks[next]);
+    arch::set_current_task(tasks, next);
 }
 
 /// Transfers a message from caller's context into callee's. This may be called
@@ -715,11 +837,14 @@ fn explicit_panic(
     tasks: &mut [Task],
     caller: usize,
 ) -> Result<NextTask, UserError> {
-    // Make an attempt at printing the message.
+    // Make an attempt at printing the message, and keep a copy for the
+    // supervisor.
     let args = tasks[caller].save().as_panic_args();
     let message = args.message();
     drop(args);
 
+    let mut copy = [0; abi::PANIC_MESSAGE_LEN];
+    let mut copy_len = 0;
     if let Ok(uslice) = message {
         if let Ok(slice) = tasks[caller].try_read(&uslice) {
             // Plausible.
@@ -730,8 +855,12 @@ fn explicit_panic(
             } else {
                 klog!("task @{} panicked: (message unprintable)", caller);
             }
+
+            copy_len = slice.len().min(copy.len());
+            copy[..copy_len].copy_from_slice(&slice[..copy_len]);
         }
     }
+    tasks[caller].set_panic_message(&copy[..copy_len]);
 
     Ok(task::force_fault(tasks, caller, FaultInfo::Panic))
 }
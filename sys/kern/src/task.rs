@@ -7,8 +7,9 @@
 use core::sync::atomic::{AtomicU32, Ordering};
 
 use abi::{
-    FaultInfo, FaultSource, Generation, Priority, SchedState, TaskId,
-    TaskState, UsageError,
+    FaultInfo, FaultSource, Generation, Priority, SchedState, StackUsage,
+    Sysnum, TaskId, TaskState, TaskStats, UsageError, PANIC_MESSAGE_LEN,
+    STACK_PAINT,
 };
 use zerocopy::FromBytes;
 
@@ -32,6 +33,11 @@ pub fn set_fault_notification(mask: u32) {
     FAULT_NOTIFICATION.store(mask, Ordering::Relaxed);
 }
 
+/// Number of abandoned messages (see `Task::has_abandoned`) that a task can
+/// have outstanding at once. A `SEND_TIMEOUT` that would need another one
+/// doesn't expire until one of them has been answered.
+pub const MAX_ABANDONED: usize = 4;
+
 /// Internal representation of a task.
 ///
 /// The fields of this struct are private to this module so that we can maintain
@@ -50,6 +56,15 @@ pub struct Task {
     state: TaskState,
     /// State for tracking the task's timer.
     timer: TimerState,
+    /// Time at which the task's current `SEND_TIMEOUT` expires, if it's
+    /// blocked in one. This can be stale, but only matters while the task is
+    /// in `InSend` or `InReply`, and every SEND replaces it.
+    send_deadline: Option<Timestamp>,
+    /// Recipients still holding messages that this task abandoned when a
+    /// `SEND_TIMEOUT` expired. A recipient can't be given another message from
+    /// this task until it has replied to the abandoned one, since that reply
+    /// would otherwise be taken as the answer to the new message.
+    abandoned: [Option<TaskId>; MAX_ABANDONED],
     /// Restart count for this task. We increment this whenever we reinitialize
     /// the task. The low bits of this become the task's generation number.
     generation: u32,
@@ -63,6 +78,15 @@ pub struct Task {
     /// Pointer to the ROM descriptor used to create this task, so it can be
     /// restarted.
     descriptor: &'static TaskDesc,
+
+    /// Execution statistics. These survive `reinitialize`, and are laid out
+    /// predictably so that a debugger can read them out of the task table.
+    stats: TaskStats,
+
+    /// Message passed to `sys_panic` by this incarnation of the task, if it
+    /// has panicked, truncated to fit. Valid up to `panic_message_len`.
+    panic_message: [u8; PANIC_MESSAGE_LEN],
+    panic_message_len: u8,
 }
 
 impl Task {
@@ -84,9 +108,14 @@ impl Task {
             region_table,
 
             generation: 0,
+            send_deadline: None,
+            abandoned: [None; MAX_ABANDONED],
             notifications: 0,
             save: crate::arch::SavedState::default(),
             timer: crate::task::TimerState::default(),
+            stats: TaskStats::default(),
+            panic_message: [0; PANIC_MESSAGE_LEN],
+            panic_message_len: 0,
         }
     }
 
@@ -267,6 +296,9 @@ impl Task {
     ) {
         self.timer.deadline = deadline;
         self.timer.to_post = notifications;
+        if let Some(deadline) = deadline {
+            crate::arch::wake_by(deadline);
+        }
     }
 
     /// Reads out the state of this task's timer, as previously set by
@@ -288,12 +320,62 @@ impl Task {
     pub fn reinitialize(&mut self) {
         self.generation = self.generation.wrapping_add(1);
         self.timer = TimerState::default();
+        self.send_deadline = None;
+        self.abandoned = [None; MAX_ABANDONED];
         self.notifications = 0;
         self.state = TaskState::default();
+        self.panic_message_len = 0;
 
+        self.paint_stack();
         crate::arch::reinitialize(self);
     }
 
+    /// Returns the task's stack as a slice of words, running from the base of
+    /// the region that contains the initial stack pointer, up to the initial
+    /// stack pointer. Startup ensures that such a region exists.
+    fn stack(&self) -> USlice<u32> {
+        let initial_stack = self.descriptor.initial_stack;
+        let region = self
+            .region_table
+            .iter()
+            .find(|region| {
+                initial_stack.wrapping_sub(region.base) <= region.size
+                    && region.attributes.contains(
+                        RegionAttributes::READ | RegionAttributes::WRITE,
+                    )
+            })
+            .expect("task has no stack region");
+        USlice::from_raw(
+            region.base as usize,
+            (initial_stack - region.base) as usize
+                / core::mem::size_of::<u32>(),
+        )
+        .unwrap()
+    }
+
+    /// Fills the task's entire stack with `STACK_PAINT`, so that its
+    /// high-water mark can be found later by `stack_usage`. This must be done
+    /// before the architecture code sets up the initial stack frame.
+    pub fn paint_stack(&mut self) {
+        let mut stack = self.stack();
+        for word in self.try_write(&mut stack).unwrap() {
+            *word = STACK_PAINT;
+        }
+    }
+
+    /// Measures the task's stack usage by finding the lowest word that no
+    /// longer holds `STACK_PAINT`.
+    pub fn stack_usage(&self) -> StackUsage {
+        let stack = self.stack();
+        let words = self.try_read(&stack).unwrap();
+        let untouched = words.iter().take_while(|&&w| w == STACK_PAINT).count();
+        let word_size = core::mem::size_of::<u32>();
+        StackUsage {
+            size: (words.len() * word_size) as u32,
+            max_depth: ((words.len() - untouched) * word_size) as u32,
+        }
+    }
+
     /// Returns a reference to the `TaskDesc` that was used to initially create
     /// this task.
     pub fn descriptor(&self) -> &'static TaskDesc {
@@ -349,6 +431,71 @@ impl Task {
     pub fn save_mut(&mut self) -> &mut crate::arch::SavedState {
         &mut self.save
     }
+
+    /// Returns this task's execution statistics.
+    pub fn stats(&self) -> &TaskStats {
+        &self.stats
+    }
+
+    /// Charges `ticks` kernel ticks to this task. This should be called from
+    /// the tick handler on the task that was running when the tick arrived,
+    /// with the number of ticks since the handler last ran.
+    pub fn charge_ticks(&mut self, ticks: u64) {
+        self.stats.run_ticks = self.stats.run_ticks.wrapping_add(ticks);
+    }
+
+    /// Records that this task has been switched in by the scheduler.
+    pub fn record_context_switch(&mut self) {
+        self.stats.context_switches =
+            self.stats.context_switches.wrapping_add(1);
+    }
+
+    /// Returns the message this task passed to `sys_panic`, if it has panicked
+    /// since it was last initialized. Otherwise, returns an empty slice.
+    pub fn panic_message(&self) -> &[u8] {
+        &self.panic_message[..usize::from(self.panic_message_len)]
+    }
+
+    /// Keeps a copy of `message`, truncated if necessary, for
+    /// `panic_message`.
+    pub fn set_panic_message(&mut self, message: &[u8]) {
+        let len = message.len().min(PANIC_MESSAGE_LEN);
+        self.panic_message[..len].copy_from_slice(&message[..len]);
+        self.panic_message_len = len as u8;
+    }
+
+    /// Sets the time at which the task's in-progress SEND, if any, is
+    /// abandoned. `None` means it waits forever.
+    pub fn set_send_deadline(&mut self, deadline: Option<Timestamp>) {
+        self.send_deadline = deadline;
+        if let Some(deadline) = deadline {
+            crate::arch::wake_by(deadline);
+        }
+    }
+
+    /// Checks whether `recipient` is still holding a message that this task
+    /// abandoned, and so can't be given another one yet.
+    pub fn has_abandoned(&self, recipient: TaskId) -> bool {
+        self.abandoned.contains(&Some(recipient))
+    }
+
+    /// Forgets the message this task abandoned at `recipient`, returning
+    /// `true` if there was one.
+    pub fn forget_abandoned(&mut self, recipient: TaskId) -> bool {
+        match self.abandoned.iter_mut().find(|a| **a == Some(recipient)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records that this task has made the syscall `nr`.
+    pub fn record_syscall(&mut self, nr: Sysnum) {
+        let count = &mut self.stats.syscalls[nr as usize];
+        *count = count.wrapping_add(1);
+    }
 }
 
 /// Interface that must be implemented by the `arch::SavedState` type. This
@@ -548,7 +695,29 @@ impl<'a, T: ArchState> AsSendArgs<&'a T> {
     /// If the caller passed a slice that overlaps the end of the address space,
     /// or that is not aligned properly for a lease table, returns `Err`.
     pub fn lease_table(&self) -> Result<USlice<ULease>, UsageError> {
-        USlice::from_raw(self.0.arg5() as usize, self.0.arg6() as usize)
+        let count = if self.is_send_timeout() {
+            self.0.arg6() & 0xff
+        } else {
+            self.0.arg6()
+        };
+        USlice::from_raw(self.0.arg5() as usize, count as usize)
+    }
+
+    /// Extracts the timeout, in ticks, if the caller is using SEND_TIMEOUT,
+    /// which shares argument 6 between the lease count and the timeout.
+    pub fn timeout(&self) -> Option<u32> {
+        if self.is_send_timeout() {
+            Some(self.0.arg6() >> 8)
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether the arguments are for SEND_TIMEOUT rather than plain
+    /// SEND. The syscall number stays put while the caller is blocked, so
+    /// this holds for as long as the arguments are of interest.
+    fn is_send_timeout(&self) -> bool {
+        self.0.syscall_descriptor() == Sysnum::SendTimeout as u32
     }
 }
 
@@ -752,9 +921,46 @@ impl NextTask {
 
 /// Processes all enabled timers in the task table, posting notifications for
 /// any that have expired by `current_time` (and disabling them atomically).
+///
+/// This also abandons any SENDs whose timeouts have expired.
 pub fn process_timers(tasks: &mut [Task], current_time: Timestamp) -> NextTask {
     let mut sched_hint = NextTask::Same;
     for (index, task) in tasks.iter_mut().enumerate() {
+        if let Some(deadline) = task.send_deadline {
+            if deadline <= current_time {
+                task.send_deadline = None;
+                let unblock = match task.state {
+                    TaskState::Healthy(SchedState::InSend(_)) => true,
+                    TaskState::Healthy(SchedState::InReply(recipient)) => {
+                        // The recipient has the message, and may yet reply to
+                        // it or try to borrow from it. Remember that, so that
+                        // neither can be mistaken for part of a later message.
+                        match task.abandoned.iter_mut().find(|a| a.is_none()) {
+                            Some(slot) => {
+                                *slot = Some(recipient);
+                                true
+                            }
+                            None => {
+                                // Nowhere to record it. Leave the sender
+                                // blocked, and try again on the next tick, in
+                                // case one of its other abandoned messages has
+                                // been answered by then.
+                                task.send_deadline = Some(Timestamp::from(
+                                    u64::from(current_time) + 1,
+                                ));
+                                false
+                            }
+                        }
+                    }
+                    _ => false,
+                };
+                if unblock {
+                    task.save.set_send_response_and_length(abi::TIMED_OUT, 0);
+                    task.state = TaskState::Healthy(SchedState::Runnable);
+                    sched_hint = sched_hint.combine(NextTask::Specific(index));
+                }
+            }
+        }
         if let Some(deadline) = task.timer.deadline {
             if deadline <= current_time {
                 task.timer.deadline = None;
@@ -770,6 +976,15 @@ pub fn process_timers(tasks: &mut [Task], current_time: Timestamp) -> NextTask {
     sched_hint
 }
 
+/// Returns the earliest deadline -- for a timer, or a SEND timeout -- of any
+/// task in `tasks`, if there is one.
+pub fn next_deadline(tasks: &[Task]) -> Option<Timestamp> {
+    tasks
+        .iter()
+        .flat_map(|t| t.timer.deadline.into_iter().chain(t.send_deadline))
+        .min()
+}
+
 /// Checks a user-provided `TaskId` for validity against `table`.
 ///
 /// On success, returns an index that can be used to dereference `table` without
@@ -865,6 +1080,7 @@ pub fn force_fault(
     fault: FaultInfo,
 ) -> NextTask {
     let task = &mut tasks[index];
+    task.stats.faults = task.stats.faults.wrapping_add(1);
     task.state = match task.state {
         TaskState::Healthy(sched) => TaskState::Faulted {
             original_state: sched,
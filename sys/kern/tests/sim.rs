@@ -0,0 +1,205 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Boots the kernel on the simulator (see `arch/sim.rs`) with a small app
+//! and checks that IPC and timers work.
+//!
+//! This needs a 32-bit host, and is run in CI with
+//!
+//! ```text
+//! cargo test -p kern --target i686-unknown-linux-gnu --test sim
+//! ```
+//!
+//! The app is built in host memory: one region covers a static block of RAM
+//! that serves as every task's stack and holds the buffers they hand to the
+//! kernel, since the kernel checks every task memory access against the
+//! region table. (Task locals live on host thread stacks, outside any region.)
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use abi::{
+    App, RegionAttributes, RegionDesc, TaskDesc, TaskFlags, CURRENT_APP_MAGIC,
+    REGIONS_PER_TASK, TASK_NAME_LEN,
+};
+use userlib::{Generation, TaskId};
+
+/// Index of the server task. The client is task 1, at a lower priority, and
+/// the idle task (which the kernel needs whenever the others are blocked) is
+/// task 2.
+const SERVER: usize = 0;
+
+/// Operation understood by the server: replies with its argument plus one.
+const OP_INCREMENT: u16 = 1;
+
+/// The app image, laid out the way `start_kernel` expects.
+#[repr(C)]
+struct Image {
+    app: App,
+    regions: [RegionDesc; 2],
+    tasks: [TaskDesc; 3],
+}
+
+/// Task RAM. The stack is never used, since tasks run on host threads, but
+/// the kernel paints it at boot.
+#[repr(C, align(16))]
+struct Ram {
+    stack: [u32; 64],
+    request: [u8; 4],
+    reply: [u8; 4],
+    incoming: [u8; 4],
+    outgoing: [u8; 4],
+}
+
+static mut RAM: Ram = Ram {
+    stack: [0; 64],
+    request: [0; 4],
+    reply: [0; 4],
+    incoming: [0; 4],
+    outgoing: [0; 4],
+};
+
+static mut KERNEL_RAM: [u8; 4096] = [0; 4096];
+
+static ENTRY_POINTS: [kern::arch::TaskEntry; 3] = [server, client, idle];
+
+/// Progress reported by the client: 1 once its send has been answered, 2
+/// once its timer has fired, and `u32::MAX` if anything went wrong.
+static PROGRESS: AtomicU32 = AtomicU32::new(0);
+
+extern "C" fn server() -> ! {
+    // Safety: only the server touches these fields.
+    let (incoming, outgoing) =
+        unsafe { (&mut RAM.incoming, &mut RAM.outgoing) };
+    loop {
+        let msg = userlib::sys_recv_open(incoming, 0);
+        if msg.operation == u32::from(OP_INCREMENT) && msg.message_len == 4 {
+            let n = u32::from_le_bytes(*incoming) + 1;
+            *outgoing = n.to_le_bytes();
+            userlib::sys_reply(msg.sender, 0, outgoing);
+        } else {
+            userlib::sys_reply(msg.sender, 1, &[]);
+        }
+    }
+}
+
+extern "C" fn client() -> ! {
+    // Safety: only the client touches these fields.
+    let (request, reply) = unsafe { (&mut RAM.request, &mut RAM.reply) };
+    let server = TaskId::for_index_and_gen(SERVER, Generation::default());
+
+    *request = 41u32.to_le_bytes();
+    let (rc, len) =
+        userlib::sys_send(server, OP_INCREMENT, request, reply, &[]);
+    if rc != 0 || len != 4 || u32::from_le_bytes(*reply) != 42 {
+        fail();
+    }
+    PROGRESS.store(1, Ordering::SeqCst);
+
+    let deadline = userlib::sys_get_timer().now + 5;
+    userlib::sys_set_timer(Some(deadline), 1);
+    let woken = userlib::sys_recv_closed(&mut [], 1, TaskId::KERNEL);
+    if woken.map(|msg| msg.operation) != Ok(1)
+        || userlib::sys_get_timer().now < deadline
+    {
+        fail();
+    }
+    PROGRESS.store(2, Ordering::SeqCst);
+
+    loop {
+        std::thread::park();
+    }
+}
+
+extern "C" fn idle() -> ! {
+    loop {
+        std::thread::park();
+    }
+}
+
+fn fail() -> ! {
+    PROGRESS.store(u32::MAX, Ordering::SeqCst);
+    loop {
+        std::thread::park();
+    }
+}
+
+fn task(priority: u32, name: &str, ram: &Ram) -> TaskDesc {
+    let mut regions = [0; REGIONS_PER_TASK];
+    regions[0] = 1;
+    let mut padded = [0; TASK_NAME_LEN];
+    padded[..name.len()].copy_from_slice(name.as_bytes());
+
+    let base = ram as *const Ram as u32;
+    TaskDesc {
+        regions,
+        // The simulator runs `ENTRY_POINTS` instead, but the kernel still
+        // checks that this is in an executable region.
+        entry_point: base,
+        initial_stack: base + core::mem::size_of_val(&ram.stack) as u32,
+        priority,
+        flags: TaskFlags::START_AT_BOOT,
+        name: padded,
+    }
+}
+
+fn boot() {
+    // Safety: the kernel hasn't started, so nothing else is using RAM.
+    let ram = unsafe { &RAM };
+    let image = Box::leak(Box::new(Image {
+        app: App {
+            magic: CURRENT_APP_MAGIC,
+            task_count: 3,
+            region_count: 2,
+            irq_count: 0,
+            fault_notification: 0,
+            zeroed_expansion_space: [0; 12],
+        },
+        regions: [
+            // By convention, region 0 confers no access.
+            RegionDesc {
+                base: 0,
+                size: 32,
+                attributes: RegionAttributes::empty(),
+                reserved_zero: 0,
+            },
+            RegionDesc {
+                base: ram as *const Ram as u32,
+                size: core::mem::size_of::<Ram>() as u32,
+                attributes: RegionAttributes::READ
+                    | RegionAttributes::WRITE
+                    | RegionAttributes::EXECUTE,
+                reserved_zero: 0,
+            },
+        ],
+        tasks: [
+            task(0, "server", ram),
+            task(1, "client", ram),
+            task(2, "idle", ram),
+        ],
+    }));
+
+    std::thread::spawn(move || unsafe {
+        kern::arch::set_entry_points(&ENTRY_POINTS);
+        userlib::sim::set_syscall_handler(kern::arch::syscall);
+        kern::startup::start_kernel(
+            &image.app,
+            KERNEL_RAM.as_mut_ptr(),
+            KERNEL_RAM.len(),
+            1000,
+        )
+    });
+}
+
+#[test]
+fn send_and_timer() {
+    boot();
+
+    let start = Instant::now();
+    while PROGRESS.load(Ordering::SeqCst) < 2 {
+        assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(PROGRESS.load(Ordering::SeqCst), 2, "client saw bad results");
+}
@@ -1,21 +0,0 @@
-// This Source Code Form is subject to the terms of the Mozilla Public
-// License, v. 2.0. If a copy of the MPL was not distributed with this
-// file, You can obtain one at https://mozilla.org/MPL/2.0/.
-
-use std::env;
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Do an architecture check.
-    if env::var("CARGO_CFG_TARGET_OS").unwrap() != "none" {
-        eprintln!("***********************************************");
-        eprintln!("Hi!");
-        eprintln!("You appear to be building this natively,");
-        eprintln!("i.e. for your workstation. This won't work.");
-        eprintln!("Please specify --target=some-triple, e.g.");
-        eprintln!("--target=thumbv7em-none-eabihf");
-        eprintln!("***********************************************");
-        panic!()
-    }
-
-    Ok(())
-}
@@ -15,7 +15,8 @@ use zerocopy::{AsBytes, FromBytes, LayoutVerified};
 use crate::{
     sys_borrow_info, sys_borrow_read, sys_borrow_write, sys_get_timer,
     sys_recv, sys_recv_closed, sys_recv_open, sys_reply, sys_send,
-    sys_set_timer, BorrowInfo, ClosedRecvError, FromPrimitive,
+    sys_send_timeout, sys_set_timer, BorrowInfo, ClosedRecvError,
+    FromPrimitive,
 };
 
 const INTERNAL_TIMER_NOTIFICATION: u32 = 1 << 31;
@@ -525,6 +526,65 @@ where
     }
 }
 
+/// Error returned by `send_with_timeout`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SendTimeoutError<E> {
+    /// The server didn't reply in time.
+    TimedOut,
+    /// The server replied with an error.
+    Server(E),
+}
+
+/// Variant of `send` that gives up if the server hasn't replied within
+/// `timeout` ticks, so that a wedged server can't wedge its clients too.
+///
+/// If this times out, the server may or may not have acted on the message.
+///
+/// # Panics
+///
+/// If `timeout` is greater than `MAX_SEND_TIMEOUT`, or if the server sends
+/// back a successful response that is the wrong size for `M::Response`.
+pub fn send_with_timeout<M>(
+    target: TaskId,
+    message: &M,
+    timeout: u32,
+) -> Result<M::Response, SendTimeoutError<M::Err>>
+where
+    M: Call,
+{
+    use core::mem::MaybeUninit;
+
+    // As in `send`, obtain an uninitialized buffer with the right size and
+    // alignment to contain one M::Response.
+    let mut response: MaybeUninit<M::Response> = MaybeUninit::uninit();
+    let rslice = unsafe {
+        core::slice::from_raw_parts_mut(
+            response.as_mut_ptr() as *mut u8,
+            core::mem::size_of_val(&response),
+        )
+    };
+
+    let (code, rlen) = sys_send_timeout(
+        target,
+        M::OP,
+        message.as_bytes(),
+        rslice,
+        &[],
+        timeout,
+    );
+
+    match code {
+        0 if rlen == core::mem::size_of_val(&response) => {
+            Ok(unsafe { response.assume_init() })
+        }
+        // As in `send`, an ill-sized response is the server's bug, and we
+        // panic rather than make every client handle it.
+        0 => panic!(),
+        abi::TIMED_OUT => Err(SendTimeoutError::TimedOut),
+        _ => Err(SendTimeoutError::Server(M::Err::from(code))),
+    }
+}
+
 /// Typed version of `sys_send` that sends a value to another task and collects
 /// a response, retrying automatically if that task has restarted. This is a
 /// variant on `send` for operations that are idempotent (because the server may
@@ -38,3 +38,74 @@ pub fn fault_task(task: usize) {
     let (rc, _len) = sys_send(TaskId::KERNEL, 3, task.as_bytes(), &mut [], &[]);
     assert_eq!(rc, 0);
 }
+
+/// Returns the number of tasks in the system, which bounds the indices that
+/// can be passed to the other functions in this module.
+pub fn read_task_count() -> usize {
+    let mut response = [0; core::mem::size_of::<u32>()];
+    let (rc, len) = sys_send(TaskId::KERNEL, 4, &[], &mut response, &[]);
+    assert_eq!(rc, 0);
+    let count: u32 = ssmarshal::deserialize(&response[..len])
+        .map_err(|_| ())
+        .unwrap()
+        .0;
+    count as usize
+}
+
+pub fn read_task_info(task: usize) -> abi::TaskInfo {
+    // Coerce `task` to a known size (Rust doesn't assume that usize == u32)
+    let task = task as u32;
+    let mut response = [0; core::mem::size_of::<abi::TaskInfo>()];
+    let (rc, len) =
+        sys_send(TaskId::KERNEL, 5, task.as_bytes(), &mut response, &[]);
+    assert_eq!(rc, 0);
+    ssmarshal::deserialize(&response[..len])
+        .map_err(|_| ())
+        .unwrap()
+        .0
+}
+
+pub fn read_task_stats(task: usize) -> abi::TaskStats {
+    // Coerce `task` to a known size (Rust doesn't assume that usize == u32)
+    let task = task as u32;
+    let mut response = [0; core::mem::size_of::<abi::TaskStats>()];
+    let (rc, len) =
+        sys_send(TaskId::KERNEL, 6, task.as_bytes(), &mut response, &[]);
+    assert_eq!(rc, 0);
+    ssmarshal::deserialize(&response[..len])
+        .map_err(|_| ())
+        .unwrap()
+        .0
+}
+
+pub fn read_stack_usage(task: usize) -> abi::StackUsage {
+    // Coerce `task` to a known size (Rust doesn't assume that usize == u32)
+    let task = task as u32;
+    let mut response = [0; core::mem::size_of::<abi::StackUsage>()];
+    let (rc, len) =
+        sys_send(TaskId::KERNEL, 7, task.as_bytes(), &mut response, &[]);
+    assert_eq!(rc, 0);
+    ssmarshal::deserialize(&response[..len])
+        .map_err(|_| ())
+        .unwrap()
+        .0
+}
+
+/// Copies the message that `task` passed to `sys_panic` into `buf`, returning
+/// its length. The message is empty if the task hasn't panicked since it was
+/// last started. At most `abi::PANIC_MESSAGE_LEN` bytes are kept, and the
+/// message is truncated further if `buf` is smaller than that.
+pub fn read_panic_message(task: usize, buf: &mut [u8]) -> usize {
+    // Coerce `task` to a known size (Rust doesn't assume that usize == u32)
+    let task = task as u32;
+    let (rc, len) = sys_send(TaskId::KERNEL, 9, task.as_bytes(), buf, &[]);
+    assert_eq!(rc, 0);
+    len
+}
+
+/// Resets the whole system. Only the supervisor may do this; anyone else is
+/// faulted.
+pub fn reset() -> ! {
+    let _ = sys_send(TaskId::KERNEL, 8, &[], &mut [], &[]);
+    panic!("kernel declined to reset");
+}
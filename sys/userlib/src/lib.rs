@@ -23,6 +23,9 @@
 //! all registers.
 //!
 //! See: https://github.com/rust-lang/rust/issues/73450#issuecomment-650463347
+//!
+//! When building for a host OS rather than bare metal, the stubs in [`sim`]
+//! are used instead, and hand syscalls to the simulator.
 
 #![no_std]
 #![feature(asm)]
@@ -43,6 +46,11 @@ pub mod task_slot;
 pub mod units;
 pub mod util;
 
+#[cfg(not(target_os = "none"))]
+pub mod sim;
+#[cfg(not(target_os = "none"))]
+use sim::*;
+
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct Lease<'a> {
@@ -149,6 +157,7 @@ struct SendArgs<'a> {
 /// Core implementation of the SEND syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_send_stub(_args: &mut SendArgs<'_>) -> RcLen {
     asm!("
@@ -175,6 +184,133 @@ unsafe extern "C" fn sys_send_stub(_args: &mut SendArgs<'_>) -> RcLen {
     )
 }
 
+/// Like `sys_send`, but gives up if `target` hasn't replied within `timeout`
+/// ticks, returning `TIMED_OUT` as the response code.
+///
+/// If the send times out after `target` has received the message, `target`
+/// may still act on it, but can no longer reply or access `leases`, and won't
+/// be given another message from this task until it has replied.
+///
+/// # Panics
+///
+/// If `timeout` is greater than `MAX_SEND_TIMEOUT`, or if there are more than
+/// 255 leases.
+#[inline(always)]
+pub fn sys_send_timeout(
+    target: TaskId,
+    operation: u16,
+    outgoing: &[u8],
+    incoming: &mut [u8],
+    leases: &[Lease<'_>],
+    timeout: u32,
+) -> (u32, usize) {
+    assert!(timeout <= MAX_SEND_TIMEOUT);
+    assert!(leases.len() <= 0xff);
+
+    let mut args = SendArgs {
+        packed_target_operation: u32::from(target.0) << 16
+            | u32::from(operation),
+        outgoing_ptr: outgoing.as_ptr(),
+        outgoing_len: outgoing.len(),
+        incoming_ptr: incoming.as_mut_ptr(),
+        incoming_len: incoming.len(),
+        lease_ptr: leases.as_ptr(),
+        // SEND_TIMEOUT packs the timeout in above the lease count.
+        lease_len: (timeout as usize) << 8 | leases.len(),
+    };
+    unsafe { sys_send_timeout_stub(&mut args).into() }
+}
+
+/// Core implementation of the SEND_TIMEOUT syscall.
+///
+/// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
+#[naked]
+unsafe extern "C" fn sys_send_timeout_stub(_args: &mut SendArgs<'_>) -> RcLen {
+    asm!("
+        @ Spill the registers we're about to use to pass stuff.
+        push {{r4-r11}}
+        @ Load in args from the struct.
+        ldm r0, {{r4-r10}}
+        @ Load the constant syscall number.
+        mov r11, {sysnum}
+
+        @ To the kernel!
+        svc #0
+
+        @ Move the two results back into their return positions.
+        mov r0, r4
+        mov r1, r5
+        @ Restore the registers we used.
+        pop {{r4-r11}}
+        @ Fin.
+        bx lr
+        ",
+        sysnum = const Sysnum::SendTimeout as u32,
+        options(noreturn),
+    )
+}
+
+/// Sends a message to `target`, but only if it's already waiting to receive
+/// one from us, and without waiting for a reply.
+///
+/// This never blocks, which makes it safe to use from a task (such as the
+/// supervisor) that can't afford to be at the mercy of the recipient. There's
+/// no way to get a reply or lend memory; a recipient that replies anyway has
+/// its reply discarded.
+///
+/// Returns zero if the message was delivered, `WOULD_BLOCK` if `target` wasn't
+/// receiving (or faulted on receipt), or a dead code if `target` has been
+/// restarted.
+#[inline(always)]
+pub fn sys_send_async(target: TaskId, operation: u16, outgoing: &[u8]) -> u32 {
+    let mut args = SendArgs {
+        packed_target_operation: u32::from(target.0) << 16
+            | u32::from(operation),
+        outgoing_ptr: outgoing.as_ptr(),
+        outgoing_len: outgoing.len(),
+        incoming_ptr: core::ptr::null_mut(),
+        incoming_len: 0,
+        lease_ptr: core::ptr::null(),
+        lease_len: 0,
+    };
+    let (rc, _len) = unsafe { sys_send_async_stub(&mut args).into() };
+    rc
+}
+
+/// Core implementation of the ASYNC_SEND syscall.
+///
+/// This takes the same arguments as SEND, of which the kernel only uses the
+/// first three.
+///
+/// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
+#[naked]
+unsafe extern "C" fn sys_send_async_stub(_args: &mut SendArgs<'_>) -> RcLen {
+    asm!("
+        @ Spill the registers we're about to use to pass stuff.
+        push {{r4-r11}}
+        @ Load in args from the struct.
+        ldm r0, {{r4-r10}}
+        @ Load the constant syscall number.
+        mov r11, {sysnum}
+
+        @ To the kernel!
+        svc #0
+
+        @ Move the two results back into their return positions.
+        mov r0, r4
+        mov r1, r5
+        @ Restore the registers we used.
+        pop {{r4-r11}}
+        @ Fin.
+        bx lr
+        ",
+        sysnum = const Sysnum::AsyncSend as u32,
+        options(noreturn),
+    )
+}
+
 /// Performs an "open" RECV that will accept messages from any task or
 /// notifications from the kernel.
 ///
@@ -283,6 +419,7 @@ pub struct RecvMessage {
 /// Core implementation of the RECV syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 #[must_use]
 unsafe extern "C" fn sys_recv_stub(
@@ -348,6 +485,7 @@ pub fn sys_reply(peer: TaskId, code: u32, message: &[u8]) {
 /// Core implementation of the REPLY syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_reply_stub(
     _peer: u32,
@@ -411,6 +549,7 @@ pub fn sys_set_timer(deadline: Option<u64>, notifications: u32) {
 /// Core implementation of the SET_TIMER syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_set_timer_stub(
     _set_timer: u32,
@@ -467,6 +606,7 @@ pub fn sys_borrow_read(
 /// Core implementation of the BORROW_READ syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_borrow_read_stub(_args: *mut BorrowReadArgs) -> RcLen {
     asm!("
@@ -525,6 +665,7 @@ pub fn sys_borrow_write(
 /// Core implementation of the BORROW_WRITE syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_borrow_write_stub(
     _args: *mut BorrowWriteArgs,
@@ -604,6 +745,7 @@ pub struct BorrowInfo {
 /// Core implementation of the BORROW_INFO syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_borrow_info_stub(
     _lender: u32,
@@ -647,6 +789,7 @@ pub fn sys_irq_control(mask: u32, enable: bool) {
 /// Core implementation of the IRQ_CONTROL syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_irq_control_stub(_mask: u32, _enable: u32) {
     asm!("
@@ -682,6 +825,7 @@ pub fn sys_panic(msg: &[u8]) -> ! {
 /// Core implementation of the PANIC syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_panic_stub(_msg: *const u8, _len: usize) -> ! {
     asm!("
@@ -764,6 +908,7 @@ struct RawTimerState {
 /// Core implementation of the GET_TIMER syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_get_timer_stub(_out: *mut RawTimerState) {
     asm!("
@@ -789,6 +934,7 @@ unsafe extern "C" fn sys_get_timer_stub(_out: *mut RawTimerState) {
 
 /// This is the entry point for the kernel. Its job is to set up our memory
 /// before jumping to user-defined `main`.
+#[cfg(target_os = "none")]
 #[doc(hidden)]
 #[no_mangle]
 #[link_section = ".text.start"]
@@ -858,7 +1004,7 @@ pub unsafe extern "C" fn _start() -> ! {
     )
 }
 
-#[cfg(feature = "panic-messages")]
+#[cfg(all(target_os = "none", feature = "panic-messages"))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     use core::fmt::Write;
@@ -889,7 +1035,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     });
 }
 
-#[cfg(not(feature = "panic-messages"))]
+#[cfg(all(target_os = "none", not(feature = "panic-messages")))]
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo) -> ! {
     sys_panic(b"PANIC")
@@ -904,6 +1050,7 @@ pub fn sys_refresh_task_id(task_id: TaskId) -> TaskId {
 /// Core implementation of the REFRESH_TASK_ID syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_refresh_task_id_stub(_tid: u32) -> u32 {
     asm!("
@@ -940,6 +1087,7 @@ pub fn sys_post(task_id: TaskId, bits: u32) -> u32 {
 /// Core implementation of the POST syscall.
 ///
 /// See the note on syscall stubs at the top of this module for rationale.
+#[cfg(target_os = "none")]
 #[naked]
 unsafe extern "C" fn sys_post_stub(_tid: u32, _mask: u32) -> u32 {
     asm!("
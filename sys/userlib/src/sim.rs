@@ -0,0 +1,224 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Syscall stubs for tasks running on a host OS, under the simulator.
+//!
+//! These take the place of the `svc`-based stubs when building for anything
+//! other than bare metal. Instead of trapping into the kernel, each stub loads
+//! its arguments into a set of simulated registers -- in the same order that
+//! the ARM-M stubs load `r4`-`r10` -- and hands them, with the syscall number,
+//! to a handler installed by whatever is hosting the tasks. On the kernel's
+//! simulator backend, that's `kern::arch::syscall`.
+//!
+//! Syscall arguments are 32 bits wide, pointers included, so making syscalls
+//! requires a 32-bit host. Building for other hosts is still useful, though:
+//! it lets code that uses `userlib` be unit tested on the host, so long as it
+//! doesn't make any syscalls.
+
+use super::*;
+
+/// A function that performs a syscall, taking the syscall number and the
+/// argument registers and returning the result registers.
+pub type SyscallHandler = fn(u32, [u32; 7]) -> [u32; 6];
+
+static mut HANDLER: Option<SyscallHandler> = None;
+
+/// Installs `handler` to perform syscalls on behalf of tasks in this process.
+///
+/// # Safety
+///
+/// This must be called before any task makes a syscall, and not while any
+/// task might be making one.
+pub unsafe fn set_syscall_handler(handler: SyscallHandler) {
+    HANDLER = Some(handler);
+}
+
+fn syscall(nr: Sysnum, args: [u32; 7]) -> [u32; 6] {
+    // Safety: the handler is only written before any task runs.
+    let handler = unsafe { HANDLER }.expect("no syscall handler installed");
+    handler(nr as u32, args)
+}
+
+/// Converts a pointer or length into an argument register.
+fn reg(x: usize) -> u32 {
+    use core::convert::TryFrom;
+    u32::try_from(x).expect("syscall argument doesn't fit in a register")
+}
+
+fn rc_len(rets: [u32; 6]) -> RcLen {
+    RcLen(u64::from(rets[0]) | u64::from(rets[1]) << 32)
+}
+
+fn send_args(args: &SendArgs<'_>) -> [u32; 7] {
+    [
+        args.packed_target_operation,
+        reg(args.outgoing_ptr as usize),
+        reg(args.outgoing_len),
+        reg(args.incoming_ptr as usize),
+        reg(args.incoming_len),
+        reg(args.lease_ptr as usize),
+        reg(args.lease_len),
+    ]
+}
+
+pub(crate) unsafe extern "C" fn sys_send_stub(
+    args: &mut SendArgs<'_>,
+) -> RcLen {
+    rc_len(syscall(Sysnum::Send, send_args(args)))
+}
+
+pub(crate) unsafe extern "C" fn sys_send_timeout_stub(
+    args: &mut SendArgs<'_>,
+) -> RcLen {
+    rc_len(syscall(Sysnum::SendTimeout, send_args(args)))
+}
+
+pub(crate) unsafe extern "C" fn sys_send_async_stub(
+    args: &mut SendArgs<'_>,
+) -> RcLen {
+    rc_len(syscall(Sysnum::AsyncSend, send_args(args)))
+}
+
+pub(crate) unsafe extern "C" fn sys_recv_stub(
+    buffer_ptr: *mut u8,
+    buffer_len: usize,
+    notification_mask: u32,
+    specific_sender: u32,
+    out: *mut RawRecvMessage,
+) -> u32 {
+    let rets = syscall(
+        Sysnum::Recv,
+        [
+            reg(buffer_ptr as usize),
+            reg(buffer_len),
+            notification_mask,
+            specific_sender,
+            0,
+            0,
+            0,
+        ],
+    );
+    out.write(RawRecvMessage {
+        sender: rets[1],
+        operation: rets[2],
+        message_len: rets[3] as usize,
+        response_capacity: rets[4] as usize,
+        lease_count: rets[5] as usize,
+    });
+    rets[0]
+}
+
+pub(crate) unsafe extern "C" fn sys_reply_stub(
+    peer: u32,
+    code: u32,
+    message_ptr: *const u8,
+    message_len: usize,
+) {
+    syscall(
+        Sysnum::Reply,
+        [
+            peer,
+            code,
+            reg(message_ptr as usize),
+            reg(message_len),
+            0,
+            0,
+            0,
+        ],
+    );
+}
+
+pub(crate) unsafe extern "C" fn sys_set_timer_stub(
+    set_timer: u32,
+    deadline_lo: u32,
+    deadline_hi: u32,
+    notification: u32,
+) {
+    syscall(
+        Sysnum::SetTimer,
+        [set_timer, deadline_lo, deadline_hi, notification, 0, 0, 0],
+    );
+}
+
+pub(crate) unsafe extern "C" fn sys_borrow_read_stub(
+    args: *mut BorrowReadArgs,
+) -> RcLen {
+    let args = &*args;
+    rc_len(syscall(
+        Sysnum::BorrowRead,
+        [
+            args.lender,
+            reg(args.index),
+            reg(args.offset),
+            reg(args.dest as usize),
+            reg(args.dest_len),
+            0,
+            0,
+        ],
+    ))
+}
+
+pub(crate) unsafe extern "C" fn sys_borrow_write_stub(
+    args: *mut BorrowWriteArgs,
+) -> RcLen {
+    let args = &*args;
+    rc_len(syscall(
+        Sysnum::BorrowWrite,
+        [
+            args.lender,
+            reg(args.index),
+            reg(args.offset),
+            reg(args.src as usize),
+            reg(args.src_len),
+            0,
+            0,
+        ],
+    ))
+}
+
+pub(crate) unsafe extern "C" fn sys_borrow_info_stub(
+    lender: u32,
+    index: usize,
+    out: *mut RawBorrowInfo,
+) {
+    let rets = syscall(Sysnum::BorrowInfo, [lender, reg(index), 0, 0, 0, 0, 0]);
+    out.write(RawBorrowInfo {
+        rc: rets[0],
+        atts: rets[1],
+        length: rets[2] as usize,
+    });
+}
+
+pub(crate) unsafe extern "C" fn sys_irq_control_stub(mask: u32, enable: u32) {
+    syscall(Sysnum::IrqControl, [mask, enable, 0, 0, 0, 0, 0]);
+}
+
+pub(crate) unsafe extern "C" fn sys_panic_stub(
+    msg: *const u8,
+    len: usize,
+) -> ! {
+    syscall(Sysnum::Panic, [reg(msg as usize), reg(len), 0, 0, 0, 0, 0]);
+    // The kernel doesn't return from a panic, so the handler shouldn't either.
+    unreachable!()
+}
+
+pub(crate) unsafe extern "C" fn sys_get_timer_stub(out: *mut RawTimerState) {
+    let rets = syscall(Sysnum::GetTimer, [0; 7]);
+    out.write(RawTimerState {
+        now_lo: rets[0],
+        now_hi: rets[1],
+        set: rets[2],
+        dl_lo: rets[3],
+        dl_hi: rets[4],
+        on_dl: rets[5],
+    });
+}
+
+pub(crate) unsafe extern "C" fn sys_refresh_task_id_stub(tid: u32) -> u32 {
+    syscall(Sysnum::RefreshTaskId, [tid, 0, 0, 0, 0, 0, 0])[0]
+}
+
+pub(crate) unsafe extern "C" fn sys_post_stub(tid: u32, mask: u32) -> u32 {
+    syscall(Sysnum::Post, [tid, mask, 0, 0, 0, 0, 0])[0]
+}
@@ -5,23 +5,38 @@
 //!
 //! Tuple structs for units that are useful in the real world
 //!
+//! These can be sent in IPC messages as they are, so they're all
+//! `repr(transparent)`.
+//!
+
+use zerocopy::{AsBytes, FromBytes};
 
 /// Degrees Celsius
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, AsBytes, FromBytes)]
+#[repr(transparent)]
 pub struct Celsius(pub f32);
 
 /// Rotations per minute
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, AsBytes, FromBytes)]
+#[repr(transparent)]
 pub struct Rpm(pub u16);
 
 /// Volts of potential
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, AsBytes, FromBytes)]
+#[repr(transparent)]
 pub struct Volts(pub f32);
 
 /// Amperes of current
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, AsBytes, FromBytes)]
+#[repr(transparent)]
 pub struct Amperes(pub f32);
 
+/// Watts of power
+#[derive(Copy, Clone, PartialEq, Debug, AsBytes, FromBytes)]
+#[repr(transparent)]
+pub struct Watts(pub f32);
+
 /// Ohms of resistence
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, AsBytes, FromBytes)]
+#[repr(transparent)]
 pub struct Ohms(pub f32);
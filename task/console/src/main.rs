@@ -0,0 +1,520 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Serial console
+//!
+//! We sit on a USART driver and offer a simple command shell, for poking at a
+//! system that has no debug probe attached. Type `help` for the commands.
+//!
+//! Tasks can be named by index or by name. Restarting a task is done by the
+//! kernel directly; holding and releasing one is done by the supervisor,
+//! exactly as when requested by a debugger (see `jefe`'s `external` module).
+//! The supervisor only takes such requests from tasks named in its `holders`
+//! config, so that should include us.
+//!
+//! The I2C devices are those of `[config.i2c.devices]`, in the same order as
+//! the inventory task's. Only the STM32H7 GPIO driver is supported for now.
+//!
+//! Note that we can only show our own ring buffer: other tasks' ring buffers
+//! are in their memory, not ours, and still need Humility.
+
+#![no_std]
+#![no_main]
+
+use core::fmt::{self, Write};
+use drv_i2c_api::{I2cDevice, ResponseCode};
+use drv_stm32h7_gpio_api::{Gpio, Port};
+use drv_usart_api::{Usart, UsartError};
+use ringbuf::*;
+use task_jefe_api::{FaultRecord, FAULT_LOG_LEN, PANIC_MSG_LEN};
+use userlib::*;
+
+task_slot!(USART, usart_driver);
+task_slot!(I2C, i2c_driver);
+task_slot!(GPIO, gpio_driver);
+
+include!(concat!(env!("OUT_DIR"), "/i2c_config.rs"));
+
+use i2c_config::inventory::{DEVICES, NUM_DEVICES};
+
+/// Longest command line we accept; anything typed beyond this is dropped.
+const LINE_LEN: usize = 80;
+
+/// Longest I2C register read we'll do.
+const I2C_READ_LEN: usize = 16;
+
+/// How long to wait before reading again after the USART server refuses a
+/// read, in milliseconds.
+const READ_RETRY_MS: u64 = 100;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum GpioAction {
+    Set,
+    Reset,
+    Toggle,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Command {
+    Help,
+    Tasks,
+    Restart(usize),
+    Hold(usize),
+    Release(usize),
+    Faults,
+    Ringbuf,
+    Devices,
+    I2c {
+        device: usize,
+        reg: u8,
+        len: usize,
+    },
+    Gpio {
+        port: Port,
+        pin: u8,
+        action: Option<GpioAction>,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Trace {
+    None,
+    Command(Command),
+    Invalid,
+    I2cError(ResponseCode),
+    GpioError,
+    ReadError(UsartError),
+}
+
+ringbuf!(Trace, 16, Trace::None);
+
+const HELP: &[(&str, &str)] = &[
+    ("help", "show this message"),
+    ("tasks", "list tasks and their states"),
+    ("restart <task>", "restart a task"),
+    ("hold <task>", "hold a task when it next faults"),
+    ("release <task>", "release a held task"),
+    ("faults", "show the supervisor's fault log"),
+    ("ringbuf", "show our recent commands"),
+    ("devices", "list I2C devices"),
+    (
+        "i2c <device> <reg> [<len>]",
+        "read a register of an I2C device",
+    ),
+    (
+        "gpio <pin> [set|reset|toggle]",
+        "read or change a pin, e.g. gpio b14",
+    ),
+];
+
+/// Our end of the serial port. Writes translate newlines to CRLF, as
+/// terminals expect.
+struct Console {
+    usart: Usart,
+    devices: [I2cDevice; NUM_DEVICES],
+    gpio: Gpio,
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.write_bytes(b"\r\n")?;
+            }
+
+            if !line.is_empty() {
+                self.write_bytes(line.as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[export_name = "main"]
+fn main() -> ! {
+    let mut console = Console {
+        usart: Usart::from(USART.get_task_id()),
+        devices: i2c_config::inventory::i2c_devices(I2C.get_task_id()),
+        gpio: Gpio::from(GPIO.get_task_id()),
+    };
+
+    let mut line = [0; LINE_LEN];
+    let mut len = 0;
+    let mut last = 0;
+
+    // Errors writing to the console are ignored throughout: there's no-one
+    // else to tell.
+    let _ = write!(console, "\nhubris console; type `help` for commands\n> ");
+
+    loop {
+        let mut rx = [0; 16];
+
+        let n = match console.usart.read(&mut rx) {
+            Ok(n) => n as usize,
+            Err(e) => {
+                // Don't spin on a server that keeps refusing us (another
+                // reader, or one that's restarting); try again shortly.
+                ringbuf_entry!(Trace::ReadError(e));
+                hl::sleep_for(READ_RETRY_MS);
+                continue;
+            }
+        };
+
+        for &c in &rx[..n] {
+            match c {
+                // Terminals end lines with CR or CRLF; don't treat the LF of
+                // a CRLF as an empty line.
+                b'\n' if last == b'\r' => {}
+                b'\r' | b'\n' => {
+                    let _ = console.write_str("\n");
+
+                    // We only accept printable ASCII, so this is valid UTF-8.
+                    let cmd = core::str::from_utf8(&line[..len]).unwrap_or("");
+                    let _ = console.run(cmd.trim());
+                    let _ = console.write_str("> ");
+                    len = 0;
+                }
+                // Backspace or delete
+                0x08 | 0x7f => {
+                    if len > 0 {
+                        len -= 1;
+                        let _ = console.write_bytes(b"\x08 \x08");
+                    }
+                }
+                0x20..=0x7e => {
+                    if len < LINE_LEN {
+                        line[len] = c;
+                        len += 1;
+                        let _ = console.write_bytes(&[c]);
+                    }
+                }
+                _ => {}
+            }
+
+            last = c;
+        }
+    }
+}
+
+impl Console {
+    fn write_bytes(&self, bytes: &[u8]) -> fmt::Result {
+        self.usart.write(bytes).map_err(|_| fmt::Error)
+    }
+
+    /// Parses and executes a command line.
+    fn run(&mut self, line: &str) -> fmt::Result {
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        match parse(line) {
+            Ok(cmd) => {
+                ringbuf_entry!(Trace::Command(cmd));
+                self.execute(cmd)
+            }
+            Err(msg) => {
+                ringbuf_entry!(Trace::Invalid);
+                writeln!(self, "{}", msg)
+            }
+        }
+    }
+
+    fn execute(&mut self, cmd: Command) -> fmt::Result {
+        match cmd {
+            Command::Help => {
+                for (usage, what) in HELP {
+                    writeln!(self, "{:32}{}", usage, what)?;
+                }
+            }
+            Command::Tasks => self.tasks()?,
+            Command::Restart(task) => {
+                kipc::restart_task(task, true);
+                writeln!(self, "restarted task #{}", task)?;
+            }
+            Command::Hold(task) => match task_jefe_api::hold(task) {
+                Ok(()) => writeln!(self, "holding task #{} on fault", task)?,
+                Err(e) => writeln!(self, "failed: {:?}", e)?,
+            },
+            Command::Release(task) => match task_jefe_api::release(task) {
+                Ok(()) => writeln!(self, "released task #{}", task)?,
+                Err(e) => writeln!(self, "failed: {:?}", e)?,
+            },
+            Command::Faults => self.faults()?,
+            Command::Ringbuf => self.ringbuf()?,
+            Command::Devices => {
+                for (i, d) in DEVICES.iter().enumerate() {
+                    writeln!(
+                        self,
+                        "{:3} {:12} {:8} {}",
+                        i,
+                        d.device,
+                        d.refdes.unwrap_or("-"),
+                        d.description
+                    )?;
+                }
+            }
+            Command::I2c { device, reg, len } => {
+                let mut buf = [0; I2C_READ_LEN];
+
+                match self.devices[device].read_reg_into(reg, &mut buf[..len]) {
+                    Ok(n) => {
+                        for byte in &buf[..n] {
+                            write!(self, "{:02x} ", byte)?;
+                        }
+                        writeln!(self)?;
+                    }
+                    Err(code) => {
+                        ringbuf_entry!(Trace::I2cError(code));
+                        writeln!(self, "failed: {:?}", code)?;
+                    }
+                }
+            }
+            Command::Gpio { port, pin, action } => {
+                let pins = port.pin(pin as usize);
+
+                let rval = match action {
+                    None => self.gpio.read(pins).map(|val| val != 0),
+                    Some(GpioAction::Set) => self.gpio.set(pins).map(|_| true),
+                    Some(GpioAction::Reset) => {
+                        self.gpio.reset(pins).map(|_| false)
+                    }
+                    Some(GpioAction::Toggle) => self
+                        .gpio
+                        .toggle(port, 1 << pin)
+                        .and_then(|_| self.gpio.read(pins))
+                        .map(|val| val != 0),
+                };
+
+                match rval {
+                    Ok(high) => {
+                        writeln!(self, "{:?}{} = {}", port, pin, high as u8)?
+                    }
+                    Err(e) => {
+                        ringbuf_entry!(Trace::GpioError);
+                        writeln!(self, "failed: {:?}", e)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn tasks(&mut self) -> fmt::Result {
+        writeln!(self, " ID NAME             PRI STATE")?;
+
+        for i in 0..kipc::read_task_count() {
+            let info = kipc::read_task_info(i);
+
+            write!(
+                self,
+                "{:3} {:16} {:3} ",
+                i,
+                info.name().unwrap_or("?"),
+                info.priority.0
+            )?;
+
+            match info.state {
+                TaskState::Healthy(SchedState::Stopped) => {
+                    writeln!(self, "stopped")?
+                }
+                TaskState::Healthy(SchedState::Runnable) => {
+                    writeln!(self, "ready")?
+                }
+                TaskState::Healthy(SchedState::InSend(t)) => {
+                    writeln!(self, "sending to #{}", t.index())?
+                }
+                TaskState::Healthy(SchedState::InReply(t)) => {
+                    writeln!(self, "awaiting reply from #{}", t.index())?
+                }
+                TaskState::Healthy(SchedState::InRecv(None)) => {
+                    writeln!(self, "receiving")?
+                }
+                TaskState::Healthy(SchedState::InRecv(Some(t))) => {
+                    writeln!(self, "receiving from #{}", t.index())?
+                }
+                TaskState::Faulted { fault, .. } => {
+                    writeln!(self, "FAULT: {:?}", fault)?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn faults(&mut self) -> fmt::Result {
+        for n in 0..FAULT_LOG_LEN {
+            let FaultRecord {
+                boot,
+                timestamp,
+                task,
+                fault,
+                ..
+            } = match task_jefe_api::read_fault(n) {
+                Some(record) => record,
+                None if n == 0 => return writeln!(self, "no faults"),
+                None => break,
+            };
+
+            writeln!(
+                self,
+                "boot {} at {}: task #{}: {:?}",
+                boot, timestamp, task, fault
+            )?;
+
+            let mut msg = [0; PANIC_MSG_LEN];
+            let len =
+                task_jefe_api::read_fault_message(n, &mut msg).unwrap_or(0);
+
+            if len > 0 {
+                // The message may have been truncated in the middle of a
+                // character; print as much as we can.
+                let msg = match core::str::from_utf8(&msg[..len]) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        core::str::from_utf8(&msg[..e.valid_up_to()]).unwrap()
+                    }
+                };
+                writeln!(self, "    {}", msg)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ringbuf(&mut self) -> fmt::Result {
+        // Copy the ring buffer out first, so that we're not holding it while
+        // we write (and can't possibly be adding to it).
+        let (last, buffer) = {
+            let ringbuf = __RINGBUF.borrow_mut();
+            (ringbuf.last, ringbuf.buffer)
+        };
+
+        let last = match last {
+            Some(last) => last,
+            None => return Ok(()),
+        };
+
+        // Oldest first
+        for i in 1..=buffer.len() {
+            let entry = &buffer[(last + i) % buffer.len()];
+
+            if entry.generation != 0 {
+                writeln!(self, "{:5} {:?}", entry.count, entry.payload)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse(line: &str) -> Result<Command, &'static str> {
+    let mut args = line.split_whitespace();
+    let cmd = args.next().unwrap_or("");
+
+    let command = match cmd {
+        "help" => Command::Help,
+        "tasks" => Command::Tasks,
+        "restart" | "hold" | "release" => {
+            let task = args
+                .next()
+                .and_then(find_task)
+                .ok_or("no such task; see `tasks`")?;
+
+            // The supervisor would refuse to hold or release itself anyway,
+            // and restarting it is never a good idea.
+            if task == 0 {
+                return Err("can't do that to the supervisor");
+            }
+
+            match cmd {
+                "restart" => Command::Restart(task),
+                "hold" => Command::Hold(task),
+                _ => Command::Release(task),
+            }
+        }
+        "faults" => Command::Faults,
+        "ringbuf" => Command::Ringbuf,
+        "devices" => Command::Devices,
+        "i2c" => {
+            const USAGE: &str = "usage: i2c <device> <reg> [<len>]";
+
+            let device = args.next().and_then(parse_num).ok_or(USAGE)?;
+            let reg = args.next().and_then(parse_num).ok_or(USAGE)?;
+            let len = match args.next() {
+                Some(arg) => parse_num(arg).ok_or(USAGE)?,
+                None => 1,
+            };
+
+            if device as usize >= NUM_DEVICES {
+                return Err("no such device; see `devices`");
+            }
+
+            if reg > 0xff {
+                return Err("register must be a byte");
+            }
+
+            if len == 0 || len as usize > I2C_READ_LEN {
+                return Err("length must be from 1 to 16");
+            }
+
+            Command::I2c {
+                device: device as usize,
+                reg: reg as u8,
+                len: len as usize,
+            }
+        }
+        "gpio" => {
+            const USAGE: &str = "usage: gpio <pin> [set|reset|toggle]";
+
+            let (port, pin) = args.next().and_then(parse_pin).ok_or(USAGE)?;
+            let action = match args.next() {
+                None => None,
+                Some("set") => Some(GpioAction::Set),
+                Some("reset") => Some(GpioAction::Reset),
+                Some("toggle") => Some(GpioAction::Toggle),
+                Some(_) => return Err(USAGE),
+            };
+
+            Command::Gpio { port, pin, action }
+        }
+        _ => return Err("unknown command; see `help`"),
+    };
+
+    if args.next().is_some() {
+        return Err("too many arguments");
+    }
+
+    Ok(command)
+}
+
+/// Parses a number, in decimal or (with a `0x` prefix) hex.
+fn parse_num(arg: &str) -> Option<u32> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+/// Parses a pin given as its port letter and number, e.g. `b14`.
+fn parse_pin(arg: &str) -> Option<(Port, u8)> {
+    let letter = arg.bytes().next()?.to_ascii_lowercase();
+    let port = Port::from_u8(letter.checked_sub(b'a')?)?;
+    let pin = arg.get(1..)?.parse().ok().filter(|&pin| pin < 16)?;
+
+    Some((port, pin))
+}
+
+/// Finds a task by index or name.
+fn find_task(arg: &str) -> Option<usize> {
+    let count = kipc::read_task_count();
+
+    match parse_num(arg) {
+        Some(task) => Some(task as usize).filter(|&task| task < count),
+        None => {
+            (0..count).find(|&i| kipc::read_task_info(i).name() == Some(arg))
+        }
+    }
+}
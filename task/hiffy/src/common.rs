@@ -322,3 +322,106 @@ pub(crate) fn qspi_sector_erase(
     func_err(server.sector_erase(addr))?;
     Ok(0)
 }
+
+#[cfg(feature = "sensor")]
+userlib::task_slot!(SENSOR, sensor);
+
+///
+/// Function to get the latest reading from a sensor, which takes a single
+/// parameter: the sensor ID.  The reading is returned as a
+/// `task_sensor_api::Reading`: its timestamp, value, and the number of
+/// failed reads since.
+///
+#[cfg(feature = "sensor")]
+pub(crate) fn sensor_get(
+    stack: &[Option<u32>],
+    _data: &[u8],
+    rval: &mut [u8],
+) -> Result<usize, Failure> {
+    use task_sensor_api as sensor;
+    use zerocopy::AsBytes;
+
+    if stack.len() < 1 {
+        return Err(Failure::Fault(Fault::MissingParameters));
+    }
+
+    let fp = stack.len() - 1;
+    let id = stack[fp].ok_or(Failure::Fault(Fault::EmptyParameter(0)))?;
+
+    let server = sensor::Sensor::from(SENSOR.get_task_id());
+    let reading = func_err(server.get(sensor::SensorId(id)))?;
+    let reading = reading.as_bytes();
+
+    if rval.len() < reading.len() {
+        return Err(Failure::Fault(Fault::ReturnValueOverflow));
+    }
+
+    rval[..reading.len()].copy_from_slice(reading);
+    Ok(reading.len())
+}
+
+#[cfg(feature = "inventory")]
+userlib::task_slot!(INVENTORY, inventory);
+
+#[cfg(feature = "inventory")]
+fn inventory_call(
+    stack: &[Option<u32>],
+    rval: &mut [u8],
+    call: fn(
+        &task_inventory_api::Inventory,
+        u32,
+    ) -> Result<
+        task_inventory_api::DeviceStatus,
+        task_inventory_api::InventoryError,
+    >,
+) -> Result<usize, Failure> {
+    use task_inventory_api as inventory;
+    use zerocopy::AsBytes;
+
+    if stack.len() < 1 {
+        return Err(Failure::Fault(Fault::MissingParameters));
+    }
+
+    let fp = stack.len() - 1;
+    let index = stack[fp].ok_or(Failure::Fault(Fault::EmptyParameter(0)))?;
+
+    let server = inventory::Inventory::from(INVENTORY.get_task_id());
+    let status = func_err(call(&server, index))?;
+    let status = status.as_bytes();
+
+    if rval.len() < status.len() {
+        return Err(Failure::Fault(Fault::ReturnValueOverflow));
+    }
+
+    rval[..status.len()].copy_from_slice(status);
+    Ok(status.len())
+}
+
+///
+/// Function to get the status of an I2C device from the last time it was
+/// probed, which takes a single parameter: the index of the device in the
+/// app's I2C configuration.  The status is returned as a
+/// `task_inventory_api::DeviceStatus`: when the device was probed, whether
+/// it was present, and (if there was an error) its response code.
+///
+#[cfg(feature = "inventory")]
+pub(crate) fn inventory_status(
+    stack: &[Option<u32>],
+    _data: &[u8],
+    rval: &mut [u8],
+) -> Result<usize, Failure> {
+    inventory_call(stack, rval, |server, index| server.status(index))
+}
+
+///
+/// Function to probe an I2C device now, which takes the same parameter and
+/// returns the same status as `InventoryStatus`.
+///
+#[cfg(feature = "inventory")]
+pub(crate) fn inventory_probe(
+    stack: &[Option<u32>],
+    _data: &[u8],
+    rval: &mut [u8],
+) -> Result<usize, Failure> {
+    inventory_call(stack, rval, |server, index| server.probe(index))
+}
@@ -19,6 +19,11 @@ use drv_i2c_api::{
 #[cfg(feature = "i2c")]
 task_slot!(I2C, i2c_driver);
 
+/// How long, in ticks, to wait for the I2C server, so that a wedged bus
+/// fails the HIF function rather than hanging HIF execution.
+#[cfg(feature = "i2c")]
+const I2C_TIMEOUT: u32 = 1000;
+
 #[cfg(feature = "gpio")]
 task_slot!(GPIO, gpio_driver);
 
@@ -66,6 +71,8 @@ pub enum Functions {
         (Controller, PortIndex, Mux, Segment, u8, u8, usize, usize),
         ResponseCode,
     ),
+    #[cfg(feature = "i2c")]
+    I2cScan((Controller, PortIndex, Mux, Segment), ResponseCode),
     #[cfg(feature = "gpio")]
     GpioInput(drv_stm32h7_gpio_api::Port, drv_stm32h7_gpio_api::GpioError),
     #[cfg(feature = "gpio")]
@@ -114,21 +121,18 @@ pub enum Functions {
     QspiSectorErase(u32, drv_gimlet_hf_api::HfError),
     #[cfg(feature = "qspi")]
     QspiVerify((u32, usize, usize), drv_gimlet_hf_api::HfError),
+    #[cfg(feature = "sensor")]
+    SensorGet(u32, task_sensor_api::SensorError),
+    #[cfg(feature = "inventory")]
+    InventoryStatus(u32, task_inventory_api::InventoryError),
+    #[cfg(feature = "inventory")]
+    InventoryProbe(u32, task_inventory_api::InventoryError),
 }
 
 #[cfg(feature = "i2c")]
-fn i2c_args(
+fn i2c_bus_args(
     stack: &[Option<u32>],
-) -> Result<
-    (
-        Controller,
-        PortIndex,
-        Option<(Mux, Segment)>,
-        u8,
-        Option<u8>,
-    ),
-    Failure,
-> {
+) -> Result<(Controller, PortIndex, Option<(Mux, Segment)>), Failure> {
     let controller = match stack[0] {
         Some(controller) => match Controller::from_u32(controller) {
             Some(controller) => controller,
@@ -174,6 +178,24 @@ fn i2c_args(
         _ => None,
     };
 
+    Ok((controller, port, mux))
+}
+
+#[cfg(feature = "i2c")]
+fn i2c_args(
+    stack: &[Option<u32>],
+) -> Result<
+    (
+        Controller,
+        PortIndex,
+        Option<(Mux, Segment)>,
+        u8,
+        Option<u8>,
+    ),
+    Failure,
+> {
+    let (controller, port, mux) = i2c_bus_args(stack)?;
+
     let addr = match stack[4] {
         Some(addr) => addr as u8,
         None => return Err(Failure::Fault(Fault::EmptyParameter(4))),
@@ -201,7 +223,8 @@ fn i2c_read(
     let (controller, port, mux, addr, register) = i2c_args(&stack[fp..])?;
 
     let task = I2C.get_task_id();
-    let device = I2cDevice::new(task, controller, port, mux, addr);
+    let device = I2cDevice::new(task, controller, port, mux, addr)
+        .with_timeout(I2C_TIMEOUT);
 
     match stack[fp + 6] {
         Some(nbytes) => {
@@ -271,7 +294,8 @@ fn i2c_write(
     let (controller, port, mux, addr, register) = i2c_args(&stack[fp..])?;
 
     let task = I2C.get_task_id();
-    let device = I2cDevice::new(task, controller, port, mux, addr);
+    let device = I2cDevice::new(task, controller, port, mux, addr)
+        .with_timeout(I2C_TIMEOUT);
 
     let mut offs = 0;
 
@@ -332,7 +356,8 @@ fn i2c_bulk_write(
     }
 
     let task = I2C.get_task_id();
-    let device = I2cDevice::new(task, controller, port, mux, addr);
+    let device = I2cDevice::new(task, controller, port, mux, addr)
+        .with_timeout(I2C_TIMEOUT);
 
     match device.write(&data[offset..offset + len]) {
         Ok(_) => Ok(0),
@@ -340,6 +365,40 @@ fn i2c_bulk_write(
     }
 }
 
+#[cfg(feature = "i2c")]
+fn i2c_scan(
+    stack: &[Option<u32>],
+    _data: &[u8],
+    rval: &mut [u8],
+) -> Result<usize, Failure> {
+    //
+    // We need 4 parameters: the normal i2c parameters, without the address
+    // and register.  We return a bitmap of the addresses that responded.
+    //
+    if stack.len() < 4 {
+        return Err(Failure::Fault(Fault::MissingParameters));
+    }
+
+    let fp = stack.len() - 4;
+    let (controller, port, mux) = i2c_bus_args(&stack[fp..])?;
+
+    let len = core::mem::size_of::<u128>();
+
+    if rval.len() < len {
+        return Err(Failure::Fault(Fault::ReturnValueOverflow));
+    }
+
+    let task = I2C.get_task_id();
+
+    match drv_i2c_api::scan(task, controller, port, mux, Some(I2C_TIMEOUT)) {
+        Ok(present) => {
+            rval[..len].copy_from_slice(&present.to_le_bytes());
+            Ok(len)
+        }
+        Err(err) => Err(Failure::FunctionError(err.into())),
+    }
+}
+
 #[cfg(feature = "gpio")]
 fn gpio_args(
     stack: &[Option<u32>],
@@ -535,6 +594,8 @@ pub(crate) static HIFFY_FUNCS: &[Function] = &[
     i2c_write,
     #[cfg(feature = "i2c")]
     i2c_bulk_write,
+    #[cfg(feature = "i2c")]
+    i2c_scan,
     #[cfg(feature = "gpio")]
     gpio_input,
     #[cfg(feature = "gpio")]
@@ -563,6 +624,12 @@ pub(crate) static HIFFY_FUNCS: &[Function] = &[
     crate::common::qspi_sector_erase,
     #[cfg(feature = "qspi")]
     crate::common::qspi_verify,
+    #[cfg(feature = "sensor")]
+    crate::common::sensor_get,
+    #[cfg(feature = "inventory")]
+    crate::common::inventory_status,
+    #[cfg(feature = "inventory")]
+    crate::common::inventory_probe,
 ];
 
 //
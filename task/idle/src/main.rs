@@ -13,7 +13,9 @@ extern crate userlib;
 fn main() -> ! {
     loop {
         // Wait For Interrupt to pause the processor until an ISR arrives,
-        // which could wake some higher-priority task.
+        // which could wake some higher-priority task. With a tickless kernel,
+        // the timer won't interrupt until some task's deadline is due, so we
+        // may sleep here for a good while.
         cortex_m::asm::wfi();
     }
 }
@@ -0,0 +1,55 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Client API for the inventory server, `task/inventory`.
+//!
+//! The inventory server probes each device in `[config.i2c.devices]` to see
+//! whether it's actually there. Devices are identified by their index in that
+//! list; the I2C build support makes the list itself -- with each device's
+//! part, description, reference designator and whether it's removable --
+//! available as `DEVICES` in the generated `i2c_config::inventory` module.
+//! The protocol is defined in `idl/inventory.toml`, from which most of this
+//! crate's contents are generated.
+
+#![no_std]
+
+use userlib::FromPrimitive;
+use zerocopy::{AsBytes, FromBytes};
+
+/// Whether a device was found when probed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, FromPrimitive)]
+#[repr(u32)]
+pub enum Presence {
+    /// The device hasn't been probed yet.
+    Unknown = 0,
+    /// The device responded.
+    Present = 1,
+    /// The device didn't acknowledge its address.
+    Absent = 2,
+    /// The device couldn't be probed, because of some other error (e.g., with
+    /// the bus or a mux).
+    Error = 3,
+}
+
+/// The result of the last probe of a device, as returned by
+/// `Inventory::status` and `Inventory::probe`.
+#[derive(Copy, Clone, Debug, PartialEq, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct DeviceStatus {
+    /// When the device was last probed, in ticks since boot.
+    pub timestamp: u64,
+    /// A `Presence`, as returned by `presence`.
+    pub presence: u32,
+    /// For `Presence::Error`, the `ResponseCode` from the I2C driver;
+    /// otherwise 0.
+    pub code: u32,
+}
+
+impl DeviceStatus {
+    pub fn presence(&self) -> Presence {
+        Presence::from_u32(self.presence).unwrap_or(Presence::Unknown)
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/client_stub.rs"));
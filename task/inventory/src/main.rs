@@ -0,0 +1,146 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Inventory server
+//!
+//! We probe each device in `[config.i2c.devices]` -- through whatever mux and
+//! segment it's behind -- at boot and then periodically, to see if it's
+//! actually there. A device is present if it acknowledges a single-byte read,
+//! and absent if it doesn't acknowledge its address; anything else (e.g., a
+//! missing mux, or a locked bus) is an error. Changes are recorded in our ring
+//! buffer, and the latest results are available to anyone who asks.
+//!
+//! Our IPC protocol is defined in `idl/inventory.toml`.
+
+#![no_std]
+#![no_main]
+
+use drv_i2c_api::{I2cDevice, ResponseCode};
+use ringbuf::*;
+use task_inventory_api::{DeviceStatus, InventoryError, Presence};
+use userlib::*;
+
+task_slot!(I2C, i2c_driver);
+include!(concat!(env!("OUT_DIR"), "/i2c_config.rs"));
+
+mod idl {
+    use task_inventory_api::{DeviceStatus, InventoryError};
+
+    include!(concat!(env!("OUT_DIR"), "/server_stub.rs"));
+}
+
+use i2c_config::inventory::NUM_DEVICES;
+
+/// How often we probe every device, in milliseconds.
+const PROBE_INTERVAL: u64 = 5000;
+
+/// Our notification bit for the probe timer.
+const TIMER_MASK: u32 = 1 << 0;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Trace {
+    /// A device's presence has changed, as (index, presence, code).
+    Changed(usize, Presence, u32),
+    None,
+}
+
+ringbuf!(Trace, 32, Trace::None);
+
+#[export_name = "main"]
+fn main() -> ! {
+    let mut buffer = [0; idl::INCOMING_SIZE];
+    let mut server = ServerImpl {
+        devices: i2c_config::inventory::i2c_devices(I2C.get_task_id()),
+        status: [DeviceStatus {
+            timestamp: 0,
+            presence: Presence::Unknown as u32,
+            code: 0,
+        }; NUM_DEVICES],
+        deadline: sys_get_timer().now,
+    };
+
+    server.probe_all();
+
+    loop {
+        idl::dispatch(&mut buffer, &mut server);
+    }
+}
+
+struct ServerImpl {
+    devices: [I2cDevice; NUM_DEVICES],
+    status: [DeviceStatus; NUM_DEVICES],
+    deadline: u64,
+}
+
+impl ServerImpl {
+    fn probe_device(&mut self, index: usize) -> DeviceStatus {
+        let (presence, code) = match self.devices[index].read::<u8>() {
+            Ok(_) => (Presence::Present, 0),
+            Err(ResponseCode::NoDevice) => (Presence::Absent, 0),
+            Err(code) => (Presence::Error, u32::from(code)),
+        };
+
+        let status = &mut self.status[index];
+
+        if status.presence() != presence || status.code != code {
+            ringbuf_entry!(Trace::Changed(index, presence, code));
+        }
+
+        *status = DeviceStatus {
+            timestamp: sys_get_timer().now,
+            presence: presence as u32,
+            code,
+        };
+
+        *status
+    }
+
+    /// Probes every device, and sets the timer for the next time.
+    fn probe_all(&mut self) {
+        for index in 0..NUM_DEVICES {
+            self.probe_device(index);
+        }
+
+        self.deadline += PROBE_INTERVAL;
+        sys_set_timer(Some(self.deadline), TIMER_MASK);
+    }
+
+    fn lookup(&self, index: u32) -> Result<usize, InventoryError> {
+        let index = index as usize;
+
+        if index < NUM_DEVICES {
+            Ok(index)
+        } else {
+            Err(InventoryError::InvalidDevice)
+        }
+    }
+}
+
+impl idl::InventoryServer for ServerImpl {
+    fn status(
+        &mut self,
+        _: TaskId,
+        index: u32,
+    ) -> Result<DeviceStatus, InventoryError> {
+        let index = self.lookup(index)?;
+        Ok(self.status[index])
+    }
+
+    fn probe(
+        &mut self,
+        _: TaskId,
+        index: u32,
+    ) -> Result<DeviceStatus, InventoryError> {
+        let index = self.lookup(index)?;
+        Ok(self.probe_device(index))
+    }
+
+    fn notification_mask(&self) -> u32 {
+        TIMER_MASK
+    }
+
+    fn handle_notification(&mut self, _bits: u32) {
+        self.probe_all();
+    }
+}
@@ -0,0 +1,173 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Client API for the supervisor, `jefe`.
+//!
+//! The supervisor is always task 0, and is never restarted, so unlike other
+//! servers there's no need to look it up through a task slot.
+
+#![no_std]
+
+use serde::{Deserialize, Serialize};
+use userlib::*;
+use zerocopy::AsBytes;
+
+/// Operations that the supervisor accepts from other tasks.
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq)]
+pub enum Op {
+    /// Reports that the caller is alive and making progress (`() -> ()`).
+    CheckIn = 1,
+    /// Reads an entry from the fault log, newest first (`u32 -> FaultRecord`,
+    /// in `ssmarshal` form).
+    ReadFault = 2,
+    /// Reads the panic message for an entry in the fault log, newest first
+    /// (`u32 -> [u8]`).
+    ReadFaultMessage = 3,
+    /// Holds a task the next time it faults, rather than restarting it
+    /// (`u32 -> ()`). Only tasks named in the supervisor's `holders` config
+    /// may send this.
+    Hold = 4,
+    /// Releases a held task, putting back the disposition it had before it
+    /// was held (`u32 -> ()`). Only tasks named in the supervisor's `holders`
+    /// config may send this.
+    Release = 5,
+}
+
+/// Operation of the notice that the supervisor sends to the tasks named in its
+/// `shutdown` config shortly before it resets the system, so that they can
+/// leave things in a safe state. It has no message, and any reply is ignored.
+///
+/// The notice is sent with `sys_send_async`, so a task only gets it if it's
+/// waiting in an open `RECV` at the time. Check the sender: this is only
+/// meaningful coming from [`jefe`].
+pub const SHUTDOWN_NOTICE: u16 = 0xffff;
+
+/// Errors returned by the supervisor.
+#[derive(Copy, Clone, Debug, FromPrimitive, PartialEq)]
+pub enum JefeError {
+    /// The message was malformed.
+    BadMessage = 1,
+    /// There's no fault log entry with the requested index.
+    NoSuchRecord = 2,
+    /// There's no task with the requested index, or it's the supervisor.
+    BadTask = 3,
+    /// The fault log entry couldn't be serialized.
+    BadRecord = 4,
+    /// The caller isn't allowed to make this request.
+    NotAllowed = 5,
+}
+
+/// Number of entries the supervisor's fault log holds. Once it's full, new
+/// faults replace the oldest ones.
+pub const FAULT_LOG_LEN: usize = 4;
+
+/// Longest panic message that the fault log will keep. This is as much as the
+/// kernel keeps; longer messages are truncated.
+pub const PANIC_MSG_LEN: usize = PANIC_MESSAGE_LEN;
+
+/// A fault, as recorded in the supervisor's fault log.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub struct FaultRecord {
+    /// Number of the boot during which the fault happened, counting from when
+    /// the log was initialized (normally at power-on).
+    pub boot: u32,
+    /// Time of the fault, in ticks since that boot.
+    pub timestamp: u64,
+    /// Index of the task that faulted.
+    pub task: u16,
+    /// Generation of the task that faulted.
+    pub generation: Generation,
+    /// The fault itself.
+    pub fault: FaultInfo,
+}
+
+/// Returns the `TaskId` of the supervisor.
+pub fn jefe() -> TaskId {
+    TaskId::for_index_and_gen(0, Generation::default())
+}
+
+/// Checks in with the supervisor.
+///
+/// Tasks that are marked critical in the supervisor's watchdog config must
+/// call this at least once per check-in interval, or the supervisor will stop
+/// petting the hardware watchdog and the system will reset. Whether a task is
+/// critical is up to each app, and it's harmless for other tasks to call this,
+/// so a task that might be critical should call it each time around its main
+/// loop.
+pub fn check_in() {
+    let (rc, _) = sys_send(jefe(), Op::CheckIn as u16, &[], &mut [], &[]);
+    assert_eq!(rc, 0);
+}
+
+/// Reads entry `n` of the supervisor's fault log, where entry 0 is the most
+/// recent fault. Returns `None` once `n` runs off the end of the log.
+///
+/// The log survives resets (but not power loss), so this includes faults from
+/// earlier boots; see `FaultRecord::boot`.
+pub fn read_fault(n: usize) -> Option<FaultRecord> {
+    let n = n as u32;
+    let mut response = [0; core::mem::size_of::<FaultRecord>()];
+    let (rc, len) = sys_send(
+        jefe(),
+        Op::ReadFault as u16,
+        n.as_bytes(),
+        &mut response,
+        &[],
+    );
+    if rc == JefeError::NoSuchRecord as u32 {
+        return None;
+    }
+    assert_eq!(rc, 0);
+    let (record, _) = ssmarshal::deserialize(&response[..len])
+        .map_err(|_| ())
+        .unwrap();
+    Some(record)
+}
+
+/// Reads the panic message for entry `n` of the supervisor's fault log into
+/// `buf`, returning its length. The message is empty if the task didn't panic,
+/// or if its message wasn't available. Returns `None` once `n` runs off the end
+/// of the log.
+pub fn read_fault_message(n: usize, buf: &mut [u8]) -> Option<usize> {
+    let n = n as u32;
+    let (rc, len) =
+        sys_send(jefe(), Op::ReadFaultMessage as u16, n.as_bytes(), buf, &[]);
+    if rc == JefeError::NoSuchRecord as u32 {
+        return None;
+    }
+    assert_eq!(rc, 0);
+    Some(len)
+}
+
+/// Asks the supervisor to hold `task` the next time it faults, leaving it
+/// faulted rather than restarting it. If it's already faulted, it stays that
+/// way. This is the same as a hold requested through the supervisor's
+/// external interface, and likewise exempts `task` from the watchdog.
+///
+/// Fails with `NotAllowed` unless the caller is named in the supervisor's
+/// `holders` config.
+pub fn hold(task: usize) -> Result<(), JefeError> {
+    set_disposition(Op::Hold, task)
+}
+
+/// Releases a `task` held with [`hold`], putting back the disposition it had
+/// before: normally, that means restarting it if it's faulted, but a task
+/// that had already run out of restarts stays faulted. A task held through
+/// the supervisor's external interface is restarted.
+///
+/// Fails with `NotAllowed` unless the caller is named in the supervisor's
+/// `holders` config.
+pub fn release(task: usize) -> Result<(), JefeError> {
+    set_disposition(Op::Release, task)
+}
+
+fn set_disposition(op: Op, task: usize) -> Result<(), JefeError> {
+    let task = task as u32;
+    let (rc, _) = sys_send(jefe(), op as u16, task.as_bytes(), &mut [], &[]);
+    match rc {
+        0 => Ok(()),
+        _ => Err(JefeError::from_u32(rc).unwrap_or(JefeError::BadMessage)),
+    }
+}
@@ -0,0 +1,238 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Jefe's task configuration, from `[tasks.jefe.config]` in `app.toml`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Config {
+    watchdog: Option<WatchdogConfig>,
+    /// Restart policies, keyed by task name. Tasks without one are restarted
+    /// immediately every time they fault.
+    #[serde(default)]
+    restart: BTreeMap<String, RestartConfig>,
+    /// Names of tasks allowed to hold and release other tasks through our
+    /// IPC interface, such as a console. Nobody else may.
+    #[serde(default)]
+    holders: Vec<String>,
+    /// Names of tasks to send a shutdown notice before we reset the system.
+    #[serde(default)]
+    shutdown: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct WatchdogConfig {
+    /// Hardware watchdog timeout, in milliseconds.
+    timeout_ms: u32,
+    /// Interval within which every critical task must check in, in
+    /// milliseconds.
+    checkin_ms: u32,
+    /// Names of critical tasks.
+    critical: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct RestartConfig {
+    /// Delay before restarting a task after its first fault in a window, in
+    /// milliseconds. Each further fault in the window doubles it.
+    #[serde(default)]
+    backoff_ms: u32,
+    /// Upper bound on the backoff delay, in milliseconds. Defaults to
+    /// `window-ms`.
+    max_backoff_ms: Option<u32>,
+    /// Length of the window over which faults are counted, in milliseconds.
+    /// The window starts at the first fault, and a fault after it has elapsed
+    /// starts a new one.
+    window_ms: u32,
+    /// Number of restarts allowed within a window. Once it's used up, the task
+    /// is held until released through the external interface.
+    max_restarts: Option<u32>,
+    /// Number of faults within a window after which we give up on the task
+    /// and reset the system.
+    reset_after: Option<u32>,
+}
+
+/// Jefe's timer interval in milliseconds, which bounds how often we can pet.
+/// This must agree with `TIMER_INTERVAL` in `main.rs`.
+const TIMER_INTERVAL_MS: u32 = 100;
+
+fn main() -> Result<()> {
+    let config: Config = build_util::task_config()?.unwrap_or_default();
+
+    //
+    // As with `hubris-num-tasks`, HUBRIS_TASKS may be missing -- as it is for
+    // `cargo xtask check` -- in which case there's a single anonymous task.
+    // That's fine unless there are policies naming tasks to apply to.
+    //
+    println!("cargo:rerun-if-env-changed=HUBRIS_TASKS");
+    let task_names = match env::var("HUBRIS_TASKS") {
+        Ok(task_names) => task_names,
+        Err(env::VarError::NotPresent)
+            if config.watchdog.is_none()
+                && config.restart.is_empty()
+                && config.holders.is_empty()
+                && config.shutdown.is_empty() =>
+        {
+            "anonymous".to_string()
+        }
+        Err(e) => bail!("HUBRIS_TASKS is needed to apply policies: {}", e),
+    };
+    let task_names = task_names.split(',').collect::<Vec<_>>();
+
+    let out = PathBuf::from(env::var("OUT_DIR")?);
+    gen_watchdog_config(&out, config.watchdog, &task_names)?;
+    gen_restart_config(&out, &config.restart, &task_names)?;
+    gen_task_list(
+        &out,
+        "hold_config.rs",
+        "HOLDERS",
+        &config.holders,
+        &task_names,
+    )?;
+    gen_task_list(
+        &out,
+        "shutdown_config.rs",
+        "SHUTDOWN",
+        &config.shutdown,
+        &task_names,
+    )?;
+
+    Ok(())
+}
+
+/// Finds the index of a task named in our config. The supervisor can't be
+/// named, since none of our policies make sense when applied to ourselves.
+fn task_index(task_names: &[&str], name: &str) -> Result<usize> {
+    let index = task_names
+        .iter()
+        .position(|t| *t == name)
+        .ok_or_else(|| anyhow!("unknown task {}", name))?;
+    if index == 0 {
+        bail!("the supervisor can't be subject to its own policies");
+    }
+    Ok(index)
+}
+
+fn gen_watchdog_config(
+    out: &Path,
+    watchdog: Option<WatchdogConfig>,
+    task_names: &[&str],
+) -> Result<()> {
+    let mut file = File::create(out.join("watchdog_config.rs"))?;
+
+    let wd = match watchdog {
+        Some(wd) => wd,
+        None => {
+            writeln!(file, "pub const CONFIG: Option<Config> = None;")?;
+            return Ok(());
+        }
+    };
+
+    let drivers = ["watchdog-stm32h7", "watchdog-lpc55"]
+        .iter()
+        .filter(|f| {
+            let var = format!("CARGO_FEATURE_{}", f.to_uppercase());
+            env::var_os(var.replace('-', "_")).is_some()
+        })
+        .count();
+    if drivers != 1 {
+        bail!("watchdog configured: enable exactly one watchdog-* feature");
+    }
+
+    if wd.timeout_ms < 2 * TIMER_INTERVAL_MS {
+        bail!(
+            "watchdog timeout of {} ms is too short; must be at least {} ms",
+            wd.timeout_ms,
+            2 * TIMER_INTERVAL_MS
+        );
+    }
+
+    let critical = wd
+        .critical
+        .iter()
+        .map(|name| task_index(task_names, name))
+        .collect::<Result<Vec<_>>>()?;
+
+    writeln!(file, "pub const CONFIG: Option<Config> = Some(Config {{")?;
+    writeln!(file, "    timeout_ms: {},", wd.timeout_ms)?;
+    writeln!(file, "    checkin_ms: {},", wd.checkin_ms)?;
+    writeln!(file, "    critical: &{:?},", critical)?;
+    writeln!(file, "}});")?;
+
+    Ok(())
+}
+
+fn gen_restart_config(
+    out: &Path,
+    restart: &BTreeMap<String, RestartConfig>,
+    task_names: &[&str],
+) -> Result<()> {
+    let mut policies = vec![None; task_names.len()];
+    for (name, policy) in restart {
+        if policy.window_ms == 0 {
+            bail!("restart policy for {}: window-ms must be nonzero", name);
+        }
+        if policy.reset_after == Some(0) {
+            bail!("restart policy for {}: reset-after must be nonzero", name);
+        }
+        policies[task_index(task_names, name)?] = Some(policy);
+    }
+
+    let mut file = File::create(out.join("restart_config.rs"))?;
+    writeln!(
+        file,
+        "pub const POLICIES: [Option<Policy>; {}] = [",
+        task_names.len()
+    )?;
+    for policy in policies {
+        match policy {
+            None => writeln!(file, "    None,")?,
+            Some(p) => {
+                writeln!(file, "    Some(Policy {{")?;
+                writeln!(file, "        backoff_ms: {},", p.backoff_ms)?;
+                writeln!(
+                    file,
+                    "        max_backoff_ms: {},",
+                    p.max_backoff_ms.unwrap_or(p.window_ms)
+                )?;
+                writeln!(file, "        window_ms: {},", p.window_ms)?;
+                writeln!(file, "        max_restarts: {:?},", p.max_restarts)?;
+                writeln!(file, "        reset_after: {:?},", p.reset_after)?;
+                writeln!(file, "    }}),")?;
+            }
+        }
+    }
+    writeln!(file, "];")?;
+
+    Ok(())
+}
+
+/// Writes the indices of the tasks in `names` to `file`, as a constant named
+/// `name`.
+fn gen_task_list(
+    out: &Path,
+    file: &str,
+    name: &str,
+    names: &[String],
+    task_names: &[&str],
+) -> Result<()> {
+    let indices = names
+        .iter()
+        .map(|name| task_index(task_names, name))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut file = File::create(out.join(file))?;
+    writeln!(file, "pub const {}: &[usize] = &{:?};", name, indices)?;
+
+    Ok(())
+}
@@ -0,0 +1,149 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Persistent fault log.
+//!
+//! Every fault we notice is recorded in `JEFE_FAULT_LOG`, a small ring of
+//! entries kept in uninitialized RAM, so that it survives a reset (including
+//! the ones we cause through the watchdog or a restart policy). The log is
+//! initialized at the first boot after power-on, and each entry notes which
+//! boot it came from.
+//!
+//! The log can be read by other tasks through `task_jefe_api::read_fault` and
+//! `read_fault_message`, or with a debugger (e.g. `humility readvar
+//! JEFE_FAULT_LOG`).
+
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use task_jefe_api::{FaultRecord, FAULT_LOG_LEN, PANIC_MSG_LEN};
+use userlib::*;
+
+/// A fault record plus the panic message, if any, that went with it.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct Entry {
+    pub record: FaultRecord,
+    /// Number of valid bytes in `panic_msg`.
+    pub panic_len: u32,
+    pub panic_msg: [u8; PANIC_MSG_LEN],
+}
+
+impl Entry {
+    pub fn panic_message(&self) -> &[u8] {
+        &self.panic_msg[..self.panic_len as usize]
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct Log {
+    /// Set to `LOG_MAGIC` once the log has been initialized.
+    magic: u32,
+    /// Number of boots since the log was initialized, counting this one.
+    pub boots: u32,
+    /// Number of faults ever recorded. The most recent is in
+    /// `entries[(total - 1) % FAULT_LOG_LEN]`.
+    pub total: u32,
+    pub entries: [MaybeUninit<Entry>; FAULT_LOG_LEN],
+}
+
+const LOG_MAGIC: u32 = 0xfa17_1065;
+
+#[no_mangle]
+#[link_section = ".uninit"]
+static mut JEFE_FAULT_LOG: MaybeUninit<Log> = MaybeUninit::uninit();
+
+pub struct FaultLog {
+    log: &'static mut Log,
+}
+
+impl FaultLog {
+    /// Takes over the log left by the previous boot, or starts a new one.
+    ///
+    /// This must only be called once.
+    pub fn new() -> Self {
+        let log = unsafe { JEFE_FAULT_LOG.as_mut_ptr() };
+
+        // Safety: we're only called once, so nobody else is looking at the
+        // log. It may hold garbage, so we check the magic number (which is
+        // valid for any bit pattern) before forming a reference to the whole
+        // thing; entries are only ever read once they've been written.
+        let magic = unsafe { ptr::addr_of!((*log).magic).read() };
+        let log = unsafe {
+            if magic != LOG_MAGIC {
+                log.write(Log {
+                    magic: LOG_MAGIC,
+                    boots: 0,
+                    total: 0,
+                    entries: [MaybeUninit::uninit(); FAULT_LOG_LEN],
+                });
+            }
+            &mut *log
+        };
+
+        log.boots = log.boots.wrapping_add(1);
+        if log.total != 0 {
+            sys_log!("fault log: {} faults before this boot", log.total);
+        }
+
+        Self { log }
+    }
+
+    /// Records a fault in the task at `index`, along with its panic message if
+    /// it panicked. This must be done before the task is restarted, since
+    /// that discards the message.
+    pub fn record(
+        &mut self,
+        index: usize,
+        fault: &FaultInfo,
+        now: u64,
+    ) -> &Entry {
+        let id = sys_refresh_task_id(TaskId::for_index_and_gen(
+            index,
+            Generation::default(),
+        ));
+
+        let mut panic_msg = [0; PANIC_MSG_LEN];
+        let panic_len = if *fault == FaultInfo::Panic {
+            kipc::read_panic_message(index, &mut panic_msg)
+        } else {
+            0
+        };
+
+        let slot = self.log.total as usize % FAULT_LOG_LEN;
+        self.log.entries[slot] = MaybeUninit::new(Entry {
+            record: FaultRecord {
+                boot: self.log.boots,
+                timestamp: now,
+                task: index as u16,
+                generation: id.generation(),
+                fault: *fault,
+            },
+            panic_len: panic_len as u32,
+            panic_msg,
+        });
+
+        // Only count the entry once it's complete, so that a reset partway
+        // through doesn't leave a half-written entry in the log. (Once the log
+        // has wrapped, such a reset can still mangle the oldest entry; that's
+        // not worth a second copy of the log to avoid.)
+        self.log.total = self.log.total.wrapping_add(1);
+
+        // Safety: we just wrote this entry.
+        unsafe { &*self.log.entries[slot].as_ptr() }
+    }
+
+    /// Returns entry `n`, where entry 0 is the most recent.
+    pub fn get(&self, n: usize) -> Option<&Entry> {
+        let held = (self.log.total as usize).min(FAULT_LOG_LEN);
+        if n >= held {
+            return None;
+        }
+        let slot = (self.log.total as usize - 1 - n) % FAULT_LOG_LEN;
+
+        // Safety: the `held` most recent slots have all been written.
+        Some(unsafe { &*self.log.entries[slot].as_ptr() })
+    }
+}
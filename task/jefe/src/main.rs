@@ -7,30 +7,40 @@
 //! The supervisor is responsible for:
 //!
 //! - Maintaining the system console output (currently via semihosting).
-//! - Monitoring tasks for failures and restarting them.
+//! - Monitoring tasks for failures and restarting them, subject to any
+//!   restart policies in the app config (see the `restart` module).
+//! - Keeping a log of faults that persists across resets (see the
+//!   `fault_log` module).
+//! - Managing the hardware watchdog timer, if one is configured (see the
+//!   `watchdog` module).
 //!
 //! It will probably become responsible for:
 //!
 //! - Evacuating kernel log information.
 //! - Coordinating certain shared resources, such as the RCC and GPIO muxing.
-//! - Managing a watchdog timer.
 //!
 //! It's unwise for the supervisor to use `SEND`, ever, except to talk to the
 //! kernel. This is because a `SEND` to a misbehaving task could block forever,
-//! taking out the supervisor. The long-term idea is to provide some sort of
-//! asynchronous messaging from the supervisor to less-trusted tasks, but that
-//! doesn't exist yet, so we're mostly using RECV/REPLY and notifications. This
-//! means that hardware drivers required for this task must be built in instead
-//! of running in separate tasks.
+//! taking out the supervisor. To talk to less-trusted tasks, the supervisor
+//! uses `sys_send_async`, which delivers a message only if the recipient is
+//! waiting for one and never waits for a reply; we use it to warn tasks that
+//! we're about to reset the system (see `reset`). Otherwise, we're mostly
+//! using RECV/REPLY and notifications. This means that hardware drivers
+//! required for this task must be built in instead of running in separate
+//! tasks.
 
 #![no_std]
 #![no_main]
 
 mod external;
+mod fault_log;
+mod restart;
+mod watchdog;
 
+use task_jefe_api::{FaultRecord, JefeError, Op, SHUTDOWN_NOTICE};
 use userlib::*;
 
-fn log_fault(t: usize, fault: &abi::FaultInfo) {
+fn log_fault(t: usize, fault: &abi::FaultInfo, panic_msg: &[u8]) {
     match fault {
         abi::FaultInfo::MemoryAccess { address, .. } => match address {
             Some(a) => {
@@ -77,7 +87,15 @@ fn log_fault(t: usize, fault: &abi::FaultInfo) {
         }
 
         abi::FaultInfo::Panic => {
-            sys_log!("Task #{} Panic!", t);
+            // The message may have been truncated in the middle of a
+            // character; print as much as we can.
+            let msg = match core::str::from_utf8(panic_msg) {
+                Ok(s) => s,
+                Err(e) => {
+                    core::str::from_utf8(&panic_msg[..e.valid_up_to()]).unwrap()
+                }
+            };
+            sys_log!("Task #{} Panic! {}", t, msg);
         }
 
         abi::FaultInfo::Injected(who) => {
@@ -86,12 +104,112 @@ fn log_fault(t: usize, fault: &abi::FaultInfo) {
     }
 }
 
+/// Looks up the fault log entry requested by a `ReadFault` or
+/// `ReadFaultMessage` message.
+fn read_fault_entry<'a>(
+    fault_log: &'a fault_log::FaultLog,
+    msginfo: &RecvMessage,
+    buffer: [u8; 4],
+) -> Result<&'a fault_log::Entry, JefeError> {
+    if msginfo.message_len != buffer.len() {
+        return Err(JefeError::BadMessage);
+    }
+    let n = u32::from_le_bytes(buffer) as usize;
+    fault_log.get(n).ok_or(JefeError::NoSuchRecord)
+}
+
+/// Tasks allowed to send us `Hold` and `Release`, by index, generated from
+/// `app.toml` by our build script.
+mod hold_config {
+    include!(concat!(env!("OUT_DIR"), "/hold_config.rs"));
+}
+
+/// Tasks to warn before we reset the system, by index, generated from
+/// `app.toml` by our build script.
+mod shutdown_config {
+    include!(concat!(env!("OUT_DIR"), "/shutdown_config.rs"));
+}
+
+/// Resets the system, first giving the tasks in our `shutdown` config a
+/// chance to leave things in a safe state. Each is sent `SHUTDOWN_NOTICE`
+/// asynchronously, so one that isn't waiting for a message just misses out,
+/// and those that got it have a short grace period -- during which we're
+/// blocked, so that they can run -- to act on it.
+fn reset() -> ! {
+    const GRACE_PERIOD: u64 = 100;
+
+    let mut notified = false;
+    for &i in shutdown_config::SHUTDOWN {
+        let task = TaskId::for_index_and_gen(i, Generation::default());
+        let task = sys_refresh_task_id(task);
+        notified |= sys_send_async(task, SHUTDOWN_NOTICE, &[]) == 0;
+    }
+
+    if notified {
+        hl::sleep_for(GRACE_PERIOD);
+    }
+    kipc::reset();
+}
+
+/// Applies a `Hold` or `Release` request from another task. For each task
+/// held this way, `held` records the disposition it had before, which
+/// `Release` puts back.
+fn request_disposition(
+    disposition: &mut [Disposition],
+    held: &mut [Option<Disposition>],
+    msginfo: &RecvMessage,
+    buffer: [u8; 4],
+    op: Op,
+) -> Result<(), JefeError> {
+    // Holding a task exempts it from the watchdog, so this isn't for just
+    // anybody.
+    if !hold_config::HOLDERS.contains(&msginfo.sender.index()) {
+        return Err(JefeError::NotAllowed);
+    }
+
+    if msginfo.message_len != buffer.len() {
+        return Err(JefeError::BadMessage);
+    }
+    let n = u32::from_le_bytes(buffer) as usize;
+
+    // As with external requests, we won't do this to ourselves.
+    if n == 0 || n >= disposition.len() {
+        return Err(JefeError::BadTask);
+    }
+
+    if disposition[n] != Disposition::Hold {
+        // Whatever we recorded is stale; the disposition has been changed
+        // since, probably through the external interface.
+        held[n] = None;
+    }
+
+    match op {
+        Op::Hold => {
+            if disposition[n] != Disposition::Hold {
+                held[n] = Some(disposition[n]);
+                disposition[n] = Disposition::Hold;
+            }
+        }
+        _ => {
+            if disposition[n] == Disposition::Hold {
+                // A task held through the external interface goes back to
+                // the default.
+                disposition[n] = held[n].take().unwrap_or(Disposition::Restart);
+            }
+        }
+    }
+    Ok(())
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Disposition {
     Restart,
     Start,
     Hold,
     Fault,
+    /// Held because it ran out of restarts. Unlike a task that somebody has
+    /// asked us to hold, this doesn't exempt a task from the watchdog.
+    Exhausted,
 }
 
 #[export_name = "main"]
@@ -102,6 +220,8 @@ fn main() -> ! {
         [Disposition::Restart; hubris_num_tasks::NUM_TASKS];
     let mut logged: [bool; hubris_num_tasks::NUM_TASKS] =
         [false; hubris_num_tasks::NUM_TASKS];
+    let mut held: [Option<Disposition>; hubris_num_tasks::NUM_TASKS] =
+        [None; hubris_num_tasks::NUM_TASKS];
 
     // We'll have notification 0 wired up to receive information about task
     // faults.
@@ -111,43 +231,94 @@ fn main() -> ! {
     // of our task disposition (e.g., via Humility).  This timeout should
     // generally be fast for a human but slow for a computer; we pick a
     // value of ~100 ms.  Our timer mask can't conflict with our fault
-    // notification, but can otherwise be arbitrary.
+    // notification, but can otherwise be arbitrary.  (This is also how
+    // often we pet the watchdog; the build script checks the configured
+    // watchdog timeout against it, so keep the two in sync.)
     const TIMER_MASK: u32 = 1 << 1;
     const TIMER_INTERVAL: u64 = 100;
     let mut deadline = TIMER_INTERVAL;
 
     sys_set_timer(Some(deadline), TIMER_MASK);
 
+    let mut watchdog = watchdog::CONFIG.as_ref().map(watchdog::Watchdog::new);
+    let mut restarts = restart::Restarts::new();
+    let mut fault_log = fault_log::FaultLog::new();
+
     external::set_ready();
 
+    // Set when another task changes a disposition, so that we look over our
+    // tasks on our next timer tick.
+    let mut requested = false;
+
+    // Big enough for the largest message we accept, a `u32`.
+    let mut buffer = [0; 4];
+
     loop {
-        let msginfo = sys_recv_open(&mut [], fault_mask | TIMER_MASK);
+        let msginfo = sys_recv_open(&mut buffer, fault_mask | TIMER_MASK);
 
         if msginfo.sender == TaskId::KERNEL {
-            // Check to see if we have any external requests
-            let changed = external::check(&mut disposition);
+            // Check to see if we have any external requests, or requests
+            // from other tasks.
+            let changed = external::check(&mut disposition)
+                | core::mem::replace(&mut requested, false);
+            let now = sys_get_timer().now;
 
-            // If our timer went off, we need to reestablish it
+            // If our timer went off, we need to reestablish it, and see if
+            // the watchdog has earned a pet.
             if msginfo.operation & TIMER_MASK != 0 {
                 deadline += TIMER_INTERVAL;
                 sys_set_timer(Some(deadline), TIMER_MASK);
+
+                if let Some(watchdog) = &mut watchdog {
+                    watchdog.tick(now, &disposition);
+                }
             }
 
-            // If our disposition has changed or if we have been notified of
-            // a faulting task, we need to iterate over all of our tasks.
-            if changed || (msginfo.operation & fault_mask) != 0 {
+            // If our disposition has changed, if we have been notified of a
+            // faulting task, or if a delayed restart has come due, we need to
+            // iterate over all of our tasks.
+            if changed
+                || (msginfo.operation & fault_mask) != 0
+                || restarts.any_due(now)
+            {
                 for i in 0..hubris_num_tasks::NUM_TASKS {
                     match kipc::read_task_status(i) {
                         abi::TaskState::Faulted { fault, .. } => {
                             if !logged[i] {
-                                log_fault(i, &fault);
+                                let entry = fault_log.record(i, &fault, now);
+                                log_fault(i, &fault, entry.panic_message());
                                 logged[i] = true;
+
+                                match restarts.faulted(i, now) {
+                                    restart::Verdict::Restart => (),
+                                    restart::Verdict::Hold => {
+                                        sys_log!(
+                                            "Task #{} out of restarts; held",
+                                            i
+                                        );
+                                        disposition[i] = Disposition::Exhausted;
+                                    }
+                                    restart::Verdict::Reset => {
+                                        sys_log!(
+                                            "Task #{} faulting; resetting",
+                                            i
+                                        );
+                                        reset();
+                                    }
+                                }
                             }
 
-                            if disposition[i] == Disposition::Restart {
+                            if disposition[i] == Disposition::Restart
+                                && restarts.due(i, now)
+                            {
                                 // Stand it back up
                                 kipc::restart_task(i, true);
                                 logged[i] = false;
+                                restarts.restarted(i);
+
+                                if let Some(watchdog) = &mut watchdog {
+                                    watchdog.restarted(i, now);
+                                }
                             }
                         }
 
@@ -166,8 +337,62 @@ fn main() -> ! {
                 }
             }
         } else {
-            // ...huh. A task has sent a message to us. That seems wrong.
-            sys_log!("Unexpected message from {}", msginfo.sender.0);
+            match Op::from_u32(msginfo.operation) {
+                Some(Op::CheckIn) => {
+                    if let Some(watchdog) = &mut watchdog {
+                        let now = sys_get_timer().now;
+                        watchdog.check_in(msginfo.sender.index(), now);
+                    }
+                    sys_reply(msginfo.sender, 0, &[]);
+                }
+                Some(Op::ReadFault) => {
+                    let mut out = [0; core::mem::size_of::<FaultRecord>()];
+                    let serialized =
+                        read_fault_entry(&fault_log, &msginfo, buffer)
+                            .and_then(|entry| {
+                                ssmarshal::serialize(&mut out, &entry.record)
+                                    .map_err(|_| JefeError::BadRecord)
+                            });
+
+                    match serialized {
+                        Ok(len) => sys_reply(msginfo.sender, 0, &out[..len]),
+                        Err(e) => sys_reply(msginfo.sender, e as u32, &[]),
+                    }
+                }
+                Some(Op::ReadFaultMessage) => {
+                    match read_fault_entry(&fault_log, &msginfo, buffer) {
+                        Ok(entry) => {
+                            // Truncate the message to fit, rather than
+                            // faulting a caller with a smaller buffer.
+                            let len = (entry.panic_len as usize)
+                                .min(msginfo.response_capacity);
+                            let msg = &entry.panic_msg[..len];
+                            sys_reply(msginfo.sender, 0, msg);
+                        }
+                        Err(e) => sys_reply(msginfo.sender, e as u32, &[]),
+                    }
+                }
+                Some(op @ (Op::Hold | Op::Release)) => {
+                    match request_disposition(
+                        &mut disposition,
+                        &mut held,
+                        &msginfo,
+                        buffer,
+                        op,
+                    ) {
+                        Ok(()) => {
+                            requested = true;
+                            sys_reply(msginfo.sender, 0, &[]);
+                        }
+                        Err(e) => sys_reply(msginfo.sender, e as u32, &[]),
+                    }
+                }
+                None => {
+                    // ...huh. A task has sent a message to us that we don't
+                    // understand. That seems wrong.
+                    sys_log!("Unexpected message from {}", msginfo.sender.0);
+                }
+            }
         }
     }
 }
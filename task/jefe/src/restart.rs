@@ -0,0 +1,141 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Restart policy enforcement.
+//!
+//! By default, a faulted task is restarted as soon as we hear about it. That's
+//! the right thing for a task that hit a one-off problem, but a task that
+//! faults every time it starts will just spin, burning CPU and flooding the
+//! log. Jefe's config in `app.toml` can give a task a restart policy under
+//! `[tasks.jefe.config.restart.<task>]`, which lets us:
+//!
+//! - Wait before restarting it, doubling the delay with each fault in the
+//!   current window (`backoff-ms`, capped at `max-backoff-ms`).
+//! - Stop restarting it after `max-restarts` restarts in the window. The task
+//!   is held, just as if it had been held through the external interface, and
+//!   releasing it that way starts it with a clean slate.
+//! - Reset the whole system after `reset-after` faults in the window.
+//!
+//! Windows are fixed rather than sliding: a window starts at the first fault,
+//! and the first fault after `window-ms` has elapsed starts a new one, which
+//! also resets the backoff delay.
+//!
+//! Backoff delays are only checked on each turn of our timer loop, so they're
+//! effectively rounded up to the timer interval. Note that a critical task
+//! that's waiting out its backoff is faulted, as far as the watchdog is
+//! concerned; backoff for such a task should be kept well below the watchdog
+//! timeout.
+
+/// A task's restart policy, generated from `app.toml` by our build script.
+pub struct Policy {
+    /// Delay before the first restart in a window, in milliseconds.
+    pub backoff_ms: u32,
+    /// Upper bound on the delay, in milliseconds.
+    pub max_backoff_ms: u32,
+    /// Length of the fault-counting window, in milliseconds.
+    pub window_ms: u32,
+    /// Restarts allowed in a window before the task is held.
+    pub max_restarts: Option<u32>,
+    /// Faults in a window after which the system is reset.
+    pub reset_after: Option<u32>,
+}
+
+include!(concat!(env!("OUT_DIR"), "/restart_config.rs"));
+
+/// What to do about a fault, as decided by `Restarts::faulted`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// Restart the task once `Restarts::due` says so.
+    Restart,
+    /// Stop restarting the task, and hold it.
+    Hold,
+    /// Give up, and reset the system.
+    Reset,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct State {
+    /// Start of the current window, in ticks.
+    window_start: u64,
+    /// Faults seen in the current window.
+    faults: u32,
+    /// Delay to apply to the next restart, in milliseconds.
+    backoff: u32,
+    /// Time at which a delayed restart becomes due, in ticks.
+    pending: Option<u64>,
+}
+
+pub struct Restarts {
+    state: [State; hubris_num_tasks::NUM_TASKS],
+}
+
+impl Restarts {
+    pub fn new() -> Self {
+        Self {
+            state: [State::default(); hubris_num_tasks::NUM_TASKS],
+        }
+    }
+
+    /// Applies the policy for the task at `index` to a fault that we've just
+    /// noticed. This must be called once per fault.
+    pub fn faulted(&mut self, index: usize, now: u64) -> Verdict {
+        let policy = match &POLICIES[index] {
+            Some(policy) => policy,
+            None => return Verdict::Restart,
+        };
+        let state = &mut self.state[index];
+
+        let elapsed = now.saturating_sub(state.window_start);
+        if state.faults == 0 || elapsed >= u64::from(policy.window_ms) {
+            state.window_start = now;
+            state.faults = 0;
+            state.backoff = policy.backoff_ms;
+        }
+        state.faults = state.faults.saturating_add(1);
+
+        if let Some(limit) = policy.reset_after {
+            if state.faults >= limit {
+                return Verdict::Reset;
+            }
+        }
+
+        if let Some(limit) = policy.max_restarts {
+            if state.faults > limit {
+                // Forget everything, so that if the task is released, it gets
+                // a fresh window and an immediate restart.
+                *state = State::default();
+                return Verdict::Hold;
+            }
+        }
+
+        if state.backoff != 0 {
+            state.pending = Some(now + u64::from(state.backoff));
+        }
+        state.backoff =
+            state.backoff.saturating_mul(2).min(policy.max_backoff_ms);
+
+        Verdict::Restart
+    }
+
+    /// Checks whether the task at `index`, if faulted, may be restarted yet.
+    pub fn due(&self, index: usize, now: u64) -> bool {
+        match self.state[index].pending {
+            Some(when) => now >= when,
+            None => true,
+        }
+    }
+
+    /// Checks whether any delayed restart has come due, in which case the
+    /// caller should look over its tasks again.
+    pub fn any_due(&self, now: u64) -> bool {
+        self.state
+            .iter()
+            .any(|s| matches!(s.pending, Some(when) if now >= when))
+    }
+
+    /// Notes that the task at `index` has been restarted.
+    pub fn restarted(&mut self, index: usize) {
+        self.state[index].pending = None;
+    }
+}
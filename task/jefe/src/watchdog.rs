@@ -0,0 +1,349 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Hardware watchdog management.
+//!
+//! If jefe's config in `app.toml` has a `watchdog` table, we start the chip's
+//! watchdog at boot, and pet it from our timer loop -- but only while every
+//! task listed as `critical` is healthy and has checked in (using
+//! `task_jefe_api::check_in`) within the last `checkin-ms`. If any of them
+//! stops making progress, we stop petting, and the watchdog resets the part.
+//! If jefe itself (or the kernel) wedges, the same thing happens, which is
+//! rather the point.
+//!
+//! Critical tasks that have been put on hold -- through the external
+//! interface, or with `task_jefe_api::hold` -- are exempt, since somebody is
+//! presumably debugging them. A critical task that we're holding because it
+//! ran out of restarts is not: it's dead, and we want the reset.
+//!
+//! Before we let the watchdog starve, we record why in a `ResetRecord` kept in
+//! uninitialized RAM, which survives the reset. At the next boot, we combine
+//! that with the chip's own reset-cause flag, and log what happened. The record
+//! stays put as `JEFE_RESET_RECORD`, so that it can be read with a debugger
+//! for post-mortem.
+
+use core::mem::MaybeUninit;
+
+use crate::Disposition;
+use userlib::*;
+
+/// Watchdog configuration, generated from `app.toml` by our build script.
+pub struct Config {
+    /// Hardware watchdog timeout, in milliseconds.
+    pub timeout_ms: u32,
+    /// Interval within which each critical task must check in, in
+    /// milliseconds.
+    pub checkin_ms: u32,
+    /// Indices of critical tasks.
+    pub critical: &'static [usize],
+}
+
+include!(concat!(env!("OUT_DIR"), "/watchdog_config.rs"));
+
+/// Reasons we might stop petting the watchdog, as stored in `Starvation`.
+pub const STARVE_NONE: u32 = 0;
+pub const STARVE_FAULTED: u32 = 1;
+pub const STARVE_MISSED_CHECKIN: u32 = 2;
+
+/// Details of a decision to stop petting the watchdog.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct Starvation {
+    /// One of the `STARVE_*` constants.
+    pub reason: u32,
+    /// Index of the critical task responsible.
+    pub task: u32,
+    /// Time at which we stopped petting, in ticks since boot.
+    pub timestamp: u64,
+}
+
+/// Record of watchdog activity that persists across resets (but not power
+/// loss).
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct ResetRecord {
+    /// Set to `RECORD_MAGIC` once the record has been initialized.
+    magic: u32,
+    /// Number of watchdog resets since the record was initialized, which is
+    /// normally at power-on.
+    pub watchdog_resets: u32,
+    /// Why we've stopped petting during the current boot, if we have.
+    pub starving: Starvation,
+    /// Why we stopped petting during the boot that ended in the most recent
+    /// watchdog reset. If the reason is `STARVE_NONE`, we were still petting
+    /// when the reset happened, which suggests that jefe or the kernel hung.
+    pub last: Starvation,
+}
+
+const RECORD_MAGIC: u32 = 0x7e5e_7ca5;
+
+#[no_mangle]
+#[link_section = ".uninit"]
+static mut JEFE_RESET_RECORD: MaybeUninit<ResetRecord> = MaybeUninit::uninit();
+
+pub struct Watchdog {
+    config: &'static Config,
+    record: &'static mut ResetRecord,
+    /// Time of each task's most recent check-in (or restart), in ticks.
+    last_checkin: [u64; hubris_num_tasks::NUM_TASKS],
+}
+
+impl Watchdog {
+    /// Reports on the previous reset, and starts the watchdog.
+    ///
+    /// This must only be called once.
+    pub fn new(config: &'static Config) -> Self {
+        let fired = hw::take_reset_flag();
+
+        // Safety: we're only called once, so this is the only reference to
+        // the record. It may hold garbage -- that's what the magic number is
+        // for -- but any bit pattern is a valid `ResetRecord`.
+        let record = unsafe { &mut *JEFE_RESET_RECORD.as_mut_ptr() };
+        if record.magic != RECORD_MAGIC {
+            *record = ResetRecord {
+                magic: RECORD_MAGIC,
+                watchdog_resets: 0,
+                starving: Starvation::default(),
+                last: Starvation::default(),
+            };
+        }
+
+        if fired {
+            record.watchdog_resets = record.watchdog_resets.wrapping_add(1);
+            record.last = record.starving;
+            let last = &record.last;
+            match last.reason {
+                STARVE_FAULTED => sys_log!(
+                    "watchdog reset: task #{} faulted at {}",
+                    last.task,
+                    last.timestamp
+                ),
+                STARVE_MISSED_CHECKIN => sys_log!(
+                    "watchdog reset: task #{} missed check-in at {}",
+                    last.task,
+                    last.timestamp
+                ),
+                _ => sys_log!("watchdog reset: cause unknown"),
+            }
+        }
+        record.starving = Starvation::default();
+
+        hw::start(config.timeout_ms);
+
+        Self {
+            config,
+            record,
+            last_checkin: [0; hubris_num_tasks::NUM_TASKS],
+        }
+    }
+
+    /// Notes a check-in from the task at `index`.
+    pub fn check_in(&mut self, index: usize, now: u64) {
+        if let Some(t) = self.last_checkin.get_mut(index) {
+            *t = now;
+        }
+    }
+
+    /// Notes that the task at `index` has been restarted. This gives it a
+    /// fresh check-in interval to get going.
+    pub fn restarted(&mut self, index: usize, now: u64) {
+        self.check_in(index, now);
+    }
+
+    /// Pets the watchdog if all critical tasks are in good shape. This should
+    /// be called on each turn of our timer loop.
+    pub fn tick(&mut self, now: u64, disposition: &[Disposition]) {
+        for &i in self.config.critical {
+            if disposition[i] == Disposition::Hold {
+                continue;
+            }
+
+            let reason = match kipc::read_task_status(i) {
+                abi::TaskState::Faulted { .. } => STARVE_FAULTED,
+                abi::TaskState::Healthy(..) => {
+                    let since = now.saturating_sub(self.last_checkin[i]);
+                    if since > u64::from(self.config.checkin_ms) {
+                        STARVE_MISSED_CHECKIN
+                    } else {
+                        continue;
+                    }
+                }
+            };
+
+            if self.record.starving.reason == STARVE_NONE {
+                sys_log!("task #{} is unwell; starving the watchdog", i);
+                self.record.starving = Starvation {
+                    reason,
+                    task: i as u32,
+                    timestamp: now,
+                };
+            }
+            return;
+        }
+
+        if self.record.starving.reason != STARVE_NONE {
+            sys_log!("critical tasks recovered; petting the watchdog");
+            self.record.starving = Starvation::default();
+        }
+        hw::pet();
+    }
+}
+
+/// Driver for the STM32H7 independent watchdog, IWDG1 (RM0433 section 45).
+#[cfg(feature = "watchdog-stm32h7")]
+mod hw {
+    const IWDG1: usize = 0x5800_4800;
+    const IWDG_KR: *mut u32 = IWDG1 as *mut u32;
+    const IWDG_PR: *mut u32 = (IWDG1 + 0x04) as *mut u32;
+    const IWDG_RLR: *mut u32 = (IWDG1 + 0x08) as *mut u32;
+    const IWDG_SR: *const u32 = (IWDG1 + 0x0c) as *const u32;
+
+    const KEY_START: u32 = 0xcccc;
+    const KEY_UNLOCK: u32 = 0x5555;
+    const KEY_RELOAD: u32 = 0xaaaa;
+
+    /// The IWDG runs from the LSI, at 32 kHz.
+    const LSI_HZ: u32 = 32_000;
+
+    /// The reset status register lives in the RCC, which otherwise belongs to
+    /// the RCC driver. Nobody else touches this register.
+    const RCC_RSR: *mut u32 = 0x5802_44d0 as *mut u32;
+    const RSR_IWDG1RSTF: u32 = 1 << 26;
+    const RSR_RMVF: u32 = 1 << 16;
+
+    /// Starts the watchdog with (roughly) the given timeout. Once started, the
+    /// IWDG can't be stopped short of a reset.
+    pub fn start(timeout_ms: u32) {
+        let ticks = timeout_ms.saturating_mul(LSI_HZ / 1000);
+
+        // Pick the smallest prescaler (4 << pr) that lets the reload value fit
+        // in 12 bits, to keep the resolution as fine as we can.
+        let mut pr = 0;
+        while pr < 6 && ticks / (4 << pr) > 0x1000 {
+            pr += 1;
+        }
+        let reload = (ticks / (4 << pr)).clamp(1, 0x1000) - 1;
+
+        unsafe {
+            IWDG_KR.write_volatile(KEY_START);
+            IWDG_KR.write_volatile(KEY_UNLOCK);
+            IWDG_PR.write_volatile(pr);
+            IWDG_RLR.write_volatile(reload);
+            // Wait for the new prescaler and reload values to make it across
+            // to the LSI domain.
+            while IWDG_SR.read_volatile() != 0 {}
+            IWDG_KR.write_volatile(KEY_RELOAD);
+        }
+    }
+
+    pub fn pet() {
+        unsafe {
+            IWDG_KR.write_volatile(KEY_RELOAD);
+        }
+    }
+
+    /// Checks whether the last reset was caused by the watchdog, and clears
+    /// the chip's reset flags so that the next reset is reported accurately.
+    pub fn take_reset_flag() -> bool {
+        unsafe {
+            let rsr = RCC_RSR.read_volatile();
+            RCC_RSR.write_volatile(RSR_RMVF);
+            RCC_RSR.write_volatile(0);
+            rsr & RSR_IWDG1RSTF != 0
+        }
+    }
+}
+
+/// Driver for the LPC55 windowed watchdog, WWDT (UM11126 chapter 16). We
+/// don't use the window; it's just a watchdog here.
+#[cfg(feature = "watchdog-lpc55")]
+mod hw {
+    const WWDT: usize = 0x4000_c000;
+    const WWDT_MOD: *mut u32 = WWDT as *mut u32;
+    const WWDT_TC: *mut u32 = (WWDT + 0x04) as *mut u32;
+    const WWDT_FEED: *mut u32 = (WWDT + 0x08) as *mut u32;
+
+    const MOD_WDEN: u32 = 1 << 0;
+    const MOD_WDRESET: u32 = 1 << 1;
+    const MOD_WDTOF: u32 = 1 << 2;
+
+    /// Clock control lives in SYSCON, which otherwise belongs to the SYSCON
+    /// driver. We use the atomic set register and the watchdog's own divider
+    /// where we can; the one read-modify-write, of CLOCK_CTRL, happens when we
+    /// start the watchdog at boot, before any lower-priority task has run.
+    const SYSCON: usize = 0x4000_0000;
+    const SYSCON_AHBCLKCTRLSET0: *mut u32 = (SYSCON + 0x220) as *mut u32;
+    const SYSCON_WDTCLKDIV: *mut u32 = (SYSCON + 0x38c) as *mut u32;
+    const SYSCON_CLOCK_CTRL: *mut u32 = (SYSCON + 0xa18) as *mut u32;
+    const AHBCLKCTRL0_WWDT: u32 = 1 << 22;
+    const CLOCK_CTRL_FRO1MHZ_CLK_ENA: u32 = 1 << 1;
+
+    /// The WWDT is clocked from the 1 MHz FRO, and has a fixed divide-by-4
+    /// prescaler. We leave WDTCLKDIV at divide-by-1.
+    const WDT_HZ: u32 = 1_000_000 / 4;
+
+    fn enable_clocks() {
+        unsafe {
+            SYSCON_AHBCLKCTRLSET0.write_volatile(AHBCLKCTRL0_WWDT);
+        }
+    }
+
+    /// Starts the watchdog with (roughly) the given timeout. Once started with
+    /// `WDRESET` set, the WWDT can't be stopped short of a reset.
+    pub fn start(timeout_ms: u32) {
+        enable_clocks();
+
+        let ticks = timeout_ms.saturating_mul(WDT_HZ / 1000);
+
+        unsafe {
+            let ctrl = SYSCON_CLOCK_CTRL.read_volatile();
+            SYSCON_CLOCK_CTRL.write_volatile(ctrl | CLOCK_CTRL_FRO1MHZ_CLK_ENA);
+            // Divide by 1, and clear HALT.
+            SYSCON_WDTCLKDIV.write_volatile(0);
+
+            WWDT_TC.write_volatile(ticks.clamp(0xff, 0xff_ffff));
+            WWDT_MOD.write_volatile(MOD_WDEN | MOD_WDRESET);
+        }
+
+        // The watchdog doesn't start counting until its first feed.
+        pet();
+    }
+
+    pub fn pet() {
+        unsafe {
+            WWDT_FEED.write_volatile(0xaa);
+            WWDT_FEED.write_volatile(0x55);
+        }
+    }
+
+    /// Checks whether the last reset was caused by the watchdog. The WWDT's
+    /// time-out flag survives any reset other than power-on, so we clear it
+    /// to keep the next reset from being misreported.
+    pub fn take_reset_flag() -> bool {
+        enable_clocks();
+
+        unsafe {
+            let m = WWDT_MOD.read_volatile();
+            WWDT_MOD.write_volatile(m & !MOD_WDTOF);
+            m & MOD_WDTOF != 0
+        }
+    }
+}
+
+/// Placeholder for builds without a watchdog driver. The build script refuses
+/// to configure a watchdog without one, so none of these get called.
+#[cfg(not(any(feature = "watchdog-stm32h7", feature = "watchdog-lpc55")))]
+mod hw {
+    pub fn start(_timeout_ms: u32) {
+        unreachable!()
+    }
+
+    pub fn pet() {
+        unreachable!()
+    }
+
+    pub fn take_reset_flag() -> bool {
+        unreachable!()
+    }
+}
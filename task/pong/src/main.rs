@@ -25,7 +25,16 @@ pub fn main() -> ! {
     loop {
         let msginfo = sys_recv_open(&mut msg, TIMER_NOTIFICATION);
 
-        if msginfo.sender != TaskId::KERNEL {
+        if msginfo.sender == task_jefe_api::jefe()
+            && msginfo.operation == u32::from(task_jefe_api::SHUTDOWN_NOTICE)
+        {
+            // The system is about to be reset; leave the LEDs dark rather
+            // than frozen mid-blink.
+            let mut index = 0;
+            while user_leds.led_off(index).is_ok() {
+                index += 1;
+            }
+        } else if msginfo.sender != TaskId::KERNEL {
             // We'll just assume this is a ping message and reply.
             sys_reply(msginfo.sender, response, &[]);
             response += 1;
@@ -35,6 +44,8 @@ pub fn main() -> ! {
             dl += INTERVAL;
             sys_set_timer(Some(dl), TIMER_NOTIFICATION);
 
+            task_jefe_api::check_in();
+
             // Toggle the current LED -- and if we've run out, start over
             loop {
                 match user_leds.led_toggle(current >> 1) {
@@ -45,9 +56,6 @@ pub fn main() -> ! {
                     Err(drv_user_leds_api::LedError::NoSuchLed) => {
                         current = 0;
                     }
-                    _ => {
-                        panic!("unhandled Led error");
-                    }
                 };
             }
         }
@@ -4,18 +4,27 @@
 
 //! Power monitoring
 //!
-//! This is a primordial power monitoring task.
+//! This is a primordial power monitoring task. We poll each rail for its
+//! output voltage, which we post to the sensor server, and for its PMBus
+//! status. Any warnings or faults are recorded in our ring buffer (and logged)
+//! when they change, and then cleared; those whose conditions persist will be
+//! flagged again on the next poll.
 //!
 
 #![no_std]
 #![no_main]
 
+use core::fmt::Display;
 use drv_i2c_devices::isl68224::*;
+use drv_i2c_devices::pmbus_device::PmbusDevice;
+use drv_i2c_devices::pmbus_status::{Faults, PmbusStatus};
 use ringbuf::*;
+use task_sensor_api::{Sensor, SensorId};
 use userlib::units::*;
 use userlib::*;
 
 task_slot!(I2C, i2c_driver);
+task_slot!(SENSOR, sensor);
 include!(concat!(env!("OUT_DIR"), "/i2c_config.rs"));
 
 #[derive(Copy, Clone, PartialEq)]
@@ -38,6 +47,7 @@ enum Command {
 #[derive(Copy, Clone, PartialEq)]
 enum Trace {
     Datum(Device, Command),
+    Faults(Device, Faults),
     None,
 }
 
@@ -47,23 +57,84 @@ fn trace(dev: Device, cmd: Command) {
     ringbuf_entry!(Trace::Datum(dev, cmd));
 }
 
+/// A rail that we're watching.
+struct Rail {
+    dev: Isl68224,
+    id: SensorId,
+    /// The status we last read, if any.
+    faults: Option<Faults>,
+}
+
+/// Reads the output voltage of `dev`, and posts it to the sensor server as
+/// the reading for sensor `id`.
+fn read_vout(dev: &mut Isl68224, sensor: &Sensor, id: SensorId) {
+    match dev.read_vout() {
+        Ok(vout) => {
+            trace(Device::Isl68224, Command::VOut(vout));
+            sensor.post_voltage(id, vout).unwrap();
+        }
+        Err(err) => {
+            sys_log!("{}: failed to read VOUT: {:?}", dev, err);
+            sensor.post_error(id).unwrap();
+        }
+    }
+}
+
+/// Reads the status of `dev`, recording it if it has changed since `last`,
+/// and clears any warnings or faults it has latched.
+fn check_faults<D: PmbusStatus + Display>(
+    dev: &mut D,
+    which: Device,
+    last: &mut Option<Faults>,
+) {
+    let faults = match dev.faults() {
+        Ok(faults) => faults,
+        Err(err) => {
+            sys_log!("{}: failed to read status: {:?}", dev, err);
+            return;
+        }
+    };
+
+    if *last != Some(faults) {
+        ringbuf_entry!(Trace::Faults(which, faults));
+
+        if faults.word.has_faults() {
+            sys_log!("{}: {:?}", dev, faults);
+        }
+    }
+
+    if faults.word.has_faults() {
+        if let Err(err) = dev.clear_faults() {
+            sys_log!("{}: failed to clear faults: {:?}", dev, err);
+        }
+    }
+
+    *last = Some(faults);
+}
+
 #[export_name = "main"]
 fn main() -> ! {
     let task = I2C.get_task_id();
+    let sensor = Sensor::from(SENSOR.get_task_id());
 
     cfg_if::cfg_if! {
         if #[cfg(target_board = "gemini-bu-1")] {
+            use i2c_config::sensors;
+
             let (device, rail) = i2c_config::pmbus::isl_evl_vout0(task);
-            let mut isl0 = Isl68224::new(&device, rail);
+            let isl0 = Isl68224::new(&device, rail);
+            let isl0_id = sensors::ISL_EVL_VOUT0_VOLTAGE_SENSOR;
 
             let (device, rail) = i2c_config::pmbus::isl_evl_vout1(task);
-            let mut isl1 = Isl68224::new(&device, rail);
+            let isl1 = Isl68224::new(&device, rail);
+            let isl1_id = sensors::ISL_EVL_VOUT1_VOLTAGE_SENSOR;
         } else {
             cfg_if::cfg_if! {
                 if #[cfg(feature = "standalone")] {
                     let device = &i2c_config::devices::mock(task);
-                    let mut isl0 = Isl68224::new(&device, 0);
-                    let mut isl1 = Isl68224::new(&device, 0);
+                    let isl0 = Isl68224::new(&device, 0);
+                    let isl1 = Isl68224::new(&device, 0);
+                    let (isl0_id, isl1_id) = (SensorId(0), SensorId(1));
                 } else {
                     compile_error!("unknown board");
                 }
@@ -71,19 +142,25 @@ fn main() -> ! {
         }
     }
 
+    let mut rails = [
+        Rail {
+            dev: isl0,
+            id: isl0_id,
+            faults: None,
+        },
+        Rail {
+            dev: isl1,
+            id: isl1_id,
+            faults: None,
+        },
+    ];
+
     loop {
-        isl0.turn_off().unwrap();
-        isl1.turn_on().unwrap();
-        hl::sleep_for(1000);
+        for rail in rails.iter_mut() {
+            read_vout(&mut rail.dev, &sensor, rail.id);
+            check_faults(&mut rail.dev, Device::Isl68224, &mut rail.faults);
+        }
 
-        isl0.turn_on().unwrap();
-        isl1.turn_off().unwrap();
         hl::sleep_for(1000);
-
-        let vout = isl0.read_vout().unwrap();
-        trace(Device::Isl68224, Command::VOut(vout));
-
-        let vout = isl1.read_vout().unwrap();
-        trace(Device::Isl68224, Command::VOut(vout));
     }
 }
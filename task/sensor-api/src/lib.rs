@@ -0,0 +1,53 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Client API for the sensor server, `task/sensor`.
+//!
+//! Tasks that read sensors post their readings (or their failure to get one)
+//! to the sensor server, and anyone else can ask it for the latest. The
+//! protocol is defined in `idl/sensor.toml`, from which most of this crate's
+//! contents are generated.
+//!
+//! Sensors are identified by a `SensorId`, which the I2C build support
+//! assigns to each sensor listed in a device's `sensors` in
+//! `[config.i2c.devices]`, and makes available as constants in the
+//! generated `i2c_config::sensors` module.
+
+#![no_std]
+
+use userlib::units::{Amperes, Celsius, Rpm, Volts};
+use zerocopy::{AsBytes, FromBytes};
+
+/// Identifies a sensor. These are numbered from 0, in the order that the
+/// sensors appear in the app's I2C configuration.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, AsBytes, FromBytes)]
+#[repr(transparent)]
+pub struct SensorId(pub u32);
+
+/// What a sensor measures, which determines the units of its readings.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SensorKind {
+    /// Degrees Celsius.
+    Temperature,
+    /// Volts.
+    Voltage,
+    /// Amperes.
+    Current,
+    /// Fan speed in RPM.
+    Speed,
+}
+
+/// A reading from a sensor, as returned by `Sensor::get`.
+#[derive(Copy, Clone, Debug, PartialEq, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct Reading {
+    /// When the reading was posted, in ticks since boot.
+    pub timestamp: u64,
+    /// The value read, in the units of the sensor's kind.
+    pub value: f32,
+    /// How many times the sensor has failed to be read since.
+    pub errors: u32,
+}
+
+include!(concat!(env!("OUT_DIR"), "/client_stub.rs"));
@@ -0,0 +1,19 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    build_util::expose_target_board();
+
+    let disposition = build_i2c::Disposition::Sensors;
+
+    #[cfg(feature = "standalone")]
+    let artifact = build_i2c::Artifact::Standalone;
+
+    #[cfg(not(feature = "standalone"))]
+    let artifact = build_i2c::Artifact::Dist;
+
+    build_i2c::codegen(disposition, artifact)?;
+    build_idl::server_stub("../../idl/sensor.toml", "server_stub.rs")?;
+    Ok(())
+}
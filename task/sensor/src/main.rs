@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Sensor server
+//!
+//! We keep the latest reading from each sensor on the board -- or the fact
+//! that it couldn't be read -- as posted by the tasks that drive them, and
+//! hand them out to anyone who asks. The sensors, and what kind each one is,
+//! come from the `sensors` of each device in `[config.i2c.devices]`.
+//!
+//! Our IPC protocol is defined in `idl/sensor.toml`.
+
+#![no_std]
+#![no_main]
+
+use task_sensor_api::{Reading, SensorError, SensorId, SensorKind};
+use userlib::units::*;
+use userlib::*;
+
+include!(concat!(env!("OUT_DIR"), "/i2c_config.rs"));
+
+mod idl {
+    use task_sensor_api::{Reading, SensorError, SensorId};
+    use userlib::units::{Amperes, Celsius, Rpm, Volts};
+
+    include!(concat!(env!("OUT_DIR"), "/server_stub.rs"));
+}
+
+use i2c_config::sensors::{NUM_SENSORS, SENSOR_KINDS};
+
+/// What we know about a sensor.
+#[derive(Copy, Clone)]
+struct State {
+    /// The last good reading, as (timestamp, value).
+    last: Option<(u64, f32)>,
+    /// The number of failed reads since then.
+    errors: u32,
+}
+
+#[export_name = "main"]
+fn main() -> ! {
+    let mut buffer = [0; idl::INCOMING_SIZE];
+    let mut server = ServerImpl {
+        sensors: [State {
+            last: None,
+            errors: 0,
+        }; NUM_SENSORS],
+    };
+    loop {
+        idl::dispatch(&mut buffer, &mut server);
+    }
+}
+
+struct ServerImpl {
+    sensors: [State; NUM_SENSORS],
+}
+
+impl ServerImpl {
+    fn lookup(&mut self, id: SensorId) -> Result<&mut State, SensorError> {
+        self.sensors
+            .get_mut(id.0 as usize)
+            .ok_or(SensorError::InvalidSensor)
+    }
+
+    fn post(
+        &mut self,
+        id: SensorId,
+        kind: SensorKind,
+        value: f32,
+    ) -> Result<(), SensorError> {
+        let sensor = self.lookup(id)?;
+
+        if SENSOR_KINDS[id.0 as usize] != kind {
+            return Err(SensorError::WrongKind);
+        }
+
+        sensor.last = Some((sys_get_timer().now, value));
+        sensor.errors = 0;
+        Ok(())
+    }
+}
+
+impl idl::SensorServer for ServerImpl {
+    fn post_temperature(
+        &mut self,
+        _: TaskId,
+        id: SensorId,
+        value: Celsius,
+    ) -> Result<(), SensorError> {
+        self.post(id, SensorKind::Temperature, value.0)
+    }
+
+    fn post_voltage(
+        &mut self,
+        _: TaskId,
+        id: SensorId,
+        value: Volts,
+    ) -> Result<(), SensorError> {
+        self.post(id, SensorKind::Voltage, value.0)
+    }
+
+    fn post_current(
+        &mut self,
+        _: TaskId,
+        id: SensorId,
+        value: Amperes,
+    ) -> Result<(), SensorError> {
+        self.post(id, SensorKind::Current, value.0)
+    }
+
+    fn post_speed(
+        &mut self,
+        _: TaskId,
+        id: SensorId,
+        value: Rpm,
+    ) -> Result<(), SensorError> {
+        self.post(id, SensorKind::Speed, f32::from(value.0))
+    }
+
+    fn post_error(
+        &mut self,
+        _: TaskId,
+        id: SensorId,
+    ) -> Result<(), SensorError> {
+        let sensor = self.lookup(id)?;
+        sensor.errors = sensor.errors.saturating_add(1);
+        Ok(())
+    }
+
+    fn get(&mut self, _: TaskId, id: SensorId) -> Result<Reading, SensorError> {
+        let sensor = self.lookup(id)?;
+
+        match sensor.last {
+            Some((timestamp, value)) => Ok(Reading {
+                timestamp,
+                value,
+                errors: sensor.errors,
+            }),
+            None if sensor.errors > 0 => Err(SensorError::DeviceError),
+            None => Err(SensorError::NoReading),
+        }
+    }
+}
@@ -59,6 +59,12 @@ fn configure_pins(pins: &[I2cPin]) {
 static mut SPD_DATA: [u8; 8192] = [0; 8192];
 
 const LTC4306_ADDRESS: u8 = 0b1001_010;
+
+//
+// How long (in ticks) to wait for the I2C server when reading SPD data at
+// boot, so that a wedged bus fails loudly instead of hanging us.
+//
+const I2C_TIMEOUT: u32 = 1000;
 type Bank = (Controller, drv_i2c_api::PortIndex, Option<(Mux, Segment)>);
 
 #[derive(Copy, Clone, PartialEq)]
@@ -146,7 +152,8 @@ fn main() -> ! {
         let addr = spd::Function::PageAddress(spd::Page(0))
             .to_device_code()
             .unwrap();
-        let page = I2cDevice::new(i2c_task, controller, port, None, addr);
+        let page = I2cDevice::new(i2c_task, controller, port, None, addr)
+            .with_timeout(I2C_TIMEOUT);
 
         if let Err(_) = page.write(&[0]) {
             //
@@ -159,7 +166,8 @@ fn main() -> ! {
 
         for i in 0..spd::MAX_DEVICES {
             let mem = spd::Function::Memory(i).to_device_code().unwrap();
-            let spd = I2cDevice::new(i2c_task, controller, port, mux, mem);
+            let spd = I2cDevice::new(i2c_task, controller, port, mux, mem)
+                .with_timeout(I2C_TIMEOUT);
             let ndx = (nbank * spd::MAX_DEVICES) as usize + i as usize;
             let offs = ndx * spd::MAX_SIZE;
 
@@ -198,7 +206,8 @@ fn main() -> ! {
         let addr = spd::Function::PageAddress(spd::Page(1))
             .to_device_code()
             .unwrap();
-        let page = I2cDevice::new(i2c_task, controller, port, None, addr);
+        let page = I2cDevice::new(i2c_task, controller, port, None, addr)
+            .with_timeout(I2C_TIMEOUT);
 
         //
         // We really don't expect this to fail, and if it does, tossing here
@@ -220,7 +229,8 @@ fn main() -> ! {
             ringbuf_entry!(Trace::ReadTop(ndx));
 
             let mem = spd::Function::Memory(i).to_device_code().unwrap();
-            let spd = I2cDevice::new(i2c_task, controller, port, mux, mem);
+            let spd = I2cDevice::new(i2c_task, controller, port, mux, mem)
+                .with_timeout(I2C_TIMEOUT);
 
             let chunk = 128;
             let base = offs;
@@ -2,6 +2,89 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use anyhow::{bail, Result};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Our task configuration, from `[tasks.thermal.config]` in `app.toml`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Config {
+    /// Thermal zones, keyed by name. Without any, we just monitor.
+    #[serde(default)]
+    zones: IndexMap<String, ZoneConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ZoneConfig {
+    /// Temperature sensors in this zone, which must be named devices in
+    /// `[config.i2c.devices]`.
+    sensors: Vec<SensorConfig>,
+    /// Fans that cool this zone, numbered from 1 as in the MAX31790
+    /// datasheet.
+    fans: Vec<u8>,
+    /// Fan curve, as a list of (temperature in degrees C, duty cycle in
+    /// percent) points in order of increasing temperature.
+    curve: Vec<(f32, u8)>,
+    /// Lowest duty cycle we'll ask for, in percent.
+    #[serde(default)]
+    min_duty: u8,
+    /// Highest duty cycle we'll ask for (except on sensor failure), in
+    /// percent.
+    #[serde(default = "full_duty")]
+    max_duty: u8,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct SensorConfig {
+    device: String,
+    bus: String,
+    name: String,
+}
+
+fn full_duty() -> u8 {
+    100
+}
+
+/// The parts of the app-wide configuration we look at. The config is shared
+/// with other consumers, so unknown fields are expected.
+#[derive(Clone, Debug, Deserialize)]
+struct AppConfig {
+    i2c: I2cConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct I2cConfig {
+    devices: Option<Vec<I2cDevice>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct I2cDevice {
+    device: String,
+    bus: Option<String>,
+    name: Option<String>,
+    sensors: Option<I2cSensors>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct I2cSensors {
+    #[serde(default)]
+    temperature: usize,
+}
+
+/// Devices we can use as temperature sensors, all of which are driven by the
+/// TMP116 driver.
+const SENSOR_DEVICES: &[&str] = &["tmp116", "tmp117"];
+
+/// Fans on the MAX31790.
+const MAX_FANS: u8 = 6;
+
 fn main() {
     build_util::expose_target_board();
 
@@ -17,4 +100,172 @@ fn main() {
         println!("code generation failed: {}", e);
         std::process::exit(1);
     }
+
+    if let Err(e) = gen_thermal_config() {
+        println!("thermal configuration failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn gen_thermal_config() -> Result<()> {
+    let config: Config = build_util::task_config()?.unwrap_or_default();
+
+    if !config.zones.is_empty() {
+        let app = build_util::config::<AppConfig>()?;
+        check_zones(&config.zones, &app.i2c.devices.unwrap_or_default())?;
+    }
+
+    let out = PathBuf::from(env::var("OUT_DIR")?);
+    let mut file = File::create(out.join("thermal_config.rs"))?;
+
+    let sensors = config
+        .zones
+        .values()
+        .flat_map(|z| z.sensors.iter())
+        .collect::<Vec<_>>();
+
+    writeln!(file, "pub const NUM_SENSORS: usize = {};", sensors.len())?;
+    if sensors.is_empty() {
+        writeln!(file, "pub fn sensors(_task: TaskId) -> [Tmp116; 0] {{")?;
+    } else {
+        writeln!(
+            file,
+            "pub fn sensors(task: TaskId) -> [Tmp116; {}] {{",
+            sensors.len()
+        )?;
+        writeln!(file, "    use crate::i2c_config::devices;")?;
+    }
+    writeln!(file, "    [")?;
+    for s in &sensors {
+        writeln!(
+            file,
+            "        Tmp116::new(&devices::{}_{}_{}(task)\
+             .with_timeout(crate::I2C_TIMEOUT)),",
+            s.device, s.bus, s.name
+        )?;
+    }
+    writeln!(file, "    ]")?;
+    writeln!(file, "}}")?;
+
+    writeln!(
+        file,
+        "pub const SENSOR_IDS: [SensorId; {}] = [",
+        sensors.len()
+    )?;
+    for s in &sensors {
+        writeln!(
+            file,
+            "    crate::i2c_config::sensors::{}_{}_{}_TEMPERATURE_SENSOR,",
+            s.device.to_uppercase(),
+            s.bus.to_uppercase(),
+            s.name.to_uppercase()
+        )?;
+    }
+    writeln!(file, "];")?;
+
+    writeln!(file, "pub const ZONES: [Zone; {}] = [", config.zones.len())?;
+    let mut first = 0;
+    for (name, zone) in &config.zones {
+        let last = first + zone.sensors.len();
+        let fans = zone.fans.iter().map(|f| f - 1).collect::<Vec<_>>();
+        writeln!(file, "    Zone {{")?;
+        writeln!(file, "        name: {:?},", name)?;
+        writeln!(file, "        sensors: {}..{},", first, last)?;
+        writeln!(file, "        fans: &{:?},", fans)?;
+        writeln!(file, "        curve: &{:?},", zone.curve)?;
+        writeln!(file, "        min_duty: {},", zone.min_duty)?;
+        writeln!(file, "        max_duty: {},", zone.max_duty)?;
+        writeln!(file, "    }},")?;
+        first = last;
+    }
+    writeln!(file, "];")?;
+
+    Ok(())
+}
+
+fn check_zones(
+    zones: &IndexMap<String, ZoneConfig>,
+    devices: &[I2cDevice],
+) -> Result<()> {
+    for (name, zone) in zones {
+        if zone.sensors.is_empty() {
+            bail!("zone {}: must have at least one sensor", name);
+        }
+
+        for s in &zone.sensors {
+            if !SENSOR_DEVICES.contains(&s.device.as_str()) {
+                bail!(
+                    "zone {}: {} is not a supported sensor (expected one of {:?})",
+                    name,
+                    s.device,
+                    SENSOR_DEVICES
+                );
+            }
+
+            let found = devices.iter().find(|d| {
+                d.device == s.device
+                    && d.bus.as_ref() == Some(&s.bus)
+                    && d.name.as_ref() == Some(&s.name)
+            });
+            let device = match found {
+                Some(device) => device,
+                None => bail!(
+                    "zone {}: no {} named {} on bus {} in config.i2c.devices",
+                    name,
+                    s.device,
+                    s.name,
+                    s.bus
+                ),
+            };
+
+            // We post each sensor's readings to the sensor server, so it
+            // needs a sensor ID.
+            if device.sensors.as_ref().map(|t| t.temperature) != Some(1) {
+                bail!(
+                    "zone {}: {} {} on bus {} needs \
+                     sensors = {{ temperature = 1 }}",
+                    name,
+                    s.device,
+                    s.name,
+                    s.bus
+                );
+            }
+        }
+
+        if zone.fans.is_empty() {
+            bail!("zone {}: must have at least one fan", name);
+        }
+        for &fan in &zone.fans {
+            if fan == 0 || fan > MAX_FANS {
+                bail!(
+                    "zone {}: fan {} out of range (fans are 1-{})",
+                    name,
+                    fan,
+                    MAX_FANS
+                );
+            }
+        }
+
+        if zone.curve.is_empty() {
+            bail!("zone {}: curve must have at least one point", name);
+        }
+        for pair in zone.curve.windows(2) {
+            if pair[1].0 <= pair[0].0 {
+                bail!("zone {}: curve temperatures must increase", name);
+            }
+        }
+        if zone.curve.iter().any(|&(_, duty)| duty > 100) {
+            bail!("zone {}: curve duty cycles must be percentages", name);
+        }
+        if zone.min_duty > zone.max_duty || zone.max_duty > 100 {
+            bail!(
+                "zone {}: need min-duty <= max-duty <= 100 (found {} and {})",
+                name,
+                zone.min_duty,
+                zone.max_duty
+            );
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,85 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fan control.
+//!
+//! The thermal zones are configured per board, under
+//! `[tasks.thermal.config.zones.<name>]` in `app.toml`. Each zone has a set of
+//! temperature sensors, a set of fans that cool them, and a fan curve: a list
+//! of (temperature, duty cycle) points, between which we interpolate
+//! linearly. On each turn of our loop, a zone takes its hottest sensor, maps
+//! it through its curve, and clamps the result to its `min-duty` and
+//! `max-duty` limits. If any of a zone's sensors can't be read, the zone
+//! instead asks for full speed, since we can't know how hot it is.
+//!
+//! A fan may cool several zones, in which case it runs at the highest duty
+//! cycle any of them asks for. Fans in no zone are left alone.
+
+use core::ops::Range;
+use drv_i2c_devices::tmp116::Tmp116;
+use task_sensor_api::SensorId;
+use userlib::units::Celsius;
+use userlib::TaskId;
+
+/// A thermal zone, generated from `app.toml` by our build script.
+pub struct Zone {
+    pub name: &'static str,
+    /// Indices of this zone's sensors in the array returned by `sensors`.
+    pub sensors: Range<usize>,
+    /// Fans cooling this zone, as 0-based indices.
+    pub fans: &'static [u8],
+    /// Fan curve, as (degrees C, duty cycle percentage) points in order of
+    /// increasing temperature.
+    pub curve: &'static [(f32, u8)],
+    /// Limits on the duty cycle we'll ask for, as percentages.
+    pub min_duty: u8,
+    pub max_duty: u8,
+}
+
+include!(concat!(env!("OUT_DIR"), "/thermal_config.rs"));
+
+/// Duty cycle for a zone that has lost a sensor.
+pub const FAILSAFE_DUTY: u8 = 100;
+
+impl Zone {
+    /// Determines the duty cycle this zone needs, given the latest readings
+    /// from its sensors (`None` for a sensor that couldn't be read). Returns
+    /// `None` if any sensor couldn't be read, in which case the caller should
+    /// use `FAILSAFE_DUTY`.
+    pub fn duty(&self, temps: &[Option<Celsius>]) -> Option<u8> {
+        let mut hottest = f32::MIN;
+        for temp in temps {
+            hottest = hottest.max(temp.as_ref()?.0);
+        }
+
+        Some(
+            interpolate(self.curve, hottest)
+                .max(self.min_duty)
+                .min(self.max_duty),
+        )
+    }
+}
+
+/// Looks up `temp` in `curve`. Temperatures off either end of the curve get
+/// the duty cycle at that end.
+fn interpolate(curve: &[(f32, u8)], temp: f32) -> u8 {
+    let (first, last) = (curve[0], curve[curve.len() - 1]);
+
+    if temp <= first.0 {
+        return first.1;
+    }
+
+    for pair in curve.windows(2) {
+        let ((t0, d0), (t1, d1)) = (pair[0], pair[1]);
+
+        if temp < t1 {
+            let d0 = f32::from(d0);
+            let d1 = f32::from(d1);
+            let duty = d0 + (temp - t0) / (t1 - t0) * (d1 - d0);
+            return (duty + 0.5) as u8;
+        }
+    }
+
+    last.1
+}
@@ -4,23 +4,45 @@
 
 //! Thermal loop
 //!
-//! This is a primordial thermal loop, which will ultimately reading temperature
-//! sensors and control fan duty cycles to actively manage thermals.  Right now,
-//! though it is merely reading every fan and temp sensor that it can find...
+//! Once a second, we read every temperature sensor in our thermal zones, and
+//! set the duty cycle of each fan on the MAX31790 to cool them; see the
+//! `control` module for how. Boards without any zones configured just have
+//! their fan speeds logged.
+//!
+//! We also post each temperature and fan speed that we read (or fail to
+//! read) to the sensor server, for other tasks to see.
 //!
 
 #![no_std]
 #![no_main]
 
 use drv_i2c_devices::max31790::*;
-use drv_i2c_devices::tmp116::*;
 use drv_i2c_devices::TempSensor;
+use ringbuf::*;
+use task_sensor_api::{Sensor, SensorId};
 use userlib::units::*;
 use userlib::*;
 
+mod control;
+
 task_slot!(I2C, i2c_driver);
+task_slot!(SENSOR, sensor);
 include!(concat!(env!("OUT_DIR"), "/i2c_config.rs"));
 
+#[derive(Copy, Clone, PartialEq)]
+enum Trace {
+    Temperature(usize, Celsius),
+    SensorFailed(usize),
+    Duty(usize, u8),
+    None,
+}
+
+ringbuf!(Trace, 32, Trace::None);
+
+/// How long, in ticks, to wait for the I2C server on each device operation,
+/// so that a wedged bus can't stop us from running the fans.
+const I2C_TIMEOUT: u32 = 500;
+
 fn convert_fahrenheit(temp: Celsius) -> f32 {
     temp.0 * (9.0 / 5.0) + 32.0
 }
@@ -38,36 +60,104 @@ fn print_temp<T: core::fmt::Display>(temp: Celsius, device: &T) {
     );
 }
 
-fn read_fans(fctrl: &Max31790) {
-    let mut ndx = 0;
-
-    for fan in 0..MAX_FANS {
-        let fan = Fan::new(fan).unwrap();
+/// Reads the speed of each fan, posting it to the sensor server if the fan
+/// has a sensor ID in `ids`.
+fn read_fans(fctrl: &Max31790, sensor: &Sensor, ids: &[SensorId]) {
+    for index in 0..MAX_FANS {
+        let fan = Fan::new(index).unwrap();
+        let id = ids.get(usize::from(index));
 
         match fctrl.fan_rpm(fan) {
-            Ok(rval) if rval.0 != 0 => {
-                sys_log!("{}: {}: RPM={}", fctrl, fan, rval.0);
+            Ok(rval) => {
+                if rval.0 != 0 {
+                    sys_log!("{}: {}: RPM={}", fctrl, fan, rval.0);
+                }
+                if let Some(&id) = id {
+                    sensor.post_speed(id, rval).unwrap();
+                }
             }
-            Ok(_) => {}
             Err(err) => {
                 sys_log!("{}: {}: failed: {:?}", fctrl, fan, err);
+                if let Some(&id) = id {
+                    sensor.post_error(id).unwrap();
+                }
             }
         }
+    }
+}
+
+/// Logs any fans that have faulted since we last looked, and clears their
+/// faults. Faults that persist are only logged once.
+fn check_fan_faults(fctrl: &Max31790, last: &mut Option<FanFaults>) {
+    let faults = match fctrl.fan_faults() {
+        Ok(faults) => faults,
+        Err(err) => {
+            sys_log!("{}: failed to read fan faults: {:?}", fctrl, err);
+            return;
+        }
+    };
+
+    for fan in 0..MAX_FANS {
+        let fan = Fan::new(fan).unwrap();
+
+        if faults.contains(fan) && !last.map_or(false, |l| l.contains(fan)) {
+            sys_log!("{}: {}: fan fault", fctrl, fan);
+        }
+    }
 
-        ndx = ndx + 1;
+    if !faults.is_empty() {
+        if let Err(err) = fctrl.clear_fan_faults(faults) {
+            sys_log!("{}: failed to clear fan faults: {:?}", fctrl, err);
+        }
     }
+
+    *last = Some(faults);
 }
 
 fn temp_read<E: core::fmt::Debug, T: TempSensor<E> + core::fmt::Display>(
     device: &T,
-) {
+) -> Option<Celsius> {
     match device.read_temperature() {
         Ok(temp) => {
             print_temp(temp, device);
+            Some(temp)
         }
 
         Err(err) => {
             sys_log!("{}: failed to read temp: {:?}", device, err);
+            None
+        }
+    }
+}
+
+/// Runs each zone's fan curve over the latest temperatures, and sets each fan
+/// to the highest duty cycle any of its zones asks for.
+fn control_fans(fctrl: &Max31790, temps: &[Option<Celsius>]) {
+    let mut duty: [Option<u8>; MAX_FANS as usize] = [None; MAX_FANS as usize];
+
+    for (index, zone) in control::ZONES.iter().enumerate() {
+        let zone_duty = match zone.duty(&temps[zone.sensors.clone()]) {
+            Some(duty) => duty,
+            None => {
+                sys_log!("{}: lost a sensor; fans to full speed", zone.name);
+                control::FAILSAFE_DUTY
+            }
+        };
+        ringbuf_entry!(Trace::Duty(index, zone_duty));
+
+        for &fan in zone.fans {
+            let fan = &mut duty[usize::from(fan)];
+            *fan = Some(fan.map_or(zone_duty, |d| d.max(zone_duty)));
+        }
+    }
+
+    for (index, duty) in duty.iter().enumerate() {
+        if let Some(duty) = *duty {
+            let fan = Fan::new(index as u8).unwrap();
+
+            if let Err(err) = fctrl.set_pwm(fan, PwmDuty(duty)) {
+                sys_log!("{}: {}: failed to set PWM: {:?}", fctrl, fan, err);
+            }
         }
     }
 }
@@ -75,28 +165,27 @@ fn temp_read<E: core::fmt::Debug, T: TempSensor<E> + core::fmt::Display>(
 #[export_name = "main"]
 fn main() -> ! {
     let task = I2C.get_task_id();
+    let sensor = Sensor::from(SENSOR.get_task_id());
     use i2c_config::devices;
 
     cfg_if::cfg_if! {
         if #[cfg(target_board = "gemini-bu-1")] {
-            let fctrl = Max31790::new(&devices::max31790(task)[0]);
-            let tmp116: [Tmp116; 0] = [];
+            let fctrl = Max31790::new(
+                &devices::max31790(task)[0].with_timeout(I2C_TIMEOUT),
+            );
+            let fan_sensors = &i2c_config::sensors::MAX31790_SPEED_SENSORS;
         } else if #[cfg(target_board = "gimlet-1")] {
-            let tmp116 = [
-                Tmp116::new(&devices::tmp117_front_zone1(task)),
-                Tmp116::new(&devices::tmp117_front_zone2(task)),
-                Tmp116::new(&devices::tmp117_front_zone3(task)),
-                Tmp116::new(&devices::tmp117_rear_zone1(task)),
-                Tmp116::new(&devices::tmp117_rear_zone2(task)),
-                Tmp116::new(&devices::tmp117_rear_zone3(task)),
-            ];
-
-            let fctrl = Max31790::new(&devices::max31790(task)[0]);
+            let fctrl = Max31790::new(
+                &devices::max31790(task)[0].with_timeout(I2C_TIMEOUT),
+            );
+            let fan_sensors = &i2c_config::sensors::MAX31790_SPEED_SENSORS;
         } else {
             cfg_if::cfg_if! {
                 if #[cfg(feature = "standalone")] {
-                    let fctrl = Max31790::new(&devices::mock(task));
-                    let tmp116: [Tmp116; 0] = [];
+                    let fctrl = Max31790::new(
+                        &devices::mock(task).with_timeout(I2C_TIMEOUT),
+                    );
+                    let fan_sensors: &[SensorId] = &[];
                 } else {
                     compile_error!("unknown board");
                 }
@@ -104,6 +193,10 @@ fn main() -> ! {
         }
     }
 
+    let sensors = control::sensors(task);
+    let mut temps = [None; control::NUM_SENSORS];
+    let mut faults = None;
+
     loop {
         match fctrl.initialize() {
             Ok(_) => {
@@ -112,18 +205,35 @@ fn main() -> ! {
             }
             Err(err) => {
                 sys_log!("{}: initialization failed: {:?}", fctrl, err);
+                task_jefe_api::check_in();
                 hl::sleep_for(1000);
             }
         }
     }
 
     loop {
-        read_fans(&fctrl);
+        read_fans(&fctrl, &sensor, fan_sensors);
+        check_fan_faults(&fctrl, &mut faults);
 
-        for device in &tmp116 {
-            temp_read(device);
+        for (index, device) in sensors.iter().enumerate() {
+            let id = control::SENSOR_IDS[index];
+
+            temps[index] = temp_read(device);
+            match temps[index] {
+                Some(temp) => {
+                    ringbuf_entry!(Trace::Temperature(index, temp));
+                    sensor.post_temperature(id, temp).unwrap();
+                }
+                None => {
+                    ringbuf_entry!(Trace::SensorFailed(index));
+                    sensor.post_error(id).unwrap();
+                }
+            }
         }
 
+        control_fans(&fctrl, &temps);
+
+        task_jefe_api::check_in();
         hl::sleep_for(1000);
     }
 }
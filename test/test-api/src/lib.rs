@@ -33,8 +33,17 @@ pub enum AssistOp {
     RefreshTaskIdOffByOne = 21,
     RefreshTaskIdOffByMany = 22,
     ReadNotifications = 23,
+    ReadTaskInfo = 24,
+    /// Holds the caller without replying, until the assistant is posted
+    /// `REPLY_DEFERRED`.
+    Defer = 25,
 }
 
+/// Notification that makes the assistant try to borrow from, and then reply
+/// to, a caller held by `AssistOp::Defer`. The result of the borrow becomes
+/// the response to `AssistOp::LastReply`.
+pub const REPLY_DEFERRED: u32 = 1 << 0;
+
 /// Operations that are performed by the test-suite
 #[derive(FromPrimitive)]
 pub enum SuiteOp {
@@ -148,6 +148,7 @@ fn main() -> ! {
     let mut stored_value = 0;
     let mut borrow_buffer = [0u8; 16];
     let mut posted_bits = 0;
+    let mut deferred: Option<hl::Caller<u32>> = None;
 
     let fatalops = [
         (AssistOp::BadMemory, badread as fn(u32)),
@@ -168,12 +169,26 @@ fn main() -> ! {
         hl::recv(
             &mut buffer,
             ALL_NOTIFICATIONS,
-            &mut posted_bits,
-            |posted_bits, notify_bits| {
-                // Just record any notifications so they can be read back out.
+            (&mut posted_bits, &mut last_reply, &mut deferred),
+            |(posted_bits, last_reply, deferred), notify_bits| {
+                // Record any notifications so they can be read back out.
                 *posted_bits |= notify_bits;
+
+                if notify_bits & REPLY_DEFERRED != 0 {
+                    if let Some(caller) = deferred.take() {
+                        let mut scratch = [0u8; 4];
+                        let (rc, _) = sys_borrow_read(
+                            caller.task_id(),
+                            0,
+                            0,
+                            &mut scratch,
+                        );
+                        *last_reply = rc;
+                        caller.reply(!0);
+                    }
+                }
             },
-            |posted_bits, op, msg| -> Result<(), u32> {
+            |(posted_bits, last_reply, deferred), op, msg| -> Result<(), u32> {
                 // Every incoming message uses the same payload type: it's
                 // always u32 -> u32.
                 let (msg, caller) = msg.fixed::<u32, u32>().ok_or(1u32)?;
@@ -200,7 +215,7 @@ fn main() -> ! {
                         // Ignore the result.
                     }
                     AssistOp::LastReply => {
-                        caller.reply(last_reply);
+                        caller.reply(*last_reply);
                     }
                     AssistOp::Store => {
                         caller.reply(stored_value);
@@ -243,6 +258,11 @@ fn main() -> ! {
                         let _ = kipc::read_task_status(*msg as usize);
                     }
 
+                    AssistOp::ReadTaskInfo => {
+                        caller.reply(0);
+                        let _ = kipc::read_task_info(*msg as usize);
+                    }
+
                     AssistOp::FaultTask => {
                         caller.reply(0);
                         let _ = kipc::fault_task(*msg as usize);
@@ -273,6 +293,9 @@ fn main() -> ! {
                     AssistOp::ReadNotifications => {
                         caller.reply(core::mem::replace(posted_bits, 0));
                     }
+                    AssistOp::Defer => {
+                        *deferred = Some(caller);
+                    }
                     _ => {
                         // Anything else should be fatal
                         for (which, func) in &fatalops {
@@ -346,7 +346,9 @@ fn log_fault(t: usize, fault: &FaultInfo) {
         }
 
         FaultInfo::Panic => {
-            sys_log!("Task #{} Panic!", t);
+            let mut buf = [0; PANIC_MESSAGE_LEN];
+            let len = kipc::read_panic_message(t, &mut buf);
+            sys_log!("Task #{} Panic! {}", t, printable(&buf[..len]));
         }
 
         FaultInfo::Injected(who) => {
@@ -355,6 +357,15 @@ fn log_fault(t: usize, fault: &FaultInfo) {
     }
 }
 
+/// Returns as much of `msg` as is valid UTF-8. Panic messages may have been
+/// truncated in the middle of a character.
+fn printable(msg: &[u8]) -> &str {
+    match core::str::from_utf8(msg) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&msg[..e.valid_up_to()]).unwrap(),
+    }
+}
+
 /// Scans the kernel's task table looking for a task that has fallen over.
 /// Prints any that are found.
 ///
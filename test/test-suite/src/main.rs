@@ -54,6 +54,8 @@ test_cases! {
     test_fault_divzero,
     test_fault_maxstatus,
     test_fault_badstatus,
+    test_fault_maxinfo,
+    test_fault_badinfo,
     test_fault_maxrestart,
     test_fault_badrestart,
     test_fault_maxinjection,
@@ -61,6 +63,7 @@ test_cases! {
     test_fault_superinjection,
     test_fault_selfinjection,
     test_panic,
+    test_panic_message,
     test_restart,
     test_restart_taskgen,
     test_borrow_info,
@@ -71,13 +74,20 @@ test_cases! {
     test_timer_advance,
     test_timer_notify,
     test_timer_notify_past,
+    test_timer_monotonic,
     test_task_status,
+    test_task_info,
+    test_task_stats,
+    test_stack_usage,
     test_task_fault_injection,
     test_refresh_task_id_basic,
     test_refresh_task_id_off_by_one,
     test_refresh_task_id_off_by_many,
     test_lpc55_flash_write,
     test_post,
+    test_send_async,
+    test_send_timeout,
+    test_send_timeout_deferred,
 }
 
 #[cfg(feature = "lpc55")]
@@ -297,6 +307,7 @@ fn test_fault_divzero() {
 fn test_fault_badtaskop(op: AssistOp, id: usize) {
     match op {
         AssistOp::ReadTaskStatus
+        | AssistOp::ReadTaskInfo
         | AssistOp::FaultTask
         | AssistOp::RestartTask => {}
         _ => {
@@ -318,6 +329,14 @@ fn test_fault_badstatus() {
     test_fault_badtaskop(AssistOp::ReadTaskStatus, NUM_TASKS);
 }
 
+fn test_fault_maxinfo() {
+    test_fault_badtaskop(AssistOp::ReadTaskInfo, usize::MAX);
+}
+
+fn test_fault_badinfo() {
+    test_fault_badtaskop(AssistOp::ReadTaskInfo, NUM_TASKS);
+}
+
 fn test_fault_maxrestart() {
     test_fault_badtaskop(AssistOp::RestartTask, usize::MAX);
 }
@@ -377,6 +396,41 @@ fn test_panic() {
     restart_assistant();
 }
 
+/// Tests that the kernel keeps a panicking task's message, and discards it
+/// when the task is restarted.
+fn test_panic_message() {
+    let assist = assist_task_id();
+    let mut msg = [0; PANIC_MESSAGE_LEN];
+
+    // No message before the assistant has panicked...
+    assert_eq!(kipc::read_panic_message(assist.index(), &mut msg), 0);
+
+    let mut response = 0_u32;
+    let (rc, _) = sys_send(
+        assist,
+        AssistOp::Panic as u16,
+        &0u32.to_le_bytes(),
+        response.as_bytes_mut(),
+        &[],
+    );
+    assert_eq!(rc, 0);
+
+    // ...some message after. Its exact contents depend on whether the
+    // assistant was built with `panic-messages`.
+    let len = kipc::read_panic_message(assist.index(), &mut msg);
+    assert!(len > 0);
+    assert!(len <= PANIC_MESSAGE_LEN);
+
+    // A short buffer gets a truncated message.
+    let mut short = [0; 2];
+    assert_eq!(kipc::read_panic_message(assist.index(), &mut short), 2);
+    assert_eq!(short, msg[..2]);
+
+    // And restarting the assistant clears it.
+    restart_assistant();
+    assert_eq!(kipc::read_panic_message(assist.index(), &mut msg), 0);
+}
+
 /// Tests that task restart works as expected.
 ///
 /// This is not a very thorough test right now.
@@ -722,6 +776,21 @@ fn test_timer_notify_past() {
     assert_eq!(rm.lease_count, 0);
 }
 
+/// Tests that the kernel timer never runs backwards. This is mostly of
+/// interest with tickless timekeeping, where the time is worked out from the
+/// hardware counter between interrupts.
+///
+/// Like `test_timer_advance`, this will fail by hanging if the timer is stuck.
+fn test_timer_monotonic() {
+    let start_time = sys_get_timer().now;
+    let mut last = start_time;
+    while last < start_time + 20 {
+        let now = sys_get_timer().now;
+        assert!(now >= last);
+        last = now;
+    }
+}
+
 /// Tests that floating point registers are properly saved and restored
 fn test_floating_point(highregs: bool) {
     unsafe fn read_regs(dest: &mut [u32; 16], highregs: bool) {
@@ -818,6 +887,60 @@ fn test_task_status() {
     }
 }
 
+fn test_task_info() {
+    assert_eq!(kipc::read_task_count(), NUM_TASKS);
+
+    let assist = assist_task_id();
+    let info = kipc::read_task_info(assist.index());
+    assert_eq!(info.name(), Some("assist"));
+    assert_eq!(info.generation, assist.generation());
+    assert!(matches!(info.state, TaskState::Healthy(..)));
+
+    // The assistant has at least flash and RAM regions, which must show up in
+    // its region table.
+    let rw = RegionAttributes::READ | RegionAttributes::WRITE;
+    let rx = RegionAttributes::READ | RegionAttributes::EXECUTE;
+    assert!(info.regions.iter().any(|r| r.attributes().contains(rw)));
+    assert!(info.regions.iter().any(|r| r.attributes().contains(rx)));
+
+    // And our own entry should name us.
+    let me = kipc::read_task_info(SUITE.get_task_index().into());
+    assert_eq!(me.name(), Some("suite"));
+}
+
+fn test_task_stats() {
+    let me = SUITE.get_task_index().into();
+
+    // We're clearly running, and this very call is a SEND to the kernel, which
+    // gets counted on the way in.
+    let before = kipc::read_task_stats(me);
+    assert!(before.context_switches > 0);
+    let sends = before.syscalls[Sysnum::Send as usize];
+    assert!(sends > 0);
+
+    let after = kipc::read_task_stats(me);
+    assert_eq!(after.syscalls[Sysnum::Send as usize], sends + 1);
+    assert!(after.run_ticks >= before.run_ticks);
+
+    // Faulting the assistant should be reflected in its fault count.
+    let assist = assist_task_id().index();
+    let faults = kipc::read_task_stats(assist).faults;
+    kipc::fault_task(assist);
+    assert_eq!(kipc::read_task_stats(assist).faults, faults + 1);
+}
+
+fn test_stack_usage() {
+    let usage = kipc::read_stack_usage(SUITE.get_task_index().into());
+    assert!(usage.size > 0);
+    assert!(usage.max_depth > 0);
+    assert!(usage.max_depth <= usage.size);
+
+    // A freshly restarted assistant has barely touched its stack.
+    restart_assistant();
+    let fresh = kipc::read_stack_usage(assist_task_id().index());
+    assert!(fresh.max_depth < fresh.size);
+}
+
 fn test_task_fault_injection() {
     // Assistant should be fine
     let status = kipc::read_task_status(ASSIST.get_task_index().into());
@@ -916,6 +1039,136 @@ fn test_post() {
     assert_eq!(response, ARBITRARY_MASK);
 }
 
+/// Tests that asynchronous sends are delivered to a waiting recipient, and
+/// fail without blocking otherwise.
+fn test_send_async() {
+    let assist = assist_task_id();
+
+    // The assistant is waiting in RECV, so this should be delivered. It
+    // outranks us, so it will have handled the message (and had its reply
+    // discarded) by the time we get control back.
+    const ARBITRARY_VALUE: u32 = 0xca11_ab1e;
+    let rc = sys_send_async(
+        assist,
+        AssistOp::Store as u16,
+        &ARBITRARY_VALUE.to_le_bytes(),
+    );
+    assert_eq!(rc, 0);
+
+    // Storing a new value gets us the one the asynchronous message stored.
+    let mut response = 0_u32;
+    let (rc, len) = sys_send(
+        assist,
+        AssistOp::Store as u16,
+        &0u32.to_le_bytes(),
+        response.as_bytes_mut(),
+        &[],
+    );
+    assert_eq!(rc, 0);
+    assert_eq!(len, 4);
+    assert_eq!(response, ARBITRARY_VALUE);
+
+    // A faulted assistant isn't receiving, so the send fails immediately.
+    kipc::fault_task(assist.index());
+    let rc = sys_send_async(assist, AssistOp::JustReply as u16, &[]);
+    assert_eq!(rc, WOULD_BLOCK);
+
+    // Once it's restarted, our old ID is stale.
+    restart_assistant();
+    let rc = sys_send_async(assist, AssistOp::JustReply as u16, &[]);
+    assert_eq!(rc, dead_response_code(assist_task_id().generation()));
+}
+
+/// Tests that SEND_TIMEOUT behaves like SEND when the recipient replies, and
+/// gives up when it doesn't.
+fn test_send_timeout() {
+    let assist = assist_task_id();
+
+    let challenge = 0xdeadbeef_u32;
+    let mut response = 0_u32;
+    let (rc, len) = sys_send_timeout(
+        assist,
+        AssistOp::JustReply as u16,
+        challenge.as_bytes(),
+        response.as_bytes_mut(),
+        &[],
+        100,
+    );
+    assert_eq!(rc, 0);
+    assert_eq!(len, 4);
+    assert_eq!(response, !challenge);
+
+    // A faulted assistant will never receive our message.
+    kipc::fault_task(assist.index());
+    let start = sys_get_timer().now;
+    let (rc, len) = sys_send_timeout(
+        assist,
+        AssistOp::JustReply as u16,
+        challenge.as_bytes(),
+        response.as_bytes_mut(),
+        &[],
+        10,
+    );
+    assert_eq!(rc, TIMED_OUT);
+    assert_eq!(len, 0);
+    assert!(sys_get_timer().now >= start + 10);
+
+    restart_assistant();
+}
+
+/// Tests that a recipient holding a message whose SEND_TIMEOUT expired can
+/// neither borrow from it nor have its late reply mistaken for the answer to
+/// a later message.
+fn test_send_timeout_deferred() {
+    let assist = assist_task_id();
+
+    let challenge = 0xdeadbeef_u32;
+    let lent = [0u8; 4];
+    let mut response = 0_u32;
+    let (rc, len) = sys_send_timeout(
+        assist,
+        AssistOp::Defer as u16,
+        challenge.as_bytes(),
+        response.as_bytes_mut(),
+        &[Lease::from(&lent[..])],
+        10,
+    );
+    assert_eq!(rc, TIMED_OUT);
+    assert_eq!(len, 0);
+
+    // Until the assistant replies to the abandoned message, it can't be given
+    // another one from us, even though it's waiting to receive.
+    let rc = sys_send_async(assist, AssistOp::JustReply as u16, &[]);
+    assert_eq!(rc, WOULD_BLOCK);
+    let (rc, len) = sys_send_timeout(
+        assist,
+        AssistOp::JustReply as u16,
+        challenge.as_bytes(),
+        response.as_bytes_mut(),
+        &[],
+        10,
+    );
+    assert_eq!(rc, TIMED_OUT);
+    assert_eq!(len, 0);
+
+    // Have the assistant borrow from, and reply to, the abandoned message.
+    // The borrow fails, and the reply is discarded, so the next message gets
+    // its own answer.
+    let post_rc = sys_post(assist, REPLY_DEFERRED);
+    assert_eq!(post_rc, 0);
+    let (rc, len) = sys_send(
+        assist,
+        AssistOp::LastReply as u16,
+        challenge.as_bytes(),
+        response.as_bytes_mut(),
+        &[],
+    );
+    assert_eq!(rc, 0);
+    assert_eq!(len, 4);
+    let suite = sys_refresh_task_id(SUITE.get_task_id());
+    assert_eq!(response, dead_response_code(suite.generation()));
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 // Frameworky bits follow
 